@@ -0,0 +1,218 @@
+//! `#[derive(Model)]` for the tables of moviebay, see the `Model` trait
+//! in `moviebay::model` for the attributes. The generated code refers
+//! to `crate::model` and `crate::sqlite`, so the derive only works
+//! inside moviebay.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Error, Field, Fields, GenericArgument, LitStr,
+    PathArguments, Result, Type,
+};
+
+#[proc_macro_derive(Model, attributes(model))]
+pub fn derive_model(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// `#[model(...)]` of a field
+#[derive(Default)]
+struct FieldAttrs {
+    id: bool,
+    key: bool,
+    now: bool,
+    skip: bool,
+    sql: Option<String>,
+}
+
+fn field_attrs(field: &Field) -> Result<FieldAttrs> {
+    let mut attrs = FieldAttrs::default();
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("model")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                attrs.id = true;
+            } else if meta.path.is_ident("key") {
+                attrs.key = true;
+            } else if meta.path.is_ident("now") {
+                attrs.now = true;
+            } else if meta.path.is_ident("skip") {
+                attrs.skip = true;
+            } else if meta.path.is_ident("sql") {
+                attrs.sql = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else {
+                return Err(meta.error("expected `id`, `key`, `now`, `skip` or `sql`"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(attrs)
+}
+
+/// The name given with `#[model(table = "...")]`
+fn table_name(input: &DeriveInput) -> Result<String> {
+    let mut table = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("model")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("table") {
+                table = Some(meta.value()?.parse::<LitStr>()?.value());
+                Ok(())
+            } else {
+                Err(meta.error("expected `table`"))
+            }
+        })?;
+    }
+    table.ok_or_else(|| Error::new_spanned(&input.ident, "missing #[model(table = \"...\")]"))
+}
+
+fn expand(input: &DeriveInput) -> Result<TokenStream2> {
+    let name = &input.ident;
+    let table = table_name(input)?;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new_spanned(name, "Model needs named fields")),
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                name,
+                "Model can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut id = None;
+    // index into `definitions` of the key field without `sql`
+    let mut inferred_key = None;
+    let mut columns = Vec::new();
+    let mut definitions = Vec::new();
+    let mut keys = Vec::new();
+    let mut now = Vec::new();
+    let mut inits = Vec::new();
+    let mut values = Vec::new();
+
+    for field in fields.iter() {
+        let ident = field.ident.as_ref().unwrap();
+        let attrs = field_attrs(field)?;
+        if attrs.skip {
+            inits.push(quote! { #ident: Default::default() });
+            continue;
+        }
+
+        let column = ident.to_string();
+        let definition = match &attrs.sql {
+            Some(sql) => sql.clone(),
+            None if attrs.id => "INTEGER PRIMARY KEY".to_owned(),
+            None => column_type(&field.ty).ok_or_else(|| {
+                Error::new_spanned(
+                    &field.ty,
+                    "unknown column type, set #[model(sql = \"...\")]",
+                )
+            })?,
+        };
+        if attrs.id {
+            if id.is_some() {
+                return Err(Error::new_spanned(ident, "only one field can be the id"));
+            }
+            id = Some(column.clone());
+        }
+        if attrs.id || attrs.key {
+            keys.push(column.clone());
+            if attrs.key && attrs.sql.is_none() {
+                inferred_key = Some(definitions.len());
+            }
+        }
+        if attrs.now {
+            now.push(column.clone());
+        }
+
+        let index = columns.len();
+        inits.push(quote! { #ident: row.get(#index)? });
+        values.push(quote! { crate::model::to_value(&self.#ident)? });
+        definitions.push(format!("{} {}", column, definition));
+        columns.push(column);
+    }
+
+    // a single key is declared with its column, `#[model(sql)]` has to
+    // declare it itself
+    if keys.len() > 1 {
+        definitions.push(format!("PRIMARY KEY ({})", keys.join(", ")));
+    } else if let (1, Some(i)) = (keys.len(), inferred_key) {
+        definitions[i].push_str(" PRIMARY KEY");
+    }
+
+    let id = match id {
+        Some(id) => quote! { Some(#id) },
+        None => quote! { None },
+    };
+    Ok(quote! {
+        impl crate::model::Model for #name {
+            const TABLE: &'static str = #table;
+            const COLUMNS: &'static [&'static str] = &[#(#columns),*];
+            const ID: Option<&'static str> = #id;
+            const KEY: &'static [&'static str] = &[#(#keys),*];
+            const NOW: &'static [&'static str] = &[#(#now),*];
+            const DEFINITIONS: &'static [&'static str] = &[#(#definitions),*];
+
+            fn from_row(row: &crate::sqlite::Row) -> crate::sqlite::SqlResult<Self> {
+                Ok(#name { #(#inits),* })
+            }
+
+            fn values(&self) -> crate::sqlite::SqlResult<Vec<crate::sqlite::Value>> {
+                Ok(vec![#(#values),*])
+            }
+        }
+    })
+}
+
+/// The column definition for a field of type `ty`, `NOT NULL` unless
+/// it is an `Option`
+fn column_type(ty: &Type) -> Option<String> {
+    match generic_arg(ty, "Option") {
+        Some(inner) => sql_type(inner).map(|t| t.to_owned()),
+        None => sql_type(ty).map(|t| format!("{} NOT NULL", t)),
+    }
+}
+
+fn sql_type(ty: &Type) -> Option<&'static str> {
+    let ident = last_ident(ty)?;
+    match ident.as_str() {
+        "bool" | "i8" | "i16" | "i32" | "i64" | "u8" | "u16" | "u32" => Some("INTEGER"),
+        "f32" | "f64" => Some("REAL"),
+        "String" => Some("TEXT"),
+        "Vec" => match generic_arg(ty, "Vec").and_then(last_ident) {
+            Some(inner) if inner == "u8" => Some("BLOB"),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn last_ident(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(path) => path.path.segments.last().map(|s| s.ident.to_string()),
+        _ => None,
+    }
+}
+
+/// `T` of `wrapper<T>`, e.g. of `Option<T>`
+fn generic_arg<'a>(ty: &'a Type, wrapper: &str) -> Option<&'a Type> {
+    let segment = match ty {
+        Type::Path(path) => path.path.segments.last()?,
+        _ => return None,
+    };
+    if segment.ident != wrapper {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => match args.args.first()? {
+            GenericArgument::Type(inner) => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
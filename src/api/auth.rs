@@ -0,0 +1,212 @@
+use super::router::Access;
+use crate::auth;
+use crate::jellyfin::Authorization;
+use crate::model::{SecretTable, Table, User, UserTable, URL_KEY};
+use crate::sqlite::SharedDb;
+use chrono::Utc;
+use hyper::{header, Body, Request, Response, StatusCode};
+use serde::Deserialize;
+
+/// Extracts the API token of a request. The token is taken from the
+/// `Authorization: Bearer <token>` header or, for clients which can't
+/// set headers such as `<video>` elements, from the `access_token`
+/// query parameter. The headers and parameters of the Jellyfin apps
+/// are understood as well.
+pub fn token(req: &Request<Body>) -> Option<String> {
+    let headers = req.headers();
+    if let Some(value) = headers.get(header::AUTHORIZATION) {
+        let value = value.to_str().ok()?;
+        if let Some(auth) = Authorization::parse(value) {
+            return auth.token;
+        }
+        let mut parts = value.splitn(2, ' ');
+        return match (parts.next(), parts.next()) {
+            (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                Some(token.trim().to_owned())
+            }
+            _ => None,
+        };
+    }
+    for name in ["x-emby-token", "x-mediabrowser-token"] {
+        if let Some(value) = headers.get(name) {
+            return value.to_str().ok().map(|v| v.trim().to_owned());
+        }
+    }
+    if let Some(value) = headers.get("x-emby-authorization") {
+        return Authorization::parse(value.to_str().ok()?)?.token;
+    }
+
+    let query = req.uri().query()?;
+    serde_urlencoded::from_str::<Vec<(String, String)>>(query)
+        .ok()?
+        .into_iter()
+        .find(|(k, _)| k == "access_token" || k.eq_ignore_ascii_case("api_key") || k == "ApiKey")
+        .map(|(_, v)| v)
+}
+
+/// The query parameters of a signed url
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct Signature {
+    user: i32,
+    /// Unix timestamp after which the url is invalid
+    expires: i64,
+    sig: String,
+}
+
+fn signed_message(path: &str, user_id: i32, expires: i64) -> String {
+    format!("{}\n{}\n{}", path, user_id, expires)
+}
+
+/// A url of `path` which lets players fetch it as `user_id` without a
+/// token until `expires`. It is only valid for exactly this path.
+pub fn signed_path(key: &str, path: &str, user_id: i32, expires: i64) -> String {
+    let sig = auth::sign(key, &signed_message(path, user_id, expires));
+    format!("{}?user={}&expires={}&sig={}", path, user_id, expires, sig)
+}
+
+/// Extracts the signature of a signed url
+pub fn signature(req: &Request<Body>) -> Option<Signature> {
+    serde_urlencoded::from_str(req.uri().query()?).ok()
+}
+
+/// Checks the signature of a request to `path`. Returns the user the
+/// url was signed for, or the error response to send.
+pub async fn authenticate_signed(
+    db: SharedDb,
+    path: &str,
+    signature: Signature,
+) -> Result<Option<User>, Response<Body>> {
+    let key = SecretTable::new(db.clone())
+        .get(URL_KEY)
+        .await
+        .map_err(|_| error(StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_SERVER_ERROR"))?;
+    let message = signed_message(path, signature.user, signature.expires);
+    if !auth::verify_signature(&key, &message, &signature.sig) {
+        return Err(error(StatusCode::UNAUTHORIZED, "Unauthorized"));
+    }
+    if signature.expires < Utc::now().timestamp() {
+        return Err(error(StatusCode::UNAUTHORIZED, "signed url expired"));
+    }
+    // the url dies with its user
+    let user = UserTable::new(db)
+        .by_id(signature.user)
+        .await
+        .map_err(|_| error(StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_SERVER_ERROR"))?;
+    match user {
+        Some(user) => Ok(Some(user)),
+        None => Err(error(StatusCode::UNAUTHORIZED, "Unauthorized")),
+    }
+}
+
+/// Checks if a request with `token` may call a route with `access`.
+/// Returns the authenticated user, `None` for public routes, or the
+/// error response to send.
+pub async fn authenticate(
+    db: SharedDb,
+    access: Access,
+    token: Option<String>,
+) -> Result<Option<User>, Response<Body>> {
+    if access == Access::Public {
+        return Ok(None);
+    }
+
+    let user = match token {
+        Some(token) => UserTable::new(db)
+            .by_token(&token)
+            .await
+            .map_err(|_| error(StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_SERVER_ERROR"))?,
+        None => None,
+    };
+
+    match user {
+        Some(user) if access == Access::Admin && !user.is_admin => {
+            Err(error(StatusCode::FORBIDDEN, "Forbidden"))
+        }
+        Some(user) => Ok(Some(user)),
+        None => Err(error(StatusCode::UNAUTHORIZED, "Unauthorized")),
+    }
+}
+
+fn error(status: StatusCode, msg: &'static str) -> Response<Body> {
+    let mut builder = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .status(status);
+    if status == StatusCode::UNAUTHORIZED {
+        builder = builder.header(header::WWW_AUTHENTICATE, "Bearer");
+    }
+    builder.body(Body::from(msg)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token() {
+        let req = Request::get("/movies/")
+            .header(header::AUTHORIZATION, "Bearer abc123")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(Some("abc123".to_owned()), token(&req));
+
+        let req = Request::get("/movies/")
+            .header(header::AUTHORIZATION, "Basic abc123")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(None, token(&req));
+
+        let req = Request::get("/stream/1?foo=bar&access_token=abc123")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(Some("abc123".to_owned()), token(&req));
+
+        let req = Request::get("/stream/1").body(Body::empty()).unwrap();
+        assert_eq!(None, token(&req));
+
+        let req = Request::get("/jellyfin/Users/Me")
+            .header(
+                header::AUTHORIZATION,
+                "MediaBrowser Client=\"Jellyfin Web\", Token=\"abc123\"",
+            )
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(Some("abc123".to_owned()), token(&req));
+
+        let req = Request::get("/jellyfin/Users/Me")
+            .header("X-Emby-Authorization", "Emby Client=Infuse, Token=abc123")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(Some("abc123".to_owned()), token(&req));
+
+        let req = Request::get("/jellyfin/Users/Me")
+            .header("X-Emby-Token", "abc123")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(Some("abc123".to_owned()), token(&req));
+
+        let req = Request::get("/jellyfin/Videos/1/stream.mp4?api_key=abc123")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(Some("abc123".to_owned()), token(&req));
+    }
+
+    #[test]
+    fn test_signature() {
+        let path = signed_path("key", "/stream/1", 2, 1000);
+        let req = Request::get(format!("{}&start=60", path))
+            .body(Body::empty())
+            .unwrap();
+        let sig = signature(&req).unwrap();
+        assert_eq!(2, sig.user);
+        assert_eq!(1000, sig.expires);
+        let message = signed_message("/stream/1", 2, 1000);
+        assert!(auth::verify_signature("key", &message, &sig.sig));
+        let message = signed_message("/stream/1", 3, 1000);
+        assert!(!auth::verify_signature("key", &message, &sig.sig));
+
+        let req = Request::get("/stream/1?start=60")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(None, signature(&req));
+    }
+}
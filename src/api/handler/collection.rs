@@ -0,0 +1,322 @@
+use crate::api::auth::signed_path;
+use crate::config::SharedCfg;
+use crate::model::{
+    Collection, CollectionKind, CollectionTable, MovieQuery, MovieTable, Page, PageQuery,
+    ProgressTable, SecretTable, Table, User, UserMovie, MAX_LIMIT, URL_KEY,
+};
+use crate::sqlite::SharedDb;
+use chrono::Utc;
+use hyper::{header, Body, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+/// Longest name of a collection
+const MAX_NAME: usize = 255;
+
+#[derive(Debug, Deserialize)]
+struct NewCollection {
+    name: String,
+    #[serde(default = "default_kind")]
+    kind: CollectionKind,
+    #[serde(default)]
+    shared: bool,
+    #[serde(default)]
+    filter: String,
+}
+
+fn default_kind() -> CollectionKind {
+    CollectionKind::Collection
+}
+
+/// Changes to a collection, missing fields are left alone
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CollectionChanges {
+    name: Option<String>,
+    shared: Option<bool>,
+    filter: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    movies: Vec<i32>,
+}
+
+/// A movie in the queue returned by "play all"
+#[derive(Debug, Serialize)]
+struct QueueItem {
+    id: i32,
+    title: String,
+    url: String,
+}
+
+/// Checks the name and the filter, which only smart collections have
+fn validate(collection: &Collection) -> Result<(), String> {
+    if collection.name.is_empty() || collection.name.chars().count() > MAX_NAME {
+        return Err(format!("name must have 1 to {} characters", MAX_NAME));
+    }
+    match collection.kind {
+        CollectionKind::Smart => MovieQuery::from_query(&collection.filter)
+            .map(|_| ())
+            .map_err(|e| format!("invalid filter: {}", e)),
+        _ if !collection.filter.is_empty() => {
+            Err("only smart collections have a filter".to_owned())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Loads a collection `user` may see, or the response to send instead
+pub(super) async fn visible(
+    db: SharedDb,
+    user: &User,
+    id: i32,
+) -> Result<Collection, Response<Body>> {
+    match CollectionTable::new(db).by_id(id).await {
+        Ok(Some(collection)) if collection.is_visible(user.id) => Ok(collection),
+        Ok(_) => Err(error!(StatusCode::NOT_FOUND, "Not Found")),
+        Err(e) => {
+            log::error!("{}", e);
+            Err(error!(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "INTERNAL_SERVER_ERROR"
+            ))
+        }
+    }
+}
+
+/// Loads a collection owned by `user`, or the response to send instead.
+/// Shared collections can be seen but not changed by other users.
+async fn owned(db: SharedDb, user: &User, id: i32) -> Result<Collection, Response<Body>> {
+    let collection = visible(db, user, id).await?;
+    if collection.user_id != user.id {
+        return Err(error!(StatusCode::FORBIDDEN, "Forbidden"));
+    }
+    Ok(collection)
+}
+
+/// The collections of the user and the ones shared by others
+pub async fn get_collections(db: SharedDb, user: User) -> Result<Response<Body>, hyper::Error> {
+    let collections = try_or_500!(CollectionTable::new(db).visible(user.id).await);
+    Ok(json!(&collections))
+}
+
+pub async fn post_collection(
+    db: SharedDb,
+    user: User,
+    body: Body,
+) -> Result<Response<Body>, hyper::Error> {
+    let new: NewCollection = from_json!(body);
+    let collection = Collection {
+        id: 0,
+        user_id: user.id,
+        name: new.name.trim().to_owned(),
+        kind: new.kind,
+        shared: new.shared,
+        filter: new.filter,
+        created_at: 0,
+    };
+    if let Err(msg) = validate(&collection) {
+        return Ok(error!(StatusCode::BAD_REQUEST, msg));
+    }
+
+    let collection = try_or_500!(CollectionTable::new(db).create(collection).await);
+    Ok(json!(&collection))
+}
+
+pub async fn get_collection(
+    db: SharedDb,
+    user: User,
+    id: i32,
+) -> Result<Response<Body>, hyper::Error> {
+    match visible(db, &user, id).await {
+        Ok(collection) => Ok(json!(&collection)),
+        Err(resp) => Ok(resp),
+    }
+}
+
+/// Renames a collection, shares it or changes its filter
+pub async fn put_collection(
+    db: SharedDb,
+    user: User,
+    id: i32,
+    body: Body,
+) -> Result<Response<Body>, hyper::Error> {
+    let mut collection = match owned(db.clone(), &user, id).await {
+        Ok(collection) => collection,
+        Err(resp) => return Ok(resp),
+    };
+    let changes: CollectionChanges = from_json!(body);
+    if let Some(name) = changes.name {
+        collection.name = name.trim().to_owned();
+    }
+    if let Some(shared) = changes.shared {
+        collection.shared = shared;
+    }
+    if let Some(filter) = changes.filter {
+        collection.filter = filter;
+    }
+    if let Err(msg) = validate(&collection) {
+        return Ok(error!(StatusCode::BAD_REQUEST, msg));
+    }
+
+    try_or_500!(CollectionTable::new(db).update(collection.clone()).await);
+    Ok(json!(&collection))
+}
+
+pub async fn delete_collection(
+    db: SharedDb,
+    user: User,
+    id: i32,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(resp) = owned(db.clone(), &user, id).await {
+        return Ok(resp);
+    }
+    try_or_500!(CollectionTable::new(db).delete(id).await);
+    Ok(empty!(StatusCode::NO_CONTENT))
+}
+
+/// The movies in a collection with the watch state of the user, paged
+pub async fn get_collection_movies(
+    db: SharedDb,
+    user: User,
+    id: i32,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let query = match PageQuery::from_query(&query) {
+        Ok(query) => query,
+        Err(e) => return Ok(error!(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+    let collection = match visible(db.clone(), &user, id).await {
+        Ok(collection) => collection,
+        Err(resp) => return Ok(resp),
+    };
+    let page = try_or_500!(
+        CollectionTable::new(db.clone())
+            .movies(&collection, user.id, query.limit(), query.offset)
+            .await
+    );
+    let ids = page.items.iter().map(|m| m.id).collect();
+    let states = try_or_500!(ProgressTable::new(db).states(user.id, ids).await);
+
+    Ok(json!(&Page {
+        total: page.total,
+        limit: page.limit,
+        offset: page.offset,
+        items: UserMovie::zip(page.items, &states),
+    }))
+}
+
+/// Adds a movie to (`PUT`) or removes it from (`DELETE`) a collection
+/// or playlist. Smart collections have no movies of their own.
+pub async fn set_collection_movie(
+    db: SharedDb,
+    user: User,
+    id: i32,
+    movie_id: i32,
+    add: bool,
+) -> Result<Response<Body>, hyper::Error> {
+    let collection = match owned(db.clone(), &user, id).await {
+        Ok(collection) => collection,
+        Err(resp) => return Ok(resp),
+    };
+    if collection.kind == CollectionKind::Smart {
+        return Ok(error!(
+            StatusCode::BAD_REQUEST,
+            "movies of smart collections are chosen by the filter"
+        ));
+    }
+    if try_or_500!(
+        MovieTable::new(db.clone())
+            .for_user(movie_id, user.id)
+            .await
+    )
+    .is_none()
+    {
+        return Ok(error!(StatusCode::NOT_FOUND, "Not Found"));
+    }
+
+    let table = CollectionTable::new(db);
+    if add {
+        try_or_500!(table.add(id, movie_id).await);
+    } else {
+        try_or_500!(table.remove(id, movie_id).await);
+    }
+    Ok(empty!(StatusCode::NO_CONTENT))
+}
+
+/// Puts the movies of a collection in a new order, the body lists all
+/// of their ids
+pub async fn put_collection_order(
+    db: SharedDb,
+    user: User,
+    id: i32,
+    body: Body,
+) -> Result<Response<Body>, hyper::Error> {
+    let collection = match owned(db.clone(), &user, id).await {
+        Ok(collection) => collection,
+        Err(resp) => return Ok(resp),
+    };
+    if collection.kind == CollectionKind::Smart {
+        return Ok(error!(
+            StatusCode::BAD_REQUEST,
+            "smart collections are ordered by their filter"
+        ));
+    }
+    let order: Order = from_json!(body);
+    if !try_or_500!(CollectionTable::new(db).reorder(id, order.movies).await) {
+        return Ok(error!(
+            StatusCode::BAD_REQUEST,
+            "expected every movie of the collection once"
+        ));
+    }
+    Ok(empty!(StatusCode::NO_CONTENT))
+}
+
+/// Play all: one page of the movies of a collection with signed stream
+/// urls, by default all up to `MAX_LIMIT`. `total` tells players if
+/// there are more to fetch with `offset`.
+pub async fn get_collection_play(
+    db: SharedDb,
+    config: SharedCfg,
+    user: User,
+    id: i32,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let query = match PageQuery::from_query(&query) {
+        Ok(query) => query,
+        Err(e) => return Ok(error!(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+    let collection = match visible(db.clone(), &user, id).await {
+        Ok(collection) => collection,
+        Err(resp) => return Ok(resp),
+    };
+    let limit = query.limit.unwrap_or(MAX_LIMIT).min(MAX_LIMIT);
+    let page = try_or_500!(
+        CollectionTable::new(db.clone())
+            .movies(&collection, user.id, limit, query.offset)
+            .await
+    );
+
+    let key = try_or_500!(SecretTable::new(db).get(URL_KEY).await);
+    let expires = Utc::now().timestamp() + config.auth.signed_url_ttl as i64;
+    let items = page
+        .items
+        .into_iter()
+        .map(|movie| QueueItem {
+            url: config.server.url(&signed_path(
+                &key,
+                &format!("/stream/{}", movie.id),
+                user.id,
+                expires,
+            )),
+            id: movie.id,
+            title: movie.title,
+        })
+        .collect::<Vec<_>>();
+    Ok(json!(&Page {
+        total: page.total,
+        limit: page.limit,
+        offset: page.offset,
+        items,
+    }))
+}
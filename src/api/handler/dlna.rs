@@ -0,0 +1,528 @@
+use crate::api::auth::signed_path;
+use crate::config::SharedCfg;
+use crate::dlna::content::{didl, Entry, Links, Object, PROTOCOL_INFO};
+use crate::dlna::soap::{self, Fault};
+use crate::dlna::{self, Dlna, Session};
+use crate::model::{
+    CollectionTable, Movie, MovieQuery, MovieTable, Page, SecretTable, Table, MAX_LIMIT, URL_KEY,
+};
+use crate::sqlite::SharedDb;
+use crate::thumbnail;
+use chrono::Utc;
+use hyper::{header, Body, Response, StatusCode};
+use std::fmt::Display;
+
+type Results = Vec<(&'static str, String)>;
+
+/// Seconds the links of a Browse result stay valid. Renderers browse
+/// again before they play, a stream only has to start in time.
+const LINK_TTL: i64 = 4 * 3600;
+
+fn xml(status: StatusCode, xml: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/xml; charset=\"utf-8\"")
+        .body(Body::from(xml))
+        .unwrap()
+}
+
+/// The device description, see the `LOCATION` of the SSDP announcements
+pub async fn get_description(dlna: Dlna) -> Result<Response<Body>, hyper::Error> {
+    match dlna.session() {
+        Some(session) => Ok(xml(StatusCode::OK, dlna::description(&session))),
+        None => Ok(error!(StatusCode::NOT_FOUND, "Not Found")),
+    }
+}
+
+/// The description of the actions of a service
+pub async fn get_scpd(dlna: Dlna, service: String) -> Result<Response<Body>, hyper::Error> {
+    if dlna.session().is_none() {
+        return Ok(error!(StatusCode::NOT_FOUND, "Not Found"));
+    }
+    let scpd = match service.as_ref() {
+        "ContentDirectory" => dlna::CONTENT_DIRECTORY_SCPD,
+        _ => dlna::CONNECTION_MANAGER_SCPD,
+    };
+    Ok(xml(StatusCode::OK, scpd.to_owned()))
+}
+
+/// Calls an action of a service. Renderers don't authenticate, they
+/// see what the DLNA user may see and stream from urls signed for it.
+pub async fn post_control(
+    db: SharedDb,
+    config: SharedCfg,
+    dlna: Dlna,
+    service: String,
+    host: Option<String>,
+    action: Option<String>,
+    body: Body,
+) -> Result<Response<Body>, hyper::Error> {
+    let session = match dlna.session() {
+        Some(session) => session,
+        None => return Ok(error!(StatusCode::NOT_FOUND, "Not Found")),
+    };
+    let body = hyper::body::to_bytes(body).await?;
+    let body = String::from_utf8_lossy(&body);
+    let action = match action.as_deref().and_then(soap::action) {
+        Some(action) => action.to_owned(),
+        None => {
+            return Ok(xml(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                soap::fault(Fault::InvalidAction),
+            ))
+        }
+    };
+
+    let (urn, results) = match (service.as_ref(), action.as_ref()) {
+        ("ContentDirectory", "Browse") => {
+            let results = match links(&db, &config, &session, host).await {
+                Ok(links) => browse(&db, &config, &session, &links, &body, dlna.update_id()).await,
+                Err(fault) => Err(fault),
+            };
+            (dlna::CONTENT_DIRECTORY, results)
+        }
+        ("ContentDirectory", "GetSearchCapabilities") => (
+            dlna::CONTENT_DIRECTORY,
+            Ok(vec![("SearchCaps", String::new())]),
+        ),
+        ("ContentDirectory", "GetSortCapabilities") => (
+            dlna::CONTENT_DIRECTORY,
+            Ok(vec![("SortCaps", String::new())]),
+        ),
+        ("ContentDirectory", "GetSystemUpdateID") => (
+            dlna::CONTENT_DIRECTORY,
+            Ok(vec![("Id", dlna.update_id().to_string())]),
+        ),
+        ("ConnectionManager", "GetProtocolInfo") => (
+            dlna::CONNECTION_MANAGER,
+            Ok(vec![
+                ("Source", PROTOCOL_INFO.to_owned()),
+                ("Sink", String::new()),
+            ]),
+        ),
+        ("ConnectionManager", "GetCurrentConnectionIDs") => (
+            dlna::CONNECTION_MANAGER,
+            Ok(vec![("ConnectionIDs", "0".to_owned())]),
+        ),
+        ("ConnectionManager", "GetCurrentConnectionInfo") => {
+            (dlna::CONNECTION_MANAGER, connection_info(&body))
+        }
+        _ => {
+            return Ok(xml(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                soap::fault(Fault::InvalidAction),
+            ))
+        }
+    };
+
+    match results {
+        Ok(results) => Ok(xml(StatusCode::OK, soap::response(urn, &action, &results))),
+        Err(fault) => Ok(xml(StatusCode::INTERNAL_SERVER_ERROR, soap::fault(fault))),
+    }
+}
+
+/// Signs the links of movies for the DLNA user. Renderers reach the
+/// streams where they reached the server.
+async fn links(
+    db: &SharedDb,
+    config: &SharedCfg,
+    session: &Session,
+    host: Option<String>,
+) -> Result<Links, Fault> {
+    let key = SecretTable::new(db.clone())
+        .get(URL_KEY)
+        .await
+        .map_err(failed)?;
+    let host = host.unwrap_or_else(|| format!("{}:{}", config.server.bind, config.server.port));
+    let base = format!("http://{}", host);
+    let user_id = session.user_id;
+    let expires = Utc::now().timestamp() + LINK_TTL;
+    Ok(Links::new(move |path| {
+        format!("{}{}", base, signed_path(&key, path, user_id, expires))
+    }))
+}
+
+/// Only the connection `0` exists, streams are plain HTTP requests
+fn connection_info(body: &str) -> Result<Results, Fault> {
+    if soap::arg(body, "ConnectionID").as_deref() != Some("0") {
+        return Err(Fault::InvalidArgs);
+    }
+    Ok(vec![
+        ("RcsID", "-1".to_owned()),
+        ("AVTransportID", "-1".to_owned()),
+        ("ProtocolInfo", String::new()),
+        ("PeerConnectionManager", String::new()),
+        ("PeerConnectionID", "-1".to_owned()),
+        ("Direction", "Output".to_owned()),
+        ("Status", "OK".to_owned()),
+    ])
+}
+
+fn failed<E: Display>(e: E) -> Fault {
+    log::error!("{}", e);
+    Fault::ActionFailed
+}
+
+/// Parses a numeric argument, `default` if it is missing
+fn number(body: &str, name: &str, default: u32) -> Result<u32, Fault> {
+    match soap::arg(body, name) {
+        Some(value) => value.trim().parse().map_err(|_| Fault::InvalidArgs),
+        None => Ok(default),
+    }
+}
+
+async fn browse(
+    db: &SharedDb,
+    config: &SharedCfg,
+    session: &Session,
+    links: &Links,
+    body: &str,
+    update_id: u32,
+) -> Result<Results, Fault> {
+    let object = soap::arg(body, "ObjectID")
+        .ok_or(Fault::InvalidArgs)?
+        .parse::<Object>()
+        .map_err(|_| Fault::NoSuchObject)?;
+    let start = number(body, "StartingIndex", 0)?;
+    // 0 asks for all children
+    let count = match number(body, "RequestedCount", 0)? {
+        0 => MAX_LIMIT,
+        count => count.min(MAX_LIMIT),
+    };
+
+    let (entries, total) = match soap::arg(body, "BrowseFlag").as_deref() {
+        Some("BrowseMetadata") => (vec![metadata(db, session, object).await?], 1),
+        Some("BrowseDirectChildren") => children(db, session, object, start, count).await?,
+        _ => return Err(Fault::InvalidArgs),
+    };
+    // the poster route only serves generated posters
+    let entries = entries
+        .into_iter()
+        .map(|entry| match entry {
+            Entry::Item { movie, parent, .. } => Entry::Item {
+                poster: thumbnail::poster_path(config, movie.id).is_file(),
+                movie,
+                parent,
+            },
+            container => container,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(vec![
+        ("Result", didl(&entries, links)),
+        ("NumberReturned", entries.len().to_string()),
+        ("TotalMatches", total.to_string()),
+        ("UpdateID", update_id.to_string()),
+    ])
+}
+
+fn container(object: Object, title: &str, child_count: Option<i64>) -> Entry {
+    Entry::Container {
+        object,
+        title: title.to_owned(),
+        child_count,
+    }
+}
+
+/// The number of movies the DLNA user may see
+async fn movie_count(db: &SharedDb, user_id: i32) -> Result<i64, Fault> {
+    let query = MovieQuery {
+        limit: Some(0),
+        user_id: Some(user_id),
+        ..MovieQuery::default()
+    };
+    let page = MovieTable::new(db.clone())
+        .query(query)
+        .await
+        .map_err(failed)?;
+    Ok(page.total)
+}
+
+/// An entry for the object itself
+async fn metadata(db: &SharedDb, session: &Session, object: Object) -> Result<Entry, Fault> {
+    let user_id = session.user_id;
+    let entry = match object {
+        Object::Root => container(Object::Root, &session.name, Some(3)),
+        Object::Libraries => {
+            let libraries = MovieTable::new(db.clone())
+                .libraries(user_id)
+                .await
+                .map_err(failed)?;
+            container(Object::Libraries, "Libraries", Some(libraries.len() as i64))
+        }
+        Object::Library(name) => {
+            let libraries = MovieTable::new(db.clone())
+                .libraries(user_id)
+                .await
+                .map_err(failed)?;
+            let count = libraries
+                .into_iter()
+                .find(|(library, _)| *library == name)
+                .map(|(_, count)| count)
+                .ok_or(Fault::NoSuchObject)?;
+            container(Object::Library(name.clone()), &name, Some(count))
+        }
+        Object::Collections => {
+            let collections = CollectionTable::new(db.clone())
+                .visible(user_id)
+                .await
+                .map_err(failed)?;
+            container(
+                Object::Collections,
+                "Collections",
+                Some(collections.len() as i64),
+            )
+        }
+        Object::Collection(id) => {
+            let collection = CollectionTable::new(db.clone())
+                .by_id(id)
+                .await
+                .map_err(failed)?
+                .filter(|c| c.is_visible(user_id))
+                .ok_or(Fault::NoSuchObject)?;
+            container(Object::Collection(id), &collection.name, None)
+        }
+        Object::Movies => {
+            let count = movie_count(db, user_id).await?;
+            container(Object::Movies, "All Movies", Some(count))
+        }
+        Object::Movie(id) => {
+            let movie = MovieTable::new(db.clone())
+                .for_user(id, user_id)
+                .await
+                .map_err(failed)?
+                .ok_or(Fault::NoSuchObject)?;
+            Entry::Item {
+                movie,
+                parent: Object::Movies,
+                poster: false,
+            }
+        }
+    };
+    Ok(entry)
+}
+
+/// One page of the entries in a container, with their total number
+async fn children(
+    db: &SharedDb,
+    session: &Session,
+    object: Object,
+    start: u32,
+    count: u32,
+) -> Result<(Vec<Entry>, i64), Fault> {
+    let user_id = session.user_id;
+    let (skip, take) = (start as usize, count as usize);
+    let movies = |page: Page<Movie>, parent: Object| {
+        let entries = page
+            .items
+            .into_iter()
+            .map(|movie| Entry::Item {
+                movie,
+                parent: parent.clone(),
+                poster: false,
+            })
+            .collect();
+        (entries, page.total)
+    };
+
+    let children = match object {
+        Object::Root => {
+            let mut entries = Vec::new();
+            for object in [Object::Libraries, Object::Collections, Object::Movies] {
+                entries.push(metadata(db, session, object).await?);
+            }
+            (entries.into_iter().skip(skip).take(take).collect(), 3)
+        }
+        Object::Libraries => {
+            let libraries = MovieTable::new(db.clone())
+                .libraries(user_id)
+                .await
+                .map_err(failed)?;
+            let total = libraries.len() as i64;
+            let entries = libraries
+                .into_iter()
+                .skip(skip)
+                .take(take)
+                .map(|(name, count)| container(Object::Library(name.clone()), &name, Some(count)))
+                .collect();
+            (entries, total)
+        }
+        Object::Library(name) => {
+            let query = MovieQuery {
+                limit: Some(count),
+                offset: start,
+                library: Some(name.clone()),
+                user_id: Some(user_id),
+                ..MovieQuery::default()
+            };
+            let page = MovieTable::new(db.clone())
+                .query(query)
+                .await
+                .map_err(failed)?;
+            movies(page, Object::Library(name))
+        }
+        Object::Collections => {
+            let collections = CollectionTable::new(db.clone())
+                .visible(user_id)
+                .await
+                .map_err(failed)?;
+            let total = collections.len() as i64;
+            let entries = collections
+                .into_iter()
+                .skip(skip)
+                .take(take)
+                .map(|c| container(Object::Collection(c.id), &c.name, None))
+                .collect();
+            (entries, total)
+        }
+        Object::Collection(id) => {
+            let table = CollectionTable::new(db.clone());
+            let collection = table
+                .by_id(id)
+                .await
+                .map_err(failed)?
+                .filter(|c| c.is_visible(user_id))
+                .ok_or(Fault::NoSuchObject)?;
+            let page = table
+                .movies(&collection, user_id, count, start)
+                .await
+                .map_err(failed)?;
+            movies(page, Object::Collection(id))
+        }
+        Object::Movies => {
+            let query = MovieQuery {
+                limit: Some(count),
+                offset: start,
+                user_id: Some(user_id),
+                ..MovieQuery::default()
+            };
+            let page = MovieTable::new(db.clone())
+                .query(query)
+                .await
+                .map_err(failed)?;
+            movies(page, Object::Movies)
+        }
+        Object::Movie(_) => (Vec::new(), 0),
+    };
+    Ok(children)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::auth::{authenticate_signed, signature};
+    use crate::config::{Config, DatabaseConfig};
+    use crate::events::Events;
+    use crate::model::{migrate, User, UserTable};
+    use crate::sqlite::Runtime;
+    use hyper::Request;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    const MEDIA_SERVER: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+
+    const BROWSE: &str = "<?xml version=\"1.0\"?>\
+        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+        s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+        <u:Browse xmlns:u=\"urn:schemas-upnp-org:service:ContentDirectory:1\">\
+        <ObjectID>movies</ObjectID><BrowseFlag>BrowseDirectChildren</BrowseFlag>\
+        <Filter>*</Filter><StartingIndex>0</StartingIndex><RequestedCount>10</RequestedCount>\
+        <SortCriteria></SortCriteria></u:Browse></s:Body></s:Envelope>";
+
+    #[test]
+    fn test_loopback() {
+        let func = async {
+            let (db, rt) = Runtime::channel(DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            });
+            rt.run();
+            migrate(db.clone()).await.unwrap();
+            let users = UserTable::new(db.clone());
+            users.save(User::new("tv", "secret", false)).await.unwrap();
+            let user = users.by_name("tv").await.unwrap().unwrap();
+            MovieTable::new(db.clone())
+                .save(Movie {
+                    id: 0,
+                    tmdb_id: 0,
+                    title: "Heat".to_owned(),
+                    overview: "".to_owned(),
+                    release_year: 1995,
+                    file_path: "/m/heat.mkv".to_owned(),
+                    poster_path: "".to_owned(),
+                    backdrop_path: "".to_owned(),
+                    rating: 0.0,
+                    resolution: 1080,
+                    library: "movies".to_owned(),
+                    added_at: 0,
+                    genres: vec![],
+                })
+                .await
+                .unwrap();
+            let mut config = Config::default();
+            config.server.bind = "127.0.0.1".to_owned();
+            config.server.port = 3000;
+            config.dlna.enabled = true;
+            let config = Arc::new(config);
+
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let dlna = Dlna::new();
+            dlna.announce(&config, db.clone(), Events::new(), user.clone(), socket)
+                .await
+                .unwrap();
+
+            let mut client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let search = format!(
+                "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\n\
+                 MAN: \"ssdp:discover\"\r\nMX: 1\r\nST: {}\r\n\r\n",
+                MEDIA_SERVER
+            );
+            client.send_to(search.as_bytes(), &addr).await.unwrap();
+            let mut buf = [0u8; 2048];
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let response = String::from_utf8_lossy(&buf[..len]);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(
+                response.contains("\r\nLOCATION: http://127.0.0.1:3000/dlna/description.xml\r\n")
+            );
+            assert!(response.contains(&format!("\r\nST: {}\r\n", MEDIA_SERVER)));
+
+            let response = post_control(
+                db.clone(),
+                config.clone(),
+                dlna.clone(),
+                "ContentDirectory".to_owned(),
+                Some("127.0.0.1:3000".to_owned()),
+                Some(format!("\"{}#Browse\"", dlna::CONTENT_DIRECTORY)),
+                Body::from(BROWSE),
+            )
+            .await
+            .unwrap();
+            assert_eq!(StatusCode::OK, response.status());
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let didl = soap::arg(&String::from_utf8_lossy(&body), "Result").unwrap();
+            assert!(didl.contains("<dc:title>Heat</dc:title>"));
+
+            // the stream link is signed for the DLNA user
+            let start = didl.find("http://127.0.0.1:3000/stream/1?").unwrap();
+            let end = start + didl[start..].find("</res>").unwrap();
+            let url = didl[start..end].replace("&amp;", "&");
+            let req = Request::get(url).body(Body::empty()).unwrap();
+            let signed = authenticate_signed(db.clone(), "/stream/1", signature(&req).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(Some(user.id), signed.map(|u| u.id));
+
+            dlna.stop().await;
+            assert!(dlna.session().is_none());
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+}
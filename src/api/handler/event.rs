@@ -0,0 +1,81 @@
+use crate::events::{Audience, Event, Events};
+use crate::logging;
+use crate::model::{MovieTable, User};
+use crate::sqlite::SharedDb;
+use hyper::body::Bytes;
+use hyper::{header, Body, Response};
+use log::{debug, warn};
+use std::time::Duration;
+use tokio::sync::broadcast::RecvError;
+
+/// Idle streams get a comment this often, so proxies keep them open and
+/// closed connections are noticed
+const KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// Whether `user` may receive `event`
+async fn is_visible(db: &SharedDb, user: &User, event: &Event) -> bool {
+    match event.audience() {
+        Audience::Everyone => true,
+        Audience::Admins => user.is_admin,
+        Audience::User(id) => user.id == id || user.is_admin,
+        Audience::Private(id) => user.id == id,
+        Audience::Movie(id) => match MovieTable::new(db.clone()).for_user(id, user.id).await {
+            Ok(movie) => movie.is_some(),
+            Err(e) => {
+                warn!("{}", e);
+                false
+            }
+        },
+    }
+}
+
+/// Server-Sent Events of the changes the user may see, until the client
+/// disconnects or the server shuts down. Each event is one `data` line
+/// of json with its `type`.
+pub async fn get_events(
+    db: SharedDb,
+    events: Events,
+    user: User,
+) -> Result<Response<Body>, hyper::Error> {
+    let mut rx = events.subscribe();
+    let (mut tx, body) = Body::channel();
+
+    let id = logging::request_id().unwrap_or_default();
+    tokio::spawn(logging::scope(id, async move {
+        loop {
+            let event = match tokio::time::timeout(KEEPALIVE, rx.recv()).await {
+                Ok(Ok(event)) => event,
+                Ok(Err(RecvError::Lagged(missed))) => Event::Lagged { missed },
+                Ok(Err(RecvError::Closed)) => break,
+                Err(_) => {
+                    if tx.send_data(Bytes::from(": keepalive\n\n")).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            if !is_visible(&db, &user, &event).await {
+                continue;
+            }
+            let json = match serde_json::to_string(&event) {
+                Ok(json) => json,
+                Err(e) => {
+                    warn!("could not serialize event: {}", e);
+                    continue;
+                }
+            };
+            let data = Bytes::from(format!("data: {}\n\n", json));
+            if tx.send_data(data).await.is_err() || event == Event::Shutdown {
+                break;
+            }
+        }
+        debug!("event stream of {} closed", user.name);
+    }));
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(body)
+        .unwrap())
+}
@@ -0,0 +1,595 @@
+use super::progress;
+use super::thumbnail::image;
+use super::user::authenticate;
+use crate::config::SharedCfg;
+use crate::events::{Event, Events};
+use crate::jellyfin::types::{
+    AuthenticationResult, Item, Items, MediaSource, PlaybackInfo, SessionInfo, SystemInfo,
+    UserData, UserDto,
+};
+use crate::jellyfin::{self, library_id, parse_id, server_id, Authorization};
+use crate::model::{
+    match_expr, Movie, MovieQuery, MovieTable, Order, ProgressTable, Result, SortBy, Table,
+    TrickplayTable, User, UserTable, WatchState, WatchlistEntry, WatchlistTable, MAX_LIMIT,
+};
+use crate::sqlite::SharedDb;
+use crate::thumbnail;
+use crate::tmdb;
+use hyper::{header, Body, Response, StatusCode};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Number of movies in the latest additions of a library
+const LATEST_LIMIT: u32 = 20;
+
+#[derive(Debug, Deserialize)]
+struct Credentials {
+    #[serde(rename = "Username")]
+    name: String,
+    #[serde(rename = "Pw", default)]
+    password: String,
+}
+
+/// A report of the player, only the item and position are used
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Playing {
+    item_id: String,
+    position_ticks: Option<i64>,
+}
+
+/// The parameters of a query string by lower case name, the apps don't
+/// agree on the case
+fn params(query: &str) -> HashMap<String, String> {
+    serde_urlencoded::from_str::<Vec<(String, String)>>(query)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(k, v)| (k.to_ascii_lowercase(), v))
+        .collect()
+}
+
+fn not_found() -> Response<Body> {
+    error!(StatusCode::NOT_FOUND, "Not Found")
+}
+
+/// The tag of the primary image of a movie, the apps add it to the
+/// image urls to cache them
+fn poster_tag(config: &SharedCfg, movie: &Movie) -> Option<String> {
+    if !movie.poster_path.is_empty() {
+        Some(movie.poster_path.trim_start_matches('/').to_owned())
+    } else if thumbnail::poster_path(config, movie.id).is_file() {
+        Some("poster".to_owned())
+    } else {
+        None
+    }
+}
+
+/// Items for movies with the watch state of the user
+async fn items(
+    db: &SharedDb,
+    config: &SharedCfg,
+    user_id: i32,
+    movies: Vec<Movie>,
+) -> Result<Vec<Item>> {
+    let ids = movies.iter().map(|m| m.id).collect::<Vec<_>>();
+    let states = ProgressTable::new(db.clone())
+        .states(user_id, ids.clone())
+        .await?;
+    let durations = TrickplayTable::new(db.clone()).durations(ids).await?;
+    let server_id = server_id(&config.jellyfin.name);
+
+    let items = movies
+        .into_iter()
+        .map(|movie| {
+            let state = states.get(&movie.id).cloned().unwrap_or_default();
+            let duration = durations.get(&movie.id).copied();
+            let poster = poster_tag(config, &movie);
+            Item::movie(movie, &server_id, &state, duration, poster)
+        })
+        .collect();
+    Ok(items)
+}
+
+/// One view per library the user can see
+async fn views(db: &SharedDb, config: &SharedCfg, user_id: i32) -> Result<Items> {
+    let libraries = MovieTable::new(db.clone()).libraries(user_id).await?;
+    let server_id = server_id(&config.jellyfin.name);
+    let items = libraries
+        .iter()
+        .map(|(name, count)| Item::library(name, &server_id, *count))
+        .collect::<Vec<_>>();
+    Ok(Items {
+        total_record_count: items.len() as i64,
+        items,
+        start_index: 0,
+    })
+}
+
+/// The name and size of the library with the Jellyfin id `id`
+async fn library(db: &SharedDb, user_id: i32, id: &str) -> Result<Option<(String, i64)>> {
+    let libraries = MovieTable::new(db.clone()).libraries(user_id).await?;
+    Ok(libraries
+        .into_iter()
+        .find(|(name, _)| library_id(name).eq_ignore_ascii_case(id)))
+}
+
+pub async fn get_info(config: SharedCfg) -> Result<Response<Body>, hyper::Error> {
+    let name = &config.jellyfin.name;
+    Ok(json!(&SystemInfo::new(name, &server_id(name))))
+}
+
+/// Users to pick from on the login screen, none are shown
+pub async fn get_public_users() -> Result<Response<Body>, hyper::Error> {
+    Ok(json!(&Vec::<UserDto>::new()))
+}
+
+/// Exchange name and password for a token, like `/auth/login`
+pub async fn post_authenticate(
+    db: SharedDb,
+    config: SharedCfg,
+    authorization: Option<Authorization>,
+    body: Body,
+) -> Result<Response<Body>, hyper::Error> {
+    let credentials: Credentials = from_json!(body);
+    let (user, token) = match authenticate(db, &credentials.name, credentials.password).await {
+        Ok(login) => login,
+        Err(resp) => return Ok(resp),
+    };
+    let server_id = server_id(&config.jellyfin.name);
+    let client = authorization.unwrap_or_default();
+    let session_info = SessionInfo {
+        id: token[..32].to_owned(),
+        user_id: jellyfin::id(user.id),
+        user_name: user.name.clone(),
+        client: client.client,
+        device_id: client.device_id,
+        device_name: client.device,
+        application_version: client.version,
+        server_id: server_id.clone(),
+    };
+    Ok(json!(&AuthenticationResult {
+        user: UserDto::new(&user, &server_id),
+        session_info,
+        access_token: token,
+        server_id,
+    }))
+}
+
+/// The logged in user, `id` is the Jellyfin id of the user if given
+pub async fn get_user(
+    config: SharedCfg,
+    user: User,
+    id: Option<String>,
+) -> Result<Response<Body>, hyper::Error> {
+    if id.is_some_and(|id| parse_id(&id) != Some(user.id)) {
+        return Ok(not_found());
+    }
+    Ok(json!(&UserDto::new(
+        &user,
+        &server_id(&config.jellyfin.name)
+    )))
+}
+
+pub async fn get_views(
+    db: SharedDb,
+    config: SharedCfg,
+    user: User,
+) -> Result<Response<Body>, hyper::Error> {
+    Ok(json!(&try_or_500!(views(&db, &config, user.id).await)))
+}
+
+/// Browses, searches and filters the movies. Without a parent the
+/// libraries are the items, unless all movies are asked for with
+/// `Recursive`.
+pub async fn get_items(
+    db: SharedDb,
+    config: SharedCfg,
+    user: User,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let params = params(&query);
+    let param = |name: &str| params.get(name).map(|v| v.as_str());
+    let start = param("startindex")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0u32);
+    let limit = param("limit")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(MAX_LIMIT)
+        .min(MAX_LIMIT);
+    let filters = param("filters")
+        .unwrap_or("")
+        .split(',')
+        .map(|f| f.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let filter = |name: &str| filters.iter().any(|f| f == name);
+    let none = Items {
+        items: Vec::new(),
+        total_record_count: 0,
+        start_index: start,
+    };
+
+    // there are only movies
+    if let Some(types) = param("includeitemtypes") {
+        if !types.split(',').any(|t| t.eq_ignore_ascii_case("movie")) {
+            return Ok(json!(&none));
+        }
+    }
+
+    let table = MovieTable::new(db.clone());
+    let (movies, total) = if let Some(ids) = param("ids") {
+        let mut movies = Vec::new();
+        for id in ids.split(',').filter_map(parse_id) {
+            if let Some(movie) = try_or_500!(table.for_user(id, user.id).await) {
+                movies.push(movie);
+            }
+        }
+        let total = movies.len() as i64;
+        (movies, total)
+    } else if let Some(term) = param("searchterm") {
+        let movies = match match_expr(term) {
+            Some(expr) => try_or_500!(table.search(expr, user.id, limit).await),
+            None => Vec::new(),
+        };
+        let total = movies.len() as i64;
+        (movies, total)
+    } else if filter("isfavorite") || filter("isresumable") {
+        let ids = if filter("isfavorite") {
+            let watchlist = WatchlistTable::new(db.clone());
+            let page = try_or_500!(watchlist.page(user.id, MAX_LIMIT, 0).await);
+            page.items.iter().map(|e| e.movie_id).collect::<Vec<_>>()
+        } else {
+            let progress = ProgressTable::new(db.clone());
+            let progress = try_or_500!(progress.in_progress(user.id, MAX_LIMIT).await);
+            progress.iter().map(|p| p.movie_id).collect()
+        };
+        let total = ids.len() as i64;
+        let ids = ids
+            .into_iter()
+            .skip(start as usize)
+            .take(limit as usize)
+            .collect();
+        (try_or_500!(table.by_ids(ids).await), total)
+    } else {
+        let recursive = param("recursive").is_some_and(|v| v.eq_ignore_ascii_case("true"));
+        let library = match param("parentid") {
+            Some(id) => match try_or_500!(library(&db, user.id, id).await) {
+                Some((name, _)) => Some(name),
+                None => return Ok(json!(&none)),
+            },
+            None if !recursive => {
+                return Ok(json!(&try_or_500!(views(&db, &config, user.id).await)))
+            }
+            None => None,
+        };
+        let sort = match param("sortby").and_then(|s| s.split(',').next()) {
+            Some(s) if s.eq_ignore_ascii_case("ProductionYear") => SortBy::Year,
+            Some(s) if s.eq_ignore_ascii_case("PremiereDate") => SortBy::Year,
+            Some(s) if s.eq_ignore_ascii_case("DateCreated") => SortBy::Added,
+            Some(s) if s.eq_ignore_ascii_case("CommunityRating") => SortBy::Rating,
+            _ => SortBy::Title,
+        };
+        let order = match param("sortorder") {
+            Some(o) if o.to_ascii_lowercase().starts_with("desc") => Order::Desc,
+            _ => Order::Asc,
+        };
+        let watched = if filter("isplayed") {
+            Some(true)
+        } else if filter("isunplayed") {
+            Some(false)
+        } else {
+            None
+        };
+        let query = MovieQuery {
+            limit: Some(limit),
+            offset: start,
+            sort,
+            order,
+            library,
+            watched,
+            user_id: Some(user.id),
+            ..MovieQuery::default()
+        };
+        let page = try_or_500!(table.query(query).await);
+        (page.items, page.total)
+    };
+
+    Ok(json!(&Items {
+        items: try_or_500!(items(&db, &config, user.id, movies).await),
+        total_record_count: total,
+        start_index: start,
+    }))
+}
+
+/// A movie or a library
+pub async fn get_item(
+    db: SharedDb,
+    config: SharedCfg,
+    user: User,
+    id: String,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Some((name, count)) = try_or_500!(library(&db, user.id, &id).await) {
+        let server_id = server_id(&config.jellyfin.name);
+        return Ok(json!(&Item::library(&name, &server_id, count)));
+    }
+    let id = match parse_id(&id) {
+        Some(id) => id,
+        None => return Ok(not_found()),
+    };
+    let movie = match try_or_500!(MovieTable::new(db.clone()).for_user(id, user.id).await) {
+        Some(movie) => movie,
+        None => return Ok(not_found()),
+    };
+    let mut items = try_or_500!(items(&db, &config, user.id, vec![movie]).await);
+    Ok(json!(&items.remove(0)))
+}
+
+/// Movies the user started but didn't finish, most recent first
+pub async fn get_resume(
+    db: SharedDb,
+    config: SharedCfg,
+    user: User,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let params = params(&query);
+    let limit = params
+        .get("limit")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(MAX_LIMIT)
+        .min(MAX_LIMIT);
+    let progress = ProgressTable::new(db.clone());
+    let progress = try_or_500!(progress.in_progress(user.id, limit).await);
+    let ids = progress.iter().map(|p| p.movie_id).collect();
+    let movies = try_or_500!(MovieTable::new(db.clone()).by_ids(ids).await);
+    let items = try_or_500!(items(&db, &config, user.id, movies).await);
+
+    Ok(json!(&Items {
+        total_record_count: items.len() as i64,
+        items,
+        start_index: 0,
+    }))
+}
+
+/// The movies added last, to a library if `ParentId` is given. Unlike
+/// the other lists this is a plain array.
+pub async fn get_latest(
+    db: SharedDb,
+    config: SharedCfg,
+    user: User,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let params = params(&query);
+    let limit = params
+        .get("limit")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(LATEST_LIMIT)
+        .min(MAX_LIMIT);
+    let library = match params.get("parentid") {
+        Some(id) => match try_or_500!(library(&db, user.id, id).await) {
+            Some((name, _)) => Some(name),
+            None => return Ok(json!(&Vec::<Item>::new())),
+        },
+        None => None,
+    };
+    let query = MovieQuery {
+        limit: Some(limit),
+        sort: SortBy::Added,
+        order: Order::Desc,
+        library,
+        user_id: Some(user.id),
+        ..MovieQuery::default()
+    };
+    let page = try_or_500!(MovieTable::new(db.clone()).query(query).await);
+    Ok(json!(&try_or_500!(
+        items(&db, &config, user.id, page.items).await
+    )))
+}
+
+/// The poster of a movie. The apps mostly load images without a token,
+/// so without one only the posters of movies no user is restricted from
+/// are served. With a token the movie must be visible to its user.
+pub async fn get_image(
+    db: SharedDb,
+    config: SharedCfg,
+    id: String,
+    token: Option<String>,
+) -> Result<Response<Body>, hyper::Error> {
+    let id = match parse_id(&id) {
+        Some(id) => id,
+        None => return Ok(not_found()),
+    };
+    let user = match token {
+        Some(token) => match try_or_500!(UserTable::new(db.clone()).by_token(&token).await) {
+            Some(user) => Some(user),
+            None => return Ok(error!(StatusCode::UNAUTHORIZED, "Unauthorized")),
+        },
+        None => None,
+    };
+    let movies = MovieTable::new(db);
+    let movie = match user {
+        Some(user) => try_or_500!(movies.for_user(id, user.id).await),
+        None => try_or_500!(movies.for_everyone(id).await),
+    };
+    let movie = match movie {
+        Some(movie) => movie,
+        None => return Ok(not_found()),
+    };
+    if movie.poster_path.is_empty() {
+        return image(&thumbnail::poster_path(&config, id)).await;
+    }
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(
+            header::LOCATION,
+            format!("{}{}", tmdb::POSTERS, movie.poster_path),
+        )
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// How to play a movie. The stream url carries the token, players
+/// fetch it without headers.
+pub async fn get_playback_info(
+    db: SharedDb,
+    user: User,
+    id: String,
+    token: String,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let id = match parse_id(&id) {
+        Some(id) => id,
+        None => return Ok(not_found()),
+    };
+    let movie = match try_or_500!(MovieTable::new(db.clone()).for_user(id, user.id).await) {
+        Some(movie) => movie,
+        None => return Ok(not_found()),
+    };
+    let durations = try_or_500!(TrickplayTable::new(db).durations(vec![id]).await);
+
+    let start = params(&query)
+        .get("starttimeticks")
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(0);
+    let url = format!(
+        "/Videos/{}/stream.mp4?MediaSourceId={}&StartTimeTicks={}&api_key={}",
+        jellyfin::id(id),
+        jellyfin::id(id),
+        start.max(0),
+        token
+    );
+    let source = MediaSource::new(&movie, durations.get(&id).copied(), url);
+    Ok(json!(&PlaybackInfo {
+        media_sources: vec![source],
+        play_session_id: format!("{:032x}", rand::random::<u128>()),
+    }))
+}
+
+/// The stream of `/stream/{id}`, starting at `StartTimeTicks`
+pub async fn get_video(
+    db: SharedDb,
+    config: SharedCfg,
+    events: Events,
+    user: User,
+    id: String,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let id = match parse_id(&id) {
+        Some(id) => id,
+        None => return Ok(not_found()),
+    };
+    let start = params(&query)
+        .get("starttimeticks")
+        .and_then(|v| v.parse::<i64>().ok())
+        .map_or(0.0, jellyfin::seconds);
+    let query = format!("start={}", start.max(0.0));
+    super::get_stream(db, config, events, user, id, query).await
+}
+
+/// Start, progress and stop reports of the players. They count like
+/// reports to `/movies/{id}/progress`, the duration is taken from the
+/// seek preview thumbnails if there are any.
+pub async fn post_playing(
+    db: SharedDb,
+    config: SharedCfg,
+    events: Events,
+    user: User,
+    authorization: Option<Authorization>,
+    body: Body,
+) -> Result<Response<Body>, hyper::Error> {
+    let playing: Playing = from_json!(body);
+    let id = match parse_id(&playing.item_id) {
+        Some(id) => id,
+        None => return Ok(not_found()),
+    };
+    // starting players may not know the position yet
+    let position = match playing.position_ticks {
+        Some(ticks) if ticks >= 0 => jellyfin::seconds(ticks),
+        _ => return Ok(empty!(StatusCode::NO_CONTENT)),
+    };
+    if try_or_500!(MovieTable::new(db.clone()).for_user(id, user.id).await).is_none() {
+        return Ok(not_found());
+    }
+
+    let durations = try_or_500!(TrickplayTable::new(db.clone()).durations(vec![id]).await);
+    let duration = durations.get(&id).copied().unwrap_or(0.0);
+    let device = authorization.map(|a| a.player()).unwrap_or_default();
+    let state =
+        try_or_500!(progress::record(db, &config, user.id, id, position, duration, &device).await);
+    events.publish(Event::WatchState {
+        user_id: user.id,
+        movie_id: id,
+        state,
+    });
+    Ok(empty!(StatusCode::NO_CONTENT))
+}
+
+/// Publishes the new watch state of a movie and returns it as the
+/// user data of the apps
+async fn changed(
+    db: &SharedDb,
+    events: &Events,
+    user_id: i32,
+    id: i32,
+    state: WatchState,
+) -> Result<Response<Body>, hyper::Error> {
+    let durations = try_or_500!(TrickplayTable::new(db.clone()).durations(vec![id]).await);
+    let data = UserData::new(id, &state, durations.get(&id).copied());
+    events.publish(Event::WatchState {
+        user_id,
+        movie_id: id,
+        state,
+    });
+    Ok(json!(&data))
+}
+
+/// Marks a movie as played (`POST`) or unplayed (`DELETE`)
+pub async fn set_played(
+    db: SharedDb,
+    events: Events,
+    user: User,
+    id: String,
+    played: bool,
+) -> Result<Response<Body>, hyper::Error> {
+    let id = match parse_id(&id) {
+        Some(id) => id,
+        None => return Ok(not_found()),
+    };
+    if try_or_500!(MovieTable::new(db.clone()).for_user(id, user.id).await).is_none() {
+        return Ok(not_found());
+    }
+    let table = ProgressTable::new(db.clone());
+    try_or_500!(table.set_watched(user.id, id, played).await);
+    let state = try_or_500!(table.state(user.id, id).await);
+    changed(&db, &events, user.id, id, state).await
+}
+
+/// Favorites are the watchlist, `POST` adds a movie and `DELETE`
+/// removes it
+pub async fn set_favorite(
+    db: SharedDb,
+    events: Events,
+    user: User,
+    id: String,
+    favorite: bool,
+) -> Result<Response<Body>, hyper::Error> {
+    let id = match parse_id(&id) {
+        Some(id) => id,
+        None => return Ok(not_found()),
+    };
+    if try_or_500!(MovieTable::new(db.clone()).for_user(id, user.id).await).is_none() {
+        return Ok(not_found());
+    }
+    let table = WatchlistTable::new(db.clone());
+    if favorite {
+        let entry = WatchlistEntry {
+            user_id: user.id,
+            movie_id: id,
+            added_at: 0,
+        };
+        try_or_500!(table.save(entry).await);
+    } else {
+        try_or_500!(table.remove(user.id, id).await);
+    }
+    let state = try_or_500!(ProgressTable::new(db.clone()).state(user.id, id).await);
+    changed(&db, &events, user.id, id, state).await
+}
@@ -0,0 +1,64 @@
+use crate::jobs::{JobKind, Jobs};
+use crate::model::{JobState, JobTable, Table};
+use crate::sqlite::SharedDb;
+use hyper::{header, Body, Response, StatusCode};
+use serde::Deserialize;
+
+/// Number of jobs returned by `/admin/jobs` without `limit`
+const DEFAULT_LIMIT: u32 = 50;
+
+#[derive(Debug, Deserialize)]
+struct JobsQuery {
+    state: Option<JobState>,
+    limit: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewJob {
+    kind: String,
+    priority: Option<i32>,
+}
+
+/// Latest jobs first, optionally filtered by `state`
+pub async fn get_jobs(db: SharedDb, query: String) -> Result<Response<Body>, hyper::Error> {
+    let query = match serde_urlencoded::from_str::<JobsQuery>(&query) {
+        Ok(query) => query,
+        Err(e) => return Ok(error!(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(500);
+
+    let jobs = try_or_500!(JobTable::new(db).list(query.state, limit).await);
+    Ok(json!(&jobs))
+}
+
+pub async fn get_job(db: SharedDb, id: i32) -> Result<Response<Body>, hyper::Error> {
+    match try_or_500!(JobTable::new(db).by_id(id).await) {
+        Some(job) => Ok(json!(&job)),
+        None => Ok(error!(StatusCode::NOT_FOUND, "Not Found")),
+    }
+}
+
+/// Queues a job. If a job of the same kind is already queued or
+/// running, that one is returned.
+pub async fn post_job(jobs: Jobs, body: Body) -> Result<Response<Body>, hyper::Error> {
+    let new: NewJob = from_json!(body);
+    let kind = match new.kind.parse::<JobKind>() {
+        Ok(kind) => kind,
+        Err(e) => return Ok(error!(StatusCode::BAD_REQUEST, e)),
+    };
+
+    let job = try_or_500!(jobs.enqueue(kind, new.priority).await);
+    Ok(json!(&job))
+}
+
+/// Cancels a queued or running job. Running jobs stop at their next
+/// progress report.
+pub async fn delete_job(jobs: Jobs, id: i32) -> Result<Response<Body>, hyper::Error> {
+    match try_or_500!(jobs.cancel(id).await) {
+        Some(job) if job.state.is_finished() && job.state != JobState::Cancelled => {
+            Ok(error!(StatusCode::CONFLICT, "job already finished"))
+        }
+        Some(job) => Ok(json!(&job)),
+        None => Ok(error!(StatusCode::NOT_FOUND, "Not Found")),
+    }
+}
@@ -0,0 +1,236 @@
+use crate::config::Config;
+use crate::events::{Event, Events};
+use crate::ffmpeg::{Cap, FFmpeg};
+use crate::logging;
+use crate::metrics::metrics;
+use crate::model::{
+    match_expr, Movie, MovieQuery, MovieTable, Page, ProgressTable, SearchQuery, SearchResults,
+    User, UserMovie,
+};
+use crate::sqlite::SharedDb;
+use hyper::{header, Body, Response, StatusCode};
+use serde::Deserialize;
+use std::sync::Arc;
+
+macro_rules! json {
+    ($x:expr) => {
+        match serde_json::to_string($x) {
+            Ok(json) => Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .header("Access-Control-Allow-Origin", "*")
+                .body(Body::from(json))
+                .unwrap(),
+            Err(_) => Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("INTERNAL_SERVER_ERROR".into())
+                .unwrap(),
+        }
+    };
+}
+
+macro_rules! error {
+    ($status:expr, $msg:expr) => {
+        Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .status($status)
+            .body(Body::from($msg))
+            .unwrap()
+    };
+}
+
+macro_rules! empty {
+    ($status:expr) => {
+        Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .status($status)
+            .body(Body::empty())
+            .unwrap()
+    };
+}
+
+/// Unwraps a result or logs the error and returns `500 Internal Server
+/// Error` from the handler
+macro_rules! try_or_500 {
+    ($x:expr) => {
+        match $x {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("{}", e);
+                return Ok(error!(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "INTERNAL_SERVER_ERROR"
+                ));
+            }
+        }
+    };
+}
+
+/// Reads the request body and deserializes it from json, returns
+/// `400 Bad Request` from the handler if that fails
+macro_rules! from_json {
+    ($body:expr) => {
+        match serde_json::from_slice(&hyper::body::to_bytes($body).await?) {
+            Ok(v) => v,
+            Err(e) => return Ok(error!(StatusCode::BAD_REQUEST, e.to_string())),
+        }
+    };
+}
+
+pub mod collection;
+pub mod dlna;
+pub mod event;
+pub mod jellyfin;
+pub mod job;
+pub mod parental;
+pub mod party;
+pub mod playlist;
+pub mod progress;
+pub mod share;
+pub mod thumbnail;
+pub mod user;
+pub mod web;
+
+pub async fn get_movies(
+    db: SharedDb,
+    user: User,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let mut query = match MovieQuery::from_query(&query) {
+        Ok(query) => query,
+        Err(e) => return Ok(error!(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+    query.user_id = Some(user.id);
+    let table = MovieTable::new(db.clone());
+    let page = try_or_500!(table.query(query).await);
+    let ids = page.items.iter().map(|m| m.id).collect();
+    let states = try_or_500!(ProgressTable::new(db).states(user.id, ids).await);
+
+    Ok(json!(&Page {
+        total: page.total,
+        limit: page.limit,
+        offset: page.offset,
+        items: UserMovie::zip(page.items, &states),
+    }))
+}
+
+pub async fn get_movie(db: SharedDb, user: User, id: i32) -> Result<Response<Body>, hyper::Error> {
+    let table = MovieTable::new(db.clone());
+    let movie = match try_or_500!(table.for_user(id, user.id).await) {
+        Some(movie) => movie,
+        None => return Ok(error!(StatusCode::NOT_FOUND, "Not Found")),
+    };
+    let state = try_or_500!(ProgressTable::new(db).state(user.id, id).await);
+
+    Ok(json!(&UserMovie { movie, state }))
+}
+
+/// Metrics in the Prometheus text format
+pub async fn get_metrics() -> Result<Response<Body>, hyper::Error> {
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(metrics().render()))
+        .unwrap())
+}
+
+pub async fn get_search(
+    db: SharedDb,
+    user: User,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let query = match SearchQuery::from_query(&query) {
+        Ok(query) => query,
+        Err(e) => return Ok(error!(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+    let movies = match match_expr(&query.q) {
+        Some(expr) => try_or_500!(
+            MovieTable::new(db)
+                .search(expr, user.id, query.limit())
+                .await
+        ),
+        None => Vec::new(),
+    };
+    Ok(json!(&SearchResults { movies }))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct StreamQuery {
+    /// Position in seconds to start the stream at
+    start: f64,
+}
+
+pub async fn get_stream(
+    db: SharedDb,
+    config: Arc<Config>,
+    events: Events,
+    user: User,
+    id: i32,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let query = match serde_urlencoded::from_str::<StreamQuery>(&query) {
+        Ok(query) if query.start.is_finite() && query.start >= 0.0 => query,
+        _ => return Ok(error!(StatusCode::BAD_REQUEST, "invalid start")),
+    };
+    let table = MovieTable::new(db);
+    let movie = match try_or_500!(table.for_user(id, user.id).await) {
+        Some(movie) => movie,
+        None => return Ok(error!(StatusCode::NOT_FOUND, "Not Found")),
+    };
+    Ok(stream(
+        &config,
+        events,
+        movie,
+        user.id,
+        query.start,
+        Cap::default(),
+    ))
+}
+
+/// The base of absolute urls, for players and links sent elsewhere.
+/// Without `base_url` they point to the host of the request.
+fn base_url(config: &Config, host: Option<String>) -> String {
+    match host {
+        Some(host) if config.server.base_url.is_empty() => format!("http://{}", host),
+        _ => config.server.url(""),
+    }
+}
+
+/// Streams a movie from `start` seconds on. The session counts for
+/// `user_id`.
+fn stream(
+    config: &Config,
+    events: Events,
+    movie: Movie,
+    user_id: i32,
+    start: f64,
+    cap: Cap,
+) -> Response<Body> {
+    let ffmpeg = FFmpeg::new(Arc::new(config.ffmpeg.clone()));
+    let (tx, body) = Body::channel();
+
+    // the stream outlives the request, it keeps the request id for
+    // the log and as id of the session
+    let session = logging::request_id().unwrap_or_default();
+    let id = movie.id;
+    events.publish(Event::SessionStarted {
+        session: session.clone(),
+        user_id,
+        movie_id: id,
+    });
+    tokio::spawn(logging::scope(session.clone(), async move {
+        ffmpeg.transcode(&movie.file_path, start, cap, tx).await;
+        events.publish(Event::SessionStopped {
+            session,
+            user_id,
+            movie_id: id,
+        });
+    }));
+
+    Response::builder()
+        .header("Content-Type", "video/mp4")
+        .header("Content-Disposition", "inline")
+        .header("Content-Transfer-Enconding", "binary")
+        .body(body)
+        .unwrap()
+}
@@ -0,0 +1,91 @@
+use crate::model::{min_age, Restriction, RestrictionTable, Table, User, UserTable};
+use crate::sqlite::SharedDb;
+use hyper::{header, Body, Response, StatusCode};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+struct NewRestriction {
+    country: String,
+    #[serde(default)]
+    max_rating: String,
+    #[serde(default)]
+    allow_unrated: bool,
+    #[serde(default)]
+    allowed_tags: Vec<String>,
+    #[serde(default)]
+    blocked_tags: Vec<String>,
+}
+
+/// Cleans the tags of a request, empty ones are dropped
+fn tags(tags: Vec<String>) -> Vec<String> {
+    tags.into_iter()
+        .map(|tag| tag.trim().to_owned())
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+/// The parental controls of a user. Users may see their own, admins
+/// the ones of everyone.
+pub async fn get_restrictions(
+    db: SharedDb,
+    user: User,
+    id: i32,
+) -> Result<Response<Body>, hyper::Error> {
+    if user.id != id && !user.is_admin {
+        return Ok(error!(StatusCode::FORBIDDEN, "Forbidden"));
+    }
+    match try_or_500!(RestrictionTable::new(db).get(id).await) {
+        Some(restriction) => Ok(json!(&restriction)),
+        None => Ok(error!(StatusCode::NOT_FOUND, "Not Found")),
+    }
+}
+
+/// Sets the parental controls of a user, replacing the earlier ones
+pub async fn put_restrictions(
+    db: SharedDb,
+    id: i32,
+    body: Body,
+) -> Result<Response<Body>, hyper::Error> {
+    let new: NewRestriction = from_json!(body);
+    let country = new.country.trim().to_uppercase();
+    if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Ok(error!(
+            StatusCode::BAD_REQUEST,
+            "country must be a two letter code such as US"
+        ));
+    }
+    let max_rating = new.max_rating.trim().to_owned();
+    let max_age = match min_age(&country, &max_rating) {
+        _ if max_rating.is_empty() => None,
+        Some(age) => Some(age),
+        None => {
+            let msg = format!("unknown certification {} in {}", max_rating, country);
+            return Ok(error!(StatusCode::BAD_REQUEST, msg));
+        }
+    };
+    if try_or_500!(UserTable::new(db.clone()).by_id(id).await).is_none() {
+        return Ok(error!(StatusCode::NOT_FOUND, "Not Found"));
+    }
+
+    let restriction = Restriction {
+        user_id: id,
+        country,
+        max_rating,
+        max_age,
+        allow_unrated: new.allow_unrated,
+        allowed_tags: tags(new.allowed_tags),
+        blocked_tags: tags(new.blocked_tags),
+    };
+    let table = RestrictionTable::new(db);
+    try_or_500!(table.save(restriction).await);
+    Ok(json!(&try_or_500!(table.get(id).await)))
+}
+
+/// Lifts the parental controls of a user
+pub async fn delete_restrictions(db: SharedDb, id: i32) -> Result<Response<Body>, hyper::Error> {
+    if try_or_500!(RestrictionTable::new(db).remove(id).await) {
+        Ok(empty!(StatusCode::NO_CONTENT))
+    } else {
+        Ok(error!(StatusCode::NOT_FOUND, "Not Found"))
+    }
+}
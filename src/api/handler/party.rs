@@ -0,0 +1,180 @@
+use crate::config::SharedCfg;
+use crate::logging;
+use crate::model::{MovieTable, User};
+use crate::party::{Command, Member, Parties, RoomInfo, Update};
+use crate::sqlite::SharedDb;
+use futures::{SinkExt, StreamExt};
+use hyper::upgrade::Upgraded;
+use hyper::{header, Body, Request, Response, StatusCode};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::RecvError;
+use tokio_tungstenite::tungstenite::handshake::server::create_response;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+#[derive(Debug, Deserialize)]
+struct NewParty {
+    movie_id: i32,
+}
+
+/// A room with the path members stream the movie from
+#[derive(Debug, Serialize)]
+struct Party {
+    #[serde(flatten)]
+    room: RoomInfo,
+    stream: String,
+}
+
+impl Party {
+    fn new(config: &SharedCfg, room: RoomInfo) -> Party {
+        Party {
+            stream: config.server.url(&format!("/stream/{}", room.movie_id)),
+            room,
+        }
+    }
+}
+
+/// Loads a room whose movie `user` may see, or the response to send
+/// instead
+async fn room(
+    db: SharedDb,
+    parties: &Parties,
+    user: &User,
+    code: &str,
+) -> Result<RoomInfo, Response<Body>> {
+    let room = match parties.get(code) {
+        Some(room) => room,
+        None => return Err(error!(StatusCode::NOT_FOUND, "Not Found")),
+    };
+    match MovieTable::new(db).for_user(room.movie_id, user.id).await {
+        Ok(Some(_)) => Ok(room),
+        Ok(None) => Err(error!(StatusCode::NOT_FOUND, "Not Found")),
+        Err(e) => {
+            log::error!("{}", e);
+            Err(error!(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "INTERNAL_SERVER_ERROR"
+            ))
+        }
+    }
+}
+
+/// Opens a watch party for a movie, others join with its code
+pub async fn post_party(
+    db: SharedDb,
+    config: SharedCfg,
+    parties: Parties,
+    user: User,
+    body: Body,
+) -> Result<Response<Body>, hyper::Error> {
+    let new: NewParty = from_json!(body);
+    if try_or_500!(MovieTable::new(db).for_user(new.movie_id, user.id).await).is_none() {
+        return Ok(error!(StatusCode::NOT_FOUND, "Not Found"));
+    }
+    let room = parties.create(new.movie_id, user.id);
+    Ok(json!(&Party::new(&config, room)))
+}
+
+pub async fn get_party(
+    db: SharedDb,
+    config: SharedCfg,
+    parties: Parties,
+    user: User,
+    code: String,
+) -> Result<Response<Body>, hyper::Error> {
+    match room(db, &parties, &user, &code).await {
+        Ok(room) => Ok(json!(&Party::new(&config, room))),
+        Err(resp) => Ok(resp),
+    }
+}
+
+/// Joins a watch party over a WebSocket. Members send `Command`s as
+/// json and get `Update`s, each member streams the movie on its own.
+pub async fn get_party_socket(
+    db: SharedDb,
+    parties: Parties,
+    user: User,
+    code: String,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(resp) = room(db, &parties, &user, &code).await {
+        return Ok(resp);
+    }
+    let mut handshake = Request::new(());
+    *handshake.version_mut() = req.version();
+    *handshake.headers_mut() = req.headers().clone();
+    let (parts, _) = match create_response(&handshake) {
+        Ok(resp) => resp.into_parts(),
+        Err(e) => return Ok(error!(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+
+    let id = logging::request_id().unwrap_or_default();
+    tokio::spawn(logging::scope(id, async move {
+        match req.into_body().on_upgrade().await {
+            Ok(upgraded) => {
+                let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                let member = match parties.join(&code, &user.name) {
+                    Some(member) => member,
+                    None => return,
+                };
+                let id = member.id;
+                play(socket, &parties, &code, member).await;
+                parties.leave(&code, id);
+                debug!("{} left party {}", user.name, code);
+            }
+            Err(e) => warn!("websocket upgrade failed: {}", e),
+        }
+    }));
+
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = parts.status;
+    *resp.headers_mut() = parts.headers;
+    Ok(resp)
+}
+
+/// Passes commands of a member to its room and updates of the room to
+/// the member, until either is gone
+async fn play(
+    mut socket: WebSocketStream<Upgraded>,
+    parties: &Parties,
+    code: &str,
+    mut member: Member,
+) {
+    loop {
+        let update = tokio::select! {
+            msg = socket.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    let command = serde_json::from_str::<Command>(&text)
+                        .map_err(|e| e.to_string())
+                        .and_then(|command| parties.command(code, member.id, command));
+                    match command {
+                        Ok(Some(answer)) => answer,
+                        Ok(None) => continue,
+                        Err(message) => Update::Error { message },
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+            update = member.updates.recv() => match update {
+                Ok(update) => update,
+                // the next state catches up the member
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+        };
+        let json = match serde_json::to_string(&update) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!("could not serialize update: {}", e);
+                continue;
+            }
+        };
+        if socket.send(Message::Text(json)).await.is_err() {
+            return;
+        }
+    }
+    let _ = socket.close(None).await;
+}
@@ -0,0 +1,157 @@
+use super::base_url;
+use super::collection::visible;
+use crate::api::auth::signed_path;
+use crate::config::SharedCfg;
+use crate::model::{
+    match_expr, CollectionTable, Movie, MovieQuery, MovieTable, SecretTable, TrickplayTable, User,
+    MAX_LIMIT, URL_KEY,
+};
+use crate::playlist::{Entry, Format};
+use crate::sqlite::SharedDb;
+use crate::{thumbnail, tmdb};
+use chrono::Utc;
+use hyper::{header, Body, Response, StatusCode};
+use serde::Deserialize;
+
+/// What to make a playlist of, exactly one is expected
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PlaylistQuery {
+    library: Option<String>,
+    collection: Option<i32>,
+    q: Option<String>,
+}
+
+/// A library, collection or search result as a playlist for external
+/// players such as VLC or mpv. Players can't send a token, so the urls
+/// of the streams and generated posters are signed for the user and
+/// expire after `signed_url_ttl` of `[auth]`. Libraries and collections
+/// are listed in full, searches hold the best `MAX_LIMIT` matches.
+pub async fn get_playlist(
+    db: SharedDb,
+    config: SharedCfg,
+    user: User,
+    format: String,
+    host: Option<String>,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let format = match format.parse::<Format>() {
+        Ok(format) => format,
+        Err(e) => return Ok(error!(StatusCode::BAD_REQUEST, e)),
+    };
+    let query = match serde_urlencoded::from_str::<PlaylistQuery>(&query) {
+        Ok(query) => query,
+        Err(e) => return Ok(error!(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+
+    let table = MovieTable::new(db.clone());
+    let (title, movies) = match query {
+        PlaylistQuery {
+            library: Some(library),
+            collection: None,
+            q: None,
+        } => {
+            let mut movies = Vec::new();
+            loop {
+                let query = MovieQuery {
+                    limit: Some(MAX_LIMIT),
+                    offset: movies.len() as u32,
+                    library: Some(library.clone()),
+                    user_id: Some(user.id),
+                    ..MovieQuery::default()
+                };
+                let page = try_or_500!(table.query(query).await);
+                let done = page.items.len() < MAX_LIMIT as usize;
+                movies.extend(page.items);
+                if done {
+                    break;
+                }
+            }
+            (library, movies)
+        }
+        PlaylistQuery {
+            library: None,
+            collection: Some(id),
+            q: None,
+        } => {
+            let collection = match visible(db.clone(), &user, id).await {
+                Ok(collection) => collection,
+                Err(resp) => return Ok(resp),
+            };
+            let collections = CollectionTable::new(db.clone());
+            let mut movies = Vec::new();
+            loop {
+                let offset = movies.len() as u32;
+                let page = try_or_500!(
+                    collections
+                        .movies(&collection, user.id, MAX_LIMIT, offset)
+                        .await
+                );
+                let done = page.items.len() < MAX_LIMIT as usize;
+                movies.extend(page.items);
+                if done {
+                    break;
+                }
+            }
+            (collection.name, movies)
+        }
+        PlaylistQuery {
+            library: None,
+            collection: None,
+            q: Some(q),
+        } => {
+            let movies = match match_expr(&q) {
+                Some(expr) => try_or_500!(table.search(expr, user.id, MAX_LIMIT).await),
+                None => Vec::new(),
+            };
+            (q, movies)
+        }
+        _ => {
+            let msg = "expected one of library, collection or q";
+            return Ok(error!(StatusCode::BAD_REQUEST, msg));
+        }
+    };
+
+    let key = try_or_500!(SecretTable::new(db.clone()).get(URL_KEY).await);
+    let ids = movies.iter().map(|m| m.id).collect();
+    let durations = try_or_500!(TrickplayTable::new(db).durations(ids).await);
+    let expires = Utc::now().timestamp() + config.auth.signed_url_ttl as i64;
+    let base = base_url(&config, host);
+    let sign = |path: String| format!("{}{}", base, signed_path(&key, &path, user.id, expires));
+    let poster = |movie: &Movie| {
+        if !movie.poster_path.is_empty() {
+            Some(format!("{}{}", tmdb::POSTERS, movie.poster_path))
+        } else if thumbnail::poster_path(&config, movie.id).is_file() {
+            Some(sign(format!("/movies/{}/poster", movie.id)))
+        } else {
+            None
+        }
+    };
+    let entries = movies
+        .iter()
+        .map(|movie| Entry {
+            title: movie.title.clone(),
+            duration: durations.get(&movie.id).copied(),
+            url: sign(format!("/stream/{}", movie.id)),
+            poster: poster(movie),
+        })
+        .collect::<Vec<_>>();
+
+    let filename = title
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"{}.{}\"",
+                filename,
+                format.extension()
+            ),
+        )
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(format.render(&title, &entries)))
+        .unwrap())
+}
@@ -0,0 +1,273 @@
+use crate::config::SharedCfg;
+use crate::events::{Event, Events};
+use crate::model::{
+    HistoryEntry, HistoryTable, MovieTable, Page, PageQuery, ProgressTable, Rating, RatingTable,
+    Result, Table, User, UserMovie, WatchState, WatchlistEntry, WatchlistTable, MAX_RATING,
+};
+use crate::sqlite::SharedDb;
+use hyper::{header, Body, Response, StatusCode};
+use serde::Deserialize;
+
+/// Number of movies returned by `/users/me/continue`
+const CONTINUE_LIMIT: u32 = 20;
+
+#[derive(Debug, Deserialize)]
+struct Report {
+    /// Current position in seconds
+    position: f64,
+    /// Duration in seconds, if known by the player
+    #[serde(default)]
+    duration: f64,
+    /// Name of the player, the `User-Agent` is used if not set
+    device: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Thumbs {
+    Up,
+    Down,
+}
+
+/// Either a `rating` from 1 to 10 or `thumbs`, which count as 10 and 1
+#[derive(Debug, Deserialize)]
+struct NewRating {
+    rating: Option<i32>,
+    thumbs: Option<Thumbs>,
+}
+
+/// Publishes the new watch state of a movie and returns it as response
+fn changed(events: &Events, user_id: i32, movie_id: i32, state: WatchState) -> Response<Body> {
+    let resp = json!(&state);
+    events.publish(Event::WatchState {
+        user_id,
+        movie_id,
+        state,
+    });
+    resp
+}
+
+/// Called periodically by the player to report the playback position.
+/// The report is added to the watch history as well.
+pub async fn post_progress(
+    db: SharedDb,
+    config: SharedCfg,
+    events: Events,
+    user: User,
+    id: i32,
+    user_agent: Option<String>,
+    body: Body,
+) -> Result<Response<Body>, hyper::Error> {
+    let report: Report = from_json!(body);
+    if !report.position.is_finite() || !report.duration.is_finite() {
+        return Ok(error!(StatusCode::BAD_REQUEST, "invalid position"));
+    }
+    if try_or_500!(MovieTable::new(db.clone()).for_user(id, user.id).await).is_none() {
+        return Ok(error!(StatusCode::NOT_FOUND, "Not Found"));
+    }
+
+    let device = report.device.or(user_agent).unwrap_or_default();
+    let state = try_or_500!(
+        record(
+            db,
+            &config,
+            user.id,
+            id,
+            report.position,
+            report.duration,
+            &device,
+        )
+        .await
+    );
+    Ok(changed(&events, user.id, id, state))
+}
+
+/// Saves a playback position and duration reported by `device` and adds
+/// it to the watch history. Returns the new watch state of the movie.
+pub(super) async fn record(
+    db: SharedDb,
+    config: &SharedCfg,
+    user_id: i32,
+    id: i32,
+    position: f64,
+    duration: f64,
+    device: &str,
+) -> Result<WatchState> {
+    let table = ProgressTable::new(db.clone());
+    let progress = table
+        .report(
+            user_id,
+            id,
+            position,
+            duration,
+            config.playback.watched_threshold,
+        )
+        .await?;
+    HistoryTable::new(db)
+        .record(user_id, id, device, position, progress.completed)
+        .await?;
+    table.state(user_id, id).await
+}
+
+/// Marks a movie as watched (`PUT`) or unwatched (`DELETE`)
+pub async fn set_watched(
+    db: SharedDb,
+    events: Events,
+    user: User,
+    id: i32,
+    watched: bool,
+) -> Result<Response<Body>, hyper::Error> {
+    if try_or_500!(MovieTable::new(db.clone()).for_user(id, user.id).await).is_none() {
+        return Ok(error!(StatusCode::NOT_FOUND, "Not Found"));
+    }
+    let table = ProgressTable::new(db);
+    try_or_500!(table.set_watched(user.id, id, watched).await);
+    let state = try_or_500!(table.state(user.id, id).await);
+    Ok(changed(&events, user.id, id, state))
+}
+
+/// Rates a movie, replacing the earlier rating of the user
+pub async fn put_rating(
+    db: SharedDb,
+    events: Events,
+    user: User,
+    id: i32,
+    body: Body,
+) -> Result<Response<Body>, hyper::Error> {
+    let new: NewRating = from_json!(body);
+    let rating = match (new.rating, new.thumbs) {
+        (Some(rating), None) if (1..=MAX_RATING).contains(&rating) => rating,
+        (None, Some(Thumbs::Up)) => MAX_RATING,
+        (None, Some(Thumbs::Down)) => 1,
+        _ => {
+            let msg = format!("expected a rating from 1 to {} or thumbs", MAX_RATING);
+            return Ok(error!(StatusCode::BAD_REQUEST, msg));
+        }
+    };
+    if try_or_500!(MovieTable::new(db.clone()).for_user(id, user.id).await).is_none() {
+        return Ok(error!(StatusCode::NOT_FOUND, "Not Found"));
+    }
+
+    let rating = Rating {
+        user_id: user.id,
+        movie_id: id,
+        rating,
+        rated_at: 0,
+    };
+    try_or_500!(RatingTable::new(db.clone()).save(rating).await);
+    let state = try_or_500!(ProgressTable::new(db).state(user.id, id).await);
+    Ok(changed(&events, user.id, id, state))
+}
+
+/// Removes the rating of the user for a movie
+pub async fn delete_rating(
+    db: SharedDb,
+    events: Events,
+    user: User,
+    id: i32,
+) -> Result<Response<Body>, hyper::Error> {
+    if try_or_500!(MovieTable::new(db.clone()).for_user(id, user.id).await).is_none() {
+        return Ok(error!(StatusCode::NOT_FOUND, "Not Found"));
+    }
+    try_or_500!(RatingTable::new(db.clone()).remove(user.id, id).await);
+    let state = try_or_500!(ProgressTable::new(db).state(user.id, id).await);
+    Ok(changed(&events, user.id, id, state))
+}
+
+/// Adds a movie to the watchlist (`PUT`) or removes it (`DELETE`)
+pub async fn set_watchlist(
+    db: SharedDb,
+    events: Events,
+    user: User,
+    id: i32,
+    add: bool,
+) -> Result<Response<Body>, hyper::Error> {
+    if try_or_500!(MovieTable::new(db.clone()).for_user(id, user.id).await).is_none() {
+        return Ok(error!(StatusCode::NOT_FOUND, "Not Found"));
+    }
+    let table = WatchlistTable::new(db.clone());
+    if add {
+        let entry = WatchlistEntry {
+            user_id: user.id,
+            movie_id: id,
+            added_at: 0,
+        };
+        try_or_500!(table.save(entry).await);
+    } else {
+        try_or_500!(table.remove(user.id, id).await);
+    }
+    let state = try_or_500!(ProgressTable::new(db).state(user.id, id).await);
+    Ok(changed(&events, user.id, id, state))
+}
+
+/// Movies the user started but didn't finish, most recent first
+pub async fn get_continue(db: SharedDb, user: User) -> Result<Response<Body>, hyper::Error> {
+    let table = ProgressTable::new(db.clone());
+    let progress = try_or_500!(table.in_progress(user.id, CONTINUE_LIMIT).await);
+    let ids = progress.iter().map(|p| p.movie_id).collect::<Vec<_>>();
+    let movies = try_or_500!(MovieTable::new(db).by_ids(ids.clone()).await);
+    let states = try_or_500!(table.states(user.id, ids).await);
+
+    Ok(json!(&UserMovie::zip(movies, &states)))
+}
+
+/// The watchlist of the user, latest added first
+pub async fn get_watchlist(
+    db: SharedDb,
+    user: User,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let query = match PageQuery::from_query(&query) {
+        Ok(query) => query,
+        Err(e) => return Ok(error!(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+    let page = try_or_500!(
+        WatchlistTable::new(db.clone())
+            .page(user.id, query.limit(), query.offset)
+            .await
+    );
+    let ids = page.items.iter().map(|e| e.movie_id).collect::<Vec<_>>();
+    let movies = try_or_500!(MovieTable::new(db.clone()).by_ids(ids.clone()).await);
+    let states = try_or_500!(ProgressTable::new(db).states(user.id, ids).await);
+
+    Ok(json!(&Page {
+        total: page.total,
+        limit: page.limit,
+        offset: page.offset,
+        items: UserMovie::zip(movies, &states),
+    }))
+}
+
+/// The plays of the user with their movies, latest first
+pub async fn get_history(
+    db: SharedDb,
+    user: User,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let query = match PageQuery::from_query(&query) {
+        Ok(query) => query,
+        Err(e) => return Ok(error!(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+    let page = try_or_500!(
+        HistoryTable::new(db.clone())
+            .page(user.id, query.limit(), query.offset)
+            .await
+    );
+    let ids = page.items.iter().map(|p| p.movie_id).collect();
+    let movies = try_or_500!(MovieTable::new(db).by_ids(ids).await);
+
+    let items = page
+        .items
+        .into_iter()
+        .filter_map(|play| {
+            let movie = movies.iter().find(|m| m.id == play.movie_id)?.clone();
+            Some(HistoryEntry { play, movie })
+        })
+        .collect();
+    Ok(json!(&Page {
+        total: page.total,
+        limit: page.limit,
+        offset: page.offset,
+        items,
+    }))
+}
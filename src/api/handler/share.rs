@@ -0,0 +1,614 @@
+use super::thumbnail::image;
+use super::{base_url, stream};
+use crate::auth::{sign, verify_signature};
+use crate::config::{Config, SharedCfg};
+use crate::events::Events;
+use crate::ffmpeg::Cap;
+use crate::model::{
+    Movie, MovieTable, SecretTable, Share, ShareTable, Table, TrickplayTable, User, URL_KEY,
+    VIEWING,
+};
+use crate::sqlite::SharedDb;
+use crate::{thumbnail, tmdb};
+use chrono::Utc;
+use hyper::{header, Body, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+/// Longest a share may last, in seconds
+const MAX_EXPIRES_IN: i64 = 365 * 24 * 3600;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct NewShare {
+    /// Seconds until the links expire, `signed_url_ttl` if not set
+    expires_in: Option<i64>,
+    max_plays: Option<i32>,
+    max_height: Option<i32>,
+    max_bitrate: Option<i32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct LinkQuery {
+    sig: String,
+    /// Position in seconds to start the stream at
+    start: f64,
+    /// The viewing of the stream, for clients without cookies
+    viewing: Option<String>,
+}
+
+/// The signed links of a share, `url` is the one to send
+#[derive(Debug, Serialize)]
+struct Links {
+    url: String,
+    movie: String,
+    poster: String,
+    trickplay: String,
+}
+
+impl Links {
+    fn new(base: &str, share: &Share, key: &str) -> Links {
+        let sig = sign(key, &share.signed_message());
+        let link = |path: &str| format!("{}/shares/{}{}?sig={}", base, share.id, path, sig);
+        Links {
+            url: link("/stream"),
+            movie: link(""),
+            poster: link("/poster"),
+            trickplay: link("/trickplay.vtt"),
+        }
+    }
+}
+
+/// A share as its creator and admins see it
+#[derive(Debug, Serialize)]
+struct ShareInfo {
+    #[serde(flatten)]
+    share: Share,
+    title: String,
+    links: Links,
+}
+
+/// The movie of a share as the one it is shared with sees it
+#[derive(Debug, Serialize)]
+struct SharedMovie {
+    title: String,
+    release_year: i32,
+    overview: String,
+    /// Duration in seconds, if known
+    duration: Option<f64>,
+    expires_at: i64,
+    plays_left: Option<i32>,
+    links: Links,
+}
+
+/// Checks the limits of a new share
+fn validate(new: &NewShare) -> Result<(), String> {
+    match new.expires_in {
+        Some(secs) if !(1..=MAX_EXPIRES_IN).contains(&secs) => {
+            return Err(format!("expires_in must be 1 to {}", MAX_EXPIRES_IN));
+        }
+        _ => {}
+    }
+    match (new.max_plays, new.max_height, new.max_bitrate) {
+        (Some(plays), _, _) if plays < 1 => Err("max_plays must be at least 1".to_owned()),
+        (_, Some(height), _) if height < 144 => Err("max_height must be at least 144".to_owned()),
+        (_, _, Some(bitrate)) if bitrate < 100 => {
+            Err("max_bitrate must be at least 100".to_owned())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Applies the limits of `[shares]` to a new share of a user who isn't
+/// an admin. Missing limits are set to the most allowed.
+fn limit(new: &mut NewShare, config: &Config) -> Result<(), String> {
+    let max_expires_in = config.shares.max_expires_in as i64;
+    match new.expires_in {
+        Some(secs) if secs > max_expires_in => {
+            return Err(format!("expires_in must be at most {}", max_expires_in));
+        }
+        Some(_) => {}
+        None => new.expires_in = Some(max_expires_in.min(config.auth.signed_url_ttl as i64)),
+    }
+    let max_plays = config.shares.max_plays;
+    match new.max_plays {
+        Some(plays) if plays > max_plays => Err(format!("max_plays must be at most {}", max_plays)),
+        Some(_) => Ok(()),
+        None => {
+            new.max_plays = Some(max_plays);
+            Ok(())
+        }
+    }
+}
+
+/// The shares with their titles and links
+async fn infos(
+    db: SharedDb,
+    shares: Vec<Share>,
+    base: &str,
+) -> crate::model::Result<Vec<ShareInfo>> {
+    let key = SecretTable::new(db.clone()).get(URL_KEY).await?;
+    let ids = shares.iter().map(|s| s.movie_id).collect();
+    let movies = MovieTable::new(db).by_ids(ids).await?;
+    Ok(shares
+        .into_iter()
+        .map(|share| ShareInfo {
+            title: movies
+                .iter()
+                .find(|m| m.id == share.movie_id)
+                .map(|m| m.title.clone())
+                .unwrap_or_default(),
+            links: Links::new(base, &share, &key),
+            share,
+        })
+        .collect())
+}
+
+/// Shares a movie the user may see. The links are valid for the
+/// movie only and stop working when the share expires or is revoked.
+/// Users who aren't admins need `[shares] users` and are limited by
+/// `[shares]`.
+pub async fn post_share(
+    db: SharedDb,
+    config: SharedCfg,
+    user: User,
+    id: i32,
+    host: Option<String>,
+    body: Body,
+) -> Result<Response<Body>, hyper::Error> {
+    let mut new: NewShare = from_json!(body);
+    if let Err(msg) = validate(&new) {
+        return Ok(error!(StatusCode::BAD_REQUEST, msg));
+    }
+    if !user.is_admin {
+        if !config.shares.users {
+            return Ok(error!(
+                StatusCode::FORBIDDEN,
+                "only admins may share movies"
+            ));
+        }
+        if let Err(msg) = limit(&mut new, &config) {
+            return Ok(error!(StatusCode::BAD_REQUEST, msg));
+        }
+    }
+    if try_or_500!(MovieTable::new(db.clone()).for_user(id, user.id).await).is_none() {
+        return Ok(error!(StatusCode::NOT_FOUND, "Not Found"));
+    }
+
+    let expires_in = new.expires_in.unwrap_or(config.auth.signed_url_ttl as i64);
+    let share = Share {
+        id: 0,
+        user_id: user.id,
+        movie_id: id,
+        expires_at: Utc::now().timestamp() + expires_in,
+        max_plays: new.max_plays,
+        plays: 0,
+        played_at: 0,
+        max_height: new.max_height,
+        max_bitrate: new.max_bitrate,
+        created_at: 0,
+    };
+    let share = try_or_500!(ShareTable::new(db.clone()).create(share).await);
+    let base = base_url(&config, host);
+    let mut infos = try_or_500!(infos(db, vec![share], &base).await);
+    Ok(json!(&infos.remove(0)))
+}
+
+/// The shares created by the user, or by anyone for `all`
+pub async fn get_shares(
+    db: SharedDb,
+    config: SharedCfg,
+    user: User,
+    all: bool,
+    host: Option<String>,
+) -> Result<Response<Body>, hyper::Error> {
+    let user_id = if all { None } else { Some(user.id) };
+    let shares = try_or_500!(ShareTable::new(db.clone()).list(user_id).await);
+    let base = base_url(&config, host);
+    Ok(json!(&try_or_500!(infos(db, shares, &base).await)))
+}
+
+/// Revokes a share. Users may revoke their own, admins any.
+pub async fn delete_share(
+    db: SharedDb,
+    user: User,
+    id: i32,
+) -> Result<Response<Body>, hyper::Error> {
+    let table = ShareTable::new(db);
+    match try_or_500!(table.by_id(id).await) {
+        Some(share) if share.user_id == user.id || user.is_admin => {}
+        _ => return Ok(error!(StatusCode::NOT_FOUND, "Not Found")),
+    }
+    try_or_500!(table.delete(id).await);
+    Ok(empty!(StatusCode::NO_CONTENT))
+}
+
+/// Loads the share of a signed link with its movie, or the response to
+/// send instead. Links of unknown or revoked shares, with a wrong
+/// signature or of movies hidden from the creator of the share are all
+/// not found.
+async fn shared(
+    db: SharedDb,
+    id: i32,
+    query: &LinkQuery,
+) -> Result<(Share, Movie), Response<Body>> {
+    let internal_error = || error!(StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_SERVER_ERROR");
+    let share = match ShareTable::new(db.clone()).by_id(id).await {
+        Ok(Some(share)) => share,
+        Ok(None) => return Err(error!(StatusCode::NOT_FOUND, "Not Found")),
+        Err(e) => {
+            log::error!("{}", e);
+            return Err(internal_error());
+        }
+    };
+    let key = match SecretTable::new(db.clone()).get(URL_KEY).await {
+        Ok(key) => key,
+        Err(e) => {
+            log::error!("{}", e);
+            return Err(internal_error());
+        }
+    };
+    if !verify_signature(&key, &share.signed_message(), &query.sig) {
+        return Err(error!(StatusCode::NOT_FOUND, "Not Found"));
+    }
+    if share.expires_at < Utc::now().timestamp() {
+        return Err(error!(StatusCode::GONE, "link expired"));
+    }
+    let movies = MovieTable::new(db);
+    match movies.for_user(share.movie_id, share.user_id).await {
+        Ok(Some(movie)) => Ok((share, movie)),
+        Ok(None) => Err(error!(StatusCode::NOT_FOUND, "Not Found")),
+        Err(e) => {
+            log::error!("{}", e);
+            Err(internal_error())
+        }
+    }
+}
+
+/// Token of the `viewing` of a share, see `ShareTable::play`
+fn viewing_token(key: &str, share: &Share, viewing: i32) -> String {
+    let message = format!("{}\nviewing\n{}", share.signed_message(), viewing);
+    format!("{}.{}", viewing, sign(key, &message))
+}
+
+/// The viewing of a token, `None` if it isn't one of `share`
+fn token_viewing(key: &str, share: &Share, token: &str) -> Option<i32> {
+    let mut parts = token.splitn(2, '.');
+    let viewing = parts.next()?.parse().ok()?;
+    let message = format!("{}\nviewing\n{}", share.signed_message(), viewing);
+    if verify_signature(key, &message, parts.next()?) {
+        Some(viewing)
+    } else {
+        None
+    }
+}
+
+/// The value of the cookie `name` in a `Cookie` header
+fn cookie<'a>(cookies: &'a str, name: &str) -> Option<&'a str> {
+    cookies.split(';').find_map(|cookie| {
+        let mut parts = cookie.trim().splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(n), Some(value)) if n == name => Some(value),
+            _ => None,
+        }
+    })
+}
+
+/// The query of a signed link, `None` if it is invalid
+fn link_query(query: &str) -> Option<LinkQuery> {
+    serde_urlencoded::from_str::<LinkQuery>(query)
+        .ok()
+        .filter(|query| query.start.is_finite() && query.start >= 0.0)
+}
+
+/// The movie of a signed link with the links to its stream and images
+pub async fn get_shared_movie(
+    db: SharedDb,
+    config: SharedCfg,
+    id: i32,
+    host: Option<String>,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let query = match link_query(&query) {
+        Some(query) => query,
+        None => return Ok(error!(StatusCode::BAD_REQUEST, "invalid query")),
+    };
+    let (share, movie) = match shared(db.clone(), id, &query).await {
+        Ok(shared) => shared,
+        Err(resp) => return Ok(resp),
+    };
+    let durations = try_or_500!(
+        TrickplayTable::new(db.clone())
+            .durations(vec![movie.id])
+            .await
+    );
+    let key = try_or_500!(SecretTable::new(db).get(URL_KEY).await);
+
+    let shared = SharedMovie {
+        title: movie.title,
+        release_year: movie.release_year,
+        overview: movie.overview,
+        duration: durations.get(&movie.id).copied(),
+        expires_at: share.expires_at,
+        plays_left: share.max_plays.map(|max| (max - share.plays).max(0)),
+        links: Links::new(&base_url(&config, host), &share, &key),
+    };
+    Ok(json!(&shared))
+}
+
+/// The stream of a signed link. A request counts a play and starts a
+/// viewing, which the client keeps in the cookie `viewing` or passes as
+/// `viewing`. Its requests of the next hours don't count, so seeking
+/// is free, but other clients need plays of their own. The stream is
+/// capped to the quality the share allows.
+pub async fn get_shared_stream(
+    db: SharedDb,
+    config: SharedCfg,
+    events: Events,
+    id: i32,
+    query: String,
+    cookies: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let query = match link_query(&query) {
+        Some(query) => query,
+        None => return Ok(error!(StatusCode::BAD_REQUEST, "invalid query")),
+    };
+    let (share, movie) = match shared(db.clone(), id, &query).await {
+        Ok(shared) => shared,
+        Err(resp) => return Ok(resp),
+    };
+    let key = try_or_500!(SecretTable::new(db.clone()).get(URL_KEY).await);
+    let viewing = query
+        .viewing
+        .as_deref()
+        .or_else(|| cookie(&cookies, "viewing"))
+        .and_then(|token| token_viewing(&key, &share, token));
+    let now = Utc::now().timestamp();
+    let viewing = match try_or_500!(ShareTable::new(db).play(share.id, now, viewing).await) {
+        Some(viewing) => viewing,
+        None => return Ok(error!(StatusCode::GONE, "no plays left")),
+    };
+
+    let cap = Cap {
+        height: share.max_height,
+        bitrate: share.max_bitrate,
+    };
+    let mut resp = stream(&config, events, movie, share.user_id, query.start, cap);
+    // without a path the cookie is sent along the links of this share
+    let cookie = format!(
+        "viewing={}; Max-Age={}; HttpOnly; SameSite=Lax",
+        viewing_token(&key, &share, viewing),
+        VIEWING
+    );
+    resp.headers_mut()
+        .insert(header::SET_COOKIE, cookie.parse().unwrap());
+    Ok(resp)
+}
+
+/// The poster of a signed link, from TMDB or the generated one
+pub async fn get_shared_poster(
+    db: SharedDb,
+    config: SharedCfg,
+    id: i32,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let query = match link_query(&query) {
+        Some(query) => query,
+        None => return Ok(error!(StatusCode::BAD_REQUEST, "invalid query")),
+    };
+    let (_, movie) = match shared(db, id, &query).await {
+        Ok(shared) => shared,
+        Err(resp) => return Ok(resp),
+    };
+    if movie.poster_path.is_empty() {
+        return image(&thumbnail::poster_path(&config, movie.id)).await;
+    }
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(
+            header::LOCATION,
+            format!("{}{}", tmdb::POSTERS, movie.poster_path),
+        )
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// The seek preview thumbnails of a signed link, the sprites are
+/// signed like the track and start like the other links of the share
+pub async fn get_shared_trickplay(
+    db: SharedDb,
+    config: SharedCfg,
+    id: i32,
+    host: Option<String>,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let query = match link_query(&query) {
+        Some(query) => query,
+        None => return Ok(error!(StatusCode::BAD_REQUEST, "invalid query")),
+    };
+    let (share, _) = match shared(db.clone(), id, &query).await {
+        Ok(shared) => shared,
+        Err(resp) => return Ok(resp),
+    };
+    let trickplay = match try_or_500!(TrickplayTable::new(db).by_id(share.movie_id).await) {
+        Some(trickplay) => trickplay,
+        None => return Ok(error!(StatusCode::NOT_FOUND, "Not Found")),
+    };
+    let base = base_url(&config, host);
+    let vtt = thumbnail::vtt(&trickplay, |sheet| {
+        format!(
+            "{}/shares/{}/trickplay/{}.jpg?sig={}",
+            base, id, sheet, query.sig
+        )
+    });
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/vtt; charset=utf-8")
+        .header(header::CACHE_CONTROL, "no-store")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(vtt))
+        .unwrap())
+}
+
+/// One sprite sheet of the seek preview thumbnails of a signed link
+pub async fn get_shared_sprite(
+    db: SharedDb,
+    config: SharedCfg,
+    id: i32,
+    sheet: i32,
+    query: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let query = match link_query(&query) {
+        Some(query) => query,
+        None => return Ok(error!(StatusCode::BAD_REQUEST, "invalid query")),
+    };
+    let (share, _) = match shared(db, id, &query).await {
+        Ok(shared) => shared,
+        Err(resp) => return Ok(resp),
+    };
+    image(&thumbnail::sprite_path(&config, share.movie_id, sheet)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(Ok(()), validate(&NewShare::default()));
+        let new = NewShare {
+            expires_in: Some(3600),
+            max_plays: Some(1),
+            max_height: Some(720),
+            max_bitrate: Some(2000),
+        };
+        assert_eq!(Ok(()), validate(&new));
+        for new in [
+            NewShare {
+                expires_in: Some(0),
+                ..NewShare::default()
+            },
+            NewShare {
+                expires_in: Some(MAX_EXPIRES_IN + 1),
+                ..NewShare::default()
+            },
+            NewShare {
+                max_plays: Some(0),
+                ..NewShare::default()
+            },
+            NewShare {
+                max_height: Some(100),
+                ..NewShare::default()
+            },
+            NewShare {
+                max_bitrate: Some(50),
+                ..NewShare::default()
+            },
+        ] {
+            assert!(validate(&new).is_err(), "{:?}", new);
+        }
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut config = Config::default();
+        config.auth.signed_url_ttl = 3600;
+        config.shares.max_expires_in = 7200;
+        config.shares.max_plays = 2;
+
+        let mut new = NewShare::default();
+        assert_eq!(Ok(()), limit(&mut new, &config));
+        assert_eq!((Some(3600), Some(2)), (new.expires_in, new.max_plays));
+        config.auth.signed_url_ttl = 86400;
+        let mut new = NewShare::default();
+        assert_eq!(Ok(()), limit(&mut new, &config));
+        assert_eq!(Some(7200), new.expires_in);
+
+        let mut new = NewShare {
+            expires_in: Some(60),
+            max_plays: Some(1),
+            ..NewShare::default()
+        };
+        assert_eq!(Ok(()), limit(&mut new, &config));
+        assert_eq!((Some(60), Some(1)), (new.expires_in, new.max_plays));
+        for mut new in [
+            NewShare {
+                expires_in: Some(7201),
+                ..NewShare::default()
+            },
+            NewShare {
+                max_plays: Some(3),
+                ..NewShare::default()
+            },
+        ] {
+            assert!(limit(&mut new, &config).is_err(), "{:?}", new);
+        }
+    }
+
+    #[test]
+    fn test_links() {
+        let share = Share {
+            id: 7,
+            user_id: 1,
+            movie_id: 3,
+            expires_at: 2_000_000_000,
+            max_plays: None,
+            plays: 0,
+            played_at: 0,
+            max_height: None,
+            max_bitrate: None,
+            created_at: 1_600_000_000,
+        };
+        let links = Links::new("http://localhost:3000", &share, "key");
+        let sig = sign("key", &share.signed_message());
+        assert_eq!(
+            format!("http://localhost:3000/shares/7/stream?sig={}", sig),
+            links.url
+        );
+        assert_eq!(
+            format!("http://localhost:3000/shares/7?sig={}", sig),
+            links.movie
+        );
+
+        // the signature is only good for the share it was made for
+        let other = Share {
+            id: 8,
+            ..share.clone()
+        };
+        assert!(!verify_signature("key", &other.signed_message(), &sig));
+        let longer = Share {
+            expires_at: share.expires_at + 1,
+            ..share
+        };
+        assert!(!verify_signature("key", &longer.signed_message(), &sig));
+    }
+
+    #[test]
+    fn test_viewing() {
+        let share = Share {
+            id: 7,
+            user_id: 1,
+            movie_id: 3,
+            expires_at: 2_000_000_000,
+            max_plays: Some(1),
+            plays: 1,
+            played_at: 1_700_000_000,
+            max_height: None,
+            max_bitrate: None,
+            created_at: 1_600_000_000,
+        };
+        let token = viewing_token("key", &share, 1);
+        assert_eq!(Some(1), token_viewing("key", &share, &token));
+        let cookies = format!("lang=en; viewing={}", token);
+        assert_eq!(Some(token.as_str()), cookie(&cookies, "viewing"));
+        assert_eq!(None, cookie(&cookies, "view"));
+
+        // the token names one viewing of one share
+        let other = token.replacen('1', "2", 1);
+        assert_eq!(None, token_viewing("key", &share, &other));
+        let share = Share { id: 8, ..share };
+        assert_eq!(None, token_viewing("key", &share, &token));
+        assert_eq!(None, token_viewing("key", &share, "garbage"));
+    }
+}
@@ -0,0 +1,86 @@
+use crate::api::auth::signed_path;
+use crate::config::SharedCfg;
+use crate::model::{MovieTable, SecretTable, Table, TrickplayTable, User, URL_KEY};
+use crate::sqlite::SharedDb;
+use crate::thumbnail;
+use chrono::Utc;
+use hyper::{header, Body, Response, StatusCode};
+use std::path::Path;
+
+/// Seconds the sprite urls of a WebVTT track stay valid, long enough
+/// to watch the movie with a few pauses
+const SPRITE_TTL: i64 = 6 * 3600;
+
+/// Serves a generated jpeg, the images never change once written
+pub(super) async fn image(path: &Path) -> Result<Response<Body>, hyper::Error> {
+    match tokio::fs::read(path).await {
+        Ok(contents) => Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "image/jpeg")
+            .header(header::CACHE_CONTROL, "private, max-age=86400")
+            .header("Access-Control-Allow-Origin", "*")
+            .body(Body::from(contents))
+            .unwrap()),
+        Err(_) => Ok(error!(StatusCode::NOT_FOUND, "Not Found")),
+    }
+}
+
+/// The poster generated from a frame of the movie, for movies without
+/// a TMDB poster
+pub async fn get_poster(
+    db: SharedDb,
+    config: SharedCfg,
+    user: User,
+    id: i32,
+) -> Result<Response<Body>, hyper::Error> {
+    if try_or_500!(MovieTable::new(db).for_user(id, user.id).await).is_none() {
+        return Ok(error!(StatusCode::NOT_FOUND, "Not Found"));
+    }
+    image(&thumbnail::poster_path(&config, id)).await
+}
+
+/// One sprite sheet of the seek preview thumbnails
+pub async fn get_sprite(
+    db: SharedDb,
+    config: SharedCfg,
+    user: User,
+    id: i32,
+    sheet: i32,
+) -> Result<Response<Body>, hyper::Error> {
+    if try_or_500!(MovieTable::new(db).for_user(id, user.id).await).is_none() {
+        return Ok(error!(StatusCode::NOT_FOUND, "Not Found"));
+    }
+    image(&thumbnail::sprite_path(&config, id, sheet)).await
+}
+
+/// WebVTT track of the seek preview thumbnails. Browsers load the
+/// sprites without the `Authorization` header, so their urls are signed
+/// for the user and expire after `SPRITE_TTL`.
+pub async fn get_trickplay(
+    db: SharedDb,
+    config: SharedCfg,
+    user: User,
+    id: i32,
+) -> Result<Response<Body>, hyper::Error> {
+    if try_or_500!(MovieTable::new(db.clone()).for_user(id, user.id).await).is_none() {
+        return Ok(error!(StatusCode::NOT_FOUND, "Not Found"));
+    }
+    let trickplay = match try_or_500!(TrickplayTable::new(db.clone()).by_id(id).await) {
+        Some(trickplay) => trickplay,
+        None => return Ok(error!(StatusCode::NOT_FOUND, "Not Found")),
+    };
+    let key = try_or_500!(SecretTable::new(db).get(URL_KEY).await);
+    let expires = Utc::now().timestamp() + SPRITE_TTL;
+    let vtt = thumbnail::vtt(&trickplay, |sheet| {
+        let path = format!("/movies/{}/trickplay/{}.jpg", id, sheet);
+        config
+            .server
+            .url(&signed_path(&key, &path, user.id, expires))
+    });
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/vtt; charset=utf-8")
+        .header(header::CACHE_CONTROL, "no-store")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from(vtt))
+        .unwrap())
+}
@@ -0,0 +1,238 @@
+use crate::auth;
+use crate::model::{Table, User, UserTable};
+use crate::sqlite::SharedDb;
+use hyper::{header, Body, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize)]
+struct Credentials {
+    name: String,
+    password: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Login {
+    token: String,
+    user: User,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewUser {
+    name: String,
+    password: String,
+    #[serde(default)]
+    is_admin: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewPassword {
+    password: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Switch {
+    name: String,
+    pin: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewPin {
+    pin: String,
+}
+
+/// PINs are 4 to 8 digits
+fn valid_pin(pin: &str) -> bool {
+    (4..=8).contains(&pin.len()) && pin.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Checks the password of the user `name` and issues a new API token.
+/// Returns the user with the token, or the response to send instead.
+/// Every login goes through here.
+pub(super) async fn authenticate(
+    db: SharedDb,
+    name: &str,
+    password: String,
+) -> Result<(User, String), Response<Body>> {
+    let internal_error = |e: &dyn std::fmt::Display| {
+        log::error!("{}", e);
+        error!(StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_SERVER_ERROR")
+    };
+    let table = UserTable::new(db);
+
+    let user = match table.by_name(name).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Err(error!(StatusCode::UNAUTHORIZED, "invalid name or password")),
+        Err(e) => return Err(internal_error(&e)),
+    };
+
+    // argon2 is slow on purpose, don't block the executor with it
+    let hash = user.password_hash.clone();
+    let valid = tokio::task::spawn_blocking(move || auth::verify_password(&password, &hash));
+    match valid.await {
+        Ok(true) => {}
+        Ok(false) => return Err(error!(StatusCode::UNAUTHORIZED, "invalid name or password")),
+        Err(e) => return Err(internal_error(&e)),
+    }
+
+    match table.issue_token(user.id).await {
+        Ok(token) => Ok((user, token)),
+        Err(e) => Err(internal_error(&e)),
+    }
+}
+
+/// Exchange name and password for a new API token
+pub async fn login(db: SharedDb, body: Body) -> Result<Response<Body>, hyper::Error> {
+    let credentials: Credentials = from_json!(body);
+    match authenticate(db, &credentials.name, credentials.password).await {
+        Ok((user, token)) => Ok(json!(&Login { token, user })),
+        Err(resp) => Ok(resp),
+    }
+}
+
+/// Switch the profile: exchange the token of this request and the PIN of
+/// another user for a token of that user. Only users with a PIN can be
+/// switched to, admins never. The token of this request is revoked.
+/// After five wrong PINs in a row the PIN is locked until
+/// it is set again.
+pub async fn switch(
+    db: SharedDb,
+    token: String,
+    body: Body,
+) -> Result<Response<Body>, hyper::Error> {
+    let switch: Switch = from_json!(body);
+    let table = UserTable::new(db);
+
+    let user = match try_or_500!(table.by_name(&switch.name).await) {
+        Some(user) if !user.pin_hash.is_empty() && !user.is_admin => user,
+        _ => return Ok(error!(StatusCode::UNAUTHORIZED, "invalid name or PIN")),
+    };
+    if !try_or_500!(table.pin_attempt(user.id).await) {
+        let msg = "PIN locked after too many attempts, it has to be set again";
+        return Ok(error!(StatusCode::TOO_MANY_REQUESTS, msg));
+    }
+
+    let hash = user.pin_hash.clone();
+    let valid = tokio::task::spawn_blocking(move || auth::verify_password(&switch.pin, &hash));
+    if !try_or_500!(valid.await) {
+        return Ok(error!(StatusCode::UNAUTHORIZED, "invalid name or PIN"));
+    }
+
+    try_or_500!(table.pin_succeeded(user.id).await);
+    try_or_500!(table.revoke_token(&token).await);
+    let token = try_or_500!(table.issue_token(user.id).await);
+    Ok(json!(&Login { token, user }))
+}
+
+/// Revoke the token used for this request
+pub async fn logout(db: SharedDb, token: String) -> Result<Response<Body>, hyper::Error> {
+    try_or_500!(UserTable::new(db).revoke_token(&token).await);
+    Ok(empty!(StatusCode::NO_CONTENT))
+}
+
+pub async fn get_me(user: User) -> Result<Response<Body>, hyper::Error> {
+    Ok(json!(&user))
+}
+
+pub async fn get_users(db: SharedDb) -> Result<Response<Body>, hyper::Error> {
+    let users = try_or_500!(UserTable::new(db).all().await);
+    Ok(json!(&users))
+}
+
+pub async fn post_user(db: SharedDb, body: Body) -> Result<Response<Body>, hyper::Error> {
+    let new: NewUser = from_json!(body);
+    if new.name.trim().is_empty() || new.password.is_empty() {
+        return Ok(error!(
+            StatusCode::BAD_REQUEST,
+            "name and password must not be empty"
+        ));
+    }
+
+    let table = UserTable::new(db);
+    if try_or_500!(table.by_name(&new.name).await).is_some() {
+        return Ok(error!(StatusCode::CONFLICT, "user already exists"));
+    }
+    // argon2 is slow on purpose, don't block the executor with it
+    let name = new.name.trim().to_owned();
+    let (password, is_admin) = (new.password, new.is_admin);
+    let user = tokio::task::spawn_blocking(move || User::new(&name, &password, is_admin));
+    try_or_500!(table.save(try_or_500!(user.await)).await);
+
+    let user = try_or_500!(table.by_name(new.name.trim()).await);
+    Ok(json!(&user))
+}
+
+pub async fn delete_user(db: SharedDb, id: i32) -> Result<Response<Body>, hyper::Error> {
+    if try_or_500!(UserTable::new(db).delete(id).await) {
+        Ok(empty!(StatusCode::NO_CONTENT))
+    } else {
+        Ok(error!(StatusCode::NOT_FOUND, "Not Found"))
+    }
+}
+
+/// Change the password of a user. Users may change their own password,
+/// admins the password of everyone.
+pub async fn put_password(
+    db: SharedDb,
+    user: User,
+    id: i32,
+    body: Body,
+) -> Result<Response<Body>, hyper::Error> {
+    if user.id != id && !user.is_admin {
+        return Ok(error!(StatusCode::FORBIDDEN, "Forbidden"));
+    }
+    let new: NewPassword = from_json!(body);
+    if new.password.is_empty() {
+        return Ok(error!(
+            StatusCode::BAD_REQUEST,
+            "password must not be empty"
+        ));
+    }
+
+    let table = UserTable::new(db);
+    if try_or_500!(table.by_id(id).await).is_none() {
+        return Ok(error!(StatusCode::NOT_FOUND, "Not Found"));
+    }
+    try_or_500!(table.set_password(id, &new.password).await);
+    Ok(empty!(StatusCode::NO_CONTENT))
+}
+
+/// Revoke all tokens of a user, e.g. after a device got lost
+pub async fn delete_tokens(db: SharedDb, id: i32) -> Result<Response<Body>, hyper::Error> {
+    try_or_500!(UserTable::new(db).revoke_tokens(id).await);
+    Ok(empty!(StatusCode::NO_CONTENT))
+}
+
+/// Set (`PUT`) or remove (`DELETE`) the PIN to switch to a user. Users
+/// may change their own PIN, admins the PIN of everyone. Admins can't
+/// be switched to and have no PIN.
+pub async fn set_pin(
+    db: SharedDb,
+    user: User,
+    id: i32,
+    body: Option<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    if user.id != id && !user.is_admin {
+        return Ok(error!(StatusCode::FORBIDDEN, "Forbidden"));
+    }
+    let pin = match body {
+        Some(body) => {
+            let new: NewPin = from_json!(body);
+            if !valid_pin(&new.pin) {
+                return Ok(error!(StatusCode::BAD_REQUEST, "PIN must be 4 to 8 digits"));
+            }
+            Some(new.pin)
+        }
+        None => None,
+    };
+
+    let table = UserTable::new(db);
+    match try_or_500!(table.by_id(id).await) {
+        Some(target) if target.is_admin && pin.is_some() => {
+            return Ok(error!(StatusCode::BAD_REQUEST, "admins can't have a PIN"));
+        }
+        Some(_) => {}
+        None => return Ok(error!(StatusCode::NOT_FOUND, "Not Found")),
+    }
+    try_or_500!(table.set_pin(id, pin.as_deref()).await);
+    Ok(empty!(StatusCode::NO_CONTENT))
+}
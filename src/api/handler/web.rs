@@ -0,0 +1,121 @@
+use crate::config::SharedCfg;
+use hyper::{header, Body, Response, StatusCode};
+use percent_encoding::percent_decode_str;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Resolves the path of a request to a file below `root`. Directories
+/// resolve to their `index.html`. Returns `None` if the path tries to
+/// escape `root`.
+pub fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let path = percent_decode_str(path).decode_utf8().ok()?;
+    let mut file = root.to_path_buf();
+
+    for segment in path.split('/') {
+        if segment.contains('\\') || segment.contains('\0') {
+            return None;
+        }
+        match Path::new(segment).components().next() {
+            None | Some(Component::CurDir) => continue,
+            Some(Component::Normal(_)) => file.push(segment),
+            _ => return None,
+        }
+    }
+
+    if path.ends_with('/') || file.is_dir() {
+        file.push("index.html");
+    }
+    Some(file)
+}
+
+/// Serves a file of the configured web root
+pub async fn get_file(
+    config: SharedCfg,
+    path: String,
+    if_none_match: Option<String>,
+) -> Result<Response<Body>, hyper::Error> {
+    let not_found = || Ok(error!(StatusCode::NOT_FOUND, "Not Found"));
+
+    let root = match Path::new(&config.web.root).canonicalize() {
+        Ok(root) => root,
+        Err(_) => return not_found(),
+    };
+    let file = match resolve(&root, &path).map(|f| f.canonicalize()) {
+        // symlinks must not lead out of the web root either
+        Some(Ok(file)) if file.starts_with(&root) => file,
+        _ => return not_found(),
+    };
+    let meta = match tokio::fs::metadata(&file).await {
+        Ok(meta) if meta.is_file() => meta,
+        _ => return not_found(),
+    };
+
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let etag = format!("\"{:x}-{:x}\"", modified, meta.len());
+
+    let mime = mime_guess::from_path(&file).first_or_octet_stream();
+    // html references the other files, so it must always be revalidated
+    let cache = if mime.subtype() == mime_guess::mime::HTML {
+        "no-cache"
+    } else {
+        "public, max-age=3600"
+    };
+
+    let builder = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, cache);
+
+    if if_none_match.as_deref() == Some(etag.as_str()) {
+        return Ok(builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    let content_type = match mime.type_() {
+        mime_guess::mime::TEXT => format!("{}; charset=utf-8", mime),
+        _ if mime.subtype() == mime_guess::mime::JAVASCRIPT => format!("{}; charset=utf-8", mime),
+        _ => mime.to_string(),
+    };
+    let contents = try_or_500!(tokio::fs::read(&file).await);
+
+    Ok(builder
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(contents))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let root = Path::new("static");
+        assert_eq!(Some(root.join("index.html")), resolve(root, "/"));
+        assert_eq!(Some(root.join("js/index.html")), resolve(root, "/js/"));
+        assert_eq!(Some(root.join("js/index.html")), resolve(root, "/js"));
+        assert_eq!(
+            Some(root.join("js/player.js")),
+            resolve(root, "/js/player.js")
+        );
+        assert_eq!(
+            Some(root.join("js/player.js")),
+            resolve(root, "/./js//player.js")
+        );
+        assert_eq!(Some(root.join("a b.js")), resolve(root, "/a%20b.js"));
+
+        assert_eq!(None, resolve(root, "/../Cargo.toml"));
+        assert_eq!(None, resolve(root, "/js/../../Cargo.toml"));
+        assert_eq!(None, resolve(root, "/%2e%2e/Cargo.toml"));
+        assert_eq!(None, resolve(root, "/js/..%2f..%2fCargo.toml"));
+        assert_eq!(None, resolve(root, "/..\\Cargo.toml"));
+        assert_eq!(None, resolve(root, "/%00"));
+        assert_eq!(None, resolve(root, "/%ff"));
+    }
+}
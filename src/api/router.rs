@@ -6,6 +6,17 @@ use std::pin::Pin;
 pub type Handler =
     Pin<Box<dyn Future<Output = Result<Response<Body>, Error>> + Send + Sync + 'static>>;
 
+/// Who is allowed to call a route
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    /// No authentication required
+    Public,
+    /// Any logged in user
+    User,
+    /// Only users with the admin role
+    Admin,
+}
+
 pub struct RouteBuilder {
     route: Route,
 }
@@ -15,6 +26,25 @@ impl RouteBuilder {
         RouteBuilder { route }
     }
 
+    /// The route can be called without authentication
+    pub fn public(mut self) -> RouteBuilder {
+        self.route.access = Access::Public;
+        self
+    }
+
+    /// The route can only be called by admins
+    pub fn admin(mut self) -> RouteBuilder {
+        self.route.access = Access::Admin;
+        self
+    }
+
+    /// The route can also be called with a signed url instead of a
+    /// token, see `auth::signed_path`
+    pub fn signed(mut self) -> RouteBuilder {
+        self.route.signed = true;
+        self
+    }
+
     pub fn name(mut self, name: &str) -> Route {
         self.route.name = name.to_owned();
         self.route
@@ -35,6 +65,12 @@ pub struct Route {
 
     /// Extraced parts of the path
     pub params: Vec<String>,
+
+    /// Who is allowed to call the route
+    pub access: Access,
+
+    /// Whether signed urls are accepted
+    pub signed: bool,
 }
 
 impl Route {
@@ -42,12 +78,26 @@ impl Route {
         Route::from(Method::GET, path)
     }
 
+    pub fn post(path: &str) -> RouteBuilder {
+        Route::from(Method::POST, path)
+    }
+
+    pub fn put(path: &str) -> RouteBuilder {
+        Route::from(Method::PUT, path)
+    }
+
+    pub fn delete(path: &str) -> RouteBuilder {
+        Route::from(Method::DELETE, path)
+    }
+
     fn from(method: Method, path: &str) -> RouteBuilder {
         RouteBuilder::new(Route {
             method,
             path: Path::new(path),
             name: "".to_owned(),
             params: Vec::new(),
+            access: Access::User,
+            signed: false,
         })
     }
 }
@@ -61,20 +111,68 @@ impl Router {
         Router { routes: Vec::new() }
     }
 
-    pub fn is_match(&mut self, path: &str) -> Option<Route> {
-        for route in self.routes.iter_mut() {
-            if route.path.matcher.is_match(path) {
-                let caps = route.path.matcher.captures(path).unwrap();
-                if caps.len() > 1 {
-                    route.params.push(caps[1].to_owned());
-                }
-                return Some(route.clone());
+    /// Finds the first route matching `method` and `path`. The returned
+    /// route holds the captured parts of the path in `params`.
+    pub fn is_match(&self, method: &Method, path: &str) -> Option<Route> {
+        for route in self.routes.iter() {
+            if route.method != *method {
+                continue;
+            }
+            if let Some(caps) = route.path.matcher.captures(path) {
+                let mut route = route.clone();
+                route.params = caps
+                    .iter()
+                    .skip(1)
+                    .filter_map(|c| c.map(|c| c.as_str().to_owned()))
+                    .collect();
+                return Some(route);
             }
         }
         None
     }
 
+    /// Returns `true` if any route matches `path`, regardless of the method
+    pub fn has_path(&self, path: &str) -> bool {
+        self.routes.iter().any(|r| r.path.matcher.is_match(path))
+    }
+
     pub fn add(&mut self, route: Route) {
         self.routes.push(route);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_match() {
+        let mut router = Router::new();
+        router.add(Route::get(r"/movies/(\d+)").name("get_movie"));
+        router.add(
+            Route::delete(r"/users/(\d+)/tokens/(\w+)")
+                .admin()
+                .name("revoke"),
+        );
+        router.add(Route::post("/auth/login").public().name("login"));
+
+        let route = router.is_match(&Method::GET, "/movies/1").unwrap();
+        assert_eq!("get_movie", route.name);
+        assert_eq!(vec!["1".to_owned()], route.params);
+        assert_eq!(Access::User, route.access);
+
+        // params of an earlier match must not leak into the next one
+        let route = router.is_match(&Method::GET, "/movies/2").unwrap();
+        assert_eq!(vec!["2".to_owned()], route.params);
+
+        let route = router
+            .is_match(&Method::DELETE, "/users/3/tokens/abc")
+            .unwrap();
+        assert_eq!(vec!["3".to_owned(), "abc".to_owned()], route.params);
+        assert_eq!(Access::Admin, route.access);
+
+        assert!(router.is_match(&Method::GET, "/auth/login").is_none());
+        assert!(router.has_path("/auth/login"));
+        assert!(router.is_match(&Method::GET, "/movies/1/2").is_none());
+    }
+}
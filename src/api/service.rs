@@ -1,42 +1,930 @@
+use hyper::header::HeaderValue;
 use hyper::service::Service;
-use hyper::{Body, Request, Response};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use log::warn;
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Instant;
 
 use super::{
-    handler,
+    auth, handler,
     router::{Handler, Route, Router},
 };
-use crate::config::SharedCfg;
+use crate::config::{CfgHandle, SharedCfg};
+use crate::dlna::Dlna;
+use crate::events::Events;
+use crate::jellyfin::Authorization;
+use crate::jobs::Jobs;
+use crate::logging;
+use crate::metrics::metrics;
+use crate::model::User;
+use crate::party::Parties;
 use crate::sqlite::SharedDb;
-use serde::Serialize;
 
-type FuturePin<T> = Pin<Box<dyn Future<Output = T> + Send + Sync + 'static>>;
+/// Header carrying the request id
+const REQUEST_ID: &str = "x-request-id";
 
-#[derive(Debug, Serialize)]
-struct Movie {
-    id: i32,
-    title: String,
-    release_year: i32,
-    file_path: String,
-    poster_path: String,
-    backdrop_path: String,
-}
+type FuturePin<T> = Pin<Box<dyn Future<Output = T> + Send + Sync + 'static>>;
 
 pub struct ApiService {
-    config: SharedCfg,
+    config: CfgHandle,
     db: SharedDb,
+    jobs: Jobs,
+    events: Events,
+    parties: Parties,
+    dlna: Dlna,
     router: Router,
 }
 
 impl ApiService {
-    fn new(db: SharedDb, config: SharedCfg) -> ApiService {
+    fn new(
+        db: SharedDb,
+        config: CfgHandle,
+        jobs: Jobs,
+        events: Events,
+        parties: Parties,
+        dlna: Dlna,
+    ) -> ApiService {
         let mut router = Router::new();
         router.add(Route::get(r"/movies/(\d+)").name("get_movie"));
         router.add(Route::get("/movies/").name("get_movies"));
-        router.add(Route::get(r"/stream/(\d+)").name("get_stream"));
-        ApiService { config, db, router }
+        router.add(Route::post(r"/movies/(\d+)/progress").name("post_progress"));
+        router.add(Route::put(r"/movies/(\d+)/watched").name("put_watched"));
+        router.add(Route::delete(r"/movies/(\d+)/watched").name("delete_watched"));
+        router.add(Route::put(r"/movies/(\d+)/rating").name("put_rating"));
+        router.add(Route::delete(r"/movies/(\d+)/rating").name("delete_rating"));
+        router.add(Route::put(r"/movies/(\d+)/watchlist").name("put_watchlist"));
+        router.add(Route::delete(r"/movies/(\d+)/watchlist").name("delete_watchlist"));
+        router.add(
+            Route::get(r"/movies/(\d+)/poster")
+                .signed()
+                .name("get_poster"),
+        );
+        router.add(Route::get(r"/movies/(\d+)/trickplay\.vtt").name("get_trickplay"));
+        router.add(
+            Route::get(r"/movies/(\d+)/trickplay/(\d+)\.jpg")
+                .signed()
+                .name("get_sprite"),
+        );
+        router.add(Route::get(r"/stream/(\d+)").signed().name("get_stream"));
+        router.add(Route::get("/search").name("get_search"));
+        router.add(Route::get(r"/playlist\.(m3u8|xspf)").name("get_playlist"));
+        router.add(Route::post(r"/movies/(\d+)/shares").name("post_share"));
+        router.add(Route::get("/shares").name("get_shares"));
+        router.add(Route::delete(r"/shares/(\d+)").name("delete_share"));
+        // the links of shares are checked by their handlers
+        router.add(
+            Route::get(r"/shares/(\d+)")
+                .public()
+                .name("get_shared_movie"),
+        );
+        router.add(
+            Route::get(r"/shares/(\d+)/stream")
+                .public()
+                .name("get_shared_stream"),
+        );
+        router.add(
+            Route::get(r"/shares/(\d+)/poster")
+                .public()
+                .name("get_shared_poster"),
+        );
+        router.add(
+            Route::get(r"/shares/(\d+)/trickplay\.vtt")
+                .public()
+                .name("get_shared_trickplay"),
+        );
+        router.add(
+            Route::get(r"/shares/(\d+)/trickplay/(\d+)\.jpg")
+                .public()
+                .name("get_shared_sprite"),
+        );
+        router.add(Route::get("/events").name("get_events"));
+        router.add(Route::post("/parties").name("post_party"));
+        router.add(Route::get("/parties/([0-9A-Za-z]+)").name("get_party"));
+        router.add(Route::get("/parties/([0-9A-Za-z]+)/socket").name("get_party_socket"));
+        router.add(
+            Route::get(r"/dlna/description\.xml")
+                .public()
+                .name("get_dlna_description"),
+        );
+        router.add(
+            Route::get(r"/dlna/(ContentDirectory|ConnectionManager)\.xml")
+                .public()
+                .name("get_dlna_scpd"),
+        );
+        router.add(
+            Route::post("/dlna/control/(ContentDirectory|ConnectionManager)")
+                .public()
+                .name("post_dlna_control"),
+        );
+        // the Jellyfin apps, paths are matched like Jellyfin does
+        // without regard to case and may name the user
+        const JF: &str = "(?i)/jellyfin";
+        const JF_USER: &str = "(?i)/jellyfin(?:/Users/[0-9a-f-]+)?";
+        let jf = |path: &str| format!("{}{}", JF, path);
+        let jf_user = |path: &str| format!("{}{}", JF_USER, path);
+        router.add(
+            Route::get(&jf("/System/Info/Public"))
+                .public()
+                .name("get_jellyfin_info_public"),
+        );
+        router.add(Route::get(&jf("/System/Info")).name("get_jellyfin_info"));
+        router.add(
+            Route::get(&jf("/Users/Public"))
+                .public()
+                .name("get_jellyfin_public_users"),
+        );
+        router.add(
+            Route::post(&jf("/Users/AuthenticateByName"))
+                .public()
+                .name("post_jellyfin_authenticate"),
+        );
+        router.add(Route::get(&jf("/Users/Me")).name("get_jellyfin_me"));
+        router.add(Route::get(&jf("/Users/([0-9a-f-]+)")).name("get_jellyfin_user"));
+        router.add(Route::get(&jf_user("/(?:User)?Views")).name("get_jellyfin_views"));
+        router.add(Route::get(&jf_user("/(?:User)?Items/Resume")).name("get_jellyfin_resume"));
+        router.add(Route::get(&jf_user("/Items/Latest")).name("get_jellyfin_latest"));
+        router.add(Route::get(&jf_user("/Items")).name("get_jellyfin_items"));
+        router.add(Route::get(&jf_user("/Items/([0-9a-f-]+)")).name("get_jellyfin_item"));
+        router.add(
+            Route::get(&jf(r"/Items/([0-9a-f-]+)/Images/Primary(?:/\d+)?"))
+                .public()
+                .name("get_jellyfin_image"),
+        );
+        router.add(
+            Route::get(&jf("/Items/([0-9a-f-]+)/PlaybackInfo")).name("get_jellyfin_playback_info"),
+        );
+        router.add(
+            Route::post(&jf("/Items/([0-9a-f-]+)/PlaybackInfo"))
+                .name("post_jellyfin_playback_info"),
+        );
+        router.add(
+            Route::get(&jf(r"/Videos/([0-9a-f-]+)/stream(?:\.mp4)?")).name("get_jellyfin_video"),
+        );
+        router.add(
+            Route::post(&jf("/Sessions/Playing(?:/Progress|/Stopped)?"))
+                .name("post_jellyfin_playing"),
+        );
+        router.add(
+            Route::post(&jf_user("/(?:User)?PlayedItems/([0-9a-f-]+)"))
+                .name("post_jellyfin_played"),
+        );
+        router.add(
+            Route::delete(&jf_user("/(?:User)?PlayedItems/([0-9a-f-]+)"))
+                .name("delete_jellyfin_played"),
+        );
+        router.add(
+            Route::post(&jf_user("/(?:User)?FavoriteItems/([0-9a-f-]+)"))
+                .name("post_jellyfin_favorite"),
+        );
+        router.add(
+            Route::delete(&jf_user("/(?:User)?FavoriteItems/([0-9a-f-]+)"))
+                .name("delete_jellyfin_favorite"),
+        );
+        router.add(Route::post("/auth/login").public().name("login"));
+        router.add(Route::post("/auth/logout").name("logout"));
+        router.add(Route::post("/auth/switch").name("switch"));
+        router.add(Route::get("/users/me").name("get_me"));
+        router.add(Route::get("/users/me/continue").name("get_continue"));
+        router.add(Route::get("/users/me/history").name("get_history"));
+        router.add(Route::get("/users/me/watchlist").name("get_watchlist"));
+        router.add(Route::get("/collections").name("get_collections"));
+        router.add(Route::post("/collections").name("post_collection"));
+        router.add(Route::get(r"/collections/(\d+)").name("get_collection"));
+        router.add(Route::put(r"/collections/(\d+)").name("put_collection"));
+        router.add(Route::delete(r"/collections/(\d+)").name("delete_collection"));
+        router.add(Route::get(r"/collections/(\d+)/movies").name("get_collection_movies"));
+        router.add(Route::put(r"/collections/(\d+)/movies/(\d+)").name("put_collection_movie"));
+        router
+            .add(Route::delete(r"/collections/(\d+)/movies/(\d+)").name("delete_collection_movie"));
+        router.add(Route::put(r"/collections/(\d+)/order").name("put_collection_order"));
+        router.add(Route::get(r"/collections/(\d+)/play").name("get_collection_play"));
+        router.add(Route::get("/users").admin().name("get_users"));
+        router.add(Route::post("/users").admin().name("post_user"));
+        router.add(Route::delete(r"/users/(\d+)").admin().name("delete_user"));
+        router.add(Route::put(r"/users/(\d+)/password").name("put_password"));
+        router.add(Route::put(r"/users/(\d+)/pin").name("put_pin"));
+        router.add(Route::delete(r"/users/(\d+)/pin").name("delete_pin"));
+        router.add(Route::get(r"/users/(\d+)/restrictions").name("get_restrictions"));
+        router.add(
+            Route::put(r"/users/(\d+)/restrictions")
+                .admin()
+                .name("put_restrictions"),
+        );
+        router.add(
+            Route::delete(r"/users/(\d+)/restrictions")
+                .admin()
+                .name("delete_restrictions"),
+        );
+        router.add(
+            Route::delete(r"/users/(\d+)/tokens")
+                .admin()
+                .name("delete_tokens"),
+        );
+        router.add(Route::get("/metrics").admin().name("get_metrics"));
+        router.add(Route::get("/admin/jobs").admin().name("get_jobs"));
+        router.add(Route::post("/admin/jobs").admin().name("post_job"));
+        router.add(Route::get(r"/admin/jobs/(\d+)").admin().name("get_job"));
+        router.add(
+            Route::delete(r"/admin/jobs/(\d+)")
+                .admin()
+                .name("delete_job"),
+        );
+        router.add(Route::get("/admin/shares").admin().name("get_admin_shares"));
+        router.add(
+            Route::delete(r"/admin/shares/(\d+)")
+                .admin()
+                .name("delete_admin_share"),
+        );
+        // everything else is served from the web root
+        router.add(Route::get("/(.*)").public().name("get_file"));
+        ApiService {
+            config,
+            db,
+            jobs,
+            events,
+            parties,
+            dlna,
+            router,
+        }
+    }
+}
+
+/// What the handlers of a request may use
+struct State {
+    db: SharedDb,
+    config: SharedCfg,
+    jobs: Jobs,
+    events: Events,
+    parties: Parties,
+    dlna: Dlna,
+}
+
+/// Maps a matched and authenticated route to its handler
+fn dispatch(
+    route: Route,
+    req: Request<Body>,
+    user: Option<User>,
+    token: Option<String>,
+    state: State,
+) -> Handler {
+    let State {
+        db,
+        config,
+        jobs,
+        events,
+        parties,
+        dlna,
+    } = state;
+    match route.name.as_ref() {
+        "get_movies" => {
+            let query = req.uri().query().unwrap_or("").to_owned();
+            Box::pin(handler::get_movies(db, user.unwrap(), query))
+        }
+        "get_movie" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::get_movie(db, user.unwrap(), id))
+        }
+        "get_stream" => {
+            let id = route.params[0].parse().unwrap();
+            let query = req.uri().query().unwrap_or("").to_owned();
+            Box::pin(handler::get_stream(
+                db,
+                config,
+                events,
+                user.unwrap(),
+                id,
+                query,
+            ))
+        }
+        "get_poster" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::thumbnail::get_poster(
+                db,
+                config,
+                user.unwrap(),
+                id,
+            ))
+        }
+        "get_trickplay" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::thumbnail::get_trickplay(
+                db,
+                config,
+                user.unwrap(),
+                id,
+            ))
+        }
+        "get_sprite" => {
+            let id = route.params[0].parse().unwrap();
+            let sheet = route.params[1].parse().unwrap();
+            Box::pin(handler::thumbnail::get_sprite(
+                db,
+                config,
+                user.unwrap(),
+                id,
+                sheet,
+            ))
+        }
+        "get_metrics" => Box::pin(handler::get_metrics()),
+        "get_search" => {
+            let query = req.uri().query().unwrap_or("").to_owned();
+            Box::pin(handler::get_search(db, user.unwrap(), query))
+        }
+        "get_playlist" => {
+            let query = req.uri().query().unwrap_or("").to_owned();
+            Box::pin(handler::playlist::get_playlist(
+                db,
+                config,
+                user.unwrap(),
+                route.params[0].clone(),
+                host(&req),
+                query,
+            ))
+        }
+        "post_share" => {
+            let id = route.params[0].parse().unwrap();
+            let host = host(&req);
+            Box::pin(handler::share::post_share(
+                db,
+                config,
+                user.unwrap(),
+                id,
+                host,
+                req.into_body(),
+            ))
+        }
+        "get_shares" | "get_admin_shares" => Box::pin(handler::share::get_shares(
+            db,
+            config,
+            user.unwrap(),
+            route.name == "get_admin_shares",
+            host(&req),
+        )),
+        "delete_share" | "delete_admin_share" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::share::delete_share(db, user.unwrap(), id))
+        }
+        "get_shared_movie" => {
+            let id = route.params[0].parse().unwrap();
+            let query = req.uri().query().unwrap_or("").to_owned();
+            Box::pin(handler::share::get_shared_movie(
+                db,
+                config,
+                id,
+                host(&req),
+                query,
+            ))
+        }
+        "get_shared_stream" => {
+            let id = route.params[0].parse().unwrap();
+            let query = req.uri().query().unwrap_or("").to_owned();
+            let cookies = req
+                .headers()
+                .get(header::COOKIE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("")
+                .to_owned();
+            Box::pin(handler::share::get_shared_stream(
+                db, config, events, id, query, cookies,
+            ))
+        }
+        "get_shared_poster" => {
+            let id = route.params[0].parse().unwrap();
+            let query = req.uri().query().unwrap_or("").to_owned();
+            Box::pin(handler::share::get_shared_poster(db, config, id, query))
+        }
+        "get_shared_trickplay" => {
+            let id = route.params[0].parse().unwrap();
+            let query = req.uri().query().unwrap_or("").to_owned();
+            Box::pin(handler::share::get_shared_trickplay(
+                db,
+                config,
+                id,
+                host(&req),
+                query,
+            ))
+        }
+        "get_shared_sprite" => {
+            let id = route.params[0].parse().unwrap();
+            let sheet = route.params[1].parse().unwrap();
+            let query = req.uri().query().unwrap_or("").to_owned();
+            Box::pin(handler::share::get_shared_sprite(
+                db, config, id, sheet, query,
+            ))
+        }
+        "get_events" => Box::pin(handler::event::get_events(db, events, user.unwrap())),
+        "post_party" => Box::pin(handler::party::post_party(
+            db,
+            config,
+            parties,
+            user.unwrap(),
+            req.into_body(),
+        )),
+        "get_party" => Box::pin(handler::party::get_party(
+            db,
+            config,
+            parties,
+            user.unwrap(),
+            route.params[0].clone(),
+        )),
+        "get_party_socket" => Box::pin(handler::party::get_party_socket(
+            db,
+            parties,
+            user.unwrap(),
+            route.params[0].clone(),
+            req,
+        )),
+        "get_dlna_description" => Box::pin(handler::dlna::get_description(dlna)),
+        "get_dlna_scpd" => Box::pin(handler::dlna::get_scpd(dlna, route.params[0].clone())),
+        "post_dlna_control" => {
+            let header = |name| {
+                req.headers()
+                    .get(name)
+                    .and_then(|v: &HeaderValue| v.to_str().ok())
+                    .map(|v| v.to_owned())
+            };
+            let host = header(header::HOST.as_str());
+            let action = header("soapaction");
+            Box::pin(handler::dlna::post_control(
+                db,
+                config,
+                dlna,
+                route.params[0].clone(),
+                host,
+                action,
+                req.into_body(),
+            ))
+        }
+        "get_jellyfin_info_public" | "get_jellyfin_info" => {
+            Box::pin(handler::jellyfin::get_info(config))
+        }
+        "get_jellyfin_public_users" => Box::pin(handler::jellyfin::get_public_users()),
+        "post_jellyfin_authenticate" => {
+            let authorization = jellyfin_authorization(&req);
+            Box::pin(handler::jellyfin::post_authenticate(
+                db,
+                config,
+                authorization,
+                req.into_body(),
+            ))
+        }
+        "get_jellyfin_me" => Box::pin(handler::jellyfin::get_user(config, user.unwrap(), None)),
+        "get_jellyfin_user" => Box::pin(handler::jellyfin::get_user(
+            config,
+            user.unwrap(),
+            Some(route.params[0].clone()),
+        )),
+        "get_jellyfin_views" => Box::pin(handler::jellyfin::get_views(db, config, user.unwrap())),
+        "get_jellyfin_items" | "get_jellyfin_resume" | "get_jellyfin_latest" => {
+            let query = req.uri().query().unwrap_or("").to_owned();
+            let user = user.unwrap();
+            match route.name.as_ref() {
+                "get_jellyfin_items" => {
+                    Box::pin(handler::jellyfin::get_items(db, config, user, query))
+                }
+                "get_jellyfin_resume" => {
+                    Box::pin(handler::jellyfin::get_resume(db, config, user, query))
+                }
+                _ => Box::pin(handler::jellyfin::get_latest(db, config, user, query)),
+            }
+        }
+        "get_jellyfin_item" => Box::pin(handler::jellyfin::get_item(
+            db,
+            config,
+            user.unwrap(),
+            route.params[0].clone(),
+        )),
+        "get_jellyfin_image" => Box::pin(handler::jellyfin::get_image(
+            db,
+            config,
+            route.params[0].clone(),
+            token,
+        )),
+        "get_jellyfin_playback_info" | "post_jellyfin_playback_info" => {
+            let query = req.uri().query().unwrap_or("").to_owned();
+            Box::pin(handler::jellyfin::get_playback_info(
+                db,
+                user.unwrap(),
+                route.params[0].clone(),
+                token.unwrap(),
+                query,
+            ))
+        }
+        "get_jellyfin_video" => {
+            let query = req.uri().query().unwrap_or("").to_owned();
+            Box::pin(handler::jellyfin::get_video(
+                db,
+                config,
+                events,
+                user.unwrap(),
+                route.params[0].clone(),
+                query,
+            ))
+        }
+        "post_jellyfin_playing" => {
+            let authorization = jellyfin_authorization(&req);
+            Box::pin(handler::jellyfin::post_playing(
+                db,
+                config,
+                events,
+                user.unwrap(),
+                authorization,
+                req.into_body(),
+            ))
+        }
+        "post_jellyfin_played" | "delete_jellyfin_played" => {
+            Box::pin(handler::jellyfin::set_played(
+                db,
+                events,
+                user.unwrap(),
+                route.params[0].clone(),
+                route.method == Method::POST,
+            ))
+        }
+        "post_jellyfin_favorite" | "delete_jellyfin_favorite" => {
+            Box::pin(handler::jellyfin::set_favorite(
+                db,
+                events,
+                user.unwrap(),
+                route.params[0].clone(),
+                route.method == Method::POST,
+            ))
+        }
+        "get_file" => {
+            let if_none_match = req
+                .headers()
+                .get(header::IF_NONE_MATCH)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_owned());
+            Box::pin(handler::web::get_file(
+                config,
+                route.params[0].clone(),
+                if_none_match,
+            ))
+        }
+        "login" => Box::pin(handler::user::login(db, req.into_body())),
+        "logout" => Box::pin(handler::user::logout(db, token.unwrap())),
+        "switch" => Box::pin(handler::user::switch(db, token.unwrap(), req.into_body())),
+        "post_progress" => {
+            let id = route.params[0].parse().unwrap();
+            let user_agent = req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_owned());
+            Box::pin(handler::progress::post_progress(
+                db,
+                config,
+                events,
+                user.unwrap(),
+                id,
+                user_agent,
+                req.into_body(),
+            ))
+        }
+        "put_watched" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::progress::set_watched(
+                db,
+                events,
+                user.unwrap(),
+                id,
+                true,
+            ))
+        }
+        "delete_watched" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::progress::set_watched(
+                db,
+                events,
+                user.unwrap(),
+                id,
+                false,
+            ))
+        }
+        "put_rating" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::progress::put_rating(
+                db,
+                events,
+                user.unwrap(),
+                id,
+                req.into_body(),
+            ))
+        }
+        "delete_rating" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::progress::delete_rating(
+                db,
+                events,
+                user.unwrap(),
+                id,
+            ))
+        }
+        "put_watchlist" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::progress::set_watchlist(
+                db,
+                events,
+                user.unwrap(),
+                id,
+                true,
+            ))
+        }
+        "delete_watchlist" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::progress::set_watchlist(
+                db,
+                events,
+                user.unwrap(),
+                id,
+                false,
+            ))
+        }
+        "get_continue" => Box::pin(handler::progress::get_continue(db, user.unwrap())),
+        "get_history" => {
+            let query = req.uri().query().unwrap_or("").to_owned();
+            Box::pin(handler::progress::get_history(db, user.unwrap(), query))
+        }
+        "get_watchlist" => {
+            let query = req.uri().query().unwrap_or("").to_owned();
+            Box::pin(handler::progress::get_watchlist(db, user.unwrap(), query))
+        }
+        "get_collections" => Box::pin(handler::collection::get_collections(db, user.unwrap())),
+        "post_collection" => Box::pin(handler::collection::post_collection(
+            db,
+            user.unwrap(),
+            req.into_body(),
+        )),
+        "get_collection" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::collection::get_collection(db, user.unwrap(), id))
+        }
+        "put_collection" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::collection::put_collection(
+                db,
+                user.unwrap(),
+                id,
+                req.into_body(),
+            ))
+        }
+        "delete_collection" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::collection::delete_collection(
+                db,
+                user.unwrap(),
+                id,
+            ))
+        }
+        "get_collection_movies" => {
+            let id = route.params[0].parse().unwrap();
+            let query = req.uri().query().unwrap_or("").to_owned();
+            Box::pin(handler::collection::get_collection_movies(
+                db,
+                user.unwrap(),
+                id,
+                query,
+            ))
+        }
+        "put_collection_movie" => {
+            let id = route.params[0].parse().unwrap();
+            let movie_id = route.params[1].parse().unwrap();
+            Box::pin(handler::collection::set_collection_movie(
+                db,
+                user.unwrap(),
+                id,
+                movie_id,
+                true,
+            ))
+        }
+        "delete_collection_movie" => {
+            let id = route.params[0].parse().unwrap();
+            let movie_id = route.params[1].parse().unwrap();
+            Box::pin(handler::collection::set_collection_movie(
+                db,
+                user.unwrap(),
+                id,
+                movie_id,
+                false,
+            ))
+        }
+        "put_collection_order" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::collection::put_collection_order(
+                db,
+                user.unwrap(),
+                id,
+                req.into_body(),
+            ))
+        }
+        "get_collection_play" => {
+            let id = route.params[0].parse().unwrap();
+            let query = req.uri().query().unwrap_or("").to_owned();
+            Box::pin(handler::collection::get_collection_play(
+                db,
+                config,
+                user.unwrap(),
+                id,
+                query,
+            ))
+        }
+        "get_me" => Box::pin(handler::user::get_me(user.unwrap())),
+        "get_users" => Box::pin(handler::user::get_users(db)),
+        "post_user" => Box::pin(handler::user::post_user(db, req.into_body())),
+        "delete_user" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::user::delete_user(db, id))
+        }
+        "put_password" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::user::put_password(
+                db,
+                user.unwrap(),
+                id,
+                req.into_body(),
+            ))
+        }
+        "put_pin" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::user::set_pin(
+                db,
+                user.unwrap(),
+                id,
+                Some(req.into_body()),
+            ))
+        }
+        "delete_pin" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::user::set_pin(db, user.unwrap(), id, None))
+        }
+        "get_restrictions" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::parental::get_restrictions(db, user.unwrap(), id))
+        }
+        "put_restrictions" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::parental::put_restrictions(db, id, req.into_body()))
+        }
+        "delete_restrictions" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::parental::delete_restrictions(db, id))
+        }
+        "delete_tokens" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::user::delete_tokens(db, id))
+        }
+        "get_jobs" => {
+            let query = req.uri().query().unwrap_or("").to_owned();
+            Box::pin(handler::job::get_jobs(db, query))
+        }
+        "post_job" => Box::pin(handler::job::post_job(jobs, req.into_body())),
+        "get_job" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::job::get_job(db, id))
+        }
+        "delete_job" => {
+            let id = route.params[0].parse().unwrap();
+            Box::pin(handler::job::delete_job(jobs, id))
+        }
+        _ => unimplemented!(),
+    }
+}
+
+/// The host the request was sent to
+fn host(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned())
+}
+
+/// The client as described by a Jellyfin app
+fn jellyfin_authorization(req: &Request<Body>) -> Option<Authorization> {
+    let headers = req.headers();
+    headers
+        .get(header::AUTHORIZATION)
+        .or_else(|| headers.get("x-emby-authorization"))
+        .and_then(|v| v.to_str().ok())
+        .and_then(Authorization::parse)
+}
+
+/// Answers CORS preflight requests, so browsers may send the
+/// `Authorization` header from other origins
+fn preflight() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header("Access-Control-Allow-Origin", "*")
+        .header(
+            "Access-Control-Allow-Methods",
+            "GET, POST, PUT, DELETE, OPTIONS",
+        )
+        .header(
+            "Access-Control-Allow-Headers",
+            "Authorization, Content-Type",
+        )
+        .header("Access-Control-Max-Age", "86400")
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Id of a request for the log, taken from the `X-Request-Id` header if
+/// the client or a proxy set a sane one
+fn request_id(req: &Request<Body>) -> String {
+    let header = req
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| {
+            !v.is_empty()
+                && v.len() <= 64
+                && v.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        });
+    match header {
+        Some(id) => id.to_owned(),
+        None => format!("{:08x}", rand::random::<u32>()),
+    }
+}
+
+impl ApiService {
+    /// Routes and authenticates a request and runs its handler. Returns
+    /// the name of the route along with the response.
+    fn handle(&mut self, req: Request<Body>) -> (String, Handler) {
+        let path = req.uri().path();
+        let route = match self.router.is_match(req.method(), path) {
+            Some(route) => route,
+            None if req.method() == Method::OPTIONS && self.router.has_path(path) => {
+                return ("preflight".to_owned(), Box::pin(async { Ok(preflight()) }));
+            }
+            None if self.router.has_path(path) => {
+                let handler = Box::pin(async {
+                    Ok(Response::builder()
+                        .status(StatusCode::METHOD_NOT_ALLOWED)
+                        .header("Access-Control-Allow-Origin", "*")
+                        .body(Body::from("Method Not Allowed"))
+                        .unwrap())
+                });
+                return ("method_not_allowed".to_owned(), handler);
+            }
+            None => {
+                let handler = Box::pin(async {
+                    Ok(Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .header(header::CONTENT_TYPE, "text/plain")
+                        .body(Body::from("Not Found"))
+                        .unwrap())
+                });
+                return ("not_found".to_owned(), handler);
+            }
+        };
+
+        // the request keeps this config even if it is reloaded meanwhile
+        let config = self.config.get();
+        if route.name.contains("_jellyfin_") && !config.jellyfin.enabled {
+            let handler = Box::pin(async {
+                Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .header(header::CONTENT_TYPE, "text/plain")
+                    .body(Body::from("Not Found"))
+                    .unwrap())
+            });
+            return ("not_found".to_owned(), handler);
+        }
+
+        let token = auth::token(&req);
+        let signature = match token {
+            None if route.signed => auth::signature(&req),
+            _ => None,
+        };
+        let state = State {
+            db: self.db.clone(),
+            config,
+            jobs: self.jobs.clone(),
+            events: self.events.clone(),
+            parties: self.parties.clone(),
+            dlna: self.dlna.clone(),
+        };
+
+        let name = route.name.clone();
+        let handler = Box::pin(async move {
+            let db = state.db.clone();
+            let user = match signature {
+                Some(signature) => {
+                    let path = req.uri().path().to_owned();
+                    auth::authenticate_signed(db, &path, signature).await
+                }
+                None => auth::authenticate(db, route.access, token.clone()).await,
+            };
+            let user = match user {
+                Ok(user) => user,
+                Err(resp) => return Ok(resp),
+            };
+            dispatch(route, req, user, token, state).await
+        });
+        (name, handler)
     }
 }
 
@@ -49,39 +937,61 @@ impl Service<Request<Body>> for ApiService {
         Poll::Ready(Ok(()))
     }
 
+    /// Handles a request with a request id for the log, writes the
+    /// access log and counts the request in the metrics. The path is
+    /// logged without the query, which may carry the access token.
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        if let Some(route) = self.router.is_match(req.uri().path()) {
-            let res: Handler = match route.name.as_ref() {
-                "get_movies" => Box::pin(handler::get_movies(self.db.clone())),
-                "get_movie" => {
-                    let id = route.params[0].parse().unwrap();
-                    Box::pin(handler::get_movie(self.db.clone(), id))
-                }
-                "get_stream" => {
-                    let id = route.params[0].parse().unwrap();
-                    Box::pin(handler::get_stream(
-                        self.db.clone(),
-                        self.config.clone(),
-                        id,
-                    ))
+        let id = request_id(&req);
+        let method = req.method().clone();
+        let path = req.uri().path().to_owned();
+        let start = Instant::now();
+        let (name, handler) = self.handle(req);
+
+        Box::pin(logging::scope(id.clone(), async move {
+            let mut resp = match handler.await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    warn!("{} {} failed: {}", method, path, e);
+                    return Err(e);
                 }
-                _ => unimplemented!(),
             };
-            return res;
-        }
-
-        Box::pin(async { Ok(Response::builder().body(Body::from("Not Found")).unwrap()) })
+            let (status, elapsed) = (resp.status().as_u16(), start.elapsed());
+            logging::access(method.as_str(), &path, status, elapsed);
+            metrics().request(&name, method.as_str(), status, elapsed);
+            if let Ok(id) = HeaderValue::from_str(&id) {
+                resp.headers_mut().insert(REQUEST_ID, id);
+            }
+            Ok(resp)
+        }))
     }
 }
 
 pub struct MakeApiSvc {
-    config: SharedCfg,
+    config: CfgHandle,
     db: SharedDb,
+    jobs: Jobs,
+    events: Events,
+    parties: Parties,
+    dlna: Dlna,
 }
 
 impl MakeApiSvc {
-    pub fn new(config: SharedCfg, db: SharedDb) -> MakeApiSvc {
-        MakeApiSvc { config, db }
+    pub fn new(
+        config: CfgHandle,
+        db: SharedDb,
+        jobs: Jobs,
+        events: Events,
+        parties: Parties,
+        dlna: Dlna,
+    ) -> MakeApiSvc {
+        MakeApiSvc {
+            config,
+            db,
+            jobs,
+            events,
+            parties,
+            dlna,
+        }
     }
 }
 
@@ -96,10 +1006,14 @@ impl<T> Service<T> for MakeApiSvc {
     fn call(&mut self, _: T) -> Self::Future {
         let config = self.config.clone();
         let db = self.db.clone();
+        let jobs = self.jobs.clone();
+        let events = self.events.clone();
+        let parties = self.parties.clone();
+        let dlna = self.dlna.clone();
 
         // routes
 
-        let fut = async move { Ok(ApiService::new(db, config)) };
+        let fut = async move { Ok(ApiService::new(db, config, jobs, events, parties, dlna)) };
         Box::pin(fut)
     }
 }
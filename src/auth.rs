@@ -0,0 +1,116 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+/// Length of an API token in bytes, before hex encoding
+const TOKEN_LEN: usize = 32;
+
+/// Hash a password with argon2 and a random salt. The result is a
+/// PHC string which contains the salt and parameters.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("failed to hash password")
+        .to_string()
+}
+
+/// Verify a password against a PHC string created by `hash_password`
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Generate a new random API token
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_LEN];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// Tokens are stored hashed, so a leaked database doesn't leak
+/// valid tokens. Tokens have enough entropy that a plain SHA-256 is
+/// sufficient.
+pub fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn mac(key: &str, message: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes any key");
+    mac.update(message.as_bytes());
+    mac
+}
+
+/// Sign a message with HMAC-SHA256, hex encoded
+pub fn sign(key: &str, message: &str) -> String {
+    to_hex(&mac(key, message).finalize().into_bytes())
+}
+
+/// Check a signature created by `sign`, in constant time
+pub fn verify_signature(key: &str, message: &str, signature: &str) -> bool {
+    if signature.len() != 64 || !signature.chars().all(|c| c.is_ascii_hexdigit()) {
+        return false;
+    }
+    let bytes = (0..signature.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&signature[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>();
+    match bytes {
+        Ok(bytes) => mac(key, message).verify_slice(&bytes).is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(hex, "{:02x}", b).unwrap();
+    }
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password() {
+        let hash = hash_password("secret");
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password("secret", &hash));
+        assert!(!verify_password("Secret", &hash));
+        assert!(!verify_password("secret", "not a hash"));
+        assert_ne!(hash, hash_password("secret"));
+    }
+
+    #[test]
+    fn test_token() {
+        let token = generate_token();
+        assert_eq!(TOKEN_LEN * 2, token.len());
+        assert_ne!(token, generate_token());
+        assert_eq!(hash_token(&token), hash_token(&token));
+        assert_eq!(
+            "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b",
+            hash_token("secret")
+        );
+    }
+
+    #[test]
+    fn test_sign() {
+        let signature = sign("key", "/stream/1");
+        assert_eq!(64, signature.len());
+        assert!(verify_signature("key", "/stream/1", &signature));
+        assert!(!verify_signature("key", "/stream/2", &signature));
+        assert!(!verify_signature("other", "/stream/1", &signature));
+        assert!(!verify_signature("key", "/stream/1", &signature[2..]));
+        assert!(!verify_signature("key", "/stream/1", "not hex"));
+    }
+}
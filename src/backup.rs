@@ -0,0 +1,112 @@
+use crate::config::BackupConfig;
+use crate::sqlite::SharedDb;
+use chrono::Local;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Backups are named `moviebay-<timestamp>.db`, the timestamp sorts
+/// them by age
+const PREFIX: &str = "moviebay-";
+const EXTENSION: &str = ".db";
+
+/// Writes a backup of the running database into `[backup] dir`, named
+/// by the current time, then deletes the oldest backups beyond `keep`.
+/// Returns the path of the backup and the number of deleted ones.
+pub async fn create(config: &BackupConfig, db: &SharedDb) -> Result<(PathBuf, usize), Error> {
+    fs::create_dir_all(&config.dir)?;
+    let name = format!(
+        "{}{}{}",
+        PREFIX,
+        Local::now().format("%Y%m%d-%H%M%S"),
+        EXTENSION
+    );
+    let path = Path::new(&config.dir).join(name);
+
+    // an interrupted backup must not look like a complete one
+    let part = path.with_extension("db.part");
+    db.backup(&part).await.map_err(|e| e.to_string())?;
+    fs::rename(&part, &path)?;
+
+    let removed = rotate(&config.dir, config.keep)?;
+    Ok((path, removed))
+}
+
+/// The backups in `dir`, oldest first. A missing `dir` has none.
+pub fn list<P: AsRef<Path>>(dir: P) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut backups = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(PREFIX) && name.ends_with(EXTENSION) {
+            backups.push(entry.path());
+        }
+    }
+    backups.sort();
+    Ok(backups)
+}
+
+/// The newest backup in `dir`
+pub fn latest<P: AsRef<Path>>(dir: P) -> io::Result<Option<PathBuf>> {
+    Ok(list(dir)?.pop())
+}
+
+/// Deletes the oldest backups in `dir` until `keep` are left, at least
+/// one is always kept. Returns the number of deleted backups.
+pub fn rotate<P: AsRef<Path>>(dir: P, keep: usize) -> io::Result<usize> {
+    let backups = list(dir)?;
+    let old = backups.len().saturating_sub(keep.max(1));
+    for path in backups[..old].iter() {
+        fs::remove_file(path)?;
+    }
+    Ok(old)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("moviebay-backups-{}", std::process::id()));
+        assert!(list(&dir).unwrap().is_empty());
+        fs::create_dir_all(&dir).unwrap();
+
+        let names = [
+            "moviebay-20201003-020000.db",
+            "moviebay-20201001-020000.db",
+            "moviebay-20201002-020000.db",
+            "moviebay-20201004-020000.db.part",
+            "notes.txt",
+        ];
+        for name in names.iter() {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        assert_eq!(3, list(&dir).unwrap().len());
+        assert_eq!(
+            Some(dir.join("moviebay-20201003-020000.db")),
+            latest(&dir).unwrap()
+        );
+
+        assert_eq!(1, rotate(&dir, 2).unwrap());
+        assert!(!dir.join("moviebay-20201001-020000.db").exists());
+        assert_eq!(0, rotate(&dir, 2).unwrap());
+        assert_eq!(1, rotate(&dir, 0).unwrap());
+        assert_eq!(
+            vec![dir.join("moviebay-20201003-020000.db")],
+            list(&dir).unwrap()
+        );
+        assert!(dir.join("notes.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,519 @@
+use crate::api::MakeApiSvc;
+use crate::auth;
+use crate::backup;
+use crate::config::{Config, SharedCfg};
+use crate::context::{Context, SharedCtx};
+use crate::events::Event;
+use crate::ffmpeg::{self, FFmpeg};
+use crate::jobs::{self, JobKind, Jobs};
+use crate::lock::Lock;
+use crate::logging;
+use crate::model::{self, JobTable, Table, User, UserTable};
+use crate::sqlite::SharedDb;
+use futures::future;
+use hyper::Server;
+use log::{info, warn};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use structopt::StructOpt;
+use tokio::sync::Notify;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Self-hosted movie streaming server
+#[derive(Debug, StructOpt)]
+#[structopt(name = "moviebay")]
+pub struct Opt {
+    /// Path of the config file, `moviebay.toml` is used if it exists
+    #[structopt(short, long, env = "MOVIEBAY_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Defaults to `serve`
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Start the server, the library is scanned in the background
+    Serve,
+    /// Add the new movies of the libraries to the database
+    Scan {
+        /// Scan only the library with this name
+        #[structopt(long)]
+        library: Option<String>,
+    },
+    /// Look up movies on TMDB, by default the ones without metadata
+    RefreshMetadata {
+        /// Look up only this movie, even if it has metadata already
+        #[structopt(long)]
+        movie: Option<i32>,
+    },
+    /// Print duration and resolution of a video file
+    Probe { file: String },
+    /// Manage the database
+    Db(DbCommand),
+    /// Manage user accounts
+    User(UserCommand),
+    /// Inspect the config file
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, StructOpt)]
+enum DbCommand {
+    /// Create or update the schema of the database
+    Migrate,
+    /// Copy the database to a file, by default a timestamped backup in
+    /// `[backup] dir`
+    Backup {
+        #[structopt(parse(from_os_str))]
+        path: Option<PathBuf>,
+    },
+    /// Replace the database with a backup, by default the latest one in
+    /// `[backup] dir`
+    Restore {
+        #[structopt(parse(from_os_str))]
+        path: Option<PathBuf>,
+    },
+    /// Write movies, users and watch history to a JSON file
+    Export {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Add the movies, users and watch history of a JSON export
+    Import {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Rebuild the database file to reclaim unused space
+    Vacuum,
+}
+
+#[derive(Debug, StructOpt)]
+enum UserCommand {
+    /// Create a user, a random password is printed if none is given
+    Add {
+        name: String,
+        #[structopt(long)]
+        password: Option<String>,
+        /// Allow the user to manage users and jobs
+        #[structopt(long)]
+        admin: bool,
+    },
+    /// Change the password of a user and revoke their tokens
+    Passwd {
+        name: String,
+        #[structopt(long)]
+        password: Option<String>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ConfigCommand {
+    /// Validate the config file and the paths and programs it refers to
+    Check,
+}
+
+/// Config file used without `--config`
+const DEFAULT_CONFIG: &str = "moviebay.toml";
+
+/// Runs the command given on the command line
+pub async fn run(opt: Opt) -> Result<(), Error> {
+    let file = opt.config.or_else(|| {
+        let file = PathBuf::from(DEFAULT_CONFIG);
+        if file.is_file() {
+            Some(file)
+        } else {
+            None
+        }
+    });
+    let mut config = Config::load(file.as_deref()).map_err(|e| match &file {
+        Some(file) => format!("could not read {}: {}", file.display(), e),
+        None => e.to_string(),
+    })?;
+    let cmd = opt.cmd.unwrap_or(Command::Serve);
+    if let Command::Scan {
+        library: Some(library),
+    } = &cmd
+    {
+        if !config.library.dirs.contains_key(library) {
+            return Err(format!("no library {} in [library]", library).into());
+        }
+        config.library.dirs.retain(|name, _| name == library);
+    }
+    let config = config.into_shared();
+
+    // a broken `[log]` section is reported by `config check`
+    if let Err(e) = logging::init(&config.log) {
+        if !matches!(cmd, Command::Config(_)) {
+            return Err(format!("log: {}", e).into());
+        }
+    }
+
+    match cmd {
+        Command::Serve => serve(config).await,
+        Command::Scan { .. } => scan(config).await,
+        Command::RefreshMetadata { movie } => refresh_metadata(config, movie).await,
+        Command::Probe { file } => probe(config, &file),
+        Command::Db(cmd) => db(config, cmd).await,
+        Command::User(cmd) => user(config, cmd).await,
+        Command::Config(ConfigCommand::Check) => check(&config),
+    }
+}
+
+/// Opens the database and brings its schema up to date
+async fn open(config: SharedCfg) -> Result<SharedCtx, Error> {
+    let ctx = Context::from_config(config)?;
+    model::migrate(ctx.db()).await.map_err(|e| e.to_string())?;
+    Ok(ctx)
+}
+
+async fn save(db: &SharedDb) -> Result<(), Error> {
+    db.save().await.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+async fn serve(config: SharedCfg) -> Result<(), Error> {
+    let problems = config.validate();
+    if !problems.is_empty() {
+        let problems = problems.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        return Err(problems.join(", ").into());
+    }
+    let ip = config
+        .server
+        .bind
+        .parse::<IpAddr>()
+        .map_err(|e| format!("server.bind: {}", e))?;
+    let addr = SocketAddr::new(ip, config.server.port);
+
+    // commands that write the database refuse to run next to the server
+    let _lock = Lock::acquire(&config.database.name)?;
+    let ctx = open(config).await?;
+    let sqlite = ctx.db();
+    let config = ctx.cfg();
+
+    let users = UserTable::new(sqlite.clone());
+    if users.count().await.map_err(|e| e.to_string())? == 0 {
+        let password = match &config.auth.admin_password {
+            Some(password) => password.clone(),
+            None => {
+                let password = random_password();
+                info!(
+                    "created user {} with password {}",
+                    config.auth.admin_name, password
+                );
+                password
+            }
+        };
+        users
+            .save(User::new(&config.auth.admin_name, &password, true))
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    // the library is scanned in the background, the scan queues
+    // metadata lookups, thumbnails and a save when it is done
+    JobTable::new(sqlite.clone())
+        .requeue_running()
+        .await
+        .map_err(|e| e.to_string())?;
+    let jobs = Jobs::new(ctx.cfg_handle(), sqlite.clone(), ctx.events());
+    jobs.start();
+    jobs.enqueue(JobKind::Scan, None).await?;
+
+    if config.dlna.enabled {
+        if let Err(e) = ctx
+            .dlna()
+            .start(&config, sqlite.clone(), ctx.events())
+            .await
+        {
+            warn!("DLNA server not started: {}", e);
+        }
+    }
+
+    // requests and jobs started after a reload use the new config
+    Context::watch(ctx.clone());
+    let shutdown = Arc::new(Notify::new());
+    let svc = MakeApiSvc::new(
+        ctx.cfg_handle(),
+        sqlite.clone(),
+        jobs.clone(),
+        ctx.events(),
+        ctx.parties(),
+        ctx.dlna(),
+    );
+    let server = Server::bind(&addr)
+        .serve(svc)
+        .with_graceful_shutdown(stop_signal(shutdown.clone()));
+    info!("listening on http://{}", addr);
+
+    // after the signal the server stops accepting connections and waits
+    // for the running requests, streams included
+    let timeout = async {
+        shutdown.notified().await;
+        // ends the event streams and watch parties, which would keep
+        // the server waiting
+        ctx.events().publish(Event::Shutdown);
+        ctx.parties().close();
+        let secs = ctx.cfg().server.shutdown_timeout;
+        let streams = ffmpeg::active_sessions();
+        if streams > 0 {
+            info!("waiting up to {}s for {} streams", secs, streams);
+        }
+        tokio::time::delay_for(Duration::from_secs(secs)).await;
+    };
+    tokio::select! {
+        res = server => res?,
+        _ = timeout => warn!("streams didn't finish in time"),
+    }
+
+    let killed = ffmpeg::kill_sessions();
+    if killed > 0 {
+        info!("stopped {} streams", killed);
+    }
+    ctx.dlna().stop().await;
+    jobs.stop();
+    // the queued sqlite jobs run before the final save
+    sqlite.close().await.map_err(|e| e.to_string())?;
+    info!("database saved");
+    Ok(())
+}
+
+/// Resolves on Ctrl-C or SIGTERM and notifies `shutdown`
+async fn stop_signal(shutdown: Arc<Notify>) {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("could not listen for SIGTERM: {}", e);
+                future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+    info!("shutting down");
+    shutdown.notify();
+}
+
+async fn scan(config: SharedCfg) -> Result<(), Error> {
+    let _lock = Lock::acquire(&config.database.name)?;
+    let ctx = open(config.clone()).await?;
+    let db = ctx.db();
+    let job = Jobs::new(config.into(), db.clone(), ctx.events())
+        .run(JobKind::Scan)
+        .await?;
+    save(&db).await?;
+    println!("scan {}: {}", job.state.as_str(), job.message);
+    Ok(())
+}
+
+async fn refresh_metadata(config: SharedCfg, movie: Option<i32>) -> Result<(), Error> {
+    let _lock = Lock::acquire(&config.database.name)?;
+    let ctx = open(config.clone()).await?;
+    let db = ctx.db();
+    match movie {
+        Some(id) => {
+            if !jobs::refresh_movie(config, db.clone(), id).await? {
+                return Err(format!("movie {} not found on TMDB", id).into());
+            }
+            println!("movie {} updated", id);
+        }
+        None => {
+            let job = Jobs::new(config.into(), db.clone(), ctx.events())
+                .run(JobKind::Metadata)
+                .await?;
+            println!("metadata {}: {}", job.state.as_str(), job.message);
+        }
+    }
+    save(&db).await
+}
+
+fn probe(config: SharedCfg, file: &str) -> Result<(), Error> {
+    let probe = FFmpeg::new(Arc::new(config.ffmpeg.clone())).probe(file)?;
+    println!("duration: {:.1}s", probe.duration);
+    println!("resolution: {}x{}", probe.width, probe.height);
+    Ok(())
+}
+
+async fn db(config: SharedCfg, cmd: DbCommand) -> Result<(), Error> {
+    match cmd {
+        DbCommand::Migrate => {
+            let _lock = Lock::acquire(&config.database.name)?;
+            let db = Context::from_config(config)?.db();
+            let from = model::migrate(db.clone())
+                .await
+                .map_err(|e| e.to_string())?;
+            save(&db).await?;
+            if from == model::SCHEMA_VERSION {
+                println!("schema is up to date ({})", from);
+            } else {
+                println!("migrated schema {} to {}", from, model::SCHEMA_VERSION);
+            }
+        }
+        DbCommand::Backup { path } => {
+            if !Path::new(&config.database.name).is_file() {
+                return Err(format!("no database at {}", config.database.name).into());
+            }
+            let db = Context::from_config(config.clone())?.db();
+            let path = match path {
+                Some(path) => {
+                    db.backup(&path).await.map_err(|e| e.to_string())?;
+                    path
+                }
+                None => backup::create(&config.backup, &db).await?.0,
+            };
+            println!("saved backup to {}", path.display());
+        }
+        DbCommand::Restore { path } => {
+            let path = match path {
+                Some(path) => path,
+                None => backup::latest(&config.backup.dir)?
+                    .ok_or_else(|| format!("no backups in {}", config.backup.dir))?,
+            };
+            if !path.is_file() {
+                return Err(format!("no backup at {}", path.display()).into());
+            }
+            let _lock = Lock::acquire(&config.database.name)?;
+            let db = Context::open(config.clone(), &path)?.db();
+            model::migrate(db.clone())
+                .await
+                .map_err(|e| e.to_string())?;
+            save(&db).await?;
+            println!("restored {} from {}", config.database.name, path.display());
+        }
+        DbCommand::Export { path } => {
+            if !Path::new(&config.database.name).is_file() {
+                return Err(format!("no database at {}", config.database.name).into());
+            }
+            let db = open(config).await?.db();
+            let export = model::export(db).await.map_err(|e| e.to_string())?;
+            let file = BufWriter::new(File::create(&path)?);
+            serde_json::to_writer_pretty(file, &export)?;
+            println!(
+                "exported {} movies, {} users, {} watch states, {} plays, {} ratings \
+                 and {} watchlist entries to {}",
+                export.movies.len(),
+                export.users.len(),
+                export.watch_history.len(),
+                export.plays.len(),
+                export.ratings.len(),
+                export.watchlist.len(),
+                path.display()
+            );
+        }
+        DbCommand::Import { path } => {
+            let file = File::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let export = serde_json::from_reader(BufReader::new(file))
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            let _lock = Lock::acquire(&config.database.name)?;
+            let db = open(config).await?.db();
+            let imported = model::import(db.clone(), export)
+                .await
+                .map_err(|e| e.to_string())?;
+            save(&db).await?;
+            println!(
+                "imported {} movies, {} users, {} parental controls, {} collections, \
+                 {} watch states, {} plays, {} ratings and {} watchlist entries",
+                imported.movies,
+                imported.users,
+                imported.restrictions,
+                imported.collections,
+                imported.watch_history,
+                imported.plays,
+                imported.ratings,
+                imported.watchlist
+            );
+        }
+        DbCommand::Vacuum => {
+            let _lock = Lock::acquire(&config.database.name)?;
+            let db = open(config).await?.db();
+            db.vacuum().await.map_err(|e| e.to_string())?;
+            save(&db).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn user(config: SharedCfg, cmd: UserCommand) -> Result<(), Error> {
+    let _lock = Lock::acquire(&config.database.name)?;
+    let db = open(config).await?.db();
+    let table = UserTable::new(db.clone());
+
+    match cmd {
+        UserCommand::Add {
+            name,
+            password,
+            admin,
+        } => {
+            if table
+                .by_name(&name)
+                .await
+                .map_err(|e| e.to_string())?
+                .is_some()
+            {
+                return Err(format!("user {} already exists", name).into());
+            }
+            let password = password_or_random(password);
+            table
+                .save(User::new(&name, &password, admin))
+                .await
+                .map_err(|e| e.to_string())?;
+            println!("created user {}", name);
+        }
+        UserCommand::Passwd { name, password } => {
+            let user = match table.by_name(&name).await.map_err(|e| e.to_string())? {
+                Some(user) => user,
+                None => return Err(format!("user {} not found", name).into()),
+            };
+            let password = password_or_random(password);
+            table
+                .set_password(user.id, &password)
+                .await
+                .map_err(|e| e.to_string())?;
+            println!("changed password of {}", name);
+        }
+    }
+    save(&db).await
+}
+
+fn random_password() -> String {
+    auth::generate_token()[..16].to_owned()
+}
+
+fn password_or_random(password: Option<String>) -> String {
+    match password {
+        Some(password) => password,
+        None => {
+            let password = random_password();
+            println!("password: {}", password);
+            password
+        }
+    }
+}
+
+/// Prints the problems of the config, fails if there are any
+fn check(config: &Config) -> Result<(), Error> {
+    let problems = config.validate();
+    if problems.is_empty() {
+        println!("config ok");
+        return Ok(());
+    }
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    Err(format!("config has {} problem(s)", problems.len()).into())
+}
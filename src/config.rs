@@ -1,12 +1,86 @@
+use crate::ffmpeg;
+use crate::jobs::{JobKind, Schedule};
+use crate::logging;
 use serde::Deserialize;
-use std::collections::HashMap;
-use std::fs::File;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, File};
 use std::io::{self, Read};
-use std::path::Path;
-use std::sync::Arc;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 
 pub type SharedCfg = Arc<Config>;
 
+/// Holds the current `SharedCfg`, which is replaced when the config is
+/// reloaded. Readers take a snapshot with `get`, so work in progress
+/// keeps the config it started with.
+#[derive(Debug, Clone)]
+pub struct CfgHandle {
+    current: Arc<RwLock<SharedCfg>>,
+}
+
+impl CfgHandle {
+    pub fn new(config: SharedCfg) -> CfgHandle {
+        CfgHandle {
+            current: Arc::new(RwLock::new(config)),
+        }
+    }
+
+    pub fn get(&self) -> SharedCfg {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn set(&self, config: SharedCfg) {
+        *self.current.write().unwrap() = config;
+    }
+}
+
+impl From<SharedCfg> for CfgHandle {
+    fn from(config: SharedCfg) -> CfgHandle {
+        CfgHandle::new(config)
+    }
+}
+
+/// Prefix of the environment variables overriding settings, e.g.
+/// `MOVIEBAY_SERVER_PORT` for `port` of `[server]`
+const ENV_PREFIX: &str = "MOVIEBAY_";
+
+/// Settings for the HTTP server
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Address to listen on
+    pub bind: String,
+    pub port: u16,
+    /// Public URL of the server, e.g. behind a reverse proxy. Links
+    /// generated by the server are relative if empty.
+    pub base_url: String,
+    /// Seconds to wait for running streams on shutdown before they are
+    /// stopped
+    pub shutdown_timeout: u64,
+}
+
+impl ServerConfig {
+    /// Prefixes an absolute path with the base URL
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url.trim_end_matches('/'), path)
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind: "127.0.0.1".to_owned(),
+            port: 3000,
+            base_url: "".to_owned(),
+            shutdown_timeout: 10,
+        }
+    }
+}
+
 /// Codec settings used with FFmpeg
 #[derive(Debug, Clone, Deserialize)]
 pub struct CodecConfig {
@@ -15,59 +89,839 @@ pub struct CodecConfig {
 
 /// Settings for Database
 #[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
 pub struct DatabaseConfig {
     pub name: String,
+    /// Connections serving read-only queries next to the writer
+    pub readers: usize,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> DatabaseConfig {
+        DatabaseConfig {
+            name: "moviebay.db".to_owned(),
+            readers: 4,
+        }
+    }
 }
 
 /// Settings for FFmpeg
 #[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
 pub struct FFmpegConfig {
     pub bin: String,
     pub codecs: HashMap<String, CodecConfig>,
 }
 
-/// Settings for the library
+impl Default for FFmpegConfig {
+    fn default() -> FFmpegConfig {
+        let args = [
+            "-ss",
+            "%ss",
+            "-i",
+            "%i",
+            "-f",
+            "%f",
+            "-vcodec",
+            "%vcodec",
+            "-acodec",
+            "%acodec",
+            "-strict",
+            "experimental",
+            "-preset",
+            "ultrafast",
+            "-movflags",
+            "frag_keyframe+empty_moov+faststart",
+            "pipe:1",
+        ];
+        let mut codecs = HashMap::new();
+        codecs.insert(
+            "*".to_owned(),
+            CodecConfig {
+                args: args.iter().map(|a| (*a).to_owned()).collect(),
+            },
+        );
+
+        FFmpegConfig {
+            bin: "ffmpeg".to_owned(),
+            codecs,
+        }
+    }
+}
+
+/// The libraries by name, each a directory of movies, e.g.
+/// `kids = "/media/kids"`. Movies are tagged with the name of the
+/// library they are found in.
 #[derive(Debug, Clone, Deserialize)]
+#[serde(transparent)]
 pub struct LibraryConfig {
-    pub movies: String,
+    pub dirs: BTreeMap<String, String>,
+}
+
+impl Default for LibraryConfig {
+    fn default() -> LibraryConfig {
+        let mut dirs = BTreeMap::new();
+        dirs.insert("movies".to_owned(), "movies".to_owned());
+        LibraryConfig { dirs }
+    }
 }
 
 /// Settings for the The Movie Database
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
 pub struct TmdbConfig {
-    pub api_key: String,
+    /// Metadata lookups are skipped without a key
+    pub api_key: Option<String>,
+}
+
+/// Settings for authentication
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Name of the admin account created if there are no users
+    pub admin_name: String,
+    /// Password of the initial admin account, a random password is
+    /// generated and printed if unset
+    pub admin_password: Option<String>,
+    /// Seconds signed urls, e.g. in playlists, stay valid
+    pub signed_url_ttl: u64,
 }
 
-/// The base `Config` for moviebay
-#[derive(Debug, Deserialize)]
+impl Default for AuthConfig {
+    fn default() -> AuthConfig {
+        AuthConfig {
+            admin_name: "admin".to_owned(),
+            admin_password: None,
+            signed_url_ttl: 7 * 24 * 3600,
+        }
+    }
+}
+
+/// Settings for playback
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PlaybackConfig {
+    /// Fraction of a movie after which it counts as watched
+    pub watched_threshold: f64,
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> PlaybackConfig {
+        PlaybackConfig {
+            watched_threshold: 0.9,
+        }
+    }
+}
+
+/// Settings for the bundled web player
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WebConfig {
+    /// Directory served at `/`
+    pub root: String,
+}
+
+impl Default for WebConfig {
+    fn default() -> WebConfig {
+        WebConfig {
+            root: "static".to_owned(),
+        }
+    }
+}
+
+/// Settings for posters and seek preview thumbnails generated with FFmpeg
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ThumbnailConfig {
+    /// Generate thumbnails in the background after startup
+    pub enabled: bool,
+    /// Directory to store the generated images in
+    pub dir: String,
+    /// Seconds between two seek preview thumbnails
+    pub interval: u32,
+    /// Width of a seek preview thumbnail
+    pub width: i32,
+    /// Thumbnails per row of a sprite sheet
+    pub columns: u32,
+    /// Rows of a sprite sheet
+    pub rows: u32,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> ThumbnailConfig {
+        ThumbnailConfig {
+            enabled: true,
+            dir: "cache/thumbnails".to_owned(),
+            interval: 10,
+            width: 160,
+            columns: 10,
+            rows: 10,
+        }
+    }
+}
+
+/// Settings for background jobs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct JobsConfig {
+    /// Number of jobs running at the same time
+    pub workers: usize,
+    /// How often a failing job is started before it counts as failed
+    pub max_attempts: i32,
+    /// Seconds before the first retry, doubled for every further attempt
+    pub retry_delay: i64,
+    /// Days to keep finished jobs
+    pub keep_days: i64,
+    /// Cron expressions by job kind, e.g. `scan = "0 3 * * *"`
+    pub schedule: HashMap<String, String>,
+}
+
+impl Default for JobsConfig {
+    fn default() -> JobsConfig {
+        let mut schedule = HashMap::new();
+        schedule.insert("scan".to_owned(), "0 3 * * *".to_owned());
+        schedule.insert("metadata".to_owned(), "30 3 * * *".to_owned());
+        schedule.insert("cleanup".to_owned(), "0 4 * * *".to_owned());
+        schedule.insert("save".to_owned(), "*/15 * * * *".to_owned());
+        schedule.insert("backup".to_owned(), "0 2 * * *".to_owned());
+
+        JobsConfig {
+            workers: 2,
+            max_attempts: 3,
+            retry_delay: 30,
+            keep_days: 7,
+            schedule,
+        }
+    }
+}
+
+/// Settings for the backups of the database
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BackupConfig {
+    /// Directory of the timestamped backups
+    pub dir: String,
+    /// Number of backups to keep, older ones are deleted
+    pub keep: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> BackupConfig {
+        BackupConfig {
+            dir: "backups".to_owned(),
+            keep: 7,
+        }
+    }
+}
+
+/// Settings for the share links of movies
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ShareConfig {
+    /// Let users who aren't admins share movies, admins always can
+    pub users: bool,
+    /// Seconds the shares of users who aren't admins last at most
+    pub max_expires_in: u64,
+    /// Plays the shares of users who aren't admins allow at most
+    pub max_plays: i32,
+}
+
+impl Default for ShareConfig {
+    fn default() -> ShareConfig {
+        ShareConfig {
+            users: false,
+            max_expires_in: 7 * 24 * 3600,
+            max_plays: 3,
+        }
+    }
+}
+
+/// Settings for the DLNA media server, which makes the library
+/// browsable by TVs and other UPnP renderers on the local network
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DlnaConfig {
+    /// Announce the server with SSDP and serve the ContentDirectory
+    pub enabled: bool,
+    /// Name the server is listed with on the renderers
+    pub name: String,
+    /// Account the renderers browse and stream as. It must not be an
+    /// admin, its parental controls apply to everything on the network.
+    pub user: String,
+    /// Seconds between two SSDP announcements
+    pub announce_interval: u64,
+}
+
+impl Default for DlnaConfig {
+    fn default() -> DlnaConfig {
+        DlnaConfig {
+            enabled: false,
+            name: "moviebay".to_owned(),
+            user: "tv".to_owned(),
+            announce_interval: 900,
+        }
+    }
+}
+
+/// Settings for the Jellyfin compatible API at `/jellyfin`, which lets
+/// the Jellyfin apps browse and play the library
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct JellyfinConfig {
+    pub enabled: bool,
+    /// Name of the server shown by the apps
+    pub name: String,
+}
+
+impl Default for JellyfinConfig {
+    fn default() -> JellyfinConfig {
+        JellyfinConfig {
+            enabled: false,
+            name: "moviebay".to_owned(),
+        }
+    }
+}
+
+/// Settings for logging
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// `error`, `warn`, `info`, `debug`, `trace` or `off`
+    pub level: String,
+    /// Levels by module, e.g. `"moviebay::sqlite" = "debug"`
+    pub modules: HashMap<String, String>,
+    /// `text` or `json`
+    pub format: String,
+    /// Log to this file instead of stderr
+    pub file: Option<String>,
+    /// Megabytes after which the file is rotated, 0 disables rotation
+    pub max_size: u64,
+    /// Number of rotated files to keep
+    pub keep: u32,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: "info".to_owned(),
+            modules: HashMap::new(),
+            format: "text".to_owned(),
+            file: None,
+            max_size: 10,
+            keep: 5,
+        }
+    }
+}
+
+/// The base `Config` for moviebay. Every section is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 pub struct Config {
+    pub server: ServerConfig,
     pub ffmpeg: FFmpegConfig,
     pub library: LibraryConfig,
     pub database: DatabaseConfig,
     pub tmdb: TmdbConfig,
+    pub auth: AuthConfig,
+    pub playback: PlaybackConfig,
+    pub web: WebConfig,
+    pub thumbnails: ThumbnailConfig,
+    pub jobs: JobsConfig,
+    pub backup: BackupConfig,
+    pub shares: ShareConfig,
+    pub dlna: DlnaConfig,
+    pub jellyfin: JellyfinConfig,
+    pub log: LogConfig,
+    #[serde(skip)]
+    source: Source,
+}
+
+/// Where the settings come from, used to point at the cause of a
+/// `Problem`
+#[derive(Debug, Default)]
+struct Source {
+    file: Option<PathBuf>,
+    /// Line numbers by setting, e.g. `ffmpeg.bin` or `jobs.schedule.scan`
+    lines: HashMap<String, usize>,
+    /// Environment variables by the setting they override
+    env: HashMap<String, String>,
+    /// Environment variables with the prefix which match no setting
+    unknown_env: Vec<String>,
+}
+
+impl Source {
+    /// Indexes the tables and keys of a TOML file by line. Values
+    /// spanning several lines are indexed at their first line.
+    fn index(file: Option<PathBuf>, contents: &str) -> Source {
+        let mut lines = HashMap::new();
+        let mut table = String::new();
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with('[') {
+                table = unquote(line.trim_matches(|c| c == '[' || c == ']'));
+                lines.insert(table.clone(), i + 1);
+            } else if let Some(eq) = line.find('=') {
+                if line.starts_with('#') {
+                    continue;
+                }
+                let key = unquote(&line[..eq]);
+                let key = if table.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", table, key)
+                };
+                lines.entry(key).or_insert(i + 1);
+            }
+        }
+
+        Source {
+            file,
+            lines,
+            ..Source::default()
+        }
+    }
+
+    /// Where `key` is set: the environment variable overriding it or
+    /// the line of the key or its closest table in the file
+    fn locate(&self, key: &str) -> Option<String> {
+        if let Some(var) = self.env.get(key) {
+            return Some(var.clone());
+        }
+        let file = self.file.as_ref()?;
+        let mut key = key;
+        loop {
+            if let Some(line) = self.lines.get(key) {
+                return Some(format!("{}:{}", file.display(), line));
+            }
+            key = &key[..key.rfind('.')?];
+        }
+    }
+}
+
+/// Removes the quotes and whitespace around the parts of a dotted key
+fn unquote(key: &str) -> String {
+    key.split('.')
+        .map(|part| part.trim().trim_matches('"'))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// A setting which is invalid or refers to something unusable
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    /// The setting, e.g. `ffmpeg.bin`
+    pub key: String,
+    pub message: String,
+    /// `file:line` or the environment variable the setting comes from
+    pub location: Option<String>,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(location) = &self.location {
+            write!(f, "{}: ", location)?;
+        }
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+fn invalid_data<E: fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn parse_env<T: FromStr>(var: &str, value: &str) -> io::Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid_data(format!("{}: invalid value {:?}", var, value)))
 }
 
 impl Config {
-    pub fn new() -> SharedCfg {
-        Config::default().into_shared()
+    /// Reads the config file if there is one, then applies the
+    /// `MOVIEBAY_*` environment variables
+    pub fn load(file: Option<&Path>) -> io::Result<Config> {
+        let mut config = match file {
+            Some(file) => Config::from_file(file)?,
+            None => Config::default(),
+        };
+        config.apply_env(std::env::vars())?;
+        Ok(config)
     }
 
     pub fn from_file<P: AsRef<Path>>(file: P) -> io::Result<Config> {
         let mut contents = String::new();
-        let mut file = File::open(file)?;
-        file.read_to_string(&mut contents)?;
-        let config: Config = toml::from_str(&contents)?;
+        File::open(&file)?.read_to_string(&mut contents)?;
+        Config::from_toml(&contents, Some(file.as_ref().to_owned()))
+    }
 
+    fn from_toml(contents: &str, file: Option<PathBuf>) -> io::Result<Config> {
+        let mut config: Config = toml::from_str(contents).map_err(invalid_data)?;
+        config.source = Source::index(file, contents);
         Ok(config)
     }
 
+    /// The file the config was read from
+    pub fn file(&self) -> Option<&Path> {
+        self.source.file.as_deref()
+    }
+
+    /// Settings which differ from `other` but are only used on start
+    pub fn restart_required(&self, other: &Config) -> Vec<&'static str> {
+        let mut keys = Vec::new();
+        if self.server.bind != other.server.bind {
+            keys.push("server.bind");
+        }
+        if self.server.port != other.server.port {
+            keys.push("server.port");
+        }
+        if self.database.name != other.database.name {
+            keys.push("database.name");
+        }
+        if self.database.readers != other.database.readers {
+            keys.push("database.readers");
+        }
+        if self.jobs.workers != other.jobs.workers {
+            keys.push("jobs.workers");
+        }
+        let (dlna, other_dlna) = (&self.dlna, &other.dlna);
+        if dlna.enabled != other_dlna.enabled
+            || dlna.name != other_dlna.name
+            || dlna.user != other_dlna.user
+            || dlna.announce_interval != other_dlna.announce_interval
+        {
+            keys.push("dlna");
+        }
+        let (log, other_log) = (&self.log, &other.log);
+        if log.level != other_log.level
+            || log.modules != other_log.modules
+            || log.format != other_log.format
+            || log.file != other_log.file
+            || log.max_size != other_log.max_size
+            || log.keep != other_log.keep
+        {
+            keys.push("log");
+        }
+        keys
+    }
+
+    /// Overrides settings with the variables named `MOVIEBAY_<SECTION>_<KEY>`,
+    /// e.g. `MOVIEBAY_TMDB_API_KEY`
+    pub fn apply_env<I: IntoIterator<Item = (String, String)>>(
+        &mut self,
+        vars: I,
+    ) -> io::Result<()> {
+        for (var, value) in vars {
+            let name = match var.strip_prefix(ENV_PREFIX) {
+                Some(name) if name != "CONFIG" => name.to_owned(),
+                _ => continue,
+            };
+            if self.set_env(&var, &name, &value)? {
+                let key = name.to_lowercase().replacen('_', ".", 1);
+                self.source.env.insert(key, var);
+            } else {
+                self.source.unknown_env.push(var);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the setting `name` refers to. Returns `false` if there is
+    /// no such setting.
+    fn set_env(&mut self, var: &str, name: &str, value: &str) -> io::Result<bool> {
+        let value = value.to_owned();
+        match name {
+            "SERVER_BIND" => self.server.bind = value,
+            "SERVER_PORT" => self.server.port = parse_env(var, &value)?,
+            "SERVER_BASE_URL" => self.server.base_url = value,
+            "DATABASE_NAME" => self.database.name = value,
+            "DATABASE_READERS" => self.database.readers = parse_env(var, &value)?,
+            "TMDB_API_KEY" => self.tmdb.api_key = Some(value),
+            "FFMPEG_BIN" => self.ffmpeg.bin = value,
+            "AUTH_ADMIN_NAME" => self.auth.admin_name = value,
+            "AUTH_ADMIN_PASSWORD" => self.auth.admin_password = Some(value),
+            "AUTH_SIGNED_URL_TTL" => self.auth.signed_url_ttl = parse_env(var, &value)?,
+            "PLAYBACK_WATCHED_THRESHOLD" => {
+                self.playback.watched_threshold = parse_env(var, &value)?
+            }
+            "WEB_ROOT" => self.web.root = value,
+            "THUMBNAILS_ENABLED" => self.thumbnails.enabled = parse_env(var, &value)?,
+            "THUMBNAILS_DIR" => self.thumbnails.dir = value,
+            "JOBS_WORKERS" => self.jobs.workers = parse_env(var, &value)?,
+            "BACKUP_DIR" => self.backup.dir = value,
+            "BACKUP_KEEP" => self.backup.keep = parse_env(var, &value)?,
+            "SHARES_USERS" => self.shares.users = parse_env(var, &value)?,
+            "SHARES_MAX_EXPIRES_IN" => self.shares.max_expires_in = parse_env(var, &value)?,
+            "SHARES_MAX_PLAYS" => self.shares.max_plays = parse_env(var, &value)?,
+            "DLNA_ENABLED" => self.dlna.enabled = parse_env(var, &value)?,
+            "DLNA_NAME" => self.dlna.name = value,
+            "DLNA_USER" => self.dlna.user = value,
+            "JELLYFIN_ENABLED" => self.jellyfin.enabled = parse_env(var, &value)?,
+            "JELLYFIN_NAME" => self.jellyfin.name = value,
+            "LOG_LEVEL" => self.log.level = value,
+            "LOG_FORMAT" => self.log.format = value,
+            "LOG_FILE" => self.log.file = Some(value),
+            // any library, e.g. `MOVIEBAY_LIBRARY_KIDS`
+            _ => match name.strip_prefix("LIBRARY_") {
+                Some(library) if !library.is_empty() => {
+                    self.library.dirs.insert(library.to_lowercase(), value);
+                }
+                _ => return Ok(false),
+            },
+        }
+        Ok(true)
+    }
+
+    /// Checks the settings and the paths and programs they refer to.
+    /// Returns all problems found.
+    pub fn validate(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let mut problem = |key: &str, message: String| {
+            problems.push(Problem {
+                key: key.to_owned(),
+                message,
+                location: self.source.locate(key),
+            })
+        };
+
+        for var in self.source.unknown_env.iter() {
+            problem(var, "unknown setting".to_owned());
+        }
+        if self.server.bind.parse::<IpAddr>().is_err() {
+            problem(
+                "server.bind",
+                format!("{} is not an IP address", self.server.bind),
+            );
+        }
+        let base_url = &self.server.base_url;
+        if !base_url.is_empty()
+            && !base_url.starts_with("http://")
+            && !base_url.starts_with("https://")
+        {
+            problem(
+                "server.base_url",
+                "must start with http:// or https://".to_owned(),
+            );
+        }
+        if self.library.dirs.is_empty() {
+            problem("library", "has no libraries".to_owned());
+        }
+        for (name, dir) in self.library.dirs.iter() {
+            if let Err(e) = fs::read_dir(dir) {
+                problem(
+                    &format!("library.{}", name),
+                    format!("could not read {}: {}", dir, e),
+                );
+            }
+        }
+        let db_dir = Path::new(&self.database.name)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty());
+        if let Some(dir) = db_dir {
+            if !dir.is_dir() {
+                problem(
+                    "database.name",
+                    format!("directory {} does not exist", dir.display()),
+                );
+            }
+        }
+        if self.database.readers == 0 {
+            problem("database.readers", "must be at least 1".to_owned());
+        }
+        match Command::new(&self.ffmpeg.bin).arg("-version").output() {
+            Ok(output) if output.status.success() => {}
+            _ => problem("ffmpeg.bin", format!("could not run {}", self.ffmpeg.bin)),
+        }
+        if !self.ffmpeg.codecs.contains_key("*") {
+            problem("ffmpeg.codecs", "missing the \"*\" codec".to_owned());
+        }
+        for (name, codec) in self.ffmpeg.codecs.iter() {
+            let unknown = codec
+                .args
+                .iter()
+                .filter_map(|arg| arg.strip_prefix('%'))
+                .filter(|arg| !ffmpeg::PLACEHOLDERS.contains(arg));
+            for arg in unknown {
+                problem(
+                    &format!("ffmpeg.codecs.{}.args", name),
+                    format!("unknown placeholder %{}", arg),
+                );
+            }
+        }
+        if !Path::new(&self.web.root).is_dir() {
+            problem("web.root", format!("{} is not a directory", self.web.root));
+        }
+        let threshold = self.playback.watched_threshold;
+        if threshold <= 0.0 || threshold > 1.0 {
+            problem("playback.watched_threshold", "must be in (0, 1]".to_owned());
+        }
+        if self.thumbnails.interval == 0 || self.thumbnails.width <= 0 {
+            problem(
+                "thumbnails",
+                "interval and width must be positive".to_owned(),
+            );
+        }
+        if self.jobs.workers == 0 {
+            problem("jobs.workers", "must be at least 1".to_owned());
+        }
+        for (kind, expr) in self.jobs.schedule.iter() {
+            let key = format!("jobs.schedule.{}", kind);
+            if let Err(e) = kind.parse::<JobKind>() {
+                problem(&key, e.to_string());
+            }
+            if let Err(e) = expr.parse::<Schedule>() {
+                problem(&key, e.to_string());
+            }
+        }
+        if self.auth.signed_url_ttl == 0 {
+            problem("auth.signed_url_ttl", "must be at least 1".to_owned());
+        }
+        if self.backup.keep == 0 {
+            problem("backup.keep", "must be at least 1".to_owned());
+        }
+        if self.shares.max_expires_in == 0 {
+            problem("shares.max_expires_in", "must be at least 1".to_owned());
+        }
+        if self.shares.max_plays < 1 {
+            problem("shares.max_plays", "must be at least 1".to_owned());
+        }
+        if self.dlna.announce_interval < 60 {
+            problem("dlna.announce_interval", "must be at least 60".to_owned());
+        }
+        let loopback = self
+            .server
+            .bind
+            .parse::<IpAddr>()
+            .map(|ip| ip.is_loopback());
+        if self.dlna.enabled && loopback == Ok(true) {
+            problem(
+                "dlna.enabled",
+                format!(
+                    "renderers on the network can't reach server.bind {}",
+                    self.server.bind
+                ),
+            );
+        }
+        if let Err(e) = logging::parse_level(&self.log.level) {
+            problem("log.level", e);
+        }
+        for (module, level) in self.log.modules.iter() {
+            if let Err(e) = logging::parse_level(level) {
+                problem(&format!("log.modules.{}", module), e);
+            }
+        }
+        if let Err(e) = self.log.format.parse::<logging::Format>() {
+            problem("log.format", e);
+        }
+        let log_dir = self
+            .log
+            .file
+            .as_ref()
+            .and_then(|file| Path::new(file).parent())
+            .filter(|dir| !dir.as_os_str().is_empty());
+        if let Some(dir) = log_dir {
+            if !dir.is_dir() {
+                problem(
+                    "log.file",
+                    format!("directory {} does not exist", dir.display()),
+                );
+            }
+        }
+
+        problems
+    }
+
     pub fn into_shared(self) -> SharedCfg {
         Arc::new(self)
     }
 }
 
-impl Default for Config {
-    fn default() -> Config {
-        Config::from_file("moviebay.toml").unwrap()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::from_toml("", None).unwrap();
+        assert_eq!("127.0.0.1", config.server.bind);
+        assert_eq!(3000, config.server.port);
+        assert_eq!("moviebay.db", config.database.name);
+        assert!(config.tmdb.api_key.is_none());
+        assert!(config.ffmpeg.codecs.contains_key("*"));
+        assert_eq!("movies", config.library.dirs["movies"]);
+
+        let config = Config::from_toml("[server]\nport = 8080\n", None).unwrap();
+        assert_eq!(8080, config.server.port);
+        assert_eq!("127.0.0.1", config.server.bind);
+
+        let contents = "[library]\nkids = \"/media/kids\"\n\"4K\" = \"/media/4k\"\n";
+        let config = Config::from_toml(contents, None).unwrap();
+        assert_eq!(
+            vec!["4K", "kids"],
+            config.library.dirs.keys().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let mut config = Config::from_toml("[tmdb]\napi_key = \"file\"\n", None).unwrap();
+        config
+            .apply_env(vars(&[
+                ("MOVIEBAY_TMDB_API_KEY", "env"),
+                ("MOVIEBAY_SERVER_PORT", "8080"),
+                ("MOVIEBAY_LIBRARY_KIDS", "/media/kids"),
+                ("MOVIEBAY_CONFIG", "other.toml"),
+                ("MOVIEBAY_NOPE", "1"),
+                ("HOME", "/root"),
+            ]))
+            .unwrap();
+        assert_eq!(Some("env".to_owned()), config.tmdb.api_key);
+        assert_eq!(8080, config.server.port);
+        assert_eq!(
+            vec!["kids", "movies"],
+            config.library.dirs.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some("MOVIEBAY_LIBRARY_KIDS".to_owned()),
+            config.source.locate("library.kids")
+        );
+        assert_eq!(
+            Some("MOVIEBAY_TMDB_API_KEY".to_owned()),
+            config.source.locate("tmdb.api_key")
+        );
+        assert_eq!(vec!["MOVIEBAY_NOPE".to_owned()], config.source.unknown_env);
+
+        assert!(config
+            .apply_env(vars(&[("MOVIEBAY_SERVER_PORT", "http")]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_locate() {
+        let contents = "[server]\n  port = 1\n\n[ffmpeg.codecs]\n  [ffmpeg.codecs.\"*\"]\n    args = [\n      \"%x\"\n    ]\n";
+        let config = Config::from_toml(contents, Some("m.toml".into())).unwrap();
+        let source = &config.source;
+        assert_eq!(Some("m.toml:2".to_owned()), source.locate("server.port"));
+        assert_eq!(Some("m.toml:1".to_owned()), source.locate("server.bind"));
+        assert_eq!(
+            Some("m.toml:6".to_owned()),
+            source.locate("ffmpeg.codecs.*.args")
+        );
+        assert_eq!(None, source.locate("tmdb.api_key"));
+
+        let problems = config.validate();
+        let placeholder = problems
+            .iter()
+            .find(|p| p.key == "ffmpeg.codecs.*.args")
+            .unwrap();
+        assert_eq!(
+            "m.toml:6: ffmpeg.codecs.*.args: unknown placeholder %x",
+            placeholder.to_string()
+        );
     }
 }
@@ -1,27 +1,153 @@
-use crate::config::SharedCfg;
-use crate::sqlite::{Runtime, SharedDb};
+use crate::config::{CfgHandle, Config, SharedCfg};
+use crate::dlna::Dlna;
+use crate::events::Events;
+use crate::party::Parties;
+use crate::sqlite::{Runtime, SharedDb, SqlResult};
+use log::{info, warn};
+use std::fs;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 pub type SharedCtx = Arc<Context>;
 
+/// How often the config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
 pub struct Context {
     db: SharedDb,
-    config: SharedCfg,
+    config: CfgHandle,
+    events: Events,
+    parties: Parties,
+    dlna: Dlna,
 }
 
 impl Context {
-    pub fn from_config(config: SharedCfg) -> SharedCtx {
-        let (db, rt) = Runtime::channel(config.database.clone());
+    /// Opens the configured database, loading it from its file if it
+    /// exists
+    pub fn from_config(config: SharedCfg) -> SqlResult<SharedCtx> {
+        let path = config.database.name.clone();
+        Context::open(config, path)
+    }
+
+    /// Like `from_config`, but loads the database from `path`
+    pub fn open<P: AsRef<Path>>(config: SharedCfg, path: P) -> SqlResult<SharedCtx> {
+        let (db, mut rt) = Runtime::channel(config.database.clone());
+        rt.load(path)?;
         rt.run();
 
-        Arc::new(Context { db, config })
+        Ok(Arc::new(Context {
+            db,
+            config: config.into(),
+            events: Events::new(),
+            parties: Parties::new(),
+            dlna: Dlna::new(),
+        }))
     }
 
     pub fn db(&self) -> SharedDb {
         self.db.clone()
     }
 
+    /// The event bus, see `/events`
+    pub fn events(&self) -> Events {
+        self.events.clone()
+    }
+
+    /// The watch parties
+    pub fn parties(&self) -> Parties {
+        self.parties.clone()
+    }
+
+    /// The DLNA media server, started by `serve` if enabled
+    pub fn dlna(&self) -> Dlna {
+        self.dlna.clone()
+    }
+
+    /// The current config
     pub fn cfg(&self) -> SharedCfg {
+        self.config.get()
+    }
+
+    /// Handle to the config which follows reloads
+    pub fn cfg_handle(&self) -> CfgHandle {
         self.config.clone()
     }
+
+    /// Reads the config again and swaps it in. The current config stays
+    /// in place if the new one can't be read or has problems the
+    /// current one doesn't have. This blocks while validating.
+    pub fn reload(&self) -> Result<SharedCfg, String> {
+        let current = self.config.get();
+        let config = Config::load(current.file()).map_err(|e| e.to_string())?;
+
+        let known = current.validate();
+        let problems = config
+            .validate()
+            .into_iter()
+            .filter(|p| {
+                !known
+                    .iter()
+                    .any(|k| k.key == p.key && k.message == p.message)
+            })
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+        if !problems.is_empty() {
+            return Err(problems.join(", "));
+        }
+
+        for key in current.restart_required(&config) {
+            warn!("config {} changed, restart to apply", key);
+        }
+        let config = config.into_shared();
+        self.config.set(config.clone());
+        Ok(config)
+    }
+
+    /// Reloads the config on SIGHUP and whenever its file changes
+    pub fn watch(ctx: SharedCtx) {
+        #[cfg(unix)]
+        tokio::spawn(Context::watch_signal(ctx.clone()));
+        tokio::spawn(Context::watch_file(ctx));
+    }
+
+    #[cfg(unix)]
+    async fn watch_signal(ctx: SharedCtx) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                warn!("could not listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            Context::reload_logged(ctx.clone()).await;
+        }
+    }
+
+    async fn watch_file(ctx: SharedCtx) {
+        let mut last = ctx.cfg().file().and_then(modified);
+        loop {
+            tokio::time::delay_for(WATCH_INTERVAL).await;
+            let current = ctx.cfg().file().and_then(modified);
+            if current.is_some() && current != last {
+                last = current;
+                Context::reload_logged(ctx.clone()).await;
+            }
+        }
+    }
+
+    async fn reload_logged(ctx: SharedCtx) {
+        match tokio::task::spawn_blocking(move || ctx.reload()).await {
+            Ok(Ok(_)) => info!("config reloaded"),
+            Ok(Err(e)) => warn!("config reload failed, keeping the old config: {}", e),
+            Err(e) => warn!("config reload failed: {}", e),
+        }
+    }
+}
+
+fn modified(file: &Path) -> Option<SystemTime> {
+    fs::metadata(file).and_then(|m| m.modified()).ok()
 }
@@ -0,0 +1,263 @@
+use super::soap::escape;
+use crate::model::Movie;
+use std::fmt;
+use std::str::FromStr;
+
+/// `protocolInfo` of the streams. They are transcoded to MP4 on the fly
+/// (`CI=1`) and can't be seeked by the renderer (`OP=00`), the flags
+/// allow streaming and background transfers with DLNA 1.5.
+pub const PROTOCOL_INFO: &str = "http-get:*:video/mp4:DLNA.ORG_OP=00;DLNA.ORG_CI=1;\
+                                 DLNA.ORG_FLAGS=01500000000000000000000000000000";
+
+/// A node of the browsable tree. The root holds the libraries, the
+/// collections and all movies, movies are the only items.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Object {
+    Root,
+    Libraries,
+    Library(String),
+    Collections,
+    Collection(i32),
+    Movies,
+    Movie(i32),
+}
+
+impl Object {
+    /// The container an object is listed in, `None` for the root. A
+    /// movie may be in several, its parent is the list of all movies.
+    pub fn parent(&self) -> Option<Object> {
+        match self {
+            Object::Root => None,
+            Object::Libraries | Object::Collections | Object::Movies => Some(Object::Root),
+            Object::Library(_) => Some(Object::Libraries),
+            Object::Collection(_) => Some(Object::Collections),
+            Object::Movie(_) => Some(Object::Movies),
+        }
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Object::Root => write!(f, "0"),
+            Object::Libraries => write!(f, "libraries"),
+            Object::Library(name) => write!(f, "library/{}", name),
+            Object::Collections => write!(f, "collections"),
+            Object::Collection(id) => write!(f, "collection/{}", id),
+            Object::Movies => write!(f, "movies"),
+            Object::Movie(id) => write!(f, "movie/{}", id),
+        }
+    }
+}
+
+impl FromStr for Object {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Object, ()> {
+        let (kind, arg) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let object = match (kind, arg) {
+            ("0", None) => Object::Root,
+            ("libraries", None) => Object::Libraries,
+            ("library", Some(name)) if !name.is_empty() => Object::Library(name.to_owned()),
+            ("collections", None) => Object::Collections,
+            ("collection", Some(id)) => Object::Collection(id.parse().map_err(|_| ())?),
+            ("movies", None) => Object::Movies,
+            ("movie", Some(id)) => Object::Movie(id.parse().map_err(|_| ())?),
+            _ => return Err(()),
+        };
+        Ok(object)
+    }
+}
+
+/// Where renderers fetch streams and posters. They can't log in, so
+/// the urls are signed for the DLNA user.
+pub struct Links {
+    sign: Box<dyn Fn(&str) -> String + Send + Sync>,
+}
+
+impl Links {
+    /// `sign` turns a path into the full signed url
+    pub fn new<F: Fn(&str) -> String + Send + Sync + 'static>(sign: F) -> Links {
+        Links {
+            sign: Box::new(sign),
+        }
+    }
+
+    pub fn stream(&self, id: i32) -> String {
+        (self.sign)(&format!("/stream/{}", id))
+    }
+
+    pub fn poster(&self, id: i32) -> String {
+        (self.sign)(&format!("/movies/{}/poster", id))
+    }
+}
+
+/// An entry of a Browse result
+#[derive(Debug, Clone)]
+pub enum Entry {
+    Container {
+        object: Object,
+        title: String,
+        /// Left out where counting is expensive, it is optional
+        child_count: Option<i64>,
+    },
+    Item {
+        movie: Movie,
+        /// The container browsed
+        parent: Object,
+        /// Whether a poster was generated for the movie
+        poster: bool,
+    },
+}
+
+/// Renders entries as DIDL-Lite, the result of Browse
+pub fn didl(entries: &[Entry], links: &Links) -> String {
+    let mut xml = String::from(
+        "<DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" \
+         xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+         xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\" \
+         xmlns:dlna=\"urn:schemas-dlna-org:metadata-1-0/\">",
+    );
+    for entry in entries {
+        match entry {
+            Entry::Container {
+                object,
+                title,
+                child_count,
+            } => {
+                let parent = object.parent().map_or("-1".to_owned(), |p| p.to_string());
+                xml.push_str(&format!(
+                    "<container id=\"{}\" parentID=\"{}\" restricted=\"1\" searchable=\"0\"",
+                    escape(&object.to_string()),
+                    escape(&parent)
+                ));
+                if let Some(count) = child_count {
+                    xml.push_str(&format!(" childCount=\"{}\"", count));
+                }
+                xml.push_str(&format!(
+                    "><dc:title>{}</dc:title>\
+                     <upnp:class>object.container.storageFolder</upnp:class></container>",
+                    escape(title)
+                ));
+            }
+            Entry::Item {
+                movie,
+                parent,
+                poster,
+            } => {
+                xml.push_str(&format!(
+                    "<item id=\"{}\" parentID=\"{}\" restricted=\"1\">\
+                     <dc:title>{}</dc:title><upnp:class>object.item.videoItem.movie</upnp:class>",
+                    Object::Movie(movie.id),
+                    escape(&parent.to_string()),
+                    escape(&movie.title)
+                ));
+                if movie.release_year > 0 {
+                    xml.push_str(&format!("<dc:date>{}-01-01</dc:date>", movie.release_year));
+                }
+                for genre in movie.genres.iter() {
+                    xml.push_str(&format!("<upnp:genre>{}</upnp:genre>", escape(genre)));
+                }
+                if !movie.overview.is_empty() {
+                    xml.push_str(&format!(
+                        "<dc:description>{}</dc:description>",
+                        escape(&movie.overview)
+                    ));
+                }
+                if *poster {
+                    xml.push_str(&format!(
+                        "<upnp:albumArtURI>{}</upnp:albumArtURI>",
+                        escape(&links.poster(movie.id))
+                    ));
+                }
+                xml.push_str(&format!(
+                    "<res protocolInfo=\"{}\">{}</res></item>",
+                    PROTOCOL_INFO,
+                    escape(&links.stream(movie.id))
+                ));
+            }
+        }
+    }
+    xml.push_str("</DIDL-Lite>");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object() {
+        let objects = vec![
+            Object::Root,
+            Object::Libraries,
+            Object::Library("4K/HDR".to_owned()),
+            Object::Collections,
+            Object::Collection(3),
+            Object::Movies,
+            Object::Movie(42),
+        ];
+        for object in objects {
+            assert_eq!(Ok(object.clone()), object.to_string().parse());
+        }
+        assert_eq!(
+            Some(Object::Libraries),
+            Object::Library("a".into()).parent()
+        );
+        assert_eq!(None, Object::Root.parent());
+
+        assert!("".parse::<Object>().is_err());
+        assert!("library/".parse::<Object>().is_err());
+        assert!("movie/x".parse::<Object>().is_err());
+        assert!("movies/1".parse::<Object>().is_err());
+    }
+
+    #[test]
+    fn test_didl() {
+        let links = Links::new(|path| format!("http://10.0.0.2:3000{}?sig=abc", path));
+        let movie = Movie {
+            id: 7,
+            tmdb_id: 0,
+            title: "Tom & Jerry".to_owned(),
+            overview: "".to_owned(),
+            release_year: 2021,
+            file_path: "".to_owned(),
+            poster_path: "".to_owned(),
+            backdrop_path: "".to_owned(),
+            rating: 0.0,
+            resolution: 0,
+            library: "movies".to_owned(),
+            added_at: 0,
+            genres: vec!["Comedy".to_owned()],
+        };
+        let entries = vec![
+            Entry::Container {
+                object: Object::Library("movies".to_owned()),
+                title: "movies".to_owned(),
+                child_count: Some(1),
+            },
+            Entry::Item {
+                movie,
+                parent: Object::Library("movies".to_owned()),
+                poster: false,
+            },
+        ];
+        let xml = didl(&entries, &links);
+        assert!(xml.contains(
+            "<container id=\"library/movies\" parentID=\"libraries\" restricted=\"1\" \
+             searchable=\"0\" childCount=\"1\"><dc:title>movies</dc:title>"
+        ));
+        assert!(xml.contains("<item id=\"movie/7\" parentID=\"library/movies\""));
+        assert!(xml.contains("<dc:title>Tom &amp; Jerry</dc:title>"));
+        assert!(xml.contains("<dc:date>2021-01-01</dc:date><upnp:genre>Comedy</upnp:genre>"));
+        assert!(!xml.contains("dc:description"));
+        assert!(!xml.contains("albumArtURI"));
+        assert!(xml.contains(&format!(
+            "<res protocolInfo=\"{}\">http://10.0.0.2:3000/stream/7?sig=abc</res>",
+            PROTOCOL_INFO
+        )));
+    }
+}
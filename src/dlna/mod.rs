@@ -0,0 +1,228 @@
+pub mod content;
+pub mod soap;
+mod ssdp;
+
+pub use ssdp::{CONNECTION_MANAGER, CONTENT_DIRECTORY};
+
+use crate::config::SharedCfg;
+use crate::events::{Event, Events};
+use crate::model::{User, UserTable};
+use crate::sqlite::SharedDb;
+use log::info;
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast::RecvError;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+pub const CONTENT_DIRECTORY_SCPD: &str = include_str!("content_directory.xml");
+pub const CONNECTION_MANAGER_SCPD: &str = include_str!("connection_manager.xml");
+
+/// The media server while it is announced
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub uuid: String,
+    pub name: String,
+    /// The user renderers browse and stream as
+    pub user_id: i32,
+}
+
+struct Running {
+    session: Session,
+    stop: oneshot::Sender<()>,
+    ssdp: JoinHandle<()>,
+}
+
+/// Handle to the DLNA media server. Its routes answer `404 Not Found`
+/// unless it is started.
+#[derive(Clone, Default)]
+pub struct Dlna {
+    running: Arc<Mutex<Option<Running>>>,
+    /// `SystemUpdateID` of the ContentDirectory, renderers reload
+    /// cached listings when it changes
+    update_id: Arc<AtomicU32>,
+}
+
+impl Dlna {
+    pub fn new() -> Dlna {
+        Dlna::default()
+    }
+
+    pub fn session(&self) -> Option<Session> {
+        let running = self.running.lock().unwrap();
+        running.as_ref().map(|r| r.session.clone())
+    }
+
+    pub fn update_id(&self) -> u32 {
+        self.update_id.load(Ordering::Relaxed)
+    }
+
+    /// Announces the server with SSDP, renderers browse as `dlna.user`
+    /// and get signed links. Tokens issued to that user are revoked,
+    /// the account is meant for the renderers only.
+    pub async fn start(
+        &self,
+        config: &SharedCfg,
+        db: SharedDb,
+        events: Events,
+    ) -> Result<(), String> {
+        let users = UserTable::new(db.clone());
+        let user = match users.by_name(&config.dlna.user).await {
+            Ok(Some(user)) => user,
+            Ok(None) => return Err(format!("dlna.user {} does not exist", config.dlna.user)),
+            Err(e) => return Err(e.to_string()),
+        };
+        if user.is_admin {
+            return Err(format!("dlna.user {} must not be an admin", user.name));
+        }
+        let socket = ssdp::bind().map_err(|e| format!("could not bind the SSDP port: {}", e))?;
+        self.announce(config, db, events, user, socket).await
+    }
+
+    /// Answers searches on `socket` and announces the server from it
+    pub(crate) async fn announce(
+        &self,
+        config: &SharedCfg,
+        db: SharedDb,
+        events: Events,
+        user: User,
+        socket: UdpSocket,
+    ) -> Result<(), String> {
+        let ip = config
+            .server
+            .bind
+            .parse::<IpAddr>()
+            .map_err(|e| e.to_string())?;
+        let users = UserTable::new(db);
+        users
+            .revoke_tokens(user.id)
+            .await
+            .map_err(|e| e.to_string())?;
+        let session = Session {
+            uuid: uuid(&format!("{}:{}", config.dlna.name, config.server.port)),
+            name: config.dlna.name.clone(),
+            user_id: user.id,
+        };
+
+        let device = ssdp::Device {
+            uuid: session.uuid.clone(),
+            addr: SocketAddr::new(ip, config.server.port),
+            max_age: config.dlna.announce_interval * 2,
+        };
+        let interval = Duration::from_secs(config.dlna.announce_interval);
+        let (stop, stopped) = oneshot::channel();
+        let ssdp = tokio::spawn(ssdp::run(socket, device, interval, stopped));
+        tokio::spawn(count_updates(events, self.update_id.clone()));
+
+        info!("DLNA server {} browses as {}", session.name, user.name);
+        *self.running.lock().unwrap() = Some(Running {
+            session,
+            stop,
+            ssdp,
+        });
+        Ok(())
+    }
+
+    /// Says goodbye on the network
+    pub async fn stop(&self) {
+        let running = match self.running.lock().unwrap().take() {
+            Some(running) => running,
+            None => return,
+        };
+        let _ = running.stop.send(());
+        let _ = running.ssdp.await;
+    }
+}
+
+/// Bumps the update id whenever listings may have changed
+async fn count_updates(events: Events, update_id: Arc<AtomicU32>) {
+    let mut rx = events.subscribe();
+    loop {
+        match rx.recv().await {
+            Ok(Event::LibraryChanged { .. })
+            | Ok(Event::MovieUpdated { .. })
+            | Err(RecvError::Lagged(_)) => {
+                update_id.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(Event::Shutdown) | Err(RecvError::Closed) => break,
+            Ok(_) => {}
+        }
+    }
+}
+
+/// A UUID derived from `seed`, so renderers recognize the server after
+/// a restart
+fn uuid(seed: &str) -> String {
+    let hash = Sha256::digest(format!("moviebay:{}", seed).as_bytes());
+    let hex = hash[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// The device description renderers fetch from the `LOCATION` of the
+/// announcements
+pub fn description(session: &Session) -> String {
+    let service = |kind: &str, name: &str| {
+        format!(
+            "<service><serviceType>{}</serviceType>\
+             <serviceId>urn:upnp-org:serviceId:{}</serviceId>\
+             <SCPDURL>/dlna/{}.xml</SCPDURL>\
+             <controlURL>/dlna/control/{}</controlURL>\
+             <eventSubURL>/dlna/event/{}</eventSubURL></service>",
+            kind, name, name, name, name
+        )
+    };
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+         <root xmlns=\"urn:schemas-upnp-org:device-1-0\" \
+         xmlns:dlna=\"urn:schemas-dlna-org:device-1-0\">\
+         <specVersion><major>1</major><minor>0</minor></specVersion>\
+         <device><deviceType>{}</deviceType><friendlyName>{}</friendlyName>\
+         <manufacturer>moviebay</manufacturer><modelName>moviebay</modelName>\
+         <modelNumber>{}</modelNumber><UDN>uuid:{}</UDN>\
+         <dlna:X_DLNADOC>DMS-1.50</dlna:X_DLNADOC>\
+         <serviceList>{}{}</serviceList></device></root>",
+        ssdp::MEDIA_SERVER,
+        soap::escape(&session.name),
+        env!("CARGO_PKG_VERSION"),
+        session.uuid,
+        service(CONTENT_DIRECTORY, "ContentDirectory"),
+        service(CONNECTION_MANAGER, "ConnectionManager")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_description() {
+        let id = uuid("moviebay:3000");
+        assert_eq!(36, id.len());
+        assert_eq!(id, uuid("moviebay:3000"));
+        assert_ne!(id, uuid("moviebay:3001"));
+
+        let session = Session {
+            uuid: id.clone(),
+            name: "Movies & more".to_owned(),
+            user_id: 2,
+        };
+        let xml = description(&session);
+        assert!(xml.contains("<friendlyName>Movies &amp; more</friendlyName>"));
+        assert!(xml.contains(&format!("<UDN>uuid:{}</UDN>", id)));
+        assert!(xml.contains("<controlURL>/dlna/control/ContentDirectory</controlURL>"));
+    }
+}
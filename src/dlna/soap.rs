@@ -0,0 +1,137 @@
+use regex::Regex;
+
+/// An error returned to the control point as `UPnPError`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Fault {
+    InvalidAction,
+    InvalidArgs,
+    ActionFailed,
+    NoSuchObject,
+}
+
+impl Fault {
+    pub fn code(self) -> u32 {
+        match self {
+            Fault::InvalidAction => 401,
+            Fault::InvalidArgs => 402,
+            Fault::ActionFailed => 501,
+            Fault::NoSuchObject => 701,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Fault::InvalidAction => "Invalid Action",
+            Fault::InvalidArgs => "Invalid Args",
+            Fault::ActionFailed => "Action Failed",
+            Fault::NoSuchObject => "No such object",
+        }
+    }
+}
+
+/// The name of the action called, from the `SOAPACTION` header such as
+/// `"urn:schemas-upnp-org:service:ContentDirectory:1#Browse"`
+pub fn action(header: &str) -> Option<&str> {
+    let action = header.trim().trim_matches('"').rsplit('#').next()?;
+    if action.is_empty() {
+        None
+    } else {
+        Some(action)
+    }
+}
+
+/// The text of the argument `name` in a request body, unescaped
+pub fn arg(body: &str, name: &str) -> Option<String> {
+    let re = Regex::new(&format!(
+        r"(?s)<(?:\w+:)?{name}(?:\s[^>]*)?(?:/>|>(.*?)</(?:\w+:)?{name}>)",
+        name = regex::escape(name)
+    ))
+    .ok()?;
+    let caps = re.captures(body)?;
+    Some(unescape(caps.get(1).map_or("", |m| m.as_str())))
+}
+
+/// Escapes text for an XML element or attribute
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+const ENVELOPE: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+    <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+    s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>";
+
+/// The response to `action` of the service `service`, with the results
+/// in order
+pub fn response(service: &str, action: &str, results: &[(&str, String)]) -> String {
+    let mut xml = format!("{}<u:{}Response xmlns:u=\"{}\">", ENVELOPE, action, service);
+    for (name, value) in results {
+        xml.push_str(&format!("<{}>{}</{}>", name, escape(value), name));
+    }
+    xml.push_str(&format!("</u:{}Response></s:Body></s:Envelope>", action));
+    xml
+}
+
+/// The response to an action which failed
+pub fn fault(fault: Fault) -> String {
+    format!(
+        "{}<s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring>\
+         <detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">\
+         <errorCode>{}</errorCode><errorDescription>{}</errorDescription>\
+         </UPnPError></detail></s:Fault></s:Body></s:Envelope>",
+        ENVELOPE,
+        fault.code(),
+        fault.description()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        assert_eq!(
+            Some("Browse"),
+            action("\"urn:schemas-upnp-org:service:ContentDirectory:1#Browse\"")
+        );
+        assert_eq!(None, action("\"urn:x#\""));
+
+        let body = "<s:Envelope><s:Body><u:Browse xmlns:u=\"urn:x\">\
+                    <ObjectID>library/a&amp;b</ObjectID><Filter>*</Filter>\
+                    <SortCriteria/><StartingIndex>10</StartingIndex>\
+                    </u:Browse></s:Body></s:Envelope>";
+        assert_eq!(Some("library/a&b".to_owned()), arg(body, "ObjectID"));
+        assert_eq!(Some("10".to_owned()), arg(body, "StartingIndex"));
+        assert_eq!(Some("".to_owned()), arg(body, "SortCriteria"));
+        assert_eq!(None, arg(body, "Object"));
+        assert_eq!(None, arg(body, "BrowseFlag"));
+    }
+
+    #[test]
+    fn test_response() {
+        let xml = response("urn:x", "Browse", &[("Result", "<DIDL-Lite/>".to_owned())]);
+        assert!(xml.contains(
+            "<u:BrowseResponse xmlns:u=\"urn:x\"><Result>&lt;DIDL-Lite/&gt;</Result></u:BrowseResponse>"
+        ));
+        assert!(fault(Fault::NoSuchObject).contains("<errorCode>701</errorCode>"));
+    }
+}
@@ -0,0 +1,281 @@
+use chrono::Utc;
+use log::{debug, warn};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::udp::SendHalf;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+/// The SSDP multicast group
+pub const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+pub const PORT: u16 = 1900;
+
+pub const MEDIA_SERVER: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+pub const CONTENT_DIRECTORY: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
+pub const CONNECTION_MANAGER: &str = "urn:schemas-upnp-org:service:ConnectionManager:1";
+
+/// A search request of a control point
+#[derive(Debug, PartialEq)]
+pub struct Search {
+    /// The search target, e.g. `ssdp:all` or a device or service type
+    pub st: String,
+}
+
+/// Parses an `M-SEARCH` request, other messages give `None`
+pub fn parse(msg: &str) -> Option<Search> {
+    let mut lines = msg.lines();
+    if !lines.next()?.trim().starts_with("M-SEARCH * HTTP/1.1") {
+        return None;
+    }
+    let mut st = None;
+    let mut discover = false;
+    for line in lines {
+        let (name, value) = match line.find(':') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => continue,
+        };
+        if name.eq_ignore_ascii_case("ST") {
+            st = Some(value.to_owned());
+        } else if name.eq_ignore_ascii_case("MAN") {
+            discover = value.trim_matches('"') == "ssdp:discover";
+        }
+    }
+    if !discover {
+        return None;
+    }
+    Some(Search { st: st? })
+}
+
+/// The server as announced on the network
+#[derive(Debug, Clone)]
+pub struct Device {
+    /// Without the `uuid:` prefix
+    pub uuid: String,
+    /// The address of the HTTP server, may be unspecified
+    pub addr: SocketAddr,
+    /// Seconds control points keep the announcement
+    pub max_age: u64,
+}
+
+impl Device {
+    /// The notification types of the device with their unique service
+    /// names
+    pub fn targets(&self) -> Vec<(String, String)> {
+        let uuid = format!("uuid:{}", self.uuid);
+        let mut targets = vec![(uuid.clone(), uuid.clone())];
+        for nt in [
+            "upnp:rootdevice",
+            MEDIA_SERVER,
+            CONTENT_DIRECTORY,
+            CONNECTION_MANAGER,
+        ]
+        .iter()
+        {
+            targets.push(((*nt).to_owned(), format!("{}::{}", uuid, nt)));
+        }
+        targets
+    }
+
+    /// The targets a search is answered with
+    pub fn matches(&self, search: &Search) -> Vec<(String, String)> {
+        self.targets()
+            .into_iter()
+            .filter(|(nt, _)| search.st == "ssdp:all" || *nt == search.st)
+            .collect()
+    }
+
+    /// URL of the device description for a peer, which has to reach
+    /// the server on the address of the interface it talks to
+    pub fn location(&self, peer: SocketAddr) -> String {
+        let ip = if self.addr.ip().is_unspecified() {
+            local_ip(peer).unwrap_or_else(|_| self.addr.ip())
+        } else {
+            self.addr.ip()
+        };
+        format!(
+            "http://{}/dlna/description.xml",
+            SocketAddr::new(ip, self.addr.port())
+        )
+    }
+
+    /// The answer to a search for `st`
+    pub fn response(&self, st: &str, usn: &str, location: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age={}\r\nDATE: {}\r\nEXT:\r\n\
+             LOCATION: {}\r\nSERVER: {}\r\nST: {}\r\nUSN: {}\r\n\r\n",
+            self.max_age,
+            Utc::now().format("%a, %d %b %Y %H:%M:%S GMT"),
+            location,
+            server(),
+            st,
+            usn
+        )
+    }
+
+    /// The announcement of `nt`, `location` is `None` when the device
+    /// leaves the network
+    pub fn notify(&self, nt: &str, usn: &str, location: Option<&str>) -> String {
+        match location {
+            Some(location) => format!(
+                "NOTIFY * HTTP/1.1\r\nHOST: {}:{}\r\nCACHE-CONTROL: max-age={}\r\n\
+                 LOCATION: {}\r\nNT: {}\r\nNTS: ssdp:alive\r\nSERVER: {}\r\nUSN: {}\r\n\r\n",
+                GROUP,
+                PORT,
+                self.max_age,
+                location,
+                nt,
+                server(),
+                usn
+            ),
+            None => format!(
+                "NOTIFY * HTTP/1.1\r\nHOST: {}:{}\r\nNT: {}\r\nNTS: ssdp:byebye\r\nUSN: {}\r\n\r\n",
+                GROUP, PORT, nt, usn
+            ),
+        }
+    }
+}
+
+fn server() -> String {
+    format!(
+        "{}/1.0 UPnP/1.0 moviebay/{}",
+        std::env::consts::OS,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// The address of the interface packets to `peer` leave from
+fn local_ip(peer: SocketAddr) -> io::Result<IpAddr> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(peer)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Binds the SSDP port next to other UPnP software on the host and
+/// joins the multicast group. Without the group only searches sent
+/// to the host directly are answered.
+pub fn bind() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SockAddr::from(SocketAddr::new(
+        Ipv4Addr::UNSPECIFIED.into(),
+        PORT,
+    )))?;
+    if let Err(e) = socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED) {
+        warn!("could not join the SSDP multicast group: {}", e);
+    }
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into_udp_socket())
+}
+
+/// Answers searches and announces the device every `interval` until
+/// `stop` fires, then says goodbye
+pub async fn run(
+    socket: UdpSocket,
+    device: Device,
+    interval: Duration,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let (mut rx, mut tx) = socket.split();
+    let group = SocketAddr::new(GROUP.into(), PORT);
+    let mut announce = tokio::time::interval(interval);
+    let mut buf = [0u8; 2048];
+
+    loop {
+        tokio::select! {
+            _ = announce.tick() => {
+                let location = device.location(group);
+                for (nt, usn) in device.targets() {
+                    let msg = device.notify(&nt, &usn, Some(&location));
+                    send(&mut tx, &msg, group).await;
+                }
+            }
+            res = rx.recv_from(&mut buf) => {
+                let (len, peer) = match res {
+                    Ok(res) => res,
+                    Err(e) => {
+                        warn!("SSDP receive failed: {}", e);
+                        continue;
+                    }
+                };
+                let search = match parse(&String::from_utf8_lossy(&buf[..len])) {
+                    Some(search) => search,
+                    None => continue,
+                };
+                debug!("SSDP search for {} from {}", search.st, peer);
+                let location = device.location(peer);
+                for (nt, usn) in device.matches(&search) {
+                    send(&mut tx, &device.response(&nt, &usn, &location), peer).await;
+                }
+            }
+            _ = &mut stop => break,
+        }
+    }
+
+    for (nt, usn) in device.targets() {
+        send(&mut tx, &device.notify(&nt, &usn, None), group).await;
+    }
+}
+
+async fn send(tx: &mut SendHalf, msg: &str, to: SocketAddr) {
+    if let Err(e) = tx.send_to(msg.as_bytes(), &to).await {
+        debug!("SSDP send to {} failed: {}", to, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let msg = "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\n\
+                   Man: \"ssdp:discover\"\r\nMX: 2\r\nst: ssdp:all\r\n\r\n";
+        assert_eq!(
+            Some(Search {
+                st: "ssdp:all".to_owned()
+            }),
+            parse(msg)
+        );
+        assert_eq!(None, parse("M-SEARCH * HTTP/1.1\r\nST: ssdp:all\r\n\r\n"));
+        assert_eq!(
+            None,
+            parse("NOTIFY * HTTP/1.1\r\nNT: upnp:rootdevice\r\nNTS: ssdp:alive\r\n\r\n")
+        );
+    }
+
+    #[test]
+    fn test_matches() {
+        let device = Device {
+            uuid: "1234".to_owned(),
+            addr: "192.168.1.2:3000".parse().unwrap(),
+            max_age: 1800,
+        };
+        let search = |st: &str| Search { st: st.to_owned() };
+        assert_eq!(5, device.matches(&search("ssdp:all")).len());
+        assert_eq!(
+            vec![(
+                MEDIA_SERVER.to_owned(),
+                format!("uuid:1234::{}", MEDIA_SERVER)
+            )],
+            device.matches(&search(MEDIA_SERVER))
+        );
+        assert_eq!(
+            vec![("uuid:1234".to_owned(), "uuid:1234".to_owned())],
+            device.matches(&search("uuid:1234"))
+        );
+        assert!(device
+            .matches(&search("urn:schemas-upnp-org:device:MediaRenderer:1"))
+            .is_empty());
+
+        let location = device.location("192.168.1.9:1900".parse().unwrap());
+        assert_eq!("http://192.168.1.2:3000/dlna/description.xml", location);
+        let response = device.response("upnp:rootdevice", "uuid:1234::upnp:rootdevice", &location);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=1800\r\n"));
+        assert!(response.contains("\r\nST: upnp:rootdevice\r\nUSN: uuid:1234::upnp:rootdevice\r\n"));
+        let byebye = device.notify("uuid:1234", "uuid:1234", None);
+        assert!(byebye.contains("NTS: ssdp:byebye") && !byebye.contains("LOCATION"));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::model::{Job, WatchState};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events kept for slow subscribers. One which falls further behind
+/// misses the oldest ones and gets `Event::Lagged` instead.
+const CAPACITY: usize = 256;
+
+/// Something which happened on the server, sent to the clients at
+/// `/events`
+#[derive(Debug, PartialEq, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A scan added movies to the library
+    LibraryChanged { added: usize },
+    /// The metadata or images of a movie changed
+    MovieUpdated { movie_id: i32 },
+    /// A job was queued, started, made progress or finished
+    Job { job: Job },
+    /// A stream started, `session` is the id of its request
+    SessionStarted {
+        session: String,
+        user_id: i32,
+        movie_id: i32,
+    },
+    SessionStopped {
+        session: String,
+        user_id: i32,
+        movie_id: i32,
+    },
+    /// The watch state of a movie changed for a user
+    WatchState {
+        user_id: i32,
+        movie_id: i32,
+        state: WatchState,
+    },
+    /// The subscriber missed `missed` events, clients should reload
+    Lagged { missed: u64 },
+    /// The server shuts down, the event streams end after this one
+    Shutdown,
+}
+
+/// Who may receive an event
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Audience {
+    Everyone,
+    Admins,
+    /// The user and the admins
+    User(i32),
+    /// Only the user, not even admins
+    Private(i32),
+    /// The users who may see the movie, see `MovieTable::for_user`
+    Movie(i32),
+}
+
+impl Event {
+    pub fn audience(&self) -> Audience {
+        match self {
+            Event::LibraryChanged { .. } | Event::Lagged { .. } | Event::Shutdown => {
+                Audience::Everyone
+            }
+            Event::MovieUpdated { movie_id } => Audience::Movie(*movie_id),
+            Event::Job { .. } => Audience::Admins,
+            Event::SessionStarted { user_id, .. } | Event::SessionStopped { user_id, .. } => {
+                Audience::User(*user_id)
+            }
+            Event::WatchState { user_id, .. } => Audience::Private(*user_id),
+        }
+    }
+}
+
+/// The event bus of the server. Handles are cheap to clone, events are
+/// only kept while someone is subscribed.
+#[derive(Clone)]
+pub struct Events {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Events {
+    pub fn new() -> Events {
+        let (tx, _) = broadcast::channel(CAPACITY);
+        Events { tx }
+    }
+
+    /// Sends an event to all subscribers
+    pub fn publish(&self, event: Event) {
+        // an error only means nobody is listening
+        let _ = self.tx.send(event);
+    }
+
+    /// Receives the events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+}
+
+impl Default for Events {
+    fn default() -> Events {
+        Events::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        let func = async {
+            let events = Events::new();
+            // nobody listens yet
+            events.publish(Event::Shutdown);
+
+            let mut rx = events.subscribe();
+            let event = Event::WatchState {
+                user_id: 1,
+                movie_id: 2,
+                state: WatchState::default(),
+            };
+            events.publish(event.clone());
+            let received = rx.recv().await.unwrap();
+            assert_eq!(event, received);
+            assert_eq!(Audience::Private(1), received.audience());
+
+            let json = serde_json::to_value(&Event::LibraryChanged { added: 3 }).unwrap();
+            assert_eq!(
+                serde_json::json!({"type": "library_changed", "added": 3}),
+                json
+            );
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+}
@@ -1,9 +1,84 @@
 use crate::config::FFmpegConfig;
+use crate::logging;
+use crate::metrics::metrics;
 use hyper::body::Bytes;
 use hyper::body::Sender;
-use std::io::{BufReader, Read};
-use std::process::{Command, Stdio};
-use std::sync::Arc;
+use log::{debug, info, warn};
+use regex::Regex;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+/// Information about a video file as reported by ffmpeg
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Probe {
+    /// Duration in seconds
+    pub duration: f64,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Probe {
+    /// Parses the output ffmpeg writes to stderr for `ffmpeg -i <file>`
+    pub fn parse(output: &str) -> Option<Probe> {
+        let duration = Regex::new(r"Duration: (\d+):(\d{2}):(\d{2}(?:\.\d+)?)").unwrap();
+        let video = Regex::new(r"Stream #.*: Video: .*?, (\d{2,5})x(\d{2,5})").unwrap();
+
+        let caps = duration.captures(output)?;
+        let duration = caps[1].parse::<f64>().ok()? * 3600.0
+            + caps[2].parse::<f64>().ok()? * 60.0
+            + caps[3].parse::<f64>().ok()?;
+        let caps = video.captures(output)?;
+
+        Some(Probe {
+            duration,
+            width: caps[1].parse().ok()?,
+            height: caps[2].parse().ok()?,
+        })
+    }
+}
+
+/// Placeholders in the codec args which are replaced when transcoding,
+/// written as `%name`
+pub const PLACEHOLDERS: [&str; 5] = ["ss", "i", "f", "vcodec", "acodec"];
+
+/// Limits of a stream. A capped stream is encoded with H.264 and AAC
+/// instead of copying the codecs.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Cap {
+    /// Height of the video in pixels, smaller videos are left alone
+    pub height: Option<i32>,
+    /// Bitrate of the video in kbit/s
+    pub bitrate: Option<i32>,
+}
+
+impl Cap {
+    pub fn is_none(&self) -> bool {
+        self.height.is_none() && self.bitrate.is_none()
+    }
+
+    /// The output options of ffmpeg for the limits
+    fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(height) = self.height {
+            // H.264 needs an even height, commas in filter expressions
+            // have to be escaped
+            args.push("-vf".to_owned());
+            args.push(format!("scale=-2:trunc(min({}\\,ih)/2)*2", height));
+        }
+        if let Some(bitrate) = self.bitrate {
+            args.push("-maxrate".to_owned());
+            args.push(format!("{}k", bitrate));
+            args.push("-bufsize".to_owned());
+            args.push(format!("{}k", bitrate * 2));
+        }
+        args
+    }
+}
 
 struct ArgBuilder<'a> {
     args: Vec<&'a str>,
@@ -15,16 +90,84 @@ impl<'a> ArgBuilder<'a> {
         if let Some(arg) = self.args.iter_mut().find(|a| **a == search) {
             *arg = val;
         } else {
-            println!("[W]: ffmpeg argument {} could not be set.", name);
+            warn!("ffmpeg argument {} could not be set", name);
         }
         self
     }
 
+    /// Adds options in front of the output, the last argument
+    fn output_options(mut self, options: &'a [String]) -> ArgBuilder<'a> {
+        let at = self.args.len().saturating_sub(1);
+        self.args.splice(at..at, options.iter().map(|o| o.as_str()));
+        self
+    }
+
     fn build(self) -> Vec<&'a str> {
         self.args
     }
 }
 
+/// Reads until `buf` is full or the end of `reader`. Returns the number
+/// of bytes read, less than the length of `buf` only at the end.
+fn fill<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// The ffmpeg processes of the running streams, so they can be killed
+/// on shutdown
+struct Sessions;
+
+static SESSIONS: OnceLock<Mutex<HashMap<u64, Arc<Mutex<Child>>>>> = OnceLock::new();
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(0);
+
+impl Sessions {
+    fn all() -> &'static Mutex<HashMap<u64, Arc<Mutex<Child>>>> {
+        SESSIONS.get_or_init(|| Mutex::new(HashMap::new()))
+    }
+
+    fn add(child: Child) -> (u64, Arc<Mutex<Child>>) {
+        let id = NEXT_SESSION.fetch_add(1, Ordering::SeqCst);
+        let child = Arc::new(Mutex::new(child));
+        Sessions::all().lock().unwrap().insert(id, child.clone());
+        (id, child)
+    }
+
+    fn remove(id: u64) {
+        Sessions::all().lock().unwrap().remove(&id);
+    }
+}
+
+/// Number of running streams
+pub fn active_sessions() -> usize {
+    Sessions::all().lock().unwrap().len()
+}
+
+/// Kills the ffmpeg processes of all running streams. Returns how many
+/// were killed.
+pub fn kill_sessions() -> usize {
+    let sessions = Sessions::all()
+        .lock()
+        .unwrap()
+        .drain()
+        .map(|(_, child)| child)
+        .collect::<Vec<_>>();
+    for child in sessions.iter() {
+        let mut child = child.lock().unwrap();
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    sessions.len()
+}
+
 pub struct FFmpeg {
     config: Arc<FFmpegConfig>,
 }
@@ -34,33 +177,203 @@ impl FFmpeg {
         FFmpeg { config }
     }
 
-    pub async fn transcode(&self, file: &str, mut sender: Sender) {
+    pub async fn transcode(&self, file: &str, start: f64, cap: Cap, mut sender: Sender) {
+        let start = start.to_string();
+        let (vcodec, acodec) = if cap.is_none() {
+            ("copy", "copy")
+        } else {
+            ("libx264", "aac")
+        };
+        let options = cap.args();
         let args = self
             .build_args()
-            .with("ss", "0")
+            .with("ss", &start)
             .with("i", file)
             .with("f", "mp4")
-            .with("vcodec", "copy")
-            .with("acodec", "copy")
+            .with("vcodec", vcodec)
+            .with("acodec", acodec)
+            .output_options(&options)
             .build();
 
-        let mut cmd = Command::new(&self.config.bin)
+        info!("transcoding {} from {}s", file, start);
+        let mut child = match Command::new(&self.config.bin)
             .args(&args)
             .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .spawn()
-            .unwrap();
+        {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("could not start ffmpeg: {}", e);
+                return;
+            }
+        };
+
+        // ffmpeg reports its progress and errors on stderr, it goes to
+        // the log of the request
+        let stderr = BufReader::new(child.stderr.take().unwrap());
+        let request_id = logging::request_id();
+        thread::spawn(move || {
+            logging::with_request_id(request_id, || {
+                for line in stderr.lines().map_while(Result::ok) {
+                    debug!("{}", line);
+                }
+            })
+        });
 
-        let mut buf: [u8; 65536] = [0; 65536];
-        let mut stdout = BufReader::new(cmd.stdout.as_mut().unwrap());
+        // unless capped the codecs are copied into a new container
+        let session = metrics().stream(if cap.is_none() { "remux" } else { "transcode" });
+        let mut stdout = child.stdout.take().unwrap();
+        let (id, child) = Sessions::add(child);
+        // on the heap, the future of the stream is moved around
+        let mut buf = vec![0; 65536];
+        let mut disconnected = false;
 
-        while let Ok(()) = stdout.read_exact(&mut buf) {
-            let b = Bytes::copy_from_slice(&buf);
-            sender.send_data(b).await.unwrap();
-            buf = [0; 65536];
+        // reading from the pipe blocks, it must not stall a worker of the
+        // runtime
+        loop {
+            let read = tokio::task::spawn_blocking(move || {
+                let n = fill(&mut stdout, &mut buf);
+                (stdout, buf, n)
+            })
+            .await;
+            let n = match read {
+                Ok((out, b, n)) => {
+                    stdout = out;
+                    buf = b;
+                    n
+                }
+                Err(_) => break,
+            };
+            let n = match n {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    warn!("could not read from ffmpeg: {}", e);
+                    break;
+                }
+            };
+            session.sent(n);
+            let b = Bytes::copy_from_slice(&buf[..n]);
+            if sender.send_data(b).await.is_err() {
+                disconnected = true;
+                break;
+            }
+            if n < buf.len() {
+                break;
+            }
         }
 
-        let status = cmd.wait();
-        println!("Exited with status {:?}", status);
+        Sessions::remove(id);
+        let status = tokio::task::spawn_blocking(move || {
+            let mut child = child.lock().unwrap();
+            if disconnected {
+                // ffmpeg would keep running without a reader
+                let _ = child.kill();
+            }
+            child.wait()
+        })
+        .await;
+        if disconnected {
+            info!("client disconnected, stopped ffmpeg");
+            return;
+        }
+        match status {
+            Ok(Ok(status)) if status.success() => info!("ffmpeg exited"),
+            Ok(Ok(status)) => warn!("ffmpeg exited with {}", status),
+            Ok(Err(e)) => warn!("could not wait for ffmpeg: {}", e),
+            Err(e) => warn!("could not wait for ffmpeg: {}", e),
+        }
+    }
+
+    /// Reads duration and resolution of a video file. This blocks until
+    /// ffmpeg exits.
+    pub fn probe(&self, file: &str) -> io::Result<Probe> {
+        // without an output file ffmpeg exits with an error after
+        // printing the information about the input
+        let output = Command::new(&self.config.bin)
+            .args(["-hide_banner", "-i", file])
+            .output()?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        Probe::parse(&stderr).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("ffmpeg could not probe {}", file),
+            )
+        })
+    }
+
+    /// Writes a single frame at `at` seconds as jpeg, scaled to `width`.
+    /// This blocks until ffmpeg exits.
+    pub fn extract_frame(&self, file: &str, at: f64, width: i32, out: &Path) -> io::Result<()> {
+        let at = at.to_string();
+        let scale = format!("scale={}:-2", width);
+        let out = out.to_string_lossy();
+        self.run(&[
+            "-ss",
+            &at,
+            "-i",
+            file,
+            "-frames:v",
+            "1",
+            "-vf",
+            &scale,
+            "-q:v",
+            "3",
+            "-y",
+            &out,
+        ])
+    }
+
+    /// Writes one thumbnail every `interval` seconds, tiled into sprite
+    /// sheets of `columns` x `rows` thumbnails named `sprite_001.jpg`,
+    /// `sprite_002.jpg`, ... into `dir`. Only key frames are decoded to
+    /// keep this fast. This blocks until ffmpeg exits.
+    pub fn sprites(
+        &self,
+        file: &str,
+        interval: u32,
+        size: (i32, i32),
+        tile: (u32, u32),
+        dir: &Path,
+    ) -> io::Result<()> {
+        let filter = format!(
+            "fps=1/{},scale={}:{},tile={}x{}",
+            interval, size.0, size.1, tile.0, tile.1
+        );
+        let out = dir.join("sprite_%03d.jpg");
+        let out = out.to_string_lossy();
+        self.run(&[
+            "-skip_frame",
+            "nokey",
+            "-i",
+            file,
+            "-an",
+            "-vf",
+            &filter,
+            "-vsync",
+            "vfr",
+            "-q:v",
+            "5",
+            "-y",
+            &out,
+        ])
+    }
+
+    fn run(&self, args: &[&str]) -> io::Result<()> {
+        let output = Command::new(&self.config.bin)
+            .args(["-hide_banner", "-loglevel", "error"])
+            .args(args)
+            .stdout(Stdio::null())
+            .output()?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(
+                String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            ))
+        }
     }
 
     fn build_args(&self) -> ArgBuilder<'_> {
@@ -76,3 +389,63 @@ impl FFmpeg {
         ArgBuilder { args }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_probe() {
+        let output = "Input #0, matroska,webm, from 'Heat (1995).mkv':
+  Metadata:
+    encoder         : libebml v1.3.0 + libmatroska v1.4.0
+  Duration: 02:50:12.34, start: 0.000000, bitrate: 10215 kb/s
+    Stream #0:0(eng): Video: h264 (High), yuv420p(tv, bt709, progressive), 1920x800 [SAR 1:1 DAR 12:5], 23.98 fps, 23.98 tbr, 1k tbn (default)
+    Stream #0:1(eng): Audio: dts (DTS), 48000 Hz, 5.1(side), fltp, 1536 kb/s (default)
+At least one output file must be specified";
+
+        let probe = Probe::parse(output).unwrap();
+        assert_eq!(2.0 * 3600.0 + 50.0 * 60.0 + 12.34, probe.duration);
+        assert_eq!(1920, probe.width);
+        assert_eq!(800, probe.height);
+
+        assert_eq!(None, Probe::parse("Heat.mkv: No such file or directory"));
+    }
+
+    #[test]
+    fn test_fill() {
+        // each slice is read on its own, the last chunk is short
+        let mut reader = (&[1u8, 2, 3][..]).chain(&[4u8, 5][..]);
+        let mut buf = [0; 4];
+        assert_eq!(4, fill(&mut reader, &mut buf).unwrap());
+        assert_eq!([1, 2, 3, 4], buf);
+        assert_eq!(1, fill(&mut reader, &mut buf).unwrap());
+        assert_eq!(5, buf[0]);
+        assert_eq!(0, fill(&mut reader, &mut buf).unwrap());
+    }
+
+    #[test]
+    fn test_cap() {
+        let ffmpeg = FFmpeg::new(Arc::new(FFmpegConfig::default()));
+        let cap = Cap {
+            height: Some(720),
+            bitrate: Some(2000),
+        };
+        let options = cap.args();
+        let args = ffmpeg.build_args().output_options(&options).build();
+        assert_eq!(
+            [
+                "-vf",
+                "scale=-2:trunc(min(720\\,ih)/2)*2",
+                "-maxrate",
+                "2000k",
+                "-bufsize",
+                "4000k",
+                "pipe:1"
+            ],
+            args[args.len() - 7..]
+        );
+        assert!(Cap::default().is_none());
+        assert!(Cap::default().args().is_empty());
+    }
+}
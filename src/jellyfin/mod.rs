@@ -0,0 +1,145 @@
+pub mod types;
+
+use percent_encoding::percent_decode_str;
+use sha2::{Digest, Sha256};
+
+/// The Jellyfin server version the apps are told, they refuse servers
+/// older than the API they were built for
+pub const VERSION: &str = "10.8.13";
+
+/// Jellyfin counts time in ticks of 100 nanoseconds
+pub const TICKS_PER_SECOND: f64 = 10_000_000.0;
+
+pub fn ticks(seconds: f64) -> i64 {
+    (seconds * TICKS_PER_SECOND) as i64
+}
+
+pub fn seconds(ticks: i64) -> f64 {
+    ticks as f64 / TICKS_PER_SECOND
+}
+
+/// Jellyfin ids are 32 hex digits, movies and users keep their id in
+/// the lower digits
+pub fn id(id: i32) -> String {
+    format!("{:032x}", id)
+}
+
+/// The id of a movie or user in an id given by an app. Apps may send
+/// ids with dashes like a UUID, or in upper case.
+pub fn parse_id(id: &str) -> Option<i32> {
+    let hex = id.replace('-', "");
+    if hex.len() != 32 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let id = u128::from_str_radix(&hex, 16).ok()?;
+    if id == 0 || id > i32::MAX as u128 {
+        return None;
+    }
+    Some(id as i32)
+}
+
+fn hash(seed: &str) -> String {
+    Sha256::digest(format!("moviebay:{}", seed).as_bytes())[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The id of a library, the leading `f` keeps it apart from movie ids
+pub fn library_id(name: &str) -> String {
+    format!("f{}", &hash(&format!("library:{}", name))[1..])
+}
+
+/// The id of the server, apps use it to tell servers apart
+pub fn server_id(name: &str) -> String {
+    hash(&format!("server:{}", name))
+}
+
+/// The client as described by the apps in the `Authorization` or
+/// `X-Emby-Authorization` header, e.g. `MediaBrowser Client="Jellyfin
+/// Web", Device="Firefox", DeviceId="abc", Version="10.8.13",
+/// Token="..."`
+#[derive(Debug, Default, PartialEq)]
+pub struct Authorization {
+    pub client: String,
+    pub device: String,
+    pub device_id: String,
+    pub version: String,
+    pub token: Option<String>,
+}
+
+impl Authorization {
+    pub fn parse(value: &str) -> Option<Authorization> {
+        let mut parts = value.trim().splitn(2, ' ');
+        let scheme = parts.next()?;
+        if !scheme.eq_ignore_ascii_case("mediabrowser") && !scheme.eq_ignore_ascii_case("emby") {
+            return None;
+        }
+
+        let mut auth = Authorization::default();
+        for param in parts.next().unwrap_or("").split(',') {
+            let (name, value) = match param.find('=') {
+                Some(i) => (param[..i].trim(), param[i + 1..].trim().trim_matches('"')),
+                None => continue,
+            };
+            let value = percent_decode_str(value).decode_utf8_lossy().into_owned();
+            match name.to_ascii_lowercase().as_ref() {
+                "client" => auth.client = value,
+                "device" => auth.device = value,
+                "deviceid" => auth.device_id = value,
+                "version" => auth.version = value,
+                "token" if !value.is_empty() => auth.token = Some(value),
+                _ => {}
+            }
+        }
+        Some(auth)
+    }
+
+    /// The name of the player in the watch history
+    pub fn player(&self) -> String {
+        match (self.client.is_empty(), self.device.is_empty()) {
+            (false, false) => format!("{} ({})", self.client, self.device),
+            (false, true) => self.client.clone(),
+            (true, _) => self.device.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id() {
+        assert_eq!("0000000000000000000000000000002a", id(42));
+        assert_eq!(Some(42), parse_id(&id(42)));
+        assert_eq!(Some(42), parse_id("00000000-0000-0000-0000-00000000002A"));
+        assert_eq!(None, parse_id("2a"));
+        assert_eq!(None, parse_id(&id(0)));
+        assert_eq!(None, parse_id("000000000000000000000000ffffffff"));
+        assert_eq!(None, parse_id(&library_id("movies")));
+        assert_eq!(library_id("movies"), library_id("movies"));
+        assert_ne!(library_id("movies"), library_id("kids"));
+        assert_eq!(32, server_id("moviebay").len());
+        assert_eq!(90 * 10_000_000, ticks(90.0));
+        assert_eq!(1.5, seconds(15_000_000));
+    }
+
+    #[test]
+    fn test_authorization() {
+        let auth = Authorization::parse(
+            "MediaBrowser Client=\"Jellyfin Web\", Device=\"Firefox\", \
+             DeviceId=\"abc\", Version=\"10.8.13\", Token=\"123\"",
+        )
+        .unwrap();
+        assert_eq!("Jellyfin Web", auth.client);
+        assert_eq!("abc", auth.device_id);
+        assert_eq!(Some("123".to_owned()), auth.token);
+        assert_eq!("Jellyfin Web (Firefox)", auth.player());
+
+        let auth = Authorization::parse("Emby Client=Infuse, Device=Apple%20TV").unwrap();
+        assert_eq!("Apple TV", auth.device);
+        assert_eq!(None, auth.token);
+        assert_eq!(None, Authorization::parse("Bearer 123"));
+    }
+}
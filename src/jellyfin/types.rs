@@ -0,0 +1,342 @@
+use super::{id, library_id, ticks, VERSION};
+use crate::model::{Movie, User, WatchState};
+use chrono::{TimeZone, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+
+fn date(timestamp: i64) -> Option<String> {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .map(|t| t.to_rfc3339())
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SystemInfo {
+    pub server_name: String,
+    pub version: &'static str,
+    pub product_name: &'static str,
+    pub operating_system: &'static str,
+    pub id: String,
+    pub startup_wizard_completed: bool,
+}
+
+impl SystemInfo {
+    pub fn new(name: &str, server_id: &str) -> SystemInfo {
+        SystemInfo {
+            server_name: name.to_owned(),
+            version: VERSION,
+            product_name: "Jellyfin Server",
+            operating_system: std::env::consts::OS,
+            id: server_id.to_owned(),
+            startup_wizard_completed: true,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct UserPolicy {
+    pub is_administrator: bool,
+    pub is_disabled: bool,
+    pub enable_media_playback: bool,
+    pub enable_video_playback_transcoding: bool,
+    pub enable_all_folders: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct UserConfiguration {
+    pub play_default_audio_track: bool,
+    pub hide_played_in_latest: bool,
+    pub ordered_views: Vec<String>,
+    pub latest_items_excludes: Vec<String>,
+    pub my_media_excludes: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct UserDto {
+    pub name: String,
+    pub server_id: String,
+    pub id: String,
+    pub has_password: bool,
+    pub has_configured_password: bool,
+    pub enable_auto_login: bool,
+    pub policy: UserPolicy,
+    pub configuration: UserConfiguration,
+}
+
+impl UserDto {
+    pub fn new(user: &User, server_id: &str) -> UserDto {
+        UserDto {
+            name: user.name.clone(),
+            server_id: server_id.to_owned(),
+            id: id(user.id),
+            has_password: true,
+            has_configured_password: true,
+            enable_auto_login: false,
+            policy: UserPolicy {
+                is_administrator: user.is_admin,
+                is_disabled: false,
+                enable_media_playback: true,
+                enable_video_playback_transcoding: true,
+                enable_all_folders: true,
+            },
+            configuration: UserConfiguration {
+                play_default_audio_track: true,
+                hide_played_in_latest: true,
+                ..UserConfiguration::default()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SessionInfo {
+    pub id: String,
+    pub user_id: String,
+    pub user_name: String,
+    pub client: String,
+    pub device_id: String,
+    pub device_name: String,
+    pub application_version: String,
+    pub server_id: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AuthenticationResult {
+    pub user: UserDto,
+    pub session_info: SessionInfo,
+    pub access_token: String,
+    pub server_id: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct UserData {
+    pub playback_position_ticks: i64,
+    pub play_count: i32,
+    pub is_favorite: bool,
+    pub played: bool,
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub played_percentage: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_played_date: Option<String>,
+}
+
+impl UserData {
+    /// The watch state of a movie, `duration` in seconds if known
+    pub fn new(movie_id: i32, state: &WatchState, duration: Option<f64>) -> UserData {
+        let percentage = match duration {
+            Some(duration) if duration > 0.0 && state.resume_position > 0.0 => {
+                Some((state.resume_position / duration * 100.0).min(100.0))
+            }
+            _ => None,
+        };
+        UserData {
+            playback_position_ticks: ticks(state.resume_position),
+            play_count: state.watched as i32,
+            is_favorite: state.in_watchlist,
+            played: state.watched,
+            key: id(movie_id),
+            played_percentage: percentage,
+            last_played_date: state.last_watched_at.and_then(date),
+        }
+    }
+}
+
+/// A movie or a library, Jellyfin calls both items
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Item {
+    pub name: String,
+    pub server_id: String,
+    pub id: String,
+    #[serde(rename = "Type")]
+    pub kind: &'static str,
+    pub is_folder: bool,
+    pub location_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collection_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overview: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub production_year: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub community_rating: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_time_ticks: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_created: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub child_count: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub genres: Vec<String>,
+    /// Image type to a tag the apps add to the image urls for caching
+    pub image_tags: HashMap<&'static str, String>,
+    pub backdrop_image_tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_data: Option<UserData>,
+}
+
+impl Item {
+    /// A movie, `poster` is the tag of its primary image if it has one
+    pub fn movie(
+        movie: Movie,
+        server_id: &str,
+        state: &WatchState,
+        duration: Option<f64>,
+        poster: Option<String>,
+    ) -> Item {
+        let mut image_tags = HashMap::new();
+        if let Some(tag) = poster {
+            image_tags.insert("Primary", tag);
+        }
+        Item {
+            server_id: server_id.to_owned(),
+            id: id(movie.id),
+            kind: "Movie",
+            is_folder: false,
+            location_type: "FileSystem",
+            media_type: Some("Video"),
+            collection_type: None,
+            overview: Some(movie.overview).filter(|o| !o.is_empty()),
+            production_year: Some(movie.release_year).filter(|y| *y > 0),
+            community_rating: Some(movie.rating).filter(|r| *r > 0.0),
+            run_time_ticks: duration.map(ticks),
+            date_created: date(movie.added_at),
+            parent_id: Some(library_id(&movie.library)),
+            child_count: None,
+            genres: movie.genres,
+            image_tags,
+            backdrop_image_tags: Vec::new(),
+            user_data: Some(UserData::new(movie.id, state, duration)),
+            name: movie.title,
+        }
+    }
+
+    /// A library, shown by the apps as a view of movies
+    pub fn library(name: &str, server_id: &str, count: i64) -> Item {
+        Item {
+            name: name.to_owned(),
+            server_id: server_id.to_owned(),
+            id: library_id(name),
+            kind: "CollectionFolder",
+            is_folder: true,
+            location_type: "FileSystem",
+            media_type: None,
+            collection_type: Some("movies"),
+            overview: None,
+            production_year: None,
+            community_rating: None,
+            run_time_ticks: None,
+            date_created: None,
+            parent_id: None,
+            child_count: Some(count),
+            genres: Vec::new(),
+            image_tags: HashMap::new(),
+            backdrop_image_tags: Vec::new(),
+            user_data: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Items {
+    pub items: Vec<Item>,
+    pub total_record_count: i64,
+    pub start_index: u32,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MediaStream {
+    #[serde(rename = "Type")]
+    pub kind: &'static str,
+    pub index: i32,
+    pub is_default: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+}
+
+/// How a movie can be played. The apps are told to transcode, which
+/// makes them use `transcoding_url`: the stream of moviebay is whatever
+/// ffmpeg produces in an mp4 container.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MediaSource {
+    pub protocol: &'static str,
+    pub id: String,
+    #[serde(rename = "Type")]
+    pub kind: &'static str,
+    pub container: &'static str,
+    pub name: String,
+    pub is_remote: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_time_ticks: Option<i64>,
+    pub supports_transcoding: bool,
+    pub supports_direct_stream: bool,
+    pub supports_direct_play: bool,
+    pub is_infinite_stream: bool,
+    pub requires_opening: bool,
+    pub requires_closing: bool,
+    pub supports_probing: bool,
+    pub transcoding_url: String,
+    pub transcoding_sub_protocol: &'static str,
+    pub transcoding_container: &'static str,
+    pub media_streams: Vec<MediaStream>,
+}
+
+impl MediaSource {
+    pub fn new(movie: &Movie, duration: Option<f64>, url: String) -> MediaSource {
+        MediaSource {
+            protocol: "Http",
+            id: id(movie.id),
+            kind: "Default",
+            container: "mp4",
+            name: movie.title.clone(),
+            is_remote: false,
+            run_time_ticks: duration.map(ticks),
+            supports_transcoding: true,
+            supports_direct_stream: false,
+            supports_direct_play: false,
+            is_infinite_stream: false,
+            requires_opening: false,
+            requires_closing: false,
+            supports_probing: false,
+            transcoding_url: url,
+            transcoding_sub_protocol: "http",
+            transcoding_container: "mp4",
+            media_streams: vec![
+                MediaStream {
+                    kind: "Video",
+                    index: 0,
+                    is_default: true,
+                    height: Some(movie.resolution).filter(|r| *r > 0),
+                },
+                MediaStream {
+                    kind: "Audio",
+                    index: 1,
+                    is_default: true,
+                    height: None,
+                },
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PlaybackInfo {
+    pub media_sources: Vec<MediaSource>,
+    pub play_session_id: String,
+}
@@ -0,0 +1,390 @@
+mod schedule;
+mod tasks;
+
+pub use schedule::Schedule;
+pub use tasks::refresh_movie;
+
+use crate::config::{CfgHandle, Config, SharedCfg};
+use crate::events::{Event, Events};
+use crate::logging;
+use crate::model::{Job, JobState, JobTable, Table};
+use crate::sqlite::SharedDb;
+use chrono::Local;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// How long an idle worker waits before looking for due jobs again.
+/// New jobs wake up a worker right away, this only matters for retries.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The kinds of work jobs can do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JobKind {
+    /// Scan the library for new movies
+    Scan,
+    /// Look up movies without metadata on TMDB
+    Metadata,
+    /// Generate posters and seek preview thumbnails
+    Thumbnails,
+    /// Delete old jobs and images of removed movies
+    Cleanup,
+    /// Write the database to disk
+    Save,
+    /// Write a timestamped backup of the database and delete old ones
+    Backup,
+}
+
+impl JobKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobKind::Scan => "scan",
+            JobKind::Metadata => "metadata",
+            JobKind::Thumbnails => "thumbnails",
+            JobKind::Cleanup => "cleanup",
+            JobKind::Save => "save",
+            JobKind::Backup => "backup",
+        }
+    }
+
+    /// Priority of jobs queued without an explicit one
+    pub fn priority(self) -> i32 {
+        match self {
+            JobKind::Save => 20,
+            JobKind::Scan => 10,
+            JobKind::Metadata => 5,
+            JobKind::Thumbnails | JobKind::Cleanup | JobKind::Backup => 0,
+        }
+    }
+}
+
+impl FromStr for JobKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<JobKind, String> {
+        match s {
+            "scan" => Ok(JobKind::Scan),
+            "metadata" => Ok(JobKind::Metadata),
+            "thumbnails" => Ok(JobKind::Thumbnails),
+            "cleanup" => Ok(JobKind::Cleanup),
+            "save" => Ok(JobKind::Save),
+            "backup" => Ok(JobKind::Backup),
+            _ => Err(format!("unknown job kind `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for JobKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Seconds to wait before the next attempt of a job which failed
+/// `attempts` times
+fn backoff(delay: i64, attempts: i32) -> i64 {
+    let exp = (attempts - 1).clamp(0, 16) as u32;
+    delay.max(1).saturating_mul(1 << exp)
+}
+
+/// Handle to queue and cancel background jobs. Jobs are stored in the
+/// `jobs` table and run by a pool of workers, failing jobs are retried
+/// with an exponential backoff. The scheduler queues jobs by the cron
+/// expressions of `[jobs.schedule]`. Changes of jobs are published as
+/// `Event::Job`.
+#[derive(Clone)]
+pub struct Jobs {
+    config: CfgHandle,
+    db: SharedDb,
+    events: Events,
+    wakeup: Arc<Notify>,
+    /// Cancel flags of the running jobs by id
+    running: Arc<Mutex<HashMap<i32, Arc<AtomicBool>>>>,
+    /// Set on shutdown, workers don't claim jobs anymore
+    stopped: Arc<AtomicBool>,
+}
+
+impl Jobs {
+    pub fn new(config: CfgHandle, db: SharedDb, events: Events) -> Jobs {
+        Jobs {
+            config,
+            db,
+            events,
+            wakeup: Arc::new(Notify::new()),
+            running: Arc::new(Mutex::new(HashMap::new())),
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Spawn the workers and the scheduler on tokio executor
+    pub fn start(&self) {
+        for _ in 0..self.config.get().jobs.workers.max(1) {
+            tokio::spawn(self.clone().work());
+        }
+        tokio::spawn(self.clone().schedule());
+    }
+
+    /// Queues a job of `kind`, with its default priority if `priority`
+    /// is `None`. If a job of the kind is already queued or running
+    /// that one is returned.
+    pub async fn enqueue(&self, kind: JobKind, priority: Option<i32>) -> Result<Job, Error> {
+        let job = Job::new(
+            kind.as_str(),
+            priority.unwrap_or_else(|| kind.priority()),
+            self.config.get().jobs.max_attempts.max(1),
+        );
+        let job = JobTable::new(self.db.clone())
+            .enqueue(job)
+            .await
+            .map_err(|e| e.to_string())?;
+        self.wakeup.notify();
+        self.events.publish(Event::Job { job: job.clone() });
+        Ok(job)
+    }
+
+    /// Runs a job of `kind` right away in the current task instead of
+    /// on the workers, used by the command line. Returns the finished
+    /// job, or the queued one if it failed and will be retried.
+    pub async fn run(&self, kind: JobKind) -> Result<Job, Error> {
+        let table = JobTable::new(self.db.clone());
+        let job = self.enqueue(kind, None).await?;
+        let job = match table.start(job.id).await.map_err(|e| e.to_string())? {
+            Some(job) => job,
+            None => return Err(format!("a {} job is already running", kind).into()),
+        };
+
+        self.execute(job.clone()).await;
+        let job = table.by_id(job.id).await.map_err(|e| e.to_string())?;
+        Ok(job.ok_or("job vanished")?)
+    }
+
+    /// Cancels a queued job right away, a running one at its next
+    /// progress report. Returns the job, `None` if it doesn't exist.
+    pub async fn cancel(&self, id: i32) -> Result<Option<Job>, Error> {
+        let table = JobTable::new(self.db.clone());
+        let flag = self.running.lock().unwrap().get(&id).cloned();
+        match flag {
+            Some(flag) => flag.store(true, Ordering::SeqCst),
+            None => {
+                if table.cancel_queued(id).await.map_err(|e| e.to_string())? {
+                    self.publish(id).await;
+                }
+            }
+        }
+        Ok(table.by_id(id).await.map_err(|e| e.to_string())?)
+    }
+
+    /// Stops the workers and the scheduler. Running jobs aren't
+    /// interrupted, if they don't finish before the process exits they
+    /// are queued again on the next start.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Publishes the current state of a job
+    async fn publish(&self, id: i32) {
+        match JobTable::new(self.db.clone()).by_id(id).await {
+            Ok(Some(job)) => self.events.publish(Event::Job { job }),
+            Ok(None) => {}
+            Err(e) => warn!("could not load job {}: {}", id, e),
+        }
+    }
+
+    async fn work(self) {
+        let table = JobTable::new(self.db.clone());
+        while !self.stopped.load(Ordering::SeqCst) {
+            match table.claim().await.map_err(|e| e.to_string()) {
+                Ok(Some(job)) => self.execute(job).await,
+                Ok(None) => {
+                    let _ = tokio::time::timeout(POLL_INTERVAL, self.wakeup.notified()).await;
+                }
+                Err(e) => {
+                    warn!("could not claim job: {}", e);
+                    tokio::time::delay_for(POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    async fn execute(&self, job: Job) {
+        let table = JobTable::new(self.db.clone());
+        let kind = match job.kind.parse::<JobKind>() {
+            Ok(kind) => kind,
+            Err(e) => {
+                let _ = table.finish(job.id, JobState::Failed, &e).await;
+                return;
+            }
+        };
+
+        self.events.publish(Event::Job { job: job.clone() });
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.running
+            .lock()
+            .unwrap()
+            .insert(job.id, cancelled.clone());
+        // the job keeps this config even if it is reloaded meanwhile
+        let config = self.config.get();
+        let ctx = JobContext {
+            id: job.id,
+            config: config.clone(),
+            jobs: self.clone(),
+            cancelled: cancelled.clone(),
+        };
+        // a panicking task must not take the worker down. The job id
+        // stands in for the request id in the log.
+        let id = format!("job-{}", job.id);
+        let task = logging::scope(id, tasks::run(kind, ctx));
+        let result = match tokio::spawn(task).await {
+            Ok(result) => result,
+            Err(e) => Err(e.into()),
+        };
+        self.running.lock().unwrap().remove(&job.id);
+
+        let finished = match result {
+            Ok(message) => {
+                info!("job {} ({}) done: {}", job.id, kind, message);
+                table.finish(job.id, JobState::Done, &message).await
+            }
+            Err(_) if cancelled.load(Ordering::SeqCst) => {
+                table.finish(job.id, JobState::Cancelled, "cancelled").await
+            }
+            Err(e) if job.attempts < job.max_attempts => {
+                warn!("job {} ({}) failed, retrying: {}", job.id, kind, e);
+                let delay = backoff(config.jobs.retry_delay, job.attempts);
+                table.retry(job.id, delay, &e.to_string()).await
+            }
+            Err(e) => {
+                warn!("job {} ({}) failed: {}", job.id, kind, e);
+                table.finish(job.id, JobState::Failed, &e.to_string()).await
+            }
+        }
+        .map_err(|e| e.to_string());
+        if let Err(e) = finished {
+            warn!("could not update job {}: {}", job.id, e);
+            return;
+        }
+        self.publish(job.id).await;
+    }
+
+    /// Queues the jobs of `[jobs.schedule]` whenever their cron
+    /// expression matches the current minute
+    async fn schedule(self) {
+        let mut config = self.config.get();
+        let mut schedules = parse_schedules(&config);
+
+        let mut last_minute = Local::now().timestamp() / 60;
+        loop {
+            let now = Local::now();
+            let wait = 60 - now.timestamp() % 60;
+            tokio::time::delay_for(Duration::from_secs(wait as u64)).await;
+            if self.stopped.load(Ordering::SeqCst) {
+                return;
+            }
+
+            let now = Local::now();
+            // waking up early must not run the same minute twice
+            if now.timestamp() / 60 == last_minute {
+                continue;
+            }
+            last_minute = now.timestamp() / 60;
+
+            let current = self.config.get();
+            if !Arc::ptr_eq(&config, &current) {
+                config = current;
+                schedules = parse_schedules(&config);
+            }
+            for (kind, schedule) in schedules.iter() {
+                if schedule.matches(&now) {
+                    if let Err(e) = self.enqueue(*kind, None).await {
+                        warn!("could not queue {}: {}", kind, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn parse_schedules(config: &Config) -> Vec<(JobKind, Schedule)> {
+    let mut schedules = Vec::new();
+    for (kind, expr) in config.jobs.schedule.iter() {
+        match (kind.parse::<JobKind>(), expr.parse::<Schedule>()) {
+            (Ok(kind), Ok(schedule)) => schedules.push((kind, schedule)),
+            (Err(e), _) | (_, Err(e)) => warn!("schedule {}: {}", kind, e),
+        }
+    }
+    schedules
+}
+
+/// Passed to a running job to access the application and to report
+/// progress
+pub struct JobContext {
+    pub id: i32,
+    config: SharedCfg,
+    jobs: Jobs,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl JobContext {
+    pub fn config(&self) -> SharedCfg {
+        self.config.clone()
+    }
+
+    pub fn db(&self) -> SharedDb {
+        self.jobs.db.clone()
+    }
+
+    pub fn jobs(&self) -> &Jobs {
+        &self.jobs
+    }
+
+    pub fn events(&self) -> &Events {
+        &self.jobs.events
+    }
+
+    /// Stores the progress of the job, `done` of `total` steps. Returns
+    /// an error if the job was cancelled, so tasks stop with `?`.
+    pub async fn progress(&self, done: usize, total: usize, message: &str) -> Result<(), Error> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return Err("cancelled".into());
+        }
+        let progress = if total == 0 {
+            0.0
+        } else {
+            done as f64 / total as f64
+        };
+        JobTable::new(self.db())
+            .set_progress(self.id, progress, message)
+            .await
+            .map_err(|e| e.to_string())?;
+        self.jobs.publish(self.id).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(30, backoff(30, 1));
+        assert_eq!(60, backoff(30, 2));
+        assert_eq!(120, backoff(30, 3));
+        assert_eq!(1, backoff(0, 0));
+    }
+
+    #[test]
+    fn test_kind() {
+        for kind in &["scan", "metadata", "thumbnails", "cleanup", "save"] {
+            assert_eq!(*kind, kind.parse::<JobKind>().unwrap().as_str());
+        }
+        assert!("reboot".parse::<JobKind>().is_err());
+    }
+}
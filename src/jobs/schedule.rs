@@ -0,0 +1,159 @@
+use chrono::{Datelike, Timelike};
+use std::str::FromStr;
+
+/// A cron expression with the five fields `minute hour day month
+/// weekday`. Every field is `*` or a comma separated list of values
+/// and ranges (`1-5`), each optionally with a step (`*/15`, `0-30/10`).
+/// Weekdays run from 0 (sunday) to 6, 7 is sunday as well.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Schedule {
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+/// The allowed values of one field as bit set
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Field {
+    bits: u64,
+    /// The field was `*`, which matters for days and weekdays
+    any: bool,
+}
+
+impl Field {
+    fn parse(field: &str, min: u32, max: u32) -> Result<Field, String> {
+        let mut bits = 0;
+
+        for item in field.split(',') {
+            let (range, step) = match item.find('/') {
+                Some(i) => (&item[..i], parse_num(&item[i + 1..])?),
+                None => (item, 1),
+            };
+            let (from, to) = if range == "*" {
+                (min, max)
+            } else if let Some(i) = range.find('-') {
+                (parse_num(&range[..i])?, parse_num(&range[i + 1..])?)
+            } else {
+                let value = parse_num(range)?;
+                // `5/10` runs from 5 to the end like in most crons
+                (value, if step > 1 { max } else { value })
+            };
+
+            if step == 0 || from < min || to > max || from > to {
+                return Err(format!("invalid cron field `{}`", field));
+            }
+            for value in (from..=to).step_by(step as usize) {
+                bits |= 1 << value;
+            }
+        }
+
+        Ok(Field {
+            bits,
+            any: field == "*",
+        })
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        self.bits & (1 << value) != 0
+    }
+}
+
+fn parse_num(value: &str) -> Result<u32, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid cron value `{}`", value))
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(expr: &str) -> Result<Schedule, String> {
+        let fields = expr.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(format!("cron expression `{}` needs 5 fields", expr));
+        }
+
+        let mut weekdays = Field::parse(fields[4], 0, 7)?;
+        // 7 is sunday as well
+        if weekdays.matches(7) {
+            weekdays.bits |= 1;
+        }
+
+        Ok(Schedule {
+            minutes: Field::parse(fields[0], 0, 59)?,
+            hours: Field::parse(fields[1], 0, 23)?,
+            days: Field::parse(fields[2], 1, 31)?,
+            months: Field::parse(fields[3], 1, 12)?,
+            weekdays,
+        })
+    }
+}
+
+impl Schedule {
+    /// Checks if the schedule fires in the minute of `time`
+    pub fn matches<T: Datelike + Timelike>(&self, time: &T) -> bool {
+        let day = self.days.matches(time.day());
+        let weekday = self.weekdays.matches(time.weekday().num_days_from_sunday());
+        // like cron, if both day and weekday are restricted either one
+        // has to match
+        let date = match (self.days.any, self.weekdays.any) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+
+        date && self.minutes.matches(time.minute())
+            && self.hours.matches(time.hour())
+            && self.months.matches(time.month())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> chrono::NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(h, min, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!("* * * * *".parse::<Schedule>().is_ok());
+        assert!("0,30 8-18/2 1 */3 1-5".parse::<Schedule>().is_ok());
+
+        assert!("* * * *".parse::<Schedule>().is_err());
+        assert!("60 * * * *".parse::<Schedule>().is_err());
+        assert!("* * 0 * *".parse::<Schedule>().is_err());
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+        assert!("5-1 * * * *".parse::<Schedule>().is_err());
+        assert!("a * * * *".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let nightly: Schedule = "30 3 * * *".parse().unwrap();
+        assert!(nightly.matches(&at(2020, 8, 1, 3, 30)));
+        assert!(!nightly.matches(&at(2020, 8, 1, 3, 31)));
+        assert!(!nightly.matches(&at(2020, 8, 1, 15, 30)));
+
+        let quarter: Schedule = "*/15 * * * *".parse().unwrap();
+        assert!(quarter.matches(&at(2020, 8, 1, 12, 45)));
+        assert!(!quarter.matches(&at(2020, 8, 1, 12, 50)));
+
+        // 2020-08-02 is a sunday
+        let sunday: Schedule = "0 0 * * 7".parse().unwrap();
+        assert!(sunday.matches(&at(2020, 8, 2, 0, 0)));
+        assert!(!sunday.matches(&at(2020, 8, 3, 0, 0)));
+
+        // the 1st or any monday
+        let either: Schedule = "0 0 1 * 1".parse().unwrap();
+        assert!(either.matches(&at(2020, 8, 1, 0, 0)));
+        assert!(either.matches(&at(2020, 8, 3, 0, 0)));
+        assert!(!either.matches(&at(2020, 8, 4, 0, 0)));
+    }
+}
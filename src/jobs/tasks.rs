@@ -0,0 +1,444 @@
+use super::{Error, JobContext, JobKind};
+use crate::backup;
+use crate::config::SharedCfg;
+use crate::events::Event;
+use crate::metrics::metrics;
+use crate::model::{Certification, CertificationTable, JobTable, Movie, MovieTable, Table};
+use crate::scan::Scanner;
+use crate::sqlite::SharedDb;
+use crate::thumbnail::Thumbnailer;
+use crate::tmdb::{self, MovieResult};
+use chrono::Utc;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::time::Instant;
+
+/// Movies a scan adds to the database per transaction
+const SCAN_BATCH: usize = 500;
+
+/// Runs the work of a job. Returns the message stored with the
+/// finished job.
+pub async fn run(kind: JobKind, ctx: JobContext) -> Result<String, Error> {
+    match kind {
+        JobKind::Scan => scan(&ctx).await,
+        JobKind::Metadata => metadata(&ctx).await,
+        JobKind::Thumbnails => thumbnails(&ctx).await,
+        JobKind::Cleanup => cleanup(&ctx).await,
+        JobKind::Save => save(&ctx).await,
+        JobKind::Backup => backup(&ctx).await,
+    }
+}
+
+/// Adds the movies found in the libraries which are not in the
+/// database yet, moves the known ones found in another library, then
+/// queues the jobs to complete them
+async fn scan(ctx: &JobContext) -> Result<String, Error> {
+    let start = Instant::now();
+    let config = ctx.config();
+    let files = tokio::task::spawn_blocking(move || {
+        let mut scanner = Scanner::new(config);
+        scanner.run().map(|files| files.to_vec())
+    })
+    .await??;
+
+    let table = MovieTable::new(ctx.db());
+    let known = table
+        .all()
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|movie| (movie.file_path, (movie.id, movie.library)))
+        .collect::<HashMap<_, _>>();
+    let found = files.len();
+    let mut moved = Vec::new();
+    let mut new = Vec::new();
+    for file in files {
+        match known.get(file.path.to_string_lossy().as_ref()) {
+            Some((id, library)) if *library != file.library => moved.push((*id, file.library)),
+            Some(_) => {}
+            None => new.push(file),
+        }
+    }
+    if !moved.is_empty() {
+        table
+            .set_libraries(moved.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    // one transaction per batch instead of one round trip per movie
+    for (i, batch) in new.chunks(SCAN_BATCH).enumerate() {
+        ctx.progress(i * SCAN_BATCH, new.len(), &batch[0].title)
+            .await?;
+        let movies = batch.iter().cloned().map(Movie::from).collect();
+        table.save_all(movies).await.map_err(|e| e.to_string())?;
+    }
+
+    metrics().scan(start.elapsed(), found, new.len());
+    if !new.is_empty() || !moved.is_empty() {
+        ctx.events()
+            .publish(Event::LibraryChanged { added: new.len() });
+    }
+
+    ctx.jobs().enqueue(JobKind::Metadata, None).await?;
+    if ctx.config().thumbnails.enabled {
+        ctx.jobs().enqueue(JobKind::Thumbnails, None).await?;
+    }
+    ctx.jobs().enqueue(JobKind::Save, None).await?;
+    if moved.is_empty() {
+        Ok(format!("{} new movies", new.len()))
+    } else {
+        Ok(format!(
+            "{} new movies, {} moved to another library",
+            new.len(),
+            moved.len()
+        ))
+    }
+}
+
+/// Looks up the movies without metadata or genres on TMDB, and the
+/// certifications and people of the movies which have none
+async fn metadata(ctx: &JobContext) -> Result<String, Error> {
+    let config = ctx.config();
+    if config.tmdb.api_key.is_none() {
+        return Ok("skipped, tmdb.api_key is not set".to_owned());
+    }
+    let table = MovieTable::new(ctx.db());
+    let movies = table.without_metadata().await.map_err(|e| e.to_string())?;
+    let total = movies.len();
+    let mut matched = 0;
+
+    for (i, movie) in movies.into_iter().enumerate() {
+        ctx.progress(i, total, &movie.title).await?;
+        let movie_id = movie.id;
+        if lookup(&config, &ctx.db(), movie).await? {
+            ctx.events().publish(Event::MovieUpdated { movie_id });
+            matched += 1;
+        }
+    }
+
+    // movies matched before certifications were stored, or which TMDB
+    // had none for yet
+    let missing = CertificationTable::new(ctx.db())
+        .missing()
+        .await
+        .map_err(|e| e.to_string())?;
+    for (i, movie) in missing.iter().enumerate() {
+        ctx.progress(i, missing.len(), &movie.title).await?;
+        certify(&config, &ctx.db(), movie).await?;
+    }
+
+    // the same for the cast and crew, which weren't stored before
+    let unknown = table.without_people().await.map_err(|e| e.to_string())?;
+    for (i, movie) in unknown.iter().enumerate() {
+        ctx.progress(i, unknown.len(), &movie.title).await?;
+        people(&config, &ctx.db(), movie).await?;
+    }
+
+    if matched > 0 || !missing.is_empty() || !unknown.is_empty() {
+        ctx.jobs().enqueue(JobKind::Save, None).await?;
+    }
+    Ok(format!("{} of {} movies matched", matched, total))
+}
+
+/// Looks up one movie on TMDB again, even if it has metadata already.
+/// Returns `false` if the movie doesn't exist or TMDB doesn't know it.
+pub async fn refresh_movie(config: SharedCfg, db: SharedDb, id: i32) -> Result<bool, Error> {
+    let table = MovieTable::new(db.clone());
+    match table.by_id(id).await.map_err(|e| e.to_string())? {
+        Some(movie) => lookup(&config, &db, movie).await,
+        None => Ok(false),
+    }
+}
+
+/// Stores the metadata, certifications and people of the first TMDB
+/// search result by title and year. Returns `false` if there is no result.
+async fn lookup(config: &SharedCfg, db: &SharedDb, movie: Movie) -> Result<bool, Error> {
+    let search = tmdb::search_movie(config.tmdb.clone(), &movie.title, movie.release_year).await?;
+    let result = match search.results.into_iter().next() {
+        Some(result) => result,
+        None => return Ok(false),
+    };
+
+    let movie = with_metadata(movie, result);
+    MovieTable::new(db.clone())
+        .set_metadata(movie.clone())
+        .await
+        .map_err(|e| e.to_string())?;
+    certify(config, db, &movie).await?;
+    people(config, db, &movie).await?;
+    Ok(true)
+}
+
+/// A movie with the metadata of a TMDB search result. Its genres are
+/// replaced by the ones TMDB knows.
+fn with_metadata(mut movie: Movie, result: MovieResult) -> Movie {
+    movie.tmdb_id = result.id;
+    movie.overview = result.overview;
+    movie.poster_path = result.poster_path.unwrap_or_default();
+    movie.backdrop_path = result.backdrop_path.unwrap_or_default();
+    movie.rating = f64::from(result.vote_average);
+    movie.genres = result
+        .genre_ids
+        .into_iter()
+        .filter_map(tmdb::genre)
+        .map(|genre| genre.to_owned())
+        .collect();
+    movie
+}
+
+/// Stores the certifications of a movie with metadata
+async fn certify(config: &SharedCfg, db: &SharedDb, movie: &Movie) -> Result<(), Error> {
+    let certifications = tmdb::certifications(config.tmdb.clone(), movie.tmdb_id)
+        .await?
+        .into_iter()
+        .map(|(country, certification)| Certification::new(movie.id, &country, &certification))
+        .collect();
+    CertificationTable::new(db.clone())
+        .set(movie.id, certifications)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Stores the names of the cast and crew of a movie with metadata, so
+/// it can be found by them
+async fn people(config: &SharedCfg, db: &SharedDb, movie: &Movie) -> Result<(), Error> {
+    let people = tmdb::credits(config.tmdb.clone(), movie.tmdb_id).await?;
+    MovieTable::new(db.clone())
+        .set_people(movie.id, people)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Generates the images of all movies which don't have them yet. A
+/// broken file doesn't fail the job, it is tried again next time.
+async fn thumbnails(ctx: &JobContext) -> Result<String, Error> {
+    let thumbnailer = Thumbnailer::new(ctx.config(), ctx.db());
+    let movies = thumbnailer.pending().await.map_err(|e| e.to_string())?;
+    let mut failed = 0;
+
+    for (i, movie) in movies.iter().enumerate() {
+        ctx.progress(i, movies.len(), &movie.title).await?;
+        match thumbnailer.generate(movie).await {
+            Ok(_) => ctx
+                .events()
+                .publish(Event::MovieUpdated { movie_id: movie.id }),
+            Err(e) => {
+                warn!("thumbnails for {} failed: {}", movie.file_path, e);
+                failed += 1;
+            }
+        }
+    }
+    Ok(format!(
+        "{} movies done, {} failed",
+        movies.len() - failed,
+        failed
+    ))
+}
+
+/// Deletes finished jobs older than `keep_days` and the generated
+/// images of movies which are gone
+async fn cleanup(ctx: &JobContext) -> Result<String, Error> {
+    let config = ctx.config();
+    let before = Utc::now().timestamp() - config.jobs.keep_days * 24 * 60 * 60;
+    let jobs = JobTable::new(ctx.db())
+        .delete_finished(before)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let ids = MovieTable::new(ctx.db())
+        .all()
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|movie| movie.id)
+        .collect::<HashSet<_>>();
+    let images = tokio::task::spawn_blocking(move || {
+        let dir = match fs::read_dir(&config.thumbnails.dir) {
+            Ok(dir) => dir,
+            Err(_) => return Ok::<_, Error>(0),
+        };
+        let mut removed = 0;
+        for entry in dir {
+            let entry = entry?;
+            let id = entry.file_name().to_str().and_then(|n| n.parse().ok());
+            match id {
+                Some(id) if !ids.contains(&id) => {
+                    fs::remove_dir_all(entry.path())?;
+                    removed += 1;
+                }
+                _ => {}
+            }
+        }
+        Ok(removed)
+    })
+    .await??;
+
+    Ok(format!(
+        "deleted {} jobs and the images of {} movies",
+        jobs, images
+    ))
+}
+
+/// Writes the in-memory database to disk
+async fn save(ctx: &JobContext) -> Result<String, Error> {
+    ctx.db().save().await.map_err(|e| e.to_string())?;
+    Ok(format!("saved to {}", ctx.config().database.name))
+}
+
+/// Writes a timestamped backup of the database and deletes the oldest
+/// ones beyond `[backup] keep`
+async fn backup(ctx: &JobContext) -> Result<String, Error> {
+    let (path, removed) = backup::create(&ctx.config().backup, &ctx.db()).await?;
+    Ok(format!(
+        "saved to {}, deleted {} old backups",
+        path.display(),
+        removed
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, DatabaseConfig};
+    use crate::model::{MovieQuery, Restriction, RestrictionTable, User, UserTable};
+    use crate::scan::VideoFile;
+    use crate::sqlite::Runtime;
+    use std::sync::Arc;
+
+    /// A search result as TMDB sends it
+    fn result(id: i32, genre_ids: Vec<i32>) -> MovieResult {
+        MovieResult {
+            poster_path: Some("/poster.jpg".to_owned()),
+            adult: false,
+            overview: "In space no one can hear you scream".to_owned(),
+            release_date: "1979-05-25".to_owned(),
+            genre_ids,
+            id,
+            original_title: "Alien".to_owned(),
+            original_language: "en".to_owned(),
+            title: "Alien".to_owned(),
+            backdrop_path: None,
+            popularity: 50.0,
+            vote_count: 14000,
+            video: false,
+            vote_average: 8.1,
+        }
+    }
+
+    #[test]
+    fn test_with_metadata() {
+        let mut movie = Movie::from(VideoFile {
+            title: "Alien".to_owned(),
+            release_year: 1979,
+            path: "/movies/Alien (1979).mkv".into(),
+            poster_path: String::new(),
+            backdrop_path: String::new(),
+            library: "movies".to_owned(),
+        });
+        movie.genres = vec!["Drama".to_owned()];
+        // unknown genre ids are left out
+        let movie = with_metadata(movie, result(348, vec![27, 878, 1]));
+        assert_eq!(348, movie.tmdb_id);
+        assert_eq!("/poster.jpg", movie.poster_path);
+        assert_eq!("", movie.backdrop_path);
+        assert_eq!(vec!["Horror", "Science Fiction"], movie.genres);
+    }
+
+    #[test]
+    fn test_restricted_genres() {
+        let func = async {
+            // scan two libraries
+            let dir = std::env::temp_dir().join("moviebay-test-genres");
+            let _ = fs::remove_dir_all(&dir);
+            let mut config = Config::default();
+            config.library.dirs.clear();
+            for (library, name) in &[("movies", "Alien (1979).mkv"), ("kids", "Cars (2006).mkv")] {
+                let library_dir = dir.join(library);
+                fs::create_dir_all(&library_dir).unwrap();
+                fs::write(library_dir.join(name), b"").unwrap();
+                let library_dir = library_dir.to_string_lossy().into_owned();
+                config
+                    .library
+                    .dirs
+                    .insert((*library).to_owned(), library_dir);
+            }
+            let files = Scanner::new(Arc::new(config)).run().unwrap().to_vec();
+            fs::remove_dir_all(&dir).unwrap();
+            let libraries = files
+                .iter()
+                .map(|f| (f.title.as_str(), f.library.as_str()))
+                .collect::<Vec<_>>();
+            assert_eq!(vec![("Cars", "kids"), ("Alien", "movies")], libraries);
+
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (db, rt) = Runtime::channel(config);
+            rt.run();
+            crate::model::migrate(db.clone()).await.unwrap();
+            let table = MovieTable::new(db.clone());
+            let movies = files.into_iter().map(Movie::from).collect();
+            table.save_all(movies).await.unwrap();
+            let users = UserTable::new(db.clone());
+            users.save(User::new("kid", "secret", false)).await.unwrap();
+
+            // lookup, with the results TMDB would send
+            for movie in table.all().await.unwrap() {
+                let genres = match movie.title.as_ref() {
+                    "Alien" => vec![27, 878],
+                    _ => vec![16, 10751],
+                };
+                let movie = with_metadata(movie, result(1, genres));
+                table.set_metadata(movie).await.unwrap();
+            }
+
+            let titles = || {
+                let query = MovieQuery {
+                    user_id: Some(1),
+                    ..MovieQuery::default()
+                };
+                let table = MovieTable::new(db.clone());
+                async move {
+                    let page = table.query(query).await.unwrap();
+                    page.items.into_iter().map(|m| m.title).collect::<Vec<_>>()
+                }
+            };
+            assert_eq!(2, titles().await.len());
+            let libraries = table.libraries(1).await.unwrap();
+            assert_eq!(
+                vec![("kids".to_owned(), 1), ("movies".to_owned(), 1)],
+                libraries
+            );
+
+            let restrictions = RestrictionTable::new(db.clone());
+            let mut restriction = Restriction {
+                user_id: 1,
+                country: "US".to_owned(),
+                max_rating: "".to_owned(),
+                max_age: None,
+                allow_unrated: false,
+                allowed_tags: vec![],
+                blocked_tags: vec!["horror".to_owned()],
+            };
+            restrictions.save(restriction.clone()).await.unwrap();
+            assert_eq!(vec!["Cars"], titles().await);
+            let all = table.all().await.unwrap();
+            let alien = all.iter().find(|m| m.title == "Alien").unwrap();
+            assert_eq!(vec!["Horror", "Science Fiction"], alien.genres);
+            assert!(table.for_user(alien.id, 1).await.unwrap().is_none());
+
+            restriction.blocked_tags = vec![];
+            restriction.allowed_tags = vec!["Animation".to_owned()];
+            restrictions.save(restriction).await.unwrap();
+            assert_eq!(vec!["Cars"], titles().await);
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+}
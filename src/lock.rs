@@ -0,0 +1,95 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Marks the database file as in use by this process. The server keeps
+/// the database in memory and overwrites the file when it saves, so
+/// commands writing to the file must not run next to it. The lock file
+/// `<database>.lock` holds the pid and is removed on drop.
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Takes the lock of the database file `database`. Fails if another
+    /// running process holds it, the lock of a process that is gone is
+    /// taken over.
+    pub fn acquire<P: AsRef<Path>>(database: P) -> Result<Lock, Error> {
+        let mut path = database.as_ref().as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    write!(file, "{}", std::process::id())?;
+                    return Ok(Lock { path });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(format!("{}: {}", path.display(), e).into()),
+            }
+
+            let pid = fs::read_to_string(&path)
+                .ok()
+                .and_then(|pid| pid.trim().parse::<u32>().ok());
+            match pid {
+                Some(pid) if is_running(pid) => {
+                    return Err(format!(
+                        "the database is in use by moviebay (pid {}), stop the server \
+                         first or use the API. Remove {} if no server is running.",
+                        pid,
+                        path.display()
+                    )
+                    .into())
+                }
+                // left behind by a crash
+                _ => fs::remove_file(&path)?,
+            }
+        }
+        Err(format!("could not create {}", path.display()).into())
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Whether a process with `pid` exists. Without `/proc` every process
+/// counts as running.
+fn is_running(pid: u32) -> bool {
+    if !Path::new("/proc/self").exists() {
+        return true;
+    }
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock() {
+        let database = std::env::temp_dir().join("moviebay_test_lock.db");
+        let path = std::env::temp_dir().join("moviebay_test_lock.db.lock");
+        let _ = fs::remove_file(&path);
+
+        let lock = Lock::acquire(&database).unwrap();
+        let pid = fs::read_to_string(&path).unwrap();
+        assert_eq!(std::process::id().to_string(), pid);
+        // this process is running
+        let err = Lock::acquire(&database).err().unwrap().to_string();
+        assert!(err.contains(&pid), "{}", err);
+        drop(lock);
+        assert!(!path.exists());
+
+        // a lock without a running process is stale
+        fs::write(&path, "garbage").unwrap();
+        let lock = Lock::acquire(&database).unwrap();
+        drop(lock);
+        assert!(!path.exists());
+    }
+}
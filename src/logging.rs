@@ -0,0 +1,344 @@
+use crate::config::LogConfig;
+use chrono::{SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+tokio::task_local! {
+    /// Id of the request the current task works on
+    static REQUEST_ID: String;
+}
+
+thread_local! {
+    /// Like `REQUEST_ID`, for blocking threads such as the sqlite runtime
+    static THREAD_REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+static LOGGER: OnceLock<&'static Logger> = OnceLock::new();
+
+/// Runs `fut` with `id` as its request id
+pub async fn scope<F: Future>(id: String, fut: F) -> F::Output {
+    REQUEST_ID.scope(id, fut).await
+}
+
+/// Runs `f` on the current thread with `id` as its request id. Used to
+/// carry the id of a request into blocking threads.
+pub fn with_request_id<F: FnOnce() -> R, R>(id: Option<String>, f: F) -> R {
+    let prev = THREAD_REQUEST_ID.with(|cell| cell.replace(id));
+    let result = f();
+    THREAD_REQUEST_ID.with(|cell| cell.replace(prev));
+    result
+}
+
+/// The request id of the current task or thread
+pub fn request_id() -> Option<String> {
+    REQUEST_ID
+        .try_with(|id| id.clone())
+        .ok()
+        .or_else(|| THREAD_REQUEST_ID.with(|cell| cell.borrow().clone()))
+}
+
+/// Writes an access log line for a finished request
+pub fn access(method: &str, path: &str, status: u16, latency: Duration) {
+    let logger = match LOGGER.get() {
+        Some(logger) => logger,
+        None => return,
+    };
+    let target = "moviebay::access";
+    if logger.level(target) < Level::Info {
+        return;
+    }
+    let latency_ms = (latency.as_secs_f64() * 10000.0).round() / 10.0;
+    let fields = [
+        ("method", Value::from(method)),
+        ("path", Value::from(path)),
+        ("status", Value::from(status)),
+        ("latency_ms", Value::from(latency_ms)),
+    ];
+    logger.write(Level::Info, target, "request", &fields);
+}
+
+/// Installs the logger for the `log` macros
+pub fn init(config: &LogConfig) -> io::Result<()> {
+    let logger: &'static Logger = Box::leak(Box::new(Logger::new(config)?));
+    let max = logger
+        .modules
+        .iter()
+        .map(|(_, level)| *level)
+        .fold(logger.level, Ord::max);
+
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(max);
+        let _ = LOGGER.set(logger);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format {}", s)),
+        }
+    }
+}
+
+/// Parses a level like `info`, `debug` or `off`
+pub fn parse_level(s: &str) -> Result<LevelFilter, String> {
+    s.parse().map_err(|_| format!("unknown log level {}", s))
+}
+
+struct Logger {
+    level: LevelFilter,
+    /// Levels by module, longest first
+    modules: Vec<(String, LevelFilter)>,
+    format: Format,
+    out: Mutex<Output>,
+}
+
+impl Logger {
+    fn new(config: &LogConfig) -> io::Result<Logger> {
+        let invalid = |e| io::Error::new(io::ErrorKind::InvalidInput, e);
+
+        let mut modules = Vec::new();
+        for (module, level) in config.modules.iter() {
+            modules.push((module.clone(), parse_level(level).map_err(invalid)?));
+        }
+        modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+
+        let out = match &config.file {
+            Some(file) => Output::File(RotatingFile::open(
+                file.into(),
+                config.max_size * 1024 * 1024,
+                config.keep,
+            )?),
+            None => Output::Stderr,
+        };
+
+        Ok(Logger {
+            level: parse_level(&config.level).map_err(invalid)?,
+            modules,
+            format: config.format.parse().map_err(invalid)?,
+            out: Mutex::new(out),
+        })
+    }
+
+    /// The level of the most specific module `target` belongs to
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                target == module
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            })
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+
+    fn write(&self, level: Level, target: &str, message: &str, fields: &[(&str, Value)]) {
+        let ts = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let request_id = request_id();
+
+        let line = match self.format {
+            Format::Text => {
+                let mut line = format!("{} {:<5} {}", ts, level, target);
+                if let Some(id) = &request_id {
+                    line.push_str(&format!(" [{}]", id));
+                }
+                line.push_str(": ");
+                line.push_str(message);
+                for (key, value) in fields {
+                    line.push_str(&format!(" {}={}", key, value));
+                }
+                line
+            }
+            Format::Json => {
+                let mut obj = Map::new();
+                obj.insert("ts".to_owned(), ts.into());
+                obj.insert("level".to_owned(), level.as_str().into());
+                obj.insert("target".to_owned(), target.into());
+                if let Some(id) = request_id {
+                    obj.insert("request_id".to_owned(), id.into());
+                }
+                obj.insert("msg".to_owned(), message.into());
+                for (key, value) in fields {
+                    obj.insert((*key).to_owned(), value.clone());
+                }
+                Value::Object(obj).to_string()
+            }
+        };
+
+        if let Err(e) = self.out.lock().unwrap().write_line(&line) {
+            eprintln!("could not write log: {}", e);
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let message = record.args().to_string();
+            self.write(record.level(), record.target(), &message, &[]);
+        }
+    }
+
+    fn flush(&self) {
+        if let Output::File(file) = &mut *self.out.lock().unwrap() {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+enum Output {
+    Stderr,
+    File(RotatingFile),
+}
+
+impl Output {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Output::Stderr => writeln!(io::stderr(), "{}", line),
+            Output::File(file) => file.write_line(line),
+        }
+    }
+}
+
+/// A log file which is renamed to `<name>.1` when it reaches
+/// `max_size` bytes, older files move on to `<name>.2` and so on
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// 0 disables rotation
+    max_size: u64,
+    keep: u32,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, keep: u32) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        *self = RotatingFile::open(self.path.clone(), self.max_size, self.keep)?;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.max_size > 0 && self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn logger(modules: &[(&str, &str)]) -> Logger {
+        let config = LogConfig {
+            level: "info".to_owned(),
+            modules: modules
+                .iter()
+                .map(|(m, l)| ((*m).to_owned(), (*l).to_owned()))
+                .collect::<HashMap<_, _>>(),
+            ..LogConfig::default()
+        };
+        Logger::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_module_levels() {
+        let logger = logger(&[("moviebay::sqlite", "debug"), ("hyper", "warn")]);
+        assert_eq!(LevelFilter::Info, logger.level("moviebay::jobs"));
+        assert_eq!(LevelFilter::Debug, logger.level("moviebay::sqlite"));
+        assert_eq!(
+            LevelFilter::Debug,
+            logger.level("moviebay::sqlite::database")
+        );
+        assert_eq!(LevelFilter::Info, logger.level("moviebay::sqlite_x"));
+        assert_eq!(LevelFilter::Warn, logger.level("hyper::proto"));
+    }
+
+    #[test]
+    fn test_request_id() {
+        assert_eq!(None, request_id());
+        let id = with_request_id(Some("abc".to_owned()), request_id);
+        assert_eq!(Some("abc".to_owned()), id);
+        assert_eq!(None, request_id());
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let id = rt.block_on(scope("def".to_owned(), async { request_id() }));
+        assert_eq!(Some("def".to_owned()), id);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let path = std::env::temp_dir().join(format!("moviebay-{}.log", std::process::id()));
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["aaaaaa", "bbbbbb", "cccccc", "dddddd"].iter() {
+            file.write_line(line).unwrap();
+        }
+
+        assert_eq!("dddddd\n", fs::read_to_string(&path).unwrap());
+        assert_eq!("cccccc\n", fs::read_to_string(file.rotated(1)).unwrap());
+        assert_eq!("bbbbbb\n", fs::read_to_string(file.rotated(2)).unwrap());
+        assert!(!file.rotated(3).exists());
+
+        for n in 1..=2 {
+            fs::remove_file(file.rotated(n)).unwrap();
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// Upper bounds of the duration histogram buckets in seconds
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// The metrics of the process, rendered at `/metrics`
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::default)
+}
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative
+    counts: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|le| secs <= *le) {
+            self.counts[i] += 1;
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    /// Writes the buckets, sum and count. `labels` are put in front of
+    /// `le`, e.g. `route="get_movie",`.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (le, count) in BUCKETS.iter().zip(self.counts.iter()) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let labels = match labels.trim_end_matches(',') {
+            "" => "".to_owned(),
+            labels => format!("{{{}}}", labels),
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Requests by route, method and status
+    requests: BTreeMap<(String, String, u16), u64>,
+    request_seconds: BTreeMap<String, Histogram>,
+    /// Streams by mode, e.g. `remux`
+    streams_active: BTreeMap<String, i64>,
+    streams_total: BTreeMap<String, u64>,
+    stream_bytes: u64,
+    sqlite_queued: i64,
+    sqlite_seconds: Histogram,
+    scan_seconds: Histogram,
+    scan_files: u64,
+    scan_new: u64,
+    tmdb_requests: u64,
+    tmdb_failures: u64,
+}
+
+/// Counters and histograms in the Prometheus text format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+impl Metrics {
+    /// Counts a finished request. `route` is the name of the route.
+    pub fn request(&self, route: &str, method: &str, status: u16, duration: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (route.to_owned(), method.to_owned(), status);
+        *inner.requests.entry(key).or_insert(0) += 1;
+        inner
+            .request_seconds
+            .entry(route.to_owned())
+            .or_default()
+            .observe(duration);
+    }
+
+    /// Counts a stream, it is active until the returned guard is dropped
+    pub fn stream(&self, mode: &str) -> StreamGuard<'_> {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.streams_active.entry(mode.to_owned()).or_insert(0) += 1;
+        *inner.streams_total.entry(mode.to_owned()).or_insert(0) += 1;
+        StreamGuard {
+            metrics: self,
+            mode: mode.to_owned(),
+        }
+    }
+
+    /// A job was sent to the sqlite runtime
+    pub fn sqlite_queued(&self) {
+        self.inner.lock().unwrap().sqlite_queued += 1;
+    }
+
+    /// A sqlite job returned its result `duration` after it was queued
+    pub fn sqlite_done(&self, duration: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sqlite_queued -= 1;
+        inner.sqlite_seconds.observe(duration);
+    }
+
+    /// A scan found `files` movies in the library, `new` of them were
+    /// added to the database
+    pub fn scan(&self, duration: Duration, files: usize, new: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.scan_seconds.observe(duration);
+        inner.scan_files = files as u64;
+        inner.scan_new += new as u64;
+    }
+
+    /// Counts a call of the TMDB API
+    pub fn tmdb(&self, ok: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tmdb_requests += 1;
+        if !ok {
+            inner.tmdb_failures += 1;
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "moviebay_http_requests_total",
+            "counter",
+            "HTTP requests by route, method and status",
+        );
+        for ((route, method, status), count) in inner.requests.iter() {
+            let _ = writeln!(
+                out,
+                "moviebay_http_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+                route, method, status, count
+            );
+        }
+        header(
+            &mut out,
+            "moviebay_http_request_duration_seconds",
+            "histogram",
+            "Time until the response headers are sent, by route",
+        );
+        for (route, histogram) in inner.request_seconds.iter() {
+            let labels = format!("route=\"{}\",", route);
+            histogram.render(&mut out, "moviebay_http_request_duration_seconds", &labels);
+        }
+
+        header(
+            &mut out,
+            "moviebay_streams_active",
+            "gauge",
+            "Running ffmpeg sessions by mode",
+        );
+        for (mode, count) in inner.streams_active.iter() {
+            let _ = writeln!(
+                out,
+                "moviebay_streams_active{{mode=\"{}\"}} {}",
+                mode, count
+            );
+        }
+        header(
+            &mut out,
+            "moviebay_streams_total",
+            "counter",
+            "Started ffmpeg sessions by mode",
+        );
+        for (mode, count) in inner.streams_total.iter() {
+            let _ = writeln!(out, "moviebay_streams_total{{mode=\"{}\"}} {}", mode, count);
+        }
+        header(
+            &mut out,
+            "moviebay_stream_bytes_total",
+            "counter",
+            "Bytes sent to stream clients",
+        );
+        let _ = writeln!(out, "moviebay_stream_bytes_total {}", inner.stream_bytes);
+
+        header(
+            &mut out,
+            "moviebay_sqlite_queue_depth",
+            "gauge",
+            "Sqlite jobs waiting for or running on the runtime",
+        );
+        let _ = writeln!(out, "moviebay_sqlite_queue_depth {}", inner.sqlite_queued);
+        header(
+            &mut out,
+            "moviebay_sqlite_job_duration_seconds",
+            "histogram",
+            "Time from queueing a sqlite job to its result",
+        );
+        inner
+            .sqlite_seconds
+            .render(&mut out, "moviebay_sqlite_job_duration_seconds", "");
+
+        header(
+            &mut out,
+            "moviebay_scan_duration_seconds",
+            "histogram",
+            "Duration of library scans",
+        );
+        inner
+            .scan_seconds
+            .render(&mut out, "moviebay_scan_duration_seconds", "");
+        header(
+            &mut out,
+            "moviebay_scan_files",
+            "gauge",
+            "Movies found in the library by the last scan",
+        );
+        let _ = writeln!(out, "moviebay_scan_files {}", inner.scan_files);
+        header(
+            &mut out,
+            "moviebay_scan_new_movies_total",
+            "counter",
+            "Movies added to the database by scans",
+        );
+        let _ = writeln!(out, "moviebay_scan_new_movies_total {}", inner.scan_new);
+
+        header(
+            &mut out,
+            "moviebay_tmdb_requests_total",
+            "counter",
+            "Calls of the TMDB API",
+        );
+        let _ = writeln!(out, "moviebay_tmdb_requests_total {}", inner.tmdb_requests);
+        header(
+            &mut out,
+            "moviebay_tmdb_failures_total",
+            "counter",
+            "Failed calls of the TMDB API",
+        );
+        let _ = writeln!(out, "moviebay_tmdb_failures_total {}", inner.tmdb_failures);
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Marks a stream as active while it lives
+pub struct StreamGuard<'a> {
+    metrics: &'a Metrics,
+    mode: String,
+}
+
+impl StreamGuard<'_> {
+    /// Counts bytes sent to the client
+    pub fn sent(&self, bytes: usize) {
+        self.metrics.inner.lock().unwrap().stream_bytes += bytes as u64;
+    }
+}
+
+impl Drop for StreamGuard<'_> {
+    fn drop(&mut self) {
+        let mut inner = self.metrics.inner.lock().unwrap();
+        if let Some(active) = inner.streams_active.get_mut(&self.mode) {
+            *active -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(30));
+        histogram.observe(Duration::from_secs(60));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "x", "route=\"a\",");
+        assert!(out.contains("x_bucket{route=\"a\",le=\"0.001\"} 0\n"));
+        assert!(out.contains("x_bucket{route=\"a\",le=\"0.005\"} 1\n"));
+        assert!(out.contains("x_bucket{route=\"a\",le=\"0.05\"} 2\n"));
+        assert!(out.contains("x_bucket{route=\"a\",le=\"10\"} 2\n"));
+        assert!(out.contains("x_bucket{route=\"a\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("x_count{route=\"a\"} 3\n"));
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.request("get_movie", "GET", 200, Duration::from_millis(5));
+        metrics.request("get_movie", "GET", 200, Duration::from_millis(5));
+        {
+            let stream = metrics.stream("remux");
+            stream.sent(100);
+            assert!(metrics
+                .render()
+                .contains("moviebay_streams_active{mode=\"remux\"} 1\n"));
+        }
+        metrics.tmdb(false);
+
+        let out = metrics.render();
+        assert!(out.contains(
+            "moviebay_http_requests_total{route=\"get_movie\",method=\"GET\",status=\"200\"} 2\n"
+        ));
+        assert!(out.contains("moviebay_streams_active{mode=\"remux\"} 0\n"));
+        assert!(out.contains("moviebay_streams_total{mode=\"remux\"} 1\n"));
+        assert!(out.contains("moviebay_stream_bytes_total 100\n"));
+        assert!(out.contains("moviebay_sqlite_job_duration_seconds_count 0\n"));
+        assert!(out.contains("moviebay_tmdb_failures_total 1\n"));
+    }
+}
@@ -0,0 +1,160 @@
+use super::{FutRes, Model, Movie, MovieTable, Table};
+use crate::sqlite::{params, Connection, SharedDb, Transaction};
+use serde::{Deserialize, Serialize};
+
+/// Minimum ages of the certifications which aren't ages already
+const AGES: &[(&str, &[(&str, i32)])] = &[
+    (
+        "US",
+        &[("G", 0), ("PG", 8), ("PG-13", 13), ("R", 17), ("NC-17", 18)],
+    ),
+    (
+        "GB",
+        &[
+            ("U", 0),
+            ("PG", 8),
+            ("12A", 12),
+            ("12", 12),
+            ("15", 15),
+            ("18", 18),
+            ("R18", 18),
+        ],
+    ),
+];
+
+/// The minimum age of a certification in a country (ISO 3166-1, e.g.
+/// `DE`). Most countries certify by age, e.g. `12` or `FSK 12`, the
+/// MPAA and BBFC ratings are mapped. `None` if the certification is
+/// unknown.
+pub fn min_age(country: &str, certification: &str) -> Option<i32> {
+    let certification = certification.trim();
+    let known = AGES
+        .iter()
+        .find(|(c, _)| c.eq_ignore_ascii_case(country))
+        .and_then(|(_, ages)| {
+            ages.iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(certification))
+        });
+    if let Some((_, age)) = known {
+        return Some(*age);
+    }
+
+    let age = certification.trim_start_matches(|c: char| !c.is_ascii_digit());
+    let age = age.trim_end_matches('+');
+    if age.is_empty() || age.len() > 2 {
+        return None;
+    }
+    age.parse().ok()
+}
+
+/// The certification of a movie in one country
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Model)]
+#[model(table = "certifications")]
+pub struct Certification {
+    #[model(key, sql = "INTEGER NOT NULL REFERENCES movies (id) ON DELETE CASCADE")]
+    pub movie_id: i32,
+    /// ISO 3166-1 code, e.g. `DE`
+    #[model(key, sql = "VARCHAR(2) NOT NULL")]
+    pub country: String,
+    /// As published, e.g. `PG-13` or `12`
+    #[model(sql = "VARCHAR(16) NOT NULL")]
+    pub certification: String,
+    /// See `min_age`, `None` if the certification is unknown
+    #[model(sql = "INTEGER")]
+    pub min_age: Option<i32>,
+}
+
+impl Certification {
+    pub fn new(movie_id: i32, country: &str, certification: &str) -> Certification {
+        Certification {
+            movie_id,
+            country: country.to_uppercase(),
+            certification: certification.to_owned(),
+            min_age: min_age(country, certification),
+        }
+    }
+}
+
+/// Represents the table certifications in the database
+pub struct CertificationTable {
+    db: SharedDb,
+}
+
+impl CertificationTable {
+    /// Create a new handler to the certifications table
+    pub fn new(db: SharedDb) -> CertificationTable {
+        CertificationTable { db }
+    }
+
+    /// Replaces the certifications of a movie
+    pub fn set(&self, movie_id: i32, certifications: Vec<Certification>) -> FutRes<()> {
+        let db = self.db.clone();
+
+        let func = async move {
+            db.transaction(Box::new(move |tx: &Transaction| {
+                tx.execute(
+                    "DELETE FROM certifications WHERE movie_id=?1",
+                    params![movie_id],
+                )?;
+                for certification in certifications.iter() {
+                    tx.execute(
+                        &Certification::insert_sql(),
+                        &certification.insert_values()?,
+                    )?;
+                }
+                Ok(())
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// Movies with TMDB metadata but without certifications
+    pub fn missing(&self) -> FutRes<Vec<Movie>> {
+        let db = self.db.clone();
+        let select = format!(
+            "{} WHERE tmdb_id > 0 AND id NOT IN (SELECT movie_id FROM certifications)",
+            MovieTable::new(db.clone()).select()
+        );
+
+        let func = async move {
+            let movies = db
+                .read(Box::new(move |conn: &Connection| {
+                    let mut stmt = conn.prepare(&select)?;
+                    let iter = stmt.query_map(params![], MovieTable::from_row)?;
+                    iter.collect::<Result<Vec<_>, _>>()
+                }))
+                .await?;
+            Ok(movies)
+        };
+        Box::pin(func)
+    }
+}
+
+impl Table for CertificationTable {
+    type Model = Certification;
+
+    fn db(&self) -> SharedDb {
+        self.db.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_min_age() {
+        assert_eq!(Some(13), min_age("US", "PG-13"));
+        assert_eq!(Some(17), min_age("us", "R"));
+        assert_eq!(Some(12), min_age("GB", "12A"));
+        assert_eq!(Some(12), min_age("DE", "12"));
+        assert_eq!(Some(16), min_age("DE", "FSK 16"));
+        assert_eq!(Some(0), min_age("DE", "0"));
+        assert_eq!(Some(18), min_age("BR", "18+"));
+        assert_eq!(None, min_age("US", "NR"));
+        assert_eq!(None, min_age("DE", ""));
+        assert_eq!(None, min_age("FR", "2024"));
+    }
+}
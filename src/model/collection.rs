@@ -0,0 +1,456 @@
+use super::error::Error;
+use super::query::Page;
+use super::{allowed, FutRes, Model, Movie, MovieQuery, MovieTable, Table};
+use crate::sqlite::{params, Connection, SharedDb, SqlResult, Transaction};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// How the movies of a collection are chosen
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CollectionKind {
+    /// Movies added by hand, e.g. "Christmas movies"
+    Collection,
+    /// Movies added by hand in the order they are played
+    Playlist,
+    /// Movies matching a saved filter
+    Smart,
+}
+
+impl CollectionKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CollectionKind::Collection => "collection",
+            CollectionKind::Playlist => "playlist",
+            CollectionKind::Smart => "smart",
+        }
+    }
+}
+
+impl FromStr for CollectionKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CollectionKind, String> {
+        match s {
+            "collection" => Ok(CollectionKind::Collection),
+            "playlist" => Ok(CollectionKind::Playlist),
+            "smart" => Ok(CollectionKind::Smart),
+            _ => Err(format!("unknown collection kind `{}`", s)),
+        }
+    }
+}
+
+impl ToSql for CollectionKind {
+    fn to_sql(&self) -> SqlResult<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl FromSql for CollectionKind {
+    fn column_result(value: ValueRef) -> FromSqlResult<CollectionKind> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: String| FromSqlError::Other(e.into()))
+    }
+}
+
+/// A list of movies created by a user
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Model)]
+#[model(table = "collections")]
+pub struct Collection {
+    #[model(id)]
+    pub id: i32,
+    /// The owner, the only one who may change the collection
+    #[model(sql = "INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE")]
+    pub user_id: i32,
+    #[model(sql = "VARCHAR(255) NOT NULL")]
+    pub name: String,
+    #[model(sql = "VARCHAR(16) NOT NULL")]
+    pub kind: CollectionKind,
+    /// Visible to all users, else only to the owner
+    #[model(sql = "BOOLEAN NOT NULL DEFAULT 0")]
+    pub shared: bool,
+    /// Saved filter of a smart collection as query string of
+    /// `/movies/`, e.g. `genre=Comedy&year_from=1990&watched=false`
+    #[model(sql = "TEXT NOT NULL DEFAULT ''")]
+    pub filter: String,
+    #[model(now)]
+    pub created_at: i64,
+}
+
+impl Collection {
+    /// The movie query of a smart collection, `None` for the other
+    /// kinds. `watched` refers to the user looking at the collection.
+    pub fn query(&self, user_id: i32) -> Option<Result<MovieQuery, String>> {
+        if self.kind != CollectionKind::Smart {
+            return None;
+        }
+        let query = MovieQuery::from_query(&self.filter).map(|mut query| {
+            query.user_id = Some(user_id);
+            query
+        });
+        Some(query.map_err(|e| e.to_string()))
+    }
+
+    /// Whether `user_id` may see the collection
+    pub fn is_visible(&self, user_id: i32) -> bool {
+        self.shared || self.user_id == user_id
+    }
+}
+
+/// A movie in a collection or playlist
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Model)]
+#[model(table = "collection_items")]
+pub struct CollectionItem {
+    #[model(
+        key,
+        sql = "INTEGER NOT NULL REFERENCES collections (id) ON DELETE CASCADE"
+    )]
+    pub collection_id: i32,
+    #[model(key, sql = "INTEGER NOT NULL REFERENCES movies (id) ON DELETE CASCADE")]
+    pub movie_id: i32,
+    /// Items are listed by position, new ones are appended
+    #[model(sql = "INTEGER NOT NULL")]
+    pub position: i32,
+    #[model(now)]
+    pub added_at: i64,
+}
+
+/// Represents the tables collections and collection_items in the
+/// database
+pub struct CollectionTable {
+    db: SharedDb,
+}
+
+impl CollectionTable {
+    /// Create a new handler to the collections table
+    pub fn new(db: SharedDb) -> CollectionTable {
+        CollectionTable { db }
+    }
+
+    /// Saves a new collection and returns it with its id
+    pub fn create(&self, collection: Collection) -> FutRes<Collection> {
+        let db = self.db.clone();
+        let select = format!("{} WHERE id=?1", self.select());
+
+        let func = async move {
+            let collection = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    conn.execute(&Collection::insert_sql(), &collection.insert_values()?)?;
+                    let id = conn.last_insert_rowid();
+                    conn.query_row(&select, params![id], Collection::from_row)
+                }))
+                .await?;
+            Ok(collection)
+        };
+        Box::pin(func)
+    }
+
+    /// The collections of a user and the ones shared by others, by name
+    pub fn visible(&self, user_id: i32) -> FutRes<Vec<Collection>> {
+        let db = self.db.clone();
+        let select = format!(
+            "{} WHERE user_id=?1 OR shared=1 ORDER BY name COLLATE NOCASE, id",
+            self.select()
+        );
+
+        let func = async move {
+            let collections = db
+                .read(Box::new(move |conn: &Connection| {
+                    let mut stmt = conn.prepare(&select)?;
+                    let iter = stmt.query_map(params![user_id], Collection::from_row)?;
+                    iter.collect::<Result<Vec<_>, _>>()
+                }))
+                .await?;
+            Ok(collections)
+        };
+        Box::pin(func)
+    }
+
+    /// One page of the movie ids in a collection, by position. Movies
+    /// hidden from `user_id` are left out.
+    pub fn movie_ids(
+        &self,
+        collection_id: i32,
+        user_id: i32,
+        limit: u32,
+        offset: u32,
+    ) -> FutRes<Page<i32>> {
+        let db = self.db.clone();
+        let count = format!(
+            "SELECT COUNT(*) FROM collection_items WHERE collection_id=?1 AND {}",
+            allowed("movie_id", "?2")
+        );
+        let select = format!(
+            "SELECT movie_id FROM collection_items WHERE collection_id=?1 AND {} \
+             ORDER BY position, added_at LIMIT ?3 OFFSET ?4",
+            allowed("movie_id", "?2")
+        );
+
+        let func = async move {
+            let page = db
+                .read(Box::new(move |conn: &Connection| {
+                    let total =
+                        conn.query_row(&count, params![collection_id, user_id], |row| row.get(0))?;
+                    let mut stmt = conn.prepare(&select)?;
+                    let params = params![collection_id, user_id, limit, offset];
+                    let iter = stmt.query_map(params, |row| row.get(0))?;
+                    Ok(Page {
+                        total,
+                        limit,
+                        offset,
+                        items: iter.collect::<Result<Vec<_>, _>>()?,
+                    })
+                }))
+                .await?;
+            Ok(page)
+        };
+        Box::pin(func)
+    }
+
+    /// One page of the movies in a collection. Smart collections are
+    /// evaluated now, with their filter applied for `user_id`.
+    pub fn movies(
+        &self,
+        collection: &Collection,
+        user_id: i32,
+        limit: u32,
+        offset: u32,
+    ) -> FutRes<Page<Movie>> {
+        let table = MovieTable::new(self.db.clone());
+        let query = collection.query(user_id);
+        let ids = self.movie_ids(collection.id, user_id, limit, offset);
+
+        let func = async move {
+            if let Some(query) = query {
+                let query = MovieQuery {
+                    limit: Some(limit),
+                    offset,
+                    ..query.map_err(|e| Error::Database(e.into()))?
+                };
+                return table.query(query).await;
+            }
+
+            let ids = ids.await?;
+            let items = table.by_ids(ids.items).await?;
+            Ok(Page {
+                total: ids.total,
+                limit,
+                offset,
+                items,
+            })
+        };
+        Box::pin(func)
+    }
+
+    /// Appends a movie to a collection. Returns `false` if it is in
+    /// there already.
+    pub fn add(&self, collection_id: i32, movie_id: i32) -> FutRes<bool> {
+        let db = self.db.clone();
+
+        let func = async move {
+            let added = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    conn.execute(
+                        "INSERT OR IGNORE INTO collection_items \
+                         (collection_id, movie_id, position, added_at) \
+                         SELECT ?1, ?2, COALESCE(MAX(position) + 1, 0), strftime('%s', 'now') \
+                         FROM collection_items \
+                         WHERE collection_id=?1",
+                        params![collection_id, movie_id],
+                    )
+                }))
+                .await?;
+            Ok(added > 0)
+        };
+        Box::pin(func)
+    }
+
+    /// Removes a movie from a collection. Returns `false` if it wasn't
+    /// in there.
+    pub fn remove(&self, collection_id: i32, movie_id: i32) -> FutRes<bool> {
+        let db = self.db.clone();
+
+        let func = async move {
+            let deleted = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    conn.execute(
+                        "DELETE FROM collection_items WHERE collection_id=?1 AND movie_id=?2",
+                        params![collection_id, movie_id],
+                    )
+                }))
+                .await?;
+            Ok(deleted > 0)
+        };
+        Box::pin(func)
+    }
+
+    /// Puts the movies of a collection in the order of `movie_ids`.
+    /// Returns `false` and changes nothing unless `movie_ids` holds
+    /// every movie of the collection exactly once.
+    pub fn reorder(&self, collection_id: i32, movie_ids: Vec<i32>) -> FutRes<bool> {
+        let db = self.db.clone();
+
+        let func = async move {
+            let reordered = db
+                .transaction(Box::new(move |tx: &Transaction| {
+                    let mut stmt = tx.prepare(
+                        "SELECT movie_id FROM collection_items WHERE collection_id=?1 \
+                         ORDER BY movie_id",
+                    )?;
+                    let current = stmt
+                        .query_map(params![collection_id], |row| row.get(0))?
+                        .collect::<Result<Vec<i32>, _>>()?;
+                    let mut sorted = movie_ids.clone();
+                    sorted.sort_unstable();
+                    if sorted != current {
+                        return Ok(false);
+                    }
+
+                    for (position, movie_id) in movie_ids.iter().enumerate() {
+                        tx.execute(
+                            "UPDATE collection_items SET position=?1 \
+                             WHERE collection_id=?2 AND movie_id=?3",
+                            params![position as i64, collection_id, movie_id],
+                        )?;
+                    }
+                    Ok(true)
+                }))
+                .await?;
+            Ok(reordered)
+        };
+        Box::pin(func)
+    }
+}
+
+impl Table for CollectionTable {
+    type Model = Collection;
+
+    fn db(&self) -> SharedDb {
+        self.db.clone()
+    }
+
+    fn create_table(&self) -> FutRes<()> {
+        let db = self.db.clone();
+        let create = format!(
+            "{};\n{};\nCREATE INDEX collections_user ON collections (user_id);",
+            Collection::create_sql(),
+            CollectionItem::create_sql()
+        );
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute_batch(&create)
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use crate::model::{migrate, Movie, MovieTable, User, UserTable};
+    use crate::sqlite::Runtime;
+
+    fn collection(user_id: i32, kind: CollectionKind, filter: &str) -> Collection {
+        Collection {
+            id: 0,
+            user_id,
+            name: "Christmas".to_owned(),
+            kind,
+            shared: false,
+            filter: filter.to_owned(),
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_collections() {
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (db, rt) = Runtime::channel(config);
+            rt.run();
+            migrate(db.clone()).await.unwrap();
+            let movies = MovieTable::new(db.clone());
+            for title in &["Elf", "Die Hard", "Home Alone"] {
+                let mut movie = Movie::from(crate::model::export::ExportMovie::default());
+                movie.title = title.to_string();
+                movie.file_path = format!("/movies/{}.mkv", title);
+                movies.save(movie).await.unwrap();
+            }
+            let users = UserTable::new(db.clone());
+            users.save(User::new("jan", "secret", false)).await.unwrap();
+            users.save(User::new("eva", "secret", false)).await.unwrap();
+            let t = CollectionTable::new(db.clone());
+
+            let playlist = collection(1, CollectionKind::Playlist, "");
+            let playlist = t.create(playlist).await.unwrap();
+            assert_eq!(1, playlist.id);
+            assert!(playlist.created_at > 0);
+            assert!(t.add(1, 2).await.unwrap());
+            assert!(t.add(1, 1).await.unwrap());
+            assert!(t.add(1, 3).await.unwrap());
+            assert!(!t.add(1, 3).await.unwrap());
+            assert_eq!(vec![2, 1, 3], t.movie_ids(1, 1, 10, 0).await.unwrap().items);
+
+            assert!(!t.reorder(1, vec![3, 1]).await.unwrap());
+            assert!(!t.reorder(1, vec![3, 1, 1]).await.unwrap());
+            assert!(t.reorder(1, vec![3, 1, 2]).await.unwrap());
+            let page = t.movie_ids(1, 1, 2, 1).await.unwrap();
+            assert_eq!(3, page.total);
+            assert_eq!(vec![1, 2], page.items);
+            assert!(t.remove(1, 1).await.unwrap());
+            assert!(!t.remove(1, 1).await.unwrap());
+            assert!(t.add(1, 1).await.unwrap());
+            assert_eq!(vec![3, 2, 1], t.movie_ids(1, 1, 10, 0).await.unwrap().items);
+
+            let mut smart = collection(1, CollectionKind::Smart, "watched=false");
+            smart.shared = true;
+            let smart = t.create(smart).await.unwrap();
+            let query = smart.query(2).unwrap().unwrap();
+            assert_eq!(Some(false), query.watched);
+            assert_eq!(Some(2), query.user_id);
+            assert!(playlist.query(1).is_none());
+
+            // genres come with the metadata looked up on TMDB
+            let comedies = t
+                .create(collection(1, CollectionKind::Smart, "genre=comedy"))
+                .await
+                .unwrap();
+            assert_eq!(0, t.movies(&comedies, 1, 10, 0).await.unwrap().total);
+            let mut elf = movies.by_id(1).await.unwrap().unwrap();
+            elf.tmdb_id = 10719;
+            elf.genres = vec!["Comedy".to_owned(), "Family".to_owned()];
+            movies.set_metadata(elf).await.unwrap();
+            let page = t.movies(&comedies, 1, 10, 0).await.unwrap();
+            assert_eq!(
+                vec!["Elf"],
+                page.items.iter().map(|m| &m.title).collect::<Vec<_>>()
+            );
+            assert!(t.delete(comedies.id).await.unwrap());
+
+            assert_eq!(2, t.visible(1).await.unwrap().len());
+            let visible = t.visible(2).await.unwrap();
+            assert_eq!(
+                vec![smart.id],
+                visible.iter().map(|c| c.id).collect::<Vec<_>>()
+            );
+
+            // the items go with their collection
+            assert!(t.delete(1).await.unwrap());
+            assert_eq!(0, t.movie_ids(1, 1, 10, 0).await.unwrap().total);
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+}
@@ -8,7 +8,10 @@ pub enum Error {
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Custom Error")
+        match self {
+            Error::Database(e) => write!(f, "database error: {}", e),
+            Error::Sqlite(e) => write!(f, "sqlite error: {}", e),
+        }
     }
 }
 
@@ -0,0 +1,945 @@
+use super::error::Error;
+use super::movie::{insert_genres, insert_movie};
+use super::{min_age, CollectionKind, FutRes, Model, Movie, MovieTable, Table, User, MAX_RATING};
+use crate::sqlite::{params, Connection, OptionalExtension, SharedDb, Transaction};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Version of the export format written by this build
+pub const EXPORT_VERSION: u32 = 3;
+
+/// A portable copy of the library: movies with their metadata, users
+/// with their parental controls, collections and the watch history. Rows refer to each other by file path and
+/// user name instead of ids and missing fields take their defaults, so
+/// an export can be imported into a database with another schema.
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Export {
+    pub version: u32,
+    /// Unix timestamp of the export
+    pub exported_at: i64,
+    pub movies: Vec<ExportMovie>,
+    /// Users with their password hashes, so they can login with their
+    /// old passwords after an import
+    pub users: Vec<ExportUser>,
+    pub restrictions: Vec<ExportRestriction>,
+    /// Collections, playlists and smart collections with their movies
+    pub collections: Vec<ExportCollection>,
+    /// Resume positions and watched flags
+    pub watch_history: Vec<ExportProgress>,
+    pub plays: Vec<ExportPlay>,
+    pub ratings: Vec<ExportRating>,
+    pub watchlist: Vec<ExportWatchlist>,
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportMovie {
+    pub file_path: String,
+    pub title: String,
+    pub release_year: i32,
+    pub library: String,
+    pub resolution: i32,
+    pub added_at: i64,
+    pub tmdb_id: i32,
+    pub overview: String,
+    pub poster_path: String,
+    pub backdrop_path: String,
+    pub rating: f64,
+    pub genres: Vec<String>,
+    /// Certifications by country, e.g. `{"DE": "12"}`
+    pub certifications: BTreeMap<String, String>,
+}
+
+impl From<Movie> for ExportMovie {
+    fn from(movie: Movie) -> ExportMovie {
+        ExportMovie {
+            file_path: movie.file_path,
+            title: movie.title,
+            release_year: movie.release_year,
+            library: movie.library,
+            resolution: movie.resolution,
+            added_at: movie.added_at,
+            tmdb_id: movie.tmdb_id,
+            overview: movie.overview,
+            poster_path: movie.poster_path,
+            backdrop_path: movie.backdrop_path,
+            rating: movie.rating,
+            genres: movie.genres,
+            certifications: BTreeMap::new(),
+        }
+    }
+}
+
+impl From<ExportMovie> for Movie {
+    fn from(movie: ExportMovie) -> Movie {
+        Movie {
+            id: 0,
+            tmdb_id: movie.tmdb_id,
+            title: movie.title,
+            overview: movie.overview,
+            release_year: movie.release_year,
+            file_path: movie.file_path,
+            poster_path: movie.poster_path,
+            backdrop_path: movie.backdrop_path,
+            rating: movie.rating,
+            resolution: movie.resolution,
+            library: movie.library,
+            added_at: movie.added_at,
+            genres: movie.genres,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportUser {
+    pub name: String,
+    pub password_hash: String,
+    pub is_admin: bool,
+    pub created_at: i64,
+    pub pin_hash: String,
+    /// Failed PIN attempts in a row, a locked PIN stays locked
+    pub pin_failures: i32,
+}
+
+/// The parental controls of a user
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportRestriction {
+    pub user: String,
+    pub country: String,
+    pub max_rating: String,
+    pub max_age: Option<i32>,
+    pub allow_unrated: bool,
+    pub allowed_tags: Vec<String>,
+    pub blocked_tags: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportCollection {
+    pub user: String,
+    pub name: String,
+    pub kind: CollectionKind,
+    pub shared: bool,
+    /// Saved filter of a smart collection
+    pub filter: String,
+    pub created_at: i64,
+    /// The movies in the order of the collection
+    pub items: Vec<ExportCollectionItem>,
+}
+
+impl Default for ExportCollection {
+    fn default() -> ExportCollection {
+        ExportCollection {
+            user: String::new(),
+            name: String::new(),
+            kind: CollectionKind::Collection,
+            shared: false,
+            filter: String::new(),
+            created_at: 0,
+            items: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportCollectionItem {
+    pub file_path: String,
+    pub added_at: i64,
+}
+
+/// The progress of a user for a movie
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportProgress {
+    pub user: String,
+    pub file_path: String,
+    pub position: f64,
+    pub duration: f64,
+    pub completed: bool,
+    pub last_watched_at: i64,
+}
+
+/// A play of a movie by a user
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportPlay {
+    pub user: String,
+    pub file_path: String,
+    pub device: String,
+    pub started_at: i64,
+    pub last_watched_at: i64,
+    pub position: f64,
+    pub completed: bool,
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportRating {
+    pub user: String,
+    pub file_path: String,
+    pub rating: i32,
+    pub rated_at: i64,
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportWatchlist {
+    pub user: String,
+    pub file_path: String,
+    pub added_at: i64,
+}
+
+/// Rows added or changed by an import
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct Imported {
+    pub movies: usize,
+    pub users: usize,
+    pub restrictions: usize,
+    pub collections: usize,
+    pub watch_history: usize,
+    pub plays: usize,
+    pub ratings: usize,
+    pub watchlist: usize,
+}
+
+const RESTRICTIONS: &str = "SELECT users.name, restrictions.country, restrictions.max_rating, \
+     restrictions.max_age, restrictions.allow_unrated FROM restrictions \
+     JOIN users ON users.id = restrictions.user_id ORDER BY users.name";
+
+const RESTRICTION_TAGS: &str = "SELECT users.name, restriction_tags.tag, \
+     restriction_tags.blocked FROM restriction_tags \
+     JOIN users ON users.id = restriction_tags.user_id ORDER BY restriction_tags.tag";
+
+/// Restrictions of known users are kept, the tags are only added along
+/// with a new restriction
+const INSERT_RESTRICTION: &str = "INSERT OR IGNORE INTO restrictions \
+     (user_id, country, max_rating, max_age, allow_unrated) \
+     SELECT id, ?2, ?3, ?4, ?5 FROM users WHERE name=?1";
+
+const INSERT_RESTRICTION_TAG: &str = "INSERT OR IGNORE INTO restriction_tags \
+     (user_id, tag, blocked) SELECT id, ?2, ?3 FROM users WHERE name=?1";
+
+const COLLECTIONS: &str = "SELECT collections.id, users.name, collections.name, \
+     collections.kind, collections.shared, collections.filter, collections.created_at \
+     FROM collections JOIN users ON users.id = collections.user_id ORDER BY collections.id";
+
+const COLLECTION_ITEMS: &str = "SELECT collection_items.collection_id, movies.file_path, \
+     collection_items.added_at FROM collection_items \
+     JOIN movies ON movies.id = collection_items.movie_id \
+     ORDER BY collection_items.collection_id, collection_items.position";
+
+/// Collections are identified by user, name and kind
+const INSERT_COLLECTION: &str = "INSERT INTO collections \
+     (user_id, name, kind, shared, filter, created_at) \
+     SELECT id, ?2, ?3, ?4, ?5, ?6 FROM users WHERE name=?1 AND NOT EXISTS \
+     (SELECT 1 FROM collections WHERE user_id=users.id AND name=?2 AND kind=?3)";
+
+const INSERT_COLLECTION_ITEM: &str = "INSERT OR IGNORE INTO collection_items \
+     (collection_id, movie_id, position, added_at) SELECT ?1, id, ?3, ?4 FROM movies \
+     WHERE file_path=?2";
+
+const WATCH_HISTORY: &str = "SELECT users.name, movies.file_path, watch_progress.position, \
+     watch_progress.duration, watch_progress.completed, watch_progress.last_watched_at \
+     FROM watch_progress JOIN users ON users.id = watch_progress.user_id \
+     JOIN movies ON movies.id = watch_progress.movie_id ORDER BY users.name, movies.file_path";
+
+/// Newer progress replaces older one, progress of unknown users or
+/// movies is skipped
+const UPSERT_PROGRESS: &str = "INSERT INTO watch_progress \
+     (user_id, movie_id, position, duration, completed, last_watched_at) \
+     SELECT users.id, movies.id, ?3, ?4, ?5, ?6 FROM users, movies \
+     WHERE users.name=?1 AND movies.file_path=?2 \
+     ON CONFLICT (user_id, movie_id) DO UPDATE SET position=excluded.position, \
+     duration=excluded.duration, completed=excluded.completed, \
+     last_watched_at=excluded.last_watched_at \
+     WHERE excluded.last_watched_at > watch_progress.last_watched_at";
+
+const PLAYS: &str = "SELECT users.name, movies.file_path, watch_history.device, \
+     watch_history.started_at, watch_history.last_watched_at, watch_history.position, \
+     watch_history.completed FROM watch_history JOIN users ON users.id = watch_history.user_id \
+     JOIN movies ON movies.id = watch_history.movie_id ORDER BY watch_history.id";
+
+/// Plays are identified by user, movie, device and start
+const INSERT_PLAY: &str = "INSERT INTO watch_history \
+     (user_id, movie_id, device, started_at, last_watched_at, position, completed) \
+     SELECT users.id, movies.id, ?3, ?4, ?5, ?6, ?7 FROM users, movies \
+     WHERE users.name=?1 AND movies.file_path=?2 AND NOT EXISTS (SELECT 1 FROM watch_history \
+     WHERE user_id=users.id AND movie_id=movies.id AND device=?3 AND started_at=?4)";
+
+const RATINGS: &str = "SELECT users.name, movies.file_path, ratings.rating, ratings.rated_at \
+     FROM ratings JOIN users ON users.id = ratings.user_id \
+     JOIN movies ON movies.id = ratings.movie_id ORDER BY users.name, movies.file_path";
+
+/// Newer ratings replace older ones
+const UPSERT_RATING: &str = "INSERT INTO ratings (user_id, movie_id, rating, rated_at) \
+     SELECT users.id, movies.id, ?3, ?4 FROM users, movies \
+     WHERE users.name=?1 AND movies.file_path=?2 \
+     ON CONFLICT (user_id, movie_id) DO UPDATE SET rating=excluded.rating, \
+     rated_at=excluded.rated_at WHERE excluded.rated_at > ratings.rated_at";
+
+const WATCHLIST: &str = "SELECT users.name, movies.file_path, watchlist.added_at \
+     FROM watchlist JOIN users ON users.id = watchlist.user_id \
+     JOIN movies ON movies.id = watchlist.movie_id ORDER BY users.name, movies.file_path";
+
+const CERTIFICATIONS: &str = "SELECT movies.file_path, certifications.country, \
+     certifications.certification FROM certifications \
+     JOIN movies ON movies.id = certifications.movie_id";
+
+const INSERT_CERTIFICATION: &str = "INSERT OR IGNORE INTO certifications \
+     (movie_id, country, certification, min_age) SELECT id, ?2, ?3, ?4 FROM movies \
+     WHERE file_path=?1";
+
+const INSERT_WATCHLIST: &str = "INSERT OR IGNORE INTO watchlist (user_id, movie_id, added_at) \
+     SELECT users.id, movies.id, ?3 FROM users, movies \
+     WHERE users.name=?1 AND movies.file_path=?2";
+
+/// Reads the library into an `Export`. Runs on the writer, so the
+/// export is a consistent snapshot.
+pub fn export(db: SharedDb) -> FutRes<Export> {
+    let movies = format!("{} ORDER BY id", MovieTable::new(db.clone()).select());
+    let users = format!("{} ORDER BY id", User::select_sql());
+
+    let func = async move {
+        let export = db
+            .spawn(Box::new(move |conn: &Connection| {
+                let mut stmt = conn.prepare(&movies)?;
+                let mut movies = stmt
+                    .query_map(params![], MovieTable::from_row)?
+                    .map(|movie| movie.map(ExportMovie::from))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let index = movies
+                    .iter()
+                    .enumerate()
+                    .map(|(i, movie)| (movie.file_path.clone(), i))
+                    .collect::<HashMap<_, _>>();
+                let mut stmt = conn.prepare(CERTIFICATIONS)?;
+                let certifications = stmt.query_map(params![], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                    ))
+                })?;
+                for certification in certifications {
+                    let (file_path, country, certification) = certification?;
+                    if let Some(i) = index.get(&file_path) {
+                        movies[*i].certifications.insert(country, certification);
+                    }
+                }
+
+                let mut stmt = conn.prepare(&users)?;
+                let users = stmt
+                    .query_map(params![], User::from_row)?
+                    .map(|user| {
+                        user.map(|user| ExportUser {
+                            name: user.name,
+                            password_hash: user.password_hash,
+                            is_admin: user.is_admin,
+                            created_at: user.created_at,
+                            pin_hash: user.pin_hash,
+                            pin_failures: user.pin_failures,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let mut stmt = conn.prepare(RESTRICTIONS)?;
+                let mut restrictions = stmt
+                    .query_map(params![], |row| {
+                        Ok(ExportRestriction {
+                            user: row.get(0)?,
+                            country: row.get(1)?,
+                            max_rating: row.get(2)?,
+                            max_age: row.get(3)?,
+                            allow_unrated: row.get(4)?,
+                            ..ExportRestriction::default()
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut stmt = conn.prepare(RESTRICTION_TAGS)?;
+                let tags = stmt.query_map(params![], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, bool>(2)?,
+                    ))
+                })?;
+                for tag in tags {
+                    let (user, tag, blocked) = tag?;
+                    if let Some(r) = restrictions.iter_mut().find(|r| r.user == user) {
+                        if blocked {
+                            r.blocked_tags.push(tag);
+                        } else {
+                            r.allowed_tags.push(tag);
+                        }
+                    }
+                }
+
+                let mut stmt = conn.prepare(COLLECTIONS)?;
+                let mut ids = HashMap::new();
+                let mut collections = stmt
+                    .query_map(params![], |row| {
+                        Ok((
+                            row.get::<_, i32>(0)?,
+                            ExportCollection {
+                                user: row.get(1)?,
+                                name: row.get(2)?,
+                                kind: row.get(3)?,
+                                shared: row.get(4)?,
+                                filter: row.get(5)?,
+                                created_at: row.get(6)?,
+                                items: Vec::new(),
+                            },
+                        ))
+                    })?
+                    .enumerate()
+                    .map(|(i, collection)| {
+                        collection.map(|(id, collection)| {
+                            ids.insert(id, i);
+                            collection
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut stmt = conn.prepare(COLLECTION_ITEMS)?;
+                let items = stmt.query_map(params![], |row| {
+                    Ok((
+                        row.get::<_, i32>(0)?,
+                        ExportCollectionItem {
+                            file_path: row.get(1)?,
+                            added_at: row.get(2)?,
+                        },
+                    ))
+                })?;
+                for item in items {
+                    let (id, item) = item?;
+                    if let Some(i) = ids.get(&id) {
+                        collections[*i].items.push(item);
+                    }
+                }
+
+                let mut stmt = conn.prepare(WATCH_HISTORY)?;
+                let watch_history = stmt
+                    .query_map(params![], |row| {
+                        Ok(ExportProgress {
+                            user: row.get(0)?,
+                            file_path: row.get(1)?,
+                            position: row.get(2)?,
+                            duration: row.get(3)?,
+                            completed: row.get(4)?,
+                            last_watched_at: row.get(5)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let mut stmt = conn.prepare(PLAYS)?;
+                let plays = stmt
+                    .query_map(params![], |row| {
+                        Ok(ExportPlay {
+                            user: row.get(0)?,
+                            file_path: row.get(1)?,
+                            device: row.get(2)?,
+                            started_at: row.get(3)?,
+                            last_watched_at: row.get(4)?,
+                            position: row.get(5)?,
+                            completed: row.get(6)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let mut stmt = conn.prepare(RATINGS)?;
+                let ratings = stmt
+                    .query_map(params![], |row| {
+                        Ok(ExportRating {
+                            user: row.get(0)?,
+                            file_path: row.get(1)?,
+                            rating: row.get(2)?,
+                            rated_at: row.get(3)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let mut stmt = conn.prepare(WATCHLIST)?;
+                let watchlist = stmt
+                    .query_map(params![], |row| {
+                        Ok(ExportWatchlist {
+                            user: row.get(0)?,
+                            file_path: row.get(1)?,
+                            added_at: row.get(2)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(Export {
+                    version: EXPORT_VERSION,
+                    exported_at: Utc::now().timestamp(),
+                    movies,
+                    users,
+                    restrictions,
+                    collections,
+                    watch_history,
+                    plays,
+                    ratings,
+                    watchlist,
+                })
+            }))
+            .await?;
+        Ok(export)
+    };
+    Box::pin(func)
+}
+
+/// Merges an `Export` into the database in one transaction. Movies are
+/// matched by file path: unknown ones are added, known ones without
+/// metadata take the exported metadata. Unknown users are added with
+/// their password hash and PIN, known ones are left alone. Parental
+/// controls are added for users without any, collections for users
+/// without one of the same name and kind. Watch progress is
+/// kept if it is newer than the exported one, the same goes for
+/// ratings. Plays and watchlist entries are added if they are missing.
+pub fn import(db: SharedDb, export: Export) -> FutRes<Imported> {
+    let func = async move {
+        if export.version > EXPORT_VERSION {
+            let msg = format!(
+                "export version {} is newer than this build ({})",
+                export.version, EXPORT_VERSION
+            );
+            return Err(Error::Database(msg.into()));
+        }
+
+        let imported = db
+            .transaction(Box::new(move |tx: &Transaction| {
+                let mut imported = Imported::default();
+
+                for user in export.users.iter() {
+                    imported.users += tx.execute(
+                        "INSERT OR IGNORE INTO users \
+                         (name, password_hash, is_admin, created_at, pin_hash, pin_failures) \
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                        params![
+                            user.name,
+                            user.password_hash,
+                            user.is_admin,
+                            user.created_at,
+                            user.pin_hash,
+                            user.pin_failures
+                        ],
+                    )?;
+                }
+
+                for restriction in export.restrictions.iter() {
+                    let inserted = tx.execute(
+                        INSERT_RESTRICTION,
+                        params![
+                            restriction.user,
+                            restriction.country,
+                            restriction.max_rating,
+                            restriction.max_age,
+                            restriction.allow_unrated
+                        ],
+                    )?;
+                    if inserted == 0 {
+                        continue;
+                    }
+                    let tags = restriction
+                        .allowed_tags
+                        .iter()
+                        .map(|tag| (tag, false))
+                        .chain(restriction.blocked_tags.iter().map(|tag| (tag, true)));
+                    for (tag, blocked) in tags {
+                        tx.execute(
+                            INSERT_RESTRICTION_TAG,
+                            params![restriction.user, tag, blocked],
+                        )?;
+                    }
+                    imported.restrictions += 1;
+                }
+
+                for movie in export.movies.iter() {
+                    let known: Option<(i64, Option<i32>)> = tx
+                        .query_row(
+                            "SELECT id, tmdb_id FROM movies WHERE file_path=?1",
+                            params![movie.file_path],
+                            |row| Ok((row.get(0)?, row.get(1)?)),
+                        )
+                        .optional()?;
+                    match known {
+                        None => insert_movie(tx, &Movie::from(movie.clone()))?,
+                        Some((id, tmdb_id)) if tmdb_id.unwrap_or(0) == 0 && movie.tmdb_id != 0 => {
+                            tx.execute(
+                                "UPDATE movies SET tmdb_id=?1, overview=?2, poster_path=?3, \
+                                 backdrop_path=?4, rating=?5 WHERE id=?6",
+                                params![
+                                    movie.tmdb_id,
+                                    movie.overview,
+                                    movie.poster_path,
+                                    movie.backdrop_path,
+                                    movie.rating,
+                                    id
+                                ],
+                            )?;
+                            insert_genres(tx, id, &movie.genres)?;
+                        }
+                        Some(_) => continue,
+                    }
+                    imported.movies += 1;
+                }
+
+                for movie in export.movies.iter() {
+                    for (country, certification) in movie.certifications.iter() {
+                        let country = country.to_uppercase();
+                        tx.execute(
+                            INSERT_CERTIFICATION,
+                            params![
+                                movie.file_path,
+                                country,
+                                certification,
+                                min_age(&country, certification)
+                            ],
+                        )?;
+                    }
+                }
+
+                for collection in export.collections.iter() {
+                    let inserted = tx.execute(
+                        INSERT_COLLECTION,
+                        params![
+                            collection.user,
+                            collection.name,
+                            collection.kind,
+                            collection.shared,
+                            collection.filter,
+                            collection.created_at
+                        ],
+                    )?;
+                    if inserted == 0 {
+                        continue;
+                    }
+                    let id = tx.last_insert_rowid();
+                    for (position, item) in collection.items.iter().enumerate() {
+                        tx.execute(
+                            INSERT_COLLECTION_ITEM,
+                            params![id, item.file_path, position as i32, item.added_at],
+                        )?;
+                    }
+                    imported.collections += 1;
+                }
+
+                for progress in export.watch_history.iter() {
+                    imported.watch_history += tx.execute(
+                        UPSERT_PROGRESS,
+                        params![
+                            progress.user,
+                            progress.file_path,
+                            progress.position,
+                            progress.duration,
+                            progress.completed,
+                            progress.last_watched_at
+                        ],
+                    )?;
+                }
+
+                for play in export.plays.iter() {
+                    imported.plays += tx.execute(
+                        INSERT_PLAY,
+                        params![
+                            play.user,
+                            play.file_path,
+                            play.device,
+                            play.started_at,
+                            play.last_watched_at,
+                            play.position,
+                            play.completed
+                        ],
+                    )?;
+                }
+
+                let ratings = export
+                    .ratings
+                    .iter()
+                    .filter(|r| (1..=MAX_RATING).contains(&r.rating));
+                for rating in ratings {
+                    imported.ratings += tx.execute(
+                        UPSERT_RATING,
+                        params![
+                            rating.user,
+                            rating.file_path,
+                            rating.rating,
+                            rating.rated_at
+                        ],
+                    )?;
+                }
+
+                for entry in export.watchlist.iter() {
+                    imported.watchlist += tx.execute(
+                        INSERT_WATCHLIST,
+                        params![entry.user, entry.file_path, entry.added_at],
+                    )?;
+                }
+
+                Ok(imported)
+            }))
+            .await?;
+        Ok(imported)
+    };
+    Box::pin(func)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use crate::model::{
+        migrate, Certification, CertificationTable, Collection, CollectionTable, HistoryTable,
+        ProgressTable, Rating, RatingTable, Restriction, RestrictionTable, UserTable,
+        WatchlistEntry, WatchlistTable,
+    };
+    use crate::sqlite::Runtime;
+
+    fn movie(title: &str, file_path: &str, tmdb_id: i32) -> Movie {
+        Movie {
+            id: 0,
+            tmdb_id,
+            title: title.to_owned(),
+            overview: format!("{} overview", title),
+            release_year: 2001,
+            file_path: file_path.to_owned(),
+            poster_path: String::new(),
+            backdrop_path: String::new(),
+            rating: 7.5,
+            resolution: 1080,
+            library: "movies".to_owned(),
+            added_at: 0,
+            genres: vec!["Drama".to_owned()],
+        }
+    }
+
+    async fn database() -> SharedDb {
+        let config = DatabaseConfig {
+            name: "test.db".to_owned(),
+            ..DatabaseConfig::default()
+        };
+        let (db, rt) = Runtime::channel(config);
+        rt.run();
+        migrate(db.clone()).await.unwrap();
+        db
+    }
+
+    #[test]
+    fn test_export_import() {
+        let func = async {
+            let db = database().await;
+            let movies = MovieTable::new(db.clone());
+            movies
+                .save(movie("Amélie", "/m/amelie.mkv", 194))
+                .await
+                .unwrap();
+            movies
+                .save(movie("Heat", "/m/heat.mkv", 949))
+                .await
+                .unwrap();
+            let users = UserTable::new(db.clone());
+            users.save(User::new("jan", "secret", true)).await.unwrap();
+            users.save(User::new("kid", "secret", false)).await.unwrap();
+            users.set_pin(2, Some("1234")).await.unwrap();
+            users.pin_attempt(2).await.unwrap();
+            let kids = Restriction {
+                user_id: 2,
+                country: "US".to_owned(),
+                max_rating: "PG".to_owned(),
+                max_age: Some(8),
+                allow_unrated: false,
+                allowed_tags: vec!["Drama".to_owned()],
+                blocked_tags: vec!["Horror".to_owned()],
+            };
+            RestrictionTable::new(db.clone())
+                .save(kids.clone())
+                .await
+                .unwrap();
+            ProgressTable::new(db.clone())
+                .report(1, 2, 600.0, 6000.0, 0.9)
+                .await
+                .unwrap();
+            HistoryTable::new(db.clone())
+                .record(1, 2, "tv", 600.0, false)
+                .await
+                .unwrap();
+            let rating = Rating {
+                user_id: 1,
+                movie_id: 1,
+                rating: 9,
+                rated_at: 0,
+            };
+            RatingTable::new(db.clone()).save(rating).await.unwrap();
+            let entry = WatchlistEntry {
+                user_id: 1,
+                movie_id: 2,
+                added_at: 0,
+            };
+            WatchlistTable::new(db.clone()).save(entry).await.unwrap();
+            CertificationTable::new(db.clone())
+                .set(2, vec![Certification::new(2, "US", "R")])
+                .await
+                .unwrap();
+
+            let collections = CollectionTable::new(db.clone());
+            let playlist = Collection {
+                id: 0,
+                user_id: 1,
+                name: "Tonight".to_owned(),
+                kind: CollectionKind::Playlist,
+                shared: true,
+                filter: String::new(),
+                created_at: 0,
+            };
+            let playlist = collections.create(playlist).await.unwrap();
+            collections.add(playlist.id, 2).await.unwrap();
+            collections.add(playlist.id, 1).await.unwrap();
+            let smart = Collection {
+                name: "Dramas".to_owned(),
+                kind: CollectionKind::Smart,
+                shared: false,
+                filter: "genre=Drama".to_owned(),
+                ..playlist.clone()
+            };
+            collections.create(smart).await.unwrap();
+
+            let export = export(db).await.unwrap();
+            assert_eq!(EXPORT_VERSION, export.version);
+            assert_eq!(2, export.movies.len());
+            assert_eq!(vec!["Drama".to_owned()], export.movies[0].genres);
+            assert_eq!("jan", export.users[0].name);
+            assert_eq!("/m/heat.mkv", export.watch_history[0].file_path);
+            assert_eq!("R", export.movies[1].certifications["US"]);
+            assert_eq!("kid", export.restrictions[0].user);
+            assert_eq!("/m/heat.mkv", export.collections[0].items[0].file_path);
+            assert_eq!(
+                vec!["Horror".to_owned()],
+                export.restrictions[0].blocked_tags
+            );
+
+            // a rebuilt database: the library was scanned again, which
+            // adds the movies in another order and without metadata
+            let db = database().await;
+            let movies = MovieTable::new(db.clone());
+            movies.save(movie("Heat", "/m/heat.mkv", 0)).await.unwrap();
+
+            let json = serde_json::to_string(&export).unwrap();
+            let imported = import(db.clone(), serde_json::from_str(&json).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(
+                Imported {
+                    movies: 2,
+                    users: 2,
+                    restrictions: 1,
+                    collections: 2,
+                    watch_history: 1,
+                    plays: 1,
+                    ratings: 1,
+                    watchlist: 1,
+                },
+                imported
+            );
+
+            let heat = movies.by_id(1).await.unwrap().unwrap();
+            assert_eq!(949, heat.tmdb_id);
+            assert_eq!(vec!["Drama".to_owned()], heat.genres);
+            let user = UserTable::new(db.clone())
+                .by_name("jan")
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(crate::auth::verify_password("secret", &user.password_hash));
+            // the kid is still restricted and its PIN still counts the
+            // failed attempt
+            let kid = UserTable::new(db.clone())
+                .by_name("kid")
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(crate::auth::verify_password("1234", &kid.pin_hash));
+            assert_eq!(1, kid.pin_failures);
+            let restored = RestrictionTable::new(db.clone()).get(kid.id).await.unwrap();
+            assert_eq!(
+                Some(Restriction {
+                    user_id: kid.id,
+                    ..kids
+                }),
+                restored
+            );
+            assert!(movies.for_user(heat.id, kid.id).await.unwrap().is_none());
+            let state = ProgressTable::new(db.clone())
+                .state(user.id, heat.id)
+                .await
+                .unwrap();
+            assert_eq!(600.0, state.resume_position);
+            assert!(state.in_watchlist);
+            let collections = CollectionTable::new(db.clone());
+            let restored = collections.visible(user.id).await.unwrap();
+            assert_eq!(
+                vec![("Dramas", "genre=Drama"), ("Tonight", "")],
+                restored
+                    .iter()
+                    .map(|c| (c.name.as_str(), c.filter.as_str()))
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(CollectionKind::Playlist, restored[1].kind);
+            let ids = collections
+                .movie_ids(restored[1].id, user.id, 10, 0)
+                .await
+                .unwrap()
+                .items;
+            // heat came first and has the id 1 in the rebuilt database
+            assert_eq!(vec![heat.id, 2], ids);
+            let plays = HistoryTable::new(db.clone())
+                .page(user.id, 10, 0)
+                .await
+                .unwrap();
+            assert_eq!("tv", plays.items[0].device);
+            assert_eq!(heat.id, plays.items[0].movie_id);
+            let restriction = Restriction {
+                user_id: user.id,
+                country: "US".to_owned(),
+                max_rating: "PG".to_owned(),
+                max_age: Some(8),
+                allow_unrated: false,
+                allowed_tags: vec![],
+                blocked_tags: vec![],
+            };
+            RestrictionTable::new(db.clone())
+                .save(restriction)
+                .await
+                .unwrap();
+            let hidden = movies.for_user(heat.id, user.id).await.unwrap();
+            assert!(hidden.is_none());
+
+            // importing again changes nothing
+            let imported = import(db.clone(), export.clone()).await.unwrap();
+            assert_eq!(Imported::default(), imported);
+
+            let newer = Export {
+                version: EXPORT_VERSION + 1,
+                ..Export::default()
+            };
+            assert!(import(db, newer).await.is_err());
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+
+    #[test]
+    fn test_missing_fields() {
+        let export: Export =
+            serde_json::from_str(r#"{"version": 1, "movies": [{"file_path": "/m/a.mkv"}]}"#)
+                .unwrap();
+        assert_eq!("/m/a.mkv", export.movies[0].file_path);
+        assert!(export.movies[0].genres.is_empty());
+        assert!(export.users.is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+use super::query::Page;
+use super::{allowed, FutRes, Model, Movie, Table};
+use crate::sqlite::{params, Connection, OptionalExtension, SharedDb};
+use serde::{Deserialize, Serialize};
+
+/// A report continues the latest play of a movie on the same device if
+/// that one was reported less than this many seconds ago
+pub const PLAY_GAP: i64 = 30 * 60;
+
+/// Longest device name stored with a play
+const MAX_DEVICE: usize = 255;
+
+/// One play of a movie by a user, from the first to the last progress
+/// report
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Model)]
+#[model(table = "watch_history")]
+pub struct Play {
+    #[model(id)]
+    pub id: i32,
+    #[model(sql = "INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE")]
+    pub user_id: i32,
+    #[model(sql = "INTEGER NOT NULL REFERENCES movies (id) ON DELETE CASCADE")]
+    pub movie_id: i32,
+    /// Name of the player, empty if unknown
+    #[model(sql = "VARCHAR(255) NOT NULL DEFAULT ''")]
+    pub device: String,
+    /// Unix timestamp of the first report
+    #[model(now)]
+    pub started_at: i64,
+    /// Unix timestamp of the last report
+    #[model(now)]
+    pub last_watched_at: i64,
+    /// Last reported position in seconds
+    #[model(sql = "REAL NOT NULL DEFAULT 0")]
+    pub position: f64,
+    /// The movie was watched to the end in this play
+    #[model(sql = "BOOLEAN NOT NULL DEFAULT 0")]
+    pub completed: bool,
+}
+
+/// A play together with its movie, as returned by `/users/me/history`
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct HistoryEntry {
+    #[serde(flatten)]
+    pub play: Play,
+    pub movie: Movie,
+}
+
+/// Represents the table watch_history in the database
+pub struct HistoryTable {
+    db: SharedDb,
+}
+
+impl HistoryTable {
+    /// Create a new handler to the watch_history table
+    pub fn new(db: SharedDb) -> HistoryTable {
+        HistoryTable { db }
+    }
+
+    /// Records a progress report. It continues the latest play of the
+    /// movie on `device` if that was reported within `PLAY_GAP`, else a
+    /// new play is started.
+    pub fn record(
+        &self,
+        user_id: i32,
+        movie_id: i32,
+        device: &str,
+        position: f64,
+        completed: bool,
+    ) -> FutRes<()> {
+        let db = self.db.clone();
+        let device = device.chars().take(MAX_DEVICE).collect::<String>();
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                let latest: Option<i32> = conn
+                    .query_row(
+                        "SELECT id FROM watch_history WHERE user_id=?1 AND movie_id=?2 \
+                         AND device=?3 AND last_watched_at >= strftime('%s', 'now') - ?4 \
+                         ORDER BY last_watched_at DESC, id DESC LIMIT 1",
+                        params![user_id, movie_id, device, PLAY_GAP],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+
+                match latest {
+                    Some(id) => conn.execute(
+                        "UPDATE watch_history SET last_watched_at=strftime('%s', 'now'), \
+                         position=?1, completed=(completed OR ?2) WHERE id=?3",
+                        params![position, completed, id],
+                    ),
+                    None => {
+                        let play = Play {
+                            id: 0,
+                            user_id,
+                            movie_id,
+                            device: device.clone(),
+                            started_at: 0,
+                            last_watched_at: 0,
+                            position,
+                            completed,
+                        };
+                        conn.execute(&Play::insert_sql(), &play.insert_values()?)
+                    }
+                }
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// One page of the plays of a user, latest first. Plays of movies
+    /// hidden from the user are left out.
+    pub fn page(&self, user_id: i32, limit: u32, offset: u32) -> FutRes<Page<Play>> {
+        let db = self.db.clone();
+        let count = format!(
+            "SELECT COUNT(*) FROM watch_history WHERE user_id=?1 AND {}",
+            allowed("movie_id", "?1")
+        );
+        let select = format!(
+            "{} WHERE user_id=?1 AND {} ORDER BY last_watched_at DESC, id DESC \
+             LIMIT ?2 OFFSET ?3",
+            self.select(),
+            allowed("movie_id", "?1")
+        );
+
+        let func = async move {
+            let page = db
+                .read(Box::new(move |conn: &Connection| {
+                    let total = conn.query_row(&count, params![user_id], |row| row.get(0))?;
+                    let mut stmt = conn.prepare(&select)?;
+                    let iter = stmt.query_map(params![user_id, limit, offset], Play::from_row)?;
+                    Ok(Page {
+                        total,
+                        limit,
+                        offset,
+                        items: iter.collect::<Result<Vec<_>, _>>()?,
+                    })
+                }))
+                .await?;
+            Ok(page)
+        };
+        Box::pin(func)
+    }
+}
+
+impl Table for HistoryTable {
+    type Model = Play;
+
+    fn db(&self) -> SharedDb {
+        self.db.clone()
+    }
+
+    fn create_table(&self) -> FutRes<()> {
+        let db = self.db.clone();
+        let create = format!(
+            "{};\nCREATE INDEX watch_history_user ON watch_history (user_id, last_watched_at);",
+            Play::create_sql()
+        );
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute_batch(&create)
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use crate::model::{migrate, MovieTable, User, UserTable};
+    use crate::sqlite::Runtime;
+
+    #[test]
+    fn test_record() {
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (db, rt) = Runtime::channel(config);
+            rt.run();
+            migrate(db.clone()).await.unwrap();
+            let mut movie = Movie::from(crate::model::export::ExportMovie::default());
+            movie.title = "Heat".to_owned();
+            MovieTable::new(db.clone()).save(movie).await.unwrap();
+            UserTable::new(db.clone())
+                .save(User::new("jan", "secret", false))
+                .await
+                .unwrap();
+            let t = HistoryTable::new(db.clone());
+
+            t.record(1, 1, "tv", 60.0, false).await.unwrap();
+            t.record(1, 1, "tv", 5900.0, true).await.unwrap();
+            // reports after the end don't start a new play
+            t.record(1, 1, "tv", 5950.0, false).await.unwrap();
+            t.record(1, 1, "phone", 30.0, false).await.unwrap();
+
+            let page = t.page(1, 10, 0).await.unwrap();
+            assert_eq!(2, page.total);
+            let tv = page.items.iter().find(|p| p.device == "tv").unwrap();
+            assert_eq!(5950.0, tv.position);
+            assert!(tv.completed);
+            assert!(tv.started_at > 0);
+            assert_eq!(1, t.page(1, 1, 1).await.unwrap().items.len());
+
+            // a play reported long ago is not continued
+            db.spawn(Box::new(|conn: &Connection| {
+                conn.execute("UPDATE watch_history SET last_watched_at=0", params![])
+            }))
+            .await
+            .unwrap();
+            t.record(1, 1, "tv", 10.0, false).await.unwrap();
+            assert_eq!(3, t.page(1, 10, 0).await.unwrap().total);
+            assert_eq!(0, t.page(2, 10, 0).await.unwrap().total);
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+}
@@ -0,0 +1,438 @@
+use super::{FutRes, Model, Table};
+use crate::sqlite::{params, Connection, SharedDb, SqlResult};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Lifecycle of a job
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+
+    /// Finished jobs never run again
+    pub fn is_finished(self) -> bool {
+        !matches!(self, JobState::Queued | JobState::Running)
+    }
+}
+
+impl FromStr for JobState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<JobState, String> {
+        match s {
+            "queued" => Ok(JobState::Queued),
+            "running" => Ok(JobState::Running),
+            "done" => Ok(JobState::Done),
+            "failed" => Ok(JobState::Failed),
+            "cancelled" => Ok(JobState::Cancelled),
+            _ => Err(format!("unknown job state `{}`", s)),
+        }
+    }
+}
+
+impl ToSql for JobState {
+    fn to_sql(&self) -> SqlResult<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl FromSql for JobState {
+    fn column_result(value: ValueRef) -> FromSqlResult<JobState> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: String| FromSqlError::Other(e.into()))
+    }
+}
+
+/// A unit of background work, see `crate::jobs`
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Model)]
+#[model(table = "jobs")]
+pub struct Job {
+    #[model(id)]
+    pub id: i32,
+    #[model(sql = "VARCHAR(255) NOT NULL")]
+    pub kind: String,
+    /// Jobs with a higher priority run first
+    #[model(sql = "INTEGER NOT NULL DEFAULT 0")]
+    pub priority: i32,
+    #[model(sql = "VARCHAR(16) NOT NULL")]
+    pub state: JobState,
+    /// Number of times the job was started
+    #[model(sql = "INTEGER NOT NULL DEFAULT 0")]
+    pub attempts: i32,
+    #[model(sql = "INTEGER NOT NULL DEFAULT 1")]
+    pub max_attempts: i32,
+    /// Fraction of the work done, from 0 to 1
+    #[model(sql = "REAL NOT NULL DEFAULT 0")]
+    pub progress: f64,
+    /// Latest status or error message
+    #[model(sql = "TEXT NOT NULL DEFAULT ''")]
+    pub message: String,
+    /// Unix timestamp before which the job is not started, used to
+    /// delay retries
+    #[model(sql = "INTEGER NOT NULL DEFAULT 0")]
+    pub run_at: i64,
+    #[model(now)]
+    pub created_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+}
+
+impl Job {
+    /// A new queued job, ready to run
+    pub fn new(kind: &str, priority: i32, max_attempts: i32) -> Job {
+        Job {
+            id: 0,
+            kind: kind.to_owned(),
+            priority,
+            state: JobState::Queued,
+            attempts: 0,
+            max_attempts,
+            progress: 0.0,
+            message: "".to_owned(),
+            run_at: 0,
+            created_at: 0,
+            started_at: None,
+            finished_at: None,
+        }
+    }
+}
+
+/// Represents the table jobs in the database
+pub struct JobTable {
+    db: SharedDb,
+}
+
+impl JobTable {
+    /// Create a new handler to the jobs table
+    pub fn new(db: SharedDb) -> JobTable {
+        JobTable { db }
+    }
+
+    /// Queues a job and returns it with its id. If a job of the same
+    /// kind is already queued or running that one is returned instead.
+    pub fn enqueue(&self, job: Job) -> FutRes<Job> {
+        let db = self.db.clone();
+        let active = format!(
+            "{} WHERE kind=?1 AND state IN ('queued', 'running') LIMIT 1",
+            self.select()
+        );
+        let select = format!("{} WHERE id=?1", self.select());
+
+        let func = async move {
+            let job = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    let mut stmt = conn.prepare(&active)?;
+                    let mut iter = stmt.query_map(params![job.kind], Job::from_row)?;
+                    if let Some(active) = iter.next().transpose()? {
+                        return Ok(active);
+                    }
+
+                    insert(conn, &job)?;
+                    let id = conn.last_insert_rowid();
+                    conn.query_row(&select, params![id], Job::from_row)
+                }))
+                .await?;
+            Ok(job)
+        };
+        Box::pin(func)
+    }
+
+    /// Takes the next job which is due, by priority and age, and marks
+    /// it as running
+    pub fn claim(&self) -> FutRes<Option<Job>> {
+        let db = self.db.clone();
+        let next = "SELECT id FROM jobs WHERE state='queued' AND run_at <= strftime('%s', 'now') \
+                    ORDER BY priority DESC, id LIMIT 1";
+        let select = format!("{} WHERE id=?1", self.select());
+
+        let func = async move {
+            let job = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    let mut stmt = conn.prepare(next)?;
+                    let mut iter = stmt.query_map(params![], |row| row.get(0))?;
+                    match iter.next().transpose()? {
+                        Some(id) => start(conn, &select, id),
+                        None => Ok(None),
+                    }
+                }))
+                .await?;
+            Ok(job)
+        };
+        Box::pin(func)
+    }
+
+    /// Marks a queued job as running, regardless of its priority or
+    /// delay. Returns `None` if the job is not queued.
+    pub fn start(&self, id: i32) -> FutRes<Option<Job>> {
+        let db = self.db.clone();
+        let select = format!("{} WHERE id=?1", self.select());
+
+        let func = async move {
+            let job = db
+                .spawn(Box::new(move |conn: &Connection| start(conn, &select, id)))
+                .await?;
+            Ok(job)
+        };
+        Box::pin(func)
+    }
+
+    /// Queues the jobs which were running when the server stopped
+    pub fn requeue_running(&self) -> FutRes<usize> {
+        let db = self.db.clone();
+
+        let func = async move {
+            let requeued = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    conn.execute(
+                        "UPDATE jobs SET state='queued' WHERE state='running'",
+                        params![],
+                    )
+                }))
+                .await?;
+            Ok(requeued)
+        };
+        Box::pin(func)
+    }
+
+    /// Updates the progress of a running job
+    pub fn set_progress(&self, id: i32, progress: f64, message: &str) -> FutRes<()> {
+        let db = self.db.clone();
+        let message = message.to_owned();
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute(
+                    "UPDATE jobs SET progress=?2, message=?3 WHERE id=?1",
+                    params![id, progress.clamp(0.0, 1.0), message],
+                )
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// Moves a job into one of the finished states
+    pub fn finish(&self, id: i32, state: JobState, message: &str) -> FutRes<()> {
+        let db = self.db.clone();
+        let message = message.to_owned();
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute(
+                    "UPDATE jobs SET state=?2, message=?3, \
+                     progress=CASE WHEN ?2='done' THEN 1 ELSE progress END, \
+                     finished_at=strftime('%s', 'now') WHERE id=?1",
+                    params![id, state, message],
+                )
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// Queues a failed job again, to be started after `delay` seconds
+    pub fn retry(&self, id: i32, delay: i64, message: &str) -> FutRes<()> {
+        let db = self.db.clone();
+        let message = message.to_owned();
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute(
+                    "UPDATE jobs SET state='queued', message=?3, \
+                     run_at=strftime('%s', 'now') + ?2 WHERE id=?1",
+                    params![id, delay, message],
+                )
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// Cancels a job if it is still queued. Returns `false` if the job
+    /// is not queued (anymore).
+    pub fn cancel_queued(&self, id: i32) -> FutRes<bool> {
+        let db = self.db.clone();
+
+        let func = async move {
+            let changed = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    conn.execute(
+                        "UPDATE jobs SET state='cancelled', finished_at=strftime('%s', 'now') \
+                         WHERE id=?1 AND state='queued'",
+                        params![id],
+                    )
+                }))
+                .await?;
+            Ok(changed > 0)
+        };
+        Box::pin(func)
+    }
+
+    /// Latest jobs first, optionally only the ones in `state`
+    pub fn list(&self, state: Option<JobState>, limit: u32) -> FutRes<Vec<Job>> {
+        let db = self.db.clone();
+        let select = format!(
+            "{} WHERE ?1 IS NULL OR state=?1 ORDER BY id DESC LIMIT ?2",
+            self.select()
+        );
+
+        let func = async move {
+            let jobs = db
+                .read(Box::new(move |conn: &Connection| {
+                    let mut stmt = conn.prepare(&select)?;
+                    let iter = stmt.query_map(params![state, limit], Job::from_row)?;
+                    iter.collect::<Result<Vec<_>, _>>()
+                }))
+                .await?;
+            Ok(jobs)
+        };
+        Box::pin(func)
+    }
+
+    /// Deletes jobs which finished before the unix timestamp `before`
+    pub fn delete_finished(&self, before: i64) -> FutRes<usize> {
+        let db = self.db.clone();
+
+        let func = async move {
+            let deleted = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    conn.execute(
+                        "DELETE FROM jobs WHERE finished_at < ?1 \
+                         AND state IN ('done', 'failed', 'cancelled')",
+                        params![before],
+                    )
+                }))
+                .await?;
+            Ok(deleted)
+        };
+        Box::pin(func)
+    }
+}
+
+fn start(conn: &Connection, select: &str, id: i32) -> SqlResult<Option<Job>> {
+    let changed = conn.execute(
+        "UPDATE jobs SET state='running', attempts=attempts + 1, progress=0, \
+         message='', started_at=strftime('%s', 'now') WHERE id=?1 AND state='queued'",
+        params![id],
+    )?;
+    if changed == 0 {
+        return Ok(None);
+    }
+    conn.query_row(select, params![id], Job::from_row).map(Some)
+}
+
+fn insert(conn: &Connection, job: &Job) -> SqlResult<usize> {
+    conn.execute(&Job::insert_sql(), &job.insert_values()?)
+}
+
+impl Table for JobTable {
+    type Model = Job;
+
+    fn db(&self) -> SharedDb {
+        self.db.clone()
+    }
+
+    /// Create the table
+    fn create_table(&self) -> FutRes<()> {
+        let db = self.db.clone();
+        let create = format!(
+            "{};\nCREATE INDEX jobs_state ON jobs (state, priority);",
+            Job::create_sql()
+        );
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute_batch(&create)
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use crate::sqlite::Runtime;
+
+    #[test]
+    fn test_jobs() {
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (db, rt) = Runtime::channel(config);
+            rt.run();
+            let t = JobTable::new(db);
+            t.create_table().await.unwrap();
+
+            let scan = t.enqueue(Job::new("scan", 0, 3)).await.unwrap();
+            assert_eq!(JobState::Queued, scan.state);
+            // only one active job per kind
+            assert_eq!(scan.id, t.enqueue(Job::new("scan", 5, 3)).await.unwrap().id);
+            let save = t.enqueue(Job::new("save", 10, 1)).await.unwrap();
+
+            // higher priority first
+            let job = t.claim().await.unwrap().unwrap();
+            assert_eq!(save.id, job.id);
+            assert_eq!(JobState::Running, job.state);
+            assert_eq!(1, job.attempts);
+            t.finish(job.id, JobState::Done, "").await.unwrap();
+
+            let job = t.claim().await.unwrap().unwrap();
+            assert_eq!(scan.id, job.id);
+            assert_eq!(None, t.claim().await.unwrap());
+            assert_eq!(None, t.start(job.id).await.unwrap());
+
+            assert_eq!(1, t.requeue_running().await.unwrap());
+            let job = t.start(job.id).await.unwrap().unwrap();
+            assert_eq!(2, job.attempts);
+
+            // retries are delayed
+            t.retry(job.id, 60, "boom").await.unwrap();
+            assert_eq!(None, t.claim().await.unwrap());
+            let job = t.by_id(scan.id).await.unwrap().unwrap();
+            assert_eq!(JobState::Queued, job.state);
+            assert_eq!("boom", job.message);
+
+            assert!(t.cancel_queued(job.id).await.unwrap());
+            assert!(!t.cancel_queued(job.id).await.unwrap());
+
+            let done = t.list(Some(JobState::Done), 10).await.unwrap();
+            assert_eq!(1, done.len());
+            assert_eq!(1.0, done[0].progress);
+            assert_eq!(2, t.list(None, 10).await.unwrap().len());
+
+            assert_eq!(2, t.delete_finished(i64::MAX).await.unwrap());
+            assert!(t.all().await.unwrap().is_empty());
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+}
@@ -0,0 +1,129 @@
+use super::error::Error;
+use super::movie::ADD_PEOPLE;
+use super::share::ADD_PLAYED_AT;
+use super::user::{ADD_PIN, ADD_PIN_FAILURES};
+use super::{
+    CertificationTable, CollectionTable, FutRes, HistoryTable, JobTable, MovieTable, ProgressTable,
+    RatingTable, RestrictionTable, SearchIndex, SecretTable, ShareTable, Table, TrickplayTable,
+    UserTable, WatchlistTable,
+};
+use crate::sqlite::{params, Connection, SharedDb};
+
+/// Version of the schema created by this build, stored in the database
+/// as `user_version`
+pub const SCHEMA_VERSION: i32 = 9;
+
+/// Reads the schema version of the database, 0 for an empty database
+pub fn schema_version(db: SharedDb) -> FutRes<i32> {
+    let func = async move {
+        let version = db
+            .spawn(Box::new(|conn: &Connection| {
+                conn.query_row("PRAGMA user_version", params![], |row| row.get(0))
+            }))
+            .await?;
+        Ok(version)
+    };
+    Box::pin(func)
+}
+
+/// Brings the schema of the database up to `SCHEMA_VERSION`. Returns
+/// the version the database had before.
+pub fn migrate(db: SharedDb) -> FutRes<i32> {
+    let func = async move {
+        let version = schema_version(db.clone()).await?;
+        if version > SCHEMA_VERSION {
+            let msg = format!(
+                "database schema {} is newer than this build ({})",
+                version, SCHEMA_VERSION
+            );
+            return Err(Error::Database(msg.into()));
+        }
+
+        if version < 1 {
+            MovieTable::new(db.clone()).create_table().await?;
+            SearchIndex::new(db.clone()).create_index().await?;
+            UserTable::new(db.clone()).create_table().await?;
+            ProgressTable::new(db.clone()).create_table().await?;
+            TrickplayTable::new(db.clone()).create_table().await?;
+            JobTable::new(db.clone()).create_table().await?;
+        }
+        if version < 2 {
+            HistoryTable::new(db.clone()).create_table().await?;
+            RatingTable::new(db.clone()).create_table().await?;
+            WatchlistTable::new(db.clone()).create_table().await?;
+        }
+        if version < 3 {
+            CollectionTable::new(db.clone()).create_table().await?;
+        }
+        if version < 4 {
+            // new databases have the column already
+            if version > 0 {
+                db.spawn(Box::new(|conn: &Connection| conn.execute_batch(ADD_PIN)))
+                    .await?;
+            }
+            CertificationTable::new(db.clone()).create_table().await?;
+            RestrictionTable::new(db.clone()).create_table().await?;
+        }
+        if version < 5 {
+            SecretTable::new(db.clone()).create_table().await?;
+        }
+        if version < 6 {
+            ShareTable::new(db.clone()).create_table().await?;
+        }
+        // new databases have the column already
+        if version > 0 && version < 7 {
+            db.spawn(Box::new(|conn: &Connection| {
+                conn.execute_batch(ADD_PIN_FAILURES)
+            }))
+            .await?;
+        }
+        // shares are created with the column since version 8
+        if version > 5 && version < 8 {
+            db.spawn(Box::new(|conn: &Connection| {
+                conn.execute_batch(ADD_PLAYED_AT)
+            }))
+            .await?;
+        }
+        // the search index gains the people of the movies
+        if version > 0 && version < 9 {
+            db.spawn(Box::new(|conn: &Connection| conn.execute_batch(ADD_PEOPLE)))
+                .await?;
+            SearchIndex::new(db.clone()).create_index().await?;
+        }
+
+        db.spawn(Box::new(|conn: &Connection| {
+            conn.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))
+        }))
+        .await?;
+        Ok(version)
+    };
+    Box::pin(func)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use crate::sqlite::Runtime;
+
+    #[test]
+    fn test_migrate() {
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (db, rt) = Runtime::channel(config);
+            rt.run();
+
+            assert_eq!(0, migrate(db.clone()).await.unwrap());
+            assert_eq!(SCHEMA_VERSION, schema_version(db.clone()).await.unwrap());
+            // migrating again is a no-op
+            assert_eq!(SCHEMA_VERSION, migrate(db.clone()).await.unwrap());
+            assert!(MovieTable::new(db).all().await.unwrap().is_empty());
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+}
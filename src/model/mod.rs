@@ -1,7 +1,42 @@
+mod certification;
+mod collection;
 mod error;
+mod export;
+mod history;
+mod job;
+mod migrate;
 mod movie;
-
+mod progress;
+mod query;
+mod rating;
+mod restriction;
+mod search;
+mod secret;
+mod share;
+mod table;
+mod trickplay;
+mod user;
+mod watchlist;
+
+pub use certification::{min_age, Certification, CertificationTable};
+pub use collection::{Collection, CollectionKind, CollectionTable};
+pub use export::{export, import};
+pub use history::{HistoryEntry, HistoryTable};
+pub use job::{Job, JobState, JobTable};
+pub use migrate::{migrate, SCHEMA_VERSION};
 pub use movie::{Movie, MovieTable};
+pub use moviebay_derive::Model;
+pub use progress::{ProgressTable, UserMovie, WatchState};
+pub use query::{MovieQuery, Order, Page, PageQuery, SortBy, MAX_LIMIT};
+pub use rating::{Rating, RatingTable, MAX_RATING};
+pub use restriction::{allowed, Restriction, RestrictionTable};
+pub use search::{match_expr, SearchIndex, SearchQuery, SearchResults};
+pub use secret::{SecretTable, URL_KEY};
+pub use share::{Share, ShareTable, VIEWING};
+pub use table::{to_value, Model, Table};
+pub use trickplay::{Trickplay, TrickplayTable};
+pub use user::{User, UserTable};
+pub use watchlist::{WatchlistEntry, WatchlistTable};
 
 use error::Error;
 use std::future::Future;
@@ -9,30 +44,3 @@ use std::pin::Pin;
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 pub(crate) type FutRes<T> = Pin<Box<dyn Future<Output = Result<T>> + Send + Sync>>;
-
-// #[async_trait]
-pub trait Model {
-    // fn fetch() -> Self;
-    // async fn by_id(t: impl Table, id: i32) -> Self;
-}
-
-pub trait Table {
-    type Model;
-
-    /// Create the table
-    fn create_table(&self) -> FutRes<()>;
-
-    /// Trys to fetch a model by id
-    fn by_id(&self, id: i32) -> FutRes<Option<Self::Model>>;
-
-    /// Get all entries from the table
-    /// WARNING: This can take a lot of resouces depending on the
-    /// table size
-    fn all(&self) -> FutRes<Vec<Self::Model>>;
-
-    /// Saves a model
-    fn save(&self, model: Self::Model) -> FutRes<()>;
-
-    /// Get the name of the table
-    fn get_name(&self) -> &str;
-}
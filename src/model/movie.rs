@@ -1,71 +1,60 @@
-use super::{FutRes, Model, Table};
-use crate::sqlite::{params, Connection, SharedDb};
+use super::query::{MovieQuery, Page};
+use super::{allowed, FutRes, Model, Table, Trickplay};
+use crate::sqlite::{params, Connection, OptionalExtension, Row, SharedDb, SqlResult, Transaction};
 use serde::{Deserialize, Serialize};
 
-macro_rules! mk_movie {
-    ($x:expr) => {
-        Ok(Movie {
-            id: $x.get(0)?,
-            tmdb_id: $x.get(1)?,
-            title: $x.get(2)?,
-            overview: $x.get(3)?,
-            release_year: $x.get(4)?,
-            file_path: $x.get(5)?,
-            poster_path: $x.get(6)?,
-            backdrop_path: $x.get(7)?,
-        })
-    };
+/// Subquery to fetch the genres of a movie as one comma separated column
+const GENRES: &str =
+    "(SELECT group_concat(genre, ',') FROM movie_genres WHERE movie_genres.movie_id = movies.id)";
+
+/// Adds the names of the cast and crew to the movies table of schema
+/// version 8 and below. They are one per line and only kept for the
+/// search index.
+pub(super) const ADD_PEOPLE: &str = "ALTER TABLE movies ADD COLUMN people TEXT NOT NULL DEFAULT ''";
+
+fn split_genres(genres: Option<String>) -> Vec<String> {
+    match genres {
+        Some(genres) => genres.split(',').map(|g| g.to_owned()).collect(),
+        None => Vec::new(),
+    }
+}
+
+pub(super) fn insert_movie(conn: &Connection, model: &Movie) -> SqlResult<()> {
+    conn.execute(&Movie::insert_sql(), &model.insert_values()?)?;
+    insert_genres(conn, conn.last_insert_rowid(), &model.genres)
+}
+
+pub(super) fn insert_genres(conn: &Connection, id: i64, genres: &[String]) -> SqlResult<()> {
+    for genre in genres.iter() {
+        conn.execute(
+            "INSERT OR IGNORE INTO movie_genres (movie_id, genre) VALUES (?1, ?2)",
+            params![id, genre],
+        )?;
+    }
+    Ok(())
 }
 
 /// Represents the tabe movies in the databases
 pub struct MovieTable {
     db: SharedDb,
-    fields: [&'static str; 8],
-    name: String,
 }
 
 impl MovieTable {
     /// Create a new handler to the movies table
     pub fn new(db: SharedDb) -> MovieTable {
-        let fields = [
-            "id",
-            "tmdb_id",
-            "title",
-            "overview",
-            "release_year",
-            "file_path",
-            "poster_path",
-            "backdrop_path",
-        ];
-        MovieTable {
-            db,
-            fields,
-            name: "movies".to_owned(),
-        }
+        MovieTable { db }
     }
-}
-
-impl Table for MovieTable {
-    type Model = Movie;
 
-    fn create_table(&self) -> FutRes<()> {
+    /// Store many movies in one transaction. Nothing is stored if one
+    /// of them fails.
+    pub fn save_all(&self, models: Vec<Movie>) -> FutRes<()> {
         let db = self.db.clone();
-
         let func = async move {
-            db.spawn(Box::new(|conn: &Connection| {
-                conn.execute(
-                    "CREATE TABLE movies (
-                    id              INTEGER PRIMARY KEY,
-                    tmdb_id         INTEGER,
-                    title           VARCHAR(255) NOT NULL,
-                    overview        TEXT,
-                    release_year    INTEGER NOT NULL,
-                    file_path       VARCHAR(255) NOT NULL,
-                    poster_path     VARCHAR(255),
-                    backdrop_path   VARCHAR(255)
-                )",
-                    params![],
-                )
+            db.transaction(Box::new(move |tx: &Transaction| {
+                for model in models.iter() {
+                    insert_movie(tx, model)?;
+                }
+                Ok(())
             }))
             .await?;
             Ok(())
@@ -73,29 +62,103 @@ impl Table for MovieTable {
         Box::pin(func)
     }
 
-    fn by_id(&self, id: i32) -> FutRes<Option<Self::Model>> {
+    /// Fetch one page of movies matching the filters of `query`,
+    /// together with the total number of matches.
+    pub fn query(&self, query: MovieQuery) -> FutRes<Page<Movie>> {
         let db = self.db.clone();
+        let (filter, values) = query.where_clause();
+        let count = format!("SELECT COUNT(*) FROM {} {}", Movie::TABLE, filter);
         let select = format!(
-            "SELECT {} FROM {} WHERE ID=?1 LIMIT 1",
-            &self.fields.join(","),
-            self.get_name()
+            "{} {} {} LIMIT {} OFFSET {}",
+            self.select(),
+            filter,
+            query.order_clause(),
+            query.limit(),
+            query.offset
         );
 
         let func = async move {
-            let movie = db
-                .spawn(Box::new(move |conn: &Connection| {
+            let page = db
+                .read(Box::new(move |conn: &Connection| {
+                    let total: i64 = conn.query_row(&count, &values, |row| row.get(0))?;
+
                     let mut stmt = conn.prepare(&select)?;
-                    let movie_iter = stmt.query_map(params![id], |row| mk_movie!(row))?;
+                    let movie_iter = stmt.query_map(&values, MovieTable::from_row)?;
+                    let items = movie_iter.collect::<Result<Vec<_>, _>>()?;
+
+                    Ok(Page {
+                        total,
+                        limit: query.limit(),
+                        offset: query.offset,
+                        items,
+                    })
+                }))
+                .await?;
+            Ok(page)
+        };
+        Box::pin(func)
+    }
 
-                    let mut movies = movie_iter.map(|m| m.unwrap()).collect::<Vec<_>>();
+    /// Fetch the movies with the given ids, in the order of `ids`.
+    /// Unknown ids are skipped.
+    pub fn by_ids(&self, ids: Vec<i32>) -> FutRes<Vec<Movie>> {
+        let db = self.db.clone();
+        let placeholders = (1..=ids.len())
+            .map(|i| format!("?{}", i))
+            .collect::<Vec<_>>();
+        let select = format!("{} WHERE id IN ({})", self.select(), placeholders.join(","));
 
-                    let movie;
-                    if movies.len() != 1 {
-                        movie = None;
-                    } else {
-                        movie = Some(movies.pop().unwrap());
-                    }
-                    Ok(movie)
+        let func = async move {
+            if ids.is_empty() {
+                return Ok(Vec::new());
+            }
+            let params = ids.clone();
+            let mut movies = db
+                .read(Box::new(move |conn: &Connection| {
+                    let mut stmt = conn.prepare(&select)?;
+                    let movie_iter = stmt.query_map(&params, MovieTable::from_row)?;
+                    movie_iter.collect::<Result<Vec<_>, _>>()
+                }))
+                .await?;
+            movies.sort_by_key(|m| ids.iter().position(|id| *id == m.id));
+            Ok(movies)
+        };
+        Box::pin(func)
+    }
+
+    /// Fetch a movie by id unless it is hidden from the user by
+    /// parental controls
+    pub fn for_user(&self, id: i32, user_id: i32) -> FutRes<Option<Movie>> {
+        let db = self.db.clone();
+        let select = format!("{} WHERE id=?1 AND {}", self.select(), allowed("id", "?2"));
+
+        let func = async move {
+            let movie = db
+                .read(Box::new(move |conn: &Connection| {
+                    conn.query_row(&select, params![id, user_id], MovieTable::from_row)
+                        .optional()
+                }))
+                .await?;
+            Ok(movie)
+        };
+        Box::pin(func)
+    }
+
+    /// Fetch a movie by id unless it is hidden from any user by parental
+    /// controls, for requests which don't tell who is asking
+    pub fn for_everyone(&self, id: i32) -> FutRes<Option<Movie>> {
+        let db = self.db.clone();
+        let select = format!(
+            "{} WHERE id=?1 AND NOT EXISTS (SELECT 1 FROM restrictions u WHERE NOT {})",
+            self.select(),
+            allowed("id", "u.user_id")
+        );
+
+        let func = async move {
+            let movie = db
+                .read(Box::new(move |conn: &Connection| {
+                    conn.query_row(&select, params![id], MovieTable::from_row)
+                        .optional()
                 }))
                 .await?;
             Ok(movie)
@@ -103,16 +166,56 @@ impl Table for MovieTable {
         Box::pin(func)
     }
 
-    fn all(&self) -> FutRes<Vec<Self::Model>> {
+    /// Store the vertical resolution of a movie once it is known
+    pub fn set_resolution(&self, id: i32, resolution: i32) -> FutRes<()> {
         let db = self.db.clone();
-        let select = format!("SELECT {} FROM {}", &self.fields.join(","), self.get_name());
+        let update = format!("UPDATE {} SET resolution=?1 WHERE id=?2", Movie::TABLE);
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute(&update, params![resolution, id])
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// Moves movies, given as ids with the name of their library, to
+    /// another library
+    pub fn set_libraries(&self, libraries: Vec<(i32, String)>) -> FutRes<()> {
+        let db = self.db.clone();
+        let update = format!("UPDATE {} SET library=?1 WHERE id=?2", Movie::TABLE);
+
+        let func = async move {
+            db.transaction(Box::new(move |tx: &Transaction| {
+                for (id, library) in libraries.iter() {
+                    tx.execute(&update, params![library, id])?;
+                }
+                Ok(())
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// Movies which were not matched with TMDB yet, or without genres
+    /// as they were matched before genres were stored
+    pub fn without_metadata(&self) -> FutRes<Vec<Movie>> {
+        let db = self.db.clone();
+        let select = format!(
+            "{} WHERE tmdb_id IS NULL OR tmdb_id=0 \
+             OR NOT EXISTS (SELECT 1 FROM movie_genres g WHERE g.movie_id = movies.id)",
+            self.select()
+        );
+
         let func = async move {
             let movies = db
-                .spawn(Box::new(move |conn: &Connection| {
+                .read(Box::new(move |conn: &Connection| {
                     let mut stmt = conn.prepare(&select)?;
-                    let movie_iter = stmt.query_map(params![], |row| mk_movie!(row))?;
-                    let movies = movie_iter.map(|m| m.unwrap()).collect::<Vec<_>>();
-                    Ok(movies)
+                    let movie_iter = stmt.query_map(params![], MovieTable::from_row)?;
+                    movie_iter.collect::<Result<Vec<_>, _>>()
                 }))
                 .await?;
             Ok(movies)
@@ -120,27 +223,93 @@ impl Table for MovieTable {
         Box::pin(func)
     }
 
-    fn save(&self, model: Self::Model) -> FutRes<()> {
+    /// Movies without seek preview thumbnails
+    pub fn without_trickplay(&self) -> FutRes<Vec<Movie>> {
         let db = self.db.clone();
-        let insert = format!(
-            "INSERT INTO {} ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
-            self.get_name(),
-            &self.fields.join(",")
+        let select = format!(
+            "{} WHERE NOT EXISTS (SELECT 1 FROM {} WHERE {}.movie_id = {}.id)",
+            self.select(),
+            Trickplay::TABLE,
+            Trickplay::TABLE,
+            Movie::TABLE
         );
+
+        let func = async move {
+            let movies = db
+                .read(Box::new(move |conn: &Connection| {
+                    let mut stmt = conn.prepare(&select)?;
+                    let movie_iter = stmt.query_map(params![], MovieTable::from_row)?;
+                    movie_iter.collect::<Result<Vec<_>, _>>()
+                }))
+                .await?;
+            Ok(movies)
+        };
+        Box::pin(func)
+    }
+
+    /// Movies with TMDB metadata but without the names of their cast
+    /// and crew
+    pub fn without_people(&self) -> FutRes<Vec<Movie>> {
+        let db = self.db.clone();
+        let select = format!("{} WHERE tmdb_id > 0 AND people = ''", self.select());
+
+        let func = async move {
+            let movies = db
+                .read(Box::new(move |conn: &Connection| {
+                    let mut stmt = conn.prepare(&select)?;
+                    let movie_iter = stmt.query_map(params![], MovieTable::from_row)?;
+                    movie_iter.collect::<Result<Vec<_>, _>>()
+                }))
+                .await?;
+            Ok(movies)
+        };
+        Box::pin(func)
+    }
+
+    /// Replaces the names of the cast and crew of a movie, which can be
+    /// searched for
+    pub fn set_people(&self, id: i32, people: Vec<String>) -> FutRes<()> {
+        let db = self.db.clone();
+
         let func = async move {
             db.spawn(Box::new(move |conn: &Connection| {
                 conn.execute(
-                    &insert,
+                    "UPDATE movies SET people=?1 WHERE id=?2",
+                    params![people.join("\n"), id],
+                )
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// Store the data looked up on TMDB: `tmdb_id`, `overview`,
+    /// `poster_path`, `backdrop_path`, `rating` and `genres` of `movie`
+    pub fn set_metadata(&self, movie: Movie) -> FutRes<()> {
+        let db = self.db.clone();
+        let update = format!(
+            "UPDATE {} SET tmdb_id=?1, overview=?2, poster_path=?3, backdrop_path=?4, \
+             rating=?5 WHERE id=?6",
+            Movie::TABLE
+        );
+
+        let func = async move {
+            db.transaction(Box::new(move |tx: &Transaction| {
+                tx.execute(
+                    &update,
                     params![
-                        model.tmdb_id,
-                        model.title,
-                        model.overview,
-                        model.release_year,
-                        model.file_path,
-                        model.poster_path,
-                        model.backdrop_path
+                        movie.tmdb_id,
+                        movie.overview,
+                        movie.poster_path,
+                        movie.backdrop_path,
+                        movie.rating,
+                        movie.id
                     ],
-                )
+                )?;
+                let id = i64::from(movie.id);
+                tx.execute("DELETE FROM movie_genres WHERE movie_id=?1", params![id])?;
+                insert_genres(tx, id, &movie.genres)
             }))
             .await?;
             Ok(())
@@ -148,29 +317,186 @@ impl Table for MovieTable {
         Box::pin(func)
     }
 
-    fn get_name(&self) -> &str {
-        &self.name
+    /// Full-text search over title and overview. `expr` is an FTS5
+    /// match expression as built by `search::match_expr`, best matches
+    /// come first. Movies hidden from the user are left out.
+    pub fn search(&self, expr: String, user_id: i32, limit: u32) -> FutRes<Vec<Movie>> {
+        let db = self.db.clone();
+        let fields = Movie::COLUMNS
+            .iter()
+            .map(|f| format!("movies.{}", f))
+            .collect::<Vec<_>>();
+        let select = format!(
+            "SELECT {},{} FROM movies_fts JOIN {} ON {}.id = movies_fts.rowid \
+             WHERE movies_fts MATCH ?1 AND {} ORDER BY movies_fts.rank LIMIT ?2",
+            fields.join(","),
+            GENRES,
+            Movie::TABLE,
+            Movie::TABLE,
+            allowed("movies.id", "?3")
+        );
+
+        let func = async move {
+            let movies = db
+                .read(Box::new(move |conn: &Connection| {
+                    let mut stmt = conn.prepare(&select)?;
+                    let movie_iter =
+                        stmt.query_map(params![expr, limit, user_id], MovieTable::from_row)?;
+                    movie_iter.collect::<Result<Vec<_>, _>>()
+                }))
+                .await?;
+            Ok(movies)
+        };
+        Box::pin(func)
+    }
+
+    /// The names of the libraries with movies the user may see, with
+    /// the number of those movies in each
+    pub fn libraries(&self, user_id: i32) -> FutRes<Vec<(String, i64)>> {
+        let db = self.db.clone();
+        let select = format!(
+            "SELECT library, COUNT(*) FROM {} WHERE {} \
+             GROUP BY library ORDER BY library COLLATE NOCASE",
+            Movie::TABLE,
+            allowed("id", "?1")
+        );
+
+        let func = async move {
+            let libraries = db
+                .read(Box::new(move |conn: &Connection| {
+                    let mut stmt = conn.prepare(&select)?;
+                    let iter =
+                        stmt.query_map(params![user_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                    iter.collect::<Result<Vec<_>, _>>()
+                }))
+                .await?;
+            Ok(libraries)
+        };
+        Box::pin(func)
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+impl Table for MovieTable {
+    type Model = Movie;
+
+    fn db(&self) -> SharedDb {
+        self.db.clone()
+    }
+
+    fn select(&self) -> String {
+        format!(
+            "SELECT {},{} FROM {}",
+            Movie::COLUMNS.join(","),
+            GENRES,
+            Movie::TABLE
+        )
+    }
+
+    fn from_row(row: &Row) -> SqlResult<Movie> {
+        let mut movie = Movie::from_row(row)?;
+        movie.genres = split_genres(row.get(Movie::COLUMNS.len())?);
+        Ok(movie)
+    }
+
+    fn create_table(&self) -> FutRes<()> {
+        let db = self.db.clone();
+        let create = format!(
+            "{};
+            CREATE INDEX movies_release_year ON movies (release_year);
+            CREATE INDEX movies_added_at ON movies (added_at);
+            CREATE TABLE movie_genres (
+                movie_id        INTEGER NOT NULL REFERENCES movies (id) ON DELETE CASCADE,
+                genre           VARCHAR(255) NOT NULL,
+                PRIMARY KEY (movie_id, genre)
+            );
+            {};",
+            Movie::create_sql(),
+            ADD_PEOPLE
+        );
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute_batch(&create)
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    fn save(&self, model: Self::Model) -> FutRes<()> {
+        let db = self.db.clone();
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                insert_movie(conn, &model)
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// Updates a movie, its genres are replaced
+    fn update(&self, model: Self::Model) -> FutRes<bool> {
+        let db = self.db.clone();
+        let func = async move {
+            let changed = db
+                .transaction(Box::new(move |tx: &Transaction| {
+                    let changed = tx.execute(&Movie::update_sql(), &model.update_values()?)?;
+                    if changed > 0 {
+                        let id = i64::from(model.id);
+                        tx.execute("DELETE FROM movie_genres WHERE movie_id=?1", params![id])?;
+                        insert_genres(tx, id, &model.genres)?;
+                    }
+                    Ok(changed > 0)
+                }))
+                .await?;
+            Ok(changed)
+        };
+        Box::pin(func)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Model)]
+#[model(table = "movies")]
 pub struct Movie {
+    #[model(id)]
     pub id: i32,
+    #[model(sql = "INTEGER")]
     pub tmdb_id: i32,
+    #[model(sql = "VARCHAR(255) NOT NULL")]
     pub title: String,
+    #[model(sql = "TEXT")]
     pub overview: String,
     pub release_year: i32,
+    #[model(sql = "VARCHAR(255) NOT NULL")]
     pub file_path: String,
+    #[model(sql = "VARCHAR(255)")]
     pub poster_path: String,
+    #[model(sql = "VARCHAR(255)")]
     pub backdrop_path: String,
+    /// Average vote on TMDB (0 - 10)
+    #[model(sql = "REAL NOT NULL DEFAULT 0")]
+    pub rating: f64,
+    /// Vertical resolution of the video stream, 0 if unknown
+    #[model(sql = "INTEGER NOT NULL DEFAULT 0")]
+    pub resolution: i32,
+    /// Name of the library the movie was found in
+    #[model(sql = "VARCHAR(255) NOT NULL DEFAULT ''")]
+    pub library: String,
+    /// Unix timestamp of when the movie was added
+    #[model(now)]
+    pub added_at: i64,
+    /// Stored in the table movie_genres
+    #[model(skip)]
+    pub genres: Vec<String>,
 }
 
-impl Model for Movie {}
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::config::DatabaseConfig;
+    use crate::model::{match_expr, migrate, TrickplayTable};
     use crate::sqlite::Runtime;
 
     #[test]
@@ -178,6 +504,7 @@ mod tests {
         let func = async {
             let config = DatabaseConfig {
                 name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
             };
             let (db, rt) = Runtime::channel(config);
             rt.run();
@@ -196,6 +523,11 @@ mod tests {
                 file_path: "/test_file.mkv".to_owned(),
                 poster_path: "/test_poster.jpg".to_owned(),
                 backdrop_path: "/test_backdrop.jpg".to_owned(),
+                rating: 7.5,
+                resolution: 1080,
+                library: "movies".to_owned(),
+                added_at: 1_600_000_000,
+                genres: vec!["Action".to_owned(), "Drama".to_owned()],
             };
             t.save(newmovie.clone()).await.unwrap();
             newmovie.id = 1;
@@ -213,10 +545,11 @@ mod tests {
         let func = async {
             let config = DatabaseConfig {
                 name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
             };
             let (db, rt) = Runtime::channel(config);
             rt.run();
-            let t = MovieTable::new(db);
+            let t = MovieTable::new(db.clone());
             t.create_table().await.unwrap();
 
             let movie = Movie {
@@ -228,11 +561,215 @@ mod tests {
                 file_path: "/test_file.mkv".to_owned(),
                 poster_path: "/test_poster.jpg".to_owned(),
                 backdrop_path: "/test_backdrop.jpg".to_owned(),
+                rating: 7.5,
+                resolution: 1080,
+                library: "movies".to_owned(),
+                added_at: 1_600_000_000,
+                genres: vec!["Action".to_owned(), "Drama".to_owned()],
             };
             t.save(movie).await.unwrap();
 
             let movies = t.all().await.unwrap();
             assert_eq!(1, movies.len());
+
+            let trickplay = TrickplayTable::new(db.clone());
+            trickplay.create_table().await.unwrap();
+            assert_eq!(1, t.without_trickplay().await.unwrap().len());
+            trickplay
+                .save(Trickplay {
+                    movie_id: movies[0].id,
+                    duration: 60.0,
+                    interval: 10,
+                    width: 320,
+                    height: 180,
+                    columns: 10,
+                    rows: 10,
+                    count: 6,
+                })
+                .await
+                .unwrap();
+            assert!(t.without_trickplay().await.unwrap().is_empty());
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+
+    #[test]
+    fn test_set_metadata() {
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (db, rt) = Runtime::channel(config);
+            rt.run();
+            let t = MovieTable::new(db);
+            t.create_table().await.unwrap();
+            t.save(movie("Heat", 1995, "Crime", 1080)).await.unwrap();
+
+            let mut movies = t.without_metadata().await.unwrap();
+            assert_eq!(1, movies.len());
+
+            let mut movie = movies.remove(0);
+            movie.tmdb_id = 949;
+            movie.overview = "Obsessive master thief".to_owned();
+            movie.rating = 7.9;
+            movie.genres = vec!["Action".to_owned(), "Thriller".to_owned()];
+            t.set_metadata(movie).await.unwrap();
+
+            assert!(t.without_metadata().await.unwrap().is_empty());
+            let movie = t.by_id(1).await.unwrap().unwrap();
+            assert_eq!(949, movie.tmdb_id);
+            assert_eq!(7.9, movie.rating);
+            // the genres of TMDB replace the ones the movie had
+            assert_eq!(vec!["Action", "Thriller"], movie.genres);
+            let query = MovieQuery::from_query("genre=thriller").unwrap();
+            assert_eq!(1, t.query(query).await.unwrap().total);
+            let query = MovieQuery::from_query("genre=crime").unwrap();
+            assert_eq!(0, t.query(query).await.unwrap().total);
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+
+    fn movie(title: &str, release_year: i32, genre: &str, resolution: i32) -> Movie {
+        Movie {
+            id: 0,
+            tmdb_id: 0,
+            title: title.to_owned(),
+            overview: "".into(),
+            release_year,
+            file_path: format!("/{}.mkv", title),
+            poster_path: "".into(),
+            backdrop_path: "".into(),
+            rating: 0.0,
+            resolution,
+            library: "movies".to_owned(),
+            added_at: 0,
+            genres: vec![genre.to_owned()],
+        }
+    }
+
+    #[test]
+    fn test_query() {
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (db, rt) = Runtime::channel(config);
+            rt.run();
+            let t = MovieTable::new(db);
+            t.create_table().await.unwrap();
+
+            t.save(movie("Heat", 1995, "Crime", 1080)).await.unwrap();
+            t.save(movie("Alien", 1979, "Horror", 720)).await.unwrap();
+            t.save(movie("Collateral", 2004, "Crime", 2160))
+                .await
+                .unwrap();
+            t.save(movie("Aliens", 1986, "Action", 1080)).await.unwrap();
+
+            let query = MovieQuery::from_query("limit=2&offset=1").unwrap();
+            let page = t.query(query).await.unwrap();
+            assert_eq!(4, page.total);
+            let titles: Vec<_> = page.items.iter().map(|m| m.title.as_str()).collect();
+            assert_eq!(vec!["Aliens", "Collateral"], titles);
+
+            let query = MovieQuery::from_query("sort=year&order=desc").unwrap();
+            let page = t.query(query).await.unwrap();
+            assert_eq!("Collateral", page.items[0].title);
+
+            let query = MovieQuery::from_query("genre=crime&resolution=1080").unwrap();
+            let page = t.query(query).await.unwrap();
+            assert_eq!(2, page.total);
+            assert_eq!(vec!["Crime".to_owned()], page.items[0].genres);
+
+            let query = MovieQuery::from_query("year_from=1980&year_to=2000").unwrap();
+            let page = t.query(query).await.unwrap();
+            assert_eq!(2, page.total);
+
+            let query = MovieQuery::from_query("library=series").unwrap();
+            let page = t.query(query).await.unwrap();
+            assert_eq!(0, page.total);
+            assert!(page.items.is_empty());
+
+            t.set_libraries(vec![(1, "series".to_owned()), (3, "series".to_owned())])
+                .await
+                .unwrap();
+            let query = MovieQuery::from_query("library=series").unwrap();
+            let page = t.query(query).await.unwrap();
+            assert_eq!(2, page.total);
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+
+    #[test]
+    fn test_search() {
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (db, rt) = Runtime::channel(config);
+            rt.run();
+            let t = MovieTable::new(db.clone());
+            migrate(db).await.unwrap();
+
+            let mut amelie = movie("Amélie", 2001, "Comedy", 1080);
+            amelie.overview =
+                "A shy waitress decides to change the lives of those around her".into();
+            t.save(amelie).await.unwrap();
+            let mut heat = movie("Heat", 1995, "Crime", 1080);
+            heat.overview = "A group of professional bank robbers start to feel the heat".into();
+            t.save(heat).await.unwrap();
+            let mut collateral = movie("Collateral", 2004, "Crime", 1080);
+            collateral.overview = "A cab driver finds himself the hostage of a hitman".into();
+            t.save(collateral).await.unwrap();
+
+            let movies = t.search(match_expr("ame").unwrap(), 1, 10).await.unwrap();
+            assert_eq!(1, movies.len());
+            assert_eq!("Amélie", movies[0].title);
+
+            let movies = t
+                .search(match_expr("WAITRESS").unwrap(), 1, 10)
+                .await
+                .unwrap();
+            assert_eq!("Amélie", movies[0].title);
+
+            let movies = t.search(match_expr("heat").unwrap(), 1, 10).await.unwrap();
+            assert_eq!(1, movies.len());
+            assert_eq!(vec!["Crime".to_owned()], movies[0].genres);
+
+            let movies = t.search(match_expr("the").unwrap(), 1, 1).await.unwrap();
+            assert_eq!(1, movies.len());
+
+            // movies are found by their cast and crew
+            t.set_people(2, vec!["Al Pacino".to_owned(), "Michael Mann".to_owned()])
+                .await
+                .unwrap();
+            t.set_people(3, vec!["Michael Mann".to_owned()])
+                .await
+                .unwrap();
+            let movies = t.search(match_expr("mann").unwrap(), 1, 10).await.unwrap();
+            assert_eq!(2, movies.len());
+            let movies = t
+                .search(match_expr("pacino heat").unwrap(), 1, 10)
+                .await
+                .unwrap();
+            assert_eq!(
+                vec!["Heat"],
+                movies.iter().map(|m| m.title.as_str()).collect::<Vec<_>>()
+            );
+            t.set_people(2, Vec::new()).await.unwrap();
+            assert!(t
+                .search(match_expr("pacino").unwrap(), 1, 10)
+                .await
+                .unwrap()
+                .is_empty());
         };
 
         let mut rt = tokio::runtime::Runtime::new().unwrap();
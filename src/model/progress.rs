@@ -0,0 +1,363 @@
+use super::{allowed, FutRes, Model, Movie, Table};
+use crate::sqlite::{params, Connection, SharedDb, SqlResult, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Progress, rating and watchlist of a user (`?1`) by movie
+const STATES: &str = "SELECT movies.id, watch_progress.position, watch_progress.completed, \
+     watch_progress.last_watched_at, ratings.rating, watchlist.movie_id IS NOT NULL \
+     FROM movies \
+     LEFT JOIN watch_progress ON watch_progress.movie_id = movies.id AND watch_progress.user_id = ?1 \
+     LEFT JOIN ratings ON ratings.movie_id = movies.id AND ratings.user_id = ?1 \
+     LEFT JOIN watchlist ON watchlist.movie_id = movies.id AND watchlist.user_id = ?1";
+
+/// Playback progress of a user for one movie
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Model)]
+#[model(table = "watch_progress")]
+pub struct Progress {
+    #[model(key, sql = "INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE")]
+    pub user_id: i32,
+    #[model(key, sql = "INTEGER NOT NULL REFERENCES movies (id) ON DELETE CASCADE")]
+    pub movie_id: i32,
+    /// Position in seconds to resume from, 0 once completed
+    #[model(sql = "REAL NOT NULL DEFAULT 0")]
+    pub position: f64,
+    /// Duration of the movie in seconds as reported by the player
+    #[model(sql = "REAL NOT NULL DEFAULT 0")]
+    pub duration: f64,
+    #[model(sql = "BOOLEAN NOT NULL DEFAULT 0")]
+    pub completed: bool,
+    /// Unix timestamp of the last report
+    pub last_watched_at: i64,
+}
+
+/// The state of a movie for the logged in user, as embedded into
+/// movie responses
+#[derive(Debug, Default, PartialEq, Clone, Serialize)]
+pub struct WatchState {
+    pub resume_position: f64,
+    pub watched: bool,
+    pub last_watched_at: Option<i64>,
+    /// Rating of the user from 1 to 10
+    pub user_rating: Option<i32>,
+    pub in_watchlist: bool,
+}
+
+/// A movie together with the watch state of the logged in user
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct UserMovie {
+    #[serde(flatten)]
+    pub movie: Movie,
+    #[serde(flatten)]
+    pub state: WatchState,
+}
+
+impl UserMovie {
+    /// Pairs the movies with their state in `states`
+    pub fn zip(movies: Vec<Movie>, states: &HashMap<i32, WatchState>) -> Vec<UserMovie> {
+        movies
+            .into_iter()
+            .map(|movie| {
+                let state = states.get(&movie.id).cloned().unwrap_or_default();
+                UserMovie { movie, state }
+            })
+            .collect()
+    }
+}
+
+/// Represents the table watch_progress in the database
+pub struct ProgressTable {
+    db: SharedDb,
+}
+
+impl ProgressTable {
+    /// Create a new handler to the watch_progress table
+    pub fn new(db: SharedDb) -> ProgressTable {
+        ProgressTable { db }
+    }
+
+    /// Stores a position reported by the player. Once `position` passes
+    /// `threshold` (a fraction of `duration`) the movie counts as
+    /// watched and the resume position is reset. A zero `duration`
+    /// keeps the one reported earlier.
+    pub fn report(
+        &self,
+        user_id: i32,
+        movie_id: i32,
+        position: f64,
+        duration: f64,
+        threshold: f64,
+    ) -> FutRes<Progress> {
+        let db = self.db.clone();
+        let select = format!("{} WHERE user_id=?1 AND movie_id=?2 LIMIT 1", self.select());
+
+        let func = async move {
+            let progress = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    let mut duration = duration.max(0.0);
+                    if duration == 0.0 {
+                        let mut stmt = conn.prepare(&select)?;
+                        let mut iter =
+                            stmt.query_map(params![user_id, movie_id], Progress::from_row)?;
+                        if let Some(p) = iter.next().transpose()? {
+                            duration = p.duration;
+                        }
+                    }
+
+                    let completed = duration > 0.0 && position >= duration * threshold;
+                    let position = if completed { 0.0 } else { position.max(0.0) };
+                    upsert(conn, user_id, movie_id, position, duration, completed)?;
+                    conn.query_row(&select, params![user_id, movie_id], Progress::from_row)
+                }))
+                .await?;
+            Ok(progress)
+        };
+        Box::pin(func)
+    }
+
+    /// Marks a movie as watched or unwatched, resetting the resume position
+    pub fn set_watched(&self, user_id: i32, movie_id: i32, watched: bool) -> FutRes<Progress> {
+        let db = self.db.clone();
+        let select = format!("{} WHERE user_id=?1 AND movie_id=?2 LIMIT 1", self.select());
+
+        let func = async move {
+            let progress = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    upsert(conn, user_id, movie_id, 0.0, 0.0, watched)?;
+                    conn.query_row(&select, params![user_id, movie_id], Progress::from_row)
+                }))
+                .await?;
+            Ok(progress)
+        };
+        Box::pin(func)
+    }
+
+    /// The state of a user for the movies with the given ids. Unknown
+    /// ids are skipped.
+    pub fn states(&self, user_id: i32, ids: Vec<i32>) -> FutRes<HashMap<i32, WatchState>> {
+        let db = self.db.clone();
+        let placeholders = (2..ids.len() + 2)
+            .map(|i| format!("?{}", i))
+            .collect::<Vec<_>>();
+        let select = format!("{} WHERE movies.id IN ({})", STATES, placeholders.join(","));
+        let mut values = vec![Value::Integer(user_id.into())];
+        values.extend(ids.iter().map(|id| Value::Integer((*id).into())));
+
+        let func = async move {
+            if ids.is_empty() {
+                return Ok(HashMap::new());
+            }
+            let states = db
+                .read(Box::new(move |conn: &Connection| {
+                    let mut stmt = conn.prepare(&select)?;
+                    let iter = stmt.query_map(&values, |row| {
+                        let state = WatchState {
+                            resume_position: row.get::<_, Option<f64>>(1)?.unwrap_or(0.0),
+                            watched: row.get::<_, Option<bool>>(2)?.unwrap_or(false),
+                            last_watched_at: row.get(3)?,
+                            user_rating: row.get(4)?,
+                            in_watchlist: row.get(5)?,
+                        };
+                        Ok((row.get(0)?, state))
+                    })?;
+                    iter.collect::<Result<HashMap<_, _>, _>>()
+                }))
+                .await?;
+            Ok(states)
+        };
+        Box::pin(func)
+    }
+
+    /// The state of a user for one movie
+    pub fn state(&self, user_id: i32, movie_id: i32) -> FutRes<WatchState> {
+        let states = self.states(user_id, vec![movie_id]);
+
+        let func = async move { Ok(states.await?.remove(&movie_id).unwrap_or_default()) };
+        Box::pin(func)
+    }
+
+    /// Movies a user started but didn't finish, most recently watched first
+    pub fn in_progress(&self, user_id: i32, limit: u32) -> FutRes<Vec<Progress>> {
+        let db = self.db.clone();
+        let select = format!(
+            "{} WHERE user_id=?1 AND completed=0 AND position > 0 AND {} \
+             ORDER BY last_watched_at DESC, movie_id DESC LIMIT ?2",
+            self.select(),
+            allowed("movie_id", "?1")
+        );
+
+        let func = async move {
+            let progress = db
+                .read(Box::new(move |conn: &Connection| {
+                    let mut stmt = conn.prepare(&select)?;
+                    let iter = stmt.query_map(params![user_id, limit], Progress::from_row)?;
+                    iter.collect::<Result<Vec<_>, _>>()
+                }))
+                .await?;
+            Ok(progress)
+        };
+        Box::pin(func)
+    }
+}
+
+impl Table for ProgressTable {
+    type Model = Progress;
+
+    fn db(&self) -> SharedDb {
+        self.db.clone()
+    }
+
+    fn create_table(&self) -> FutRes<()> {
+        let db = self.db.clone();
+        let create = format!(
+            "{};\nCREATE INDEX watch_progress_last_watched \
+             ON watch_progress (user_id, last_watched_at);",
+            Progress::create_sql()
+        );
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute_batch(&create)
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+}
+
+/// Inserts or updates the progress of a user for a movie. A zero
+/// `duration` keeps the stored one.
+fn upsert(
+    conn: &Connection,
+    user_id: i32,
+    movie_id: i32,
+    position: f64,
+    duration: f64,
+    completed: bool,
+) -> SqlResult<usize> {
+    conn.execute(
+        "INSERT INTO watch_progress
+            (user_id, movie_id, position, duration, completed, last_watched_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, strftime('%s', 'now'))
+         ON CONFLICT (user_id, movie_id) DO UPDATE SET
+            position=excluded.position,
+            duration=CASE WHEN excluded.duration > 0
+                THEN excluded.duration ELSE duration END,
+            completed=excluded.completed,
+            last_watched_at=excluded.last_watched_at",
+        params![user_id, movie_id, position, duration, completed],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use crate::model::{
+        migrate, MovieQuery, MovieTable, Rating, RatingTable, Table, User, UserTable,
+        WatchlistEntry, WatchlistTable,
+    };
+    use crate::sqlite::Runtime;
+
+    fn movie(title: &str) -> Movie {
+        Movie {
+            id: 0,
+            tmdb_id: 0,
+            title: title.to_owned(),
+            overview: "".into(),
+            release_year: 2000,
+            file_path: format!("/{}.mkv", title),
+            poster_path: "".into(),
+            backdrop_path: "".into(),
+            rating: 0.0,
+            resolution: 0,
+            library: "movies".to_owned(),
+            added_at: 0,
+            genres: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_progress() {
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (db, rt) = Runtime::channel(config);
+            rt.run();
+            migrate(db.clone()).await.unwrap();
+            let movies = MovieTable::new(db.clone());
+            movies.save(movie("Heat")).await.unwrap();
+            movies.save(movie("Alien")).await.unwrap();
+            let users = UserTable::new(db.clone());
+            users.save(User::new("jan", "secret", false)).await.unwrap();
+            let t = ProgressTable::new(db.clone());
+
+            assert_eq!(WatchState::default(), t.state(1, 1).await.unwrap());
+
+            let p = t.report(1, 1, 600.0, 6000.0, 0.9).await.unwrap();
+            assert_eq!(600.0, p.position);
+            assert!(!p.completed);
+            t.report(1, 2, 60.0, 7000.0, 0.9).await.unwrap();
+
+            let started = t.in_progress(1, 10).await.unwrap();
+            assert_eq!(2, started.len());
+
+            // duration is kept if the player doesn't know it
+            let p = t.report(1, 1, 5500.0, 0.0, 0.9).await.unwrap();
+            assert_eq!(6000.0, p.duration);
+            assert!(p.completed);
+            assert_eq!(0.0, p.position);
+
+            let started = t.in_progress(1, 10).await.unwrap();
+            assert_eq!(1, started.len());
+            assert_eq!(2, started[0].movie_id);
+
+            let mut query = MovieQuery::from_query("watched=true").unwrap();
+            query.user_id = Some(1);
+            let page = movies.query(query).await.unwrap();
+            assert_eq!(1, page.total);
+            assert_eq!("Heat", page.items[0].title);
+
+            let mut query = MovieQuery::from_query("watched=false").unwrap();
+            query.user_id = Some(1);
+            let page = movies.query(query).await.unwrap();
+            assert_eq!(1, page.total);
+            assert_eq!("Alien", page.items[0].title);
+
+            let p = t.set_watched(1, 1, false).await.unwrap();
+            assert!(!p.completed);
+            let state = t.state(1, 1).await.unwrap();
+            assert!(!state.watched);
+
+            let rating = Rating {
+                user_id: 1,
+                movie_id: 2,
+                rating: 8,
+                rated_at: 0,
+            };
+            RatingTable::new(db.clone()).save(rating).await.unwrap();
+            let entry = WatchlistEntry {
+                user_id: 1,
+                movie_id: 2,
+                added_at: 0,
+            };
+            WatchlistTable::new(db.clone()).save(entry).await.unwrap();
+
+            let states = t.states(1, vec![1, 2, 3]).await.unwrap();
+            assert_eq!(2, states.len());
+            assert_eq!(Some(8), states[&2].user_rating);
+            assert!(states[&2].in_watchlist);
+            assert_eq!(60.0, states[&2].resume_position);
+            assert_eq!(None, states[&1].user_rating);
+            assert!(!states[&1].in_watchlist);
+            // other users don't see the state
+            assert_eq!(WatchState::default(), t.state(2, 2).await.unwrap());
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+}
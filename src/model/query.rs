@@ -0,0 +1,177 @@
+use super::allowed;
+use crate::sqlite::Value;
+use serde::{Deserialize, Serialize};
+
+/// Default number of items returned by a paged query
+pub const DEFAULT_LIMIT: u32 = 50;
+
+/// Upper bound for `limit`, so a client can't request the whole
+/// library in one go
+pub const MAX_LIMIT: u32 = 500;
+
+/// Columns a movie list can be sorted by
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    #[default]
+    Title,
+    Year,
+    Added,
+    Rating,
+}
+
+impl SortBy {
+    fn column(self) -> &'static str {
+        match self {
+            SortBy::Title => "title COLLATE NOCASE",
+            SortBy::Year => "release_year",
+            SortBy::Added => "added_at",
+            SortBy::Rating => "rating",
+        }
+    }
+}
+
+/// Sort direction
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl Order {
+    fn keyword(self) -> &'static str {
+        match self {
+            Order::Asc => "ASC",
+            Order::Desc => "DESC",
+        }
+    }
+}
+
+/// Paging, sorting and filtering options for listing movies.
+/// Usually deserialized from the query string of `/movies/`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MovieQuery {
+    pub limit: Option<u32>,
+    pub offset: u32,
+    pub sort: SortBy,
+    pub order: Order,
+    pub genre: Option<String>,
+    pub year_from: Option<i32>,
+    pub year_to: Option<i32>,
+    /// Minimum vertical resolution, e.g. `1080`
+    pub resolution: Option<i32>,
+    pub library: Option<String>,
+    /// Only movies the user has (not) watched, requires `user_id`
+    pub watched: Option<bool>,
+    /// The user `watched` refers to, never taken from the query string.
+    /// Movies hidden from the user by parental controls are left out.
+    #[serde(skip)]
+    pub user_id: Option<i32>,
+}
+
+impl MovieQuery {
+    /// Parse a query from an url query string such as
+    /// `limit=20&sort=year&order=desc&genre=Action`
+    pub fn from_query(query: &str) -> Result<MovieQuery, serde_urlencoded::de::Error> {
+        serde_urlencoded::from_str(query)
+    }
+
+    /// The effective limit, clamped to `MAX_LIMIT`
+    pub fn limit(&self) -> u32 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT)
+    }
+
+    /// Builds the `WHERE` clause (including the keyword, or an empty
+    /// string) and the values to bind to its placeholders.
+    pub fn where_clause(&self) -> (String, Vec<Value>) {
+        let mut conds = Vec::new();
+        let mut values = Vec::new();
+
+        if let Some(genre) = &self.genre {
+            values.push(Value::Text(genre.clone()));
+            conds.push(format!(
+                "id IN (SELECT movie_id FROM movie_genres WHERE genre = ?{} COLLATE NOCASE)",
+                values.len()
+            ));
+        }
+        if let Some(year) = self.year_from {
+            values.push(Value::Integer(year.into()));
+            conds.push(format!("release_year >= ?{}", values.len()));
+        }
+        if let Some(year) = self.year_to {
+            values.push(Value::Integer(year.into()));
+            conds.push(format!("release_year <= ?{}", values.len()));
+        }
+        if let Some(resolution) = self.resolution {
+            values.push(Value::Integer(resolution.into()));
+            conds.push(format!("resolution >= ?{}", values.len()));
+        }
+        if let Some(library) = &self.library {
+            values.push(Value::Text(library.clone()));
+            conds.push(format!("library = ?{}", values.len()));
+        }
+
+        if let (Some(watched), Some(user_id)) = (self.watched, self.user_id) {
+            values.push(Value::Integer(user_id.into()));
+            conds.push(format!(
+                "id {} (SELECT movie_id FROM watch_progress WHERE user_id = ?{} AND completed = 1)",
+                if watched { "IN" } else { "NOT IN" },
+                values.len()
+            ));
+        }
+
+        if let Some(user_id) = self.user_id {
+            values.push(Value::Integer(user_id.into()));
+            conds.push(allowed("id", &format!("?{}", values.len())));
+        }
+
+        if conds.is_empty() {
+            (String::new(), values)
+        } else {
+            (format!("WHERE {}", conds.join(" AND ")), values)
+        }
+    }
+
+    /// Builds the `ORDER BY` clause. `id` is always used as tie breaker
+    /// so paging is stable.
+    pub fn order_clause(&self) -> String {
+        format!(
+            "ORDER BY {} {}, id ASC",
+            self.sort.column(),
+            self.order.keyword()
+        )
+    }
+}
+
+/// Paging options for lists other than movies, e.g. the watch history
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PageQuery {
+    pub limit: Option<u32>,
+    pub offset: u32,
+}
+
+impl PageQuery {
+    /// Parse a query from an url query string such as `limit=20&offset=40`
+    pub fn from_query(query: &str) -> Result<PageQuery, serde_urlencoded::de::Error> {
+        serde_urlencoded::from_str(query)
+    }
+
+    /// The effective limit, clamped to `MAX_LIMIT`
+    pub fn limit(&self) -> u32 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT)
+    }
+}
+
+/// One page of a larger result set
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Page<T> {
+    /// Number of items matching the query, ignoring limit and offset
+    pub total: i64,
+    pub limit: u32,
+    pub offset: u32,
+    pub items: Vec<T>,
+}
@@ -0,0 +1,76 @@
+use super::{FutRes, Model, Table};
+use crate::sqlite::{params, Connection, SharedDb};
+use serde::{Deserialize, Serialize};
+
+/// Highest rating, the lowest is 1
+pub const MAX_RATING: i32 = 10;
+
+/// The rating of a movie by a user
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Model)]
+#[model(table = "ratings")]
+pub struct Rating {
+    #[model(key, sql = "INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE")]
+    pub user_id: i32,
+    #[model(key, sql = "INTEGER NOT NULL REFERENCES movies (id) ON DELETE CASCADE")]
+    pub movie_id: i32,
+    /// From 1 to `MAX_RATING`
+    #[model(sql = "INTEGER NOT NULL CHECK (rating BETWEEN 1 AND 10)")]
+    pub rating: i32,
+    /// Unix timestamp of when the movie was rated
+    #[model(now)]
+    pub rated_at: i64,
+}
+
+/// Represents the table ratings in the database
+pub struct RatingTable {
+    db: SharedDb,
+}
+
+impl RatingTable {
+    /// Create a new handler to the ratings table
+    pub fn new(db: SharedDb) -> RatingTable {
+        RatingTable { db }
+    }
+
+    /// Removes the rating of a user for a movie. Returns `false` if
+    /// there was none.
+    pub fn remove(&self, user_id: i32, movie_id: i32) -> FutRes<bool> {
+        let db = self.db.clone();
+
+        let func = async move {
+            let deleted = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    conn.execute(
+                        "DELETE FROM ratings WHERE user_id=?1 AND movie_id=?2",
+                        params![user_id, movie_id],
+                    )
+                }))
+                .await?;
+            Ok(deleted > 0)
+        };
+        Box::pin(func)
+    }
+}
+
+impl Table for RatingTable {
+    type Model = Rating;
+
+    fn db(&self) -> SharedDb {
+        self.db.clone()
+    }
+
+    /// Saves a rating, replacing the earlier one of the user
+    fn save(&self, model: Rating) -> FutRes<()> {
+        let db = self.db.clone();
+        let insert = Rating::insert_sql().replacen("INSERT", "INSERT OR REPLACE", 1);
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute(&insert, &model.insert_values()?)
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+}
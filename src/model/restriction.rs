@@ -0,0 +1,298 @@
+use super::{FutRes, Model, Table};
+use crate::sqlite::{params, Connection, OptionalExtension, SharedDb, Transaction};
+use serde::{Deserialize, Serialize};
+
+const RESTRICTION_TAGS: &str = "CREATE TABLE restriction_tags (
+    user_id         INTEGER NOT NULL REFERENCES restrictions (user_id) ON DELETE CASCADE,
+    tag             VARCHAR(255) NOT NULL COLLATE NOCASE,
+    blocked         BOOLEAN NOT NULL,
+    PRIMARY KEY (user_id, tag)
+)";
+
+/// SQL condition which holds if the user `user` (a placeholder such as
+/// `?1`) may see the movie with the id in `column`. Users without
+/// restrictions see every movie.
+pub fn allowed(column: &str, user: &str) -> String {
+    format!(
+        "NOT EXISTS (SELECT 1 FROM restrictions r WHERE r.user_id = {user} AND NOT (\
+         (r.max_age IS NULL \
+         OR EXISTS (SELECT 1 FROM certifications c WHERE c.movie_id = {movie} \
+         AND c.country = r.country AND c.min_age <= r.max_age) \
+         OR (r.allow_unrated AND NOT EXISTS (SELECT 1 FROM certifications c \
+         WHERE c.movie_id = {movie} AND c.country = r.country AND c.min_age IS NOT NULL))) \
+         AND (NOT EXISTS (SELECT 1 FROM restriction_tags t \
+         WHERE t.user_id = r.user_id AND NOT t.blocked) \
+         OR EXISTS (SELECT 1 FROM restriction_tags t JOIN movie_genres g \
+         ON g.genre = t.tag COLLATE NOCASE \
+         WHERE t.user_id = r.user_id AND NOT t.blocked AND g.movie_id = {movie})) \
+         AND NOT EXISTS (SELECT 1 FROM restriction_tags t JOIN movie_genres g \
+         ON g.genre = t.tag COLLATE NOCASE \
+         WHERE t.user_id = r.user_id AND t.blocked AND g.movie_id = {movie})))",
+        user = user,
+        movie = column
+    )
+}
+
+/// Parental controls of a user. Movies above the maximum rating, with a
+/// blocked tag or without an allowed one are hidden from the user.
+/// Tags are matched against the genres of the movies.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Model)]
+#[model(table = "restrictions")]
+pub struct Restriction {
+    #[model(
+        key,
+        sql = "INTEGER PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE"
+    )]
+    pub user_id: i32,
+    /// The country of the certifications, ISO 3166-1 such as `DE`
+    #[model(sql = "VARCHAR(2) NOT NULL")]
+    pub country: String,
+    /// Highest certification the user may watch, e.g. `PG-13`. Empty
+    /// if there is no limit.
+    #[model(sql = "VARCHAR(16) NOT NULL DEFAULT ''")]
+    pub max_rating: String,
+    /// Minimum age of `max_rating`
+    #[serde(skip)]
+    #[model(sql = "INTEGER")]
+    pub max_age: Option<i32>,
+    /// Movies without a known certification in `country` may be
+    /// watched, too
+    #[model(sql = "BOOLEAN NOT NULL DEFAULT 0")]
+    pub allow_unrated: bool,
+    /// Only movies with one of these tags, any movie if empty
+    #[model(skip)]
+    pub allowed_tags: Vec<String>,
+    /// Never movies with one of these tags
+    #[model(skip)]
+    pub blocked_tags: Vec<String>,
+}
+
+/// Represents the tables restrictions and restriction_tags in the
+/// database
+pub struct RestrictionTable {
+    db: SharedDb,
+}
+
+impl RestrictionTable {
+    /// Create a new handler to the restrictions table
+    pub fn new(db: SharedDb) -> RestrictionTable {
+        RestrictionTable { db }
+    }
+
+    /// The restrictions of a user with their tags, `None` if the user
+    /// has none
+    pub fn get(&self, user_id: i32) -> FutRes<Option<Restriction>> {
+        let db = self.db.clone();
+        let select = format!("{} WHERE user_id=?1", self.select());
+
+        let func = async move {
+            let restriction = db
+                .read(Box::new(move |conn: &Connection| {
+                    let restriction = conn
+                        .query_row(&select, params![user_id], Restriction::from_row)
+                        .optional()?;
+                    let mut restriction = match restriction {
+                        Some(restriction) => restriction,
+                        None => return Ok(None),
+                    };
+
+                    let mut stmt = conn.prepare(
+                        "SELECT tag, blocked FROM restriction_tags WHERE user_id=?1 ORDER BY tag",
+                    )?;
+                    let tags = stmt.query_map(params![user_id], |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?))
+                    })?;
+                    for tag in tags {
+                        match tag? {
+                            (tag, true) => restriction.blocked_tags.push(tag),
+                            (tag, false) => restriction.allowed_tags.push(tag),
+                        }
+                    }
+                    Ok(Some(restriction))
+                }))
+                .await?;
+            Ok(restriction)
+        };
+        Box::pin(func)
+    }
+
+    /// Removes the restrictions of a user. Returns `false` if there
+    /// were none.
+    pub fn remove(&self, user_id: i32) -> FutRes<bool> {
+        let db = self.db.clone();
+
+        let func = async move {
+            let deleted = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    conn.execute(
+                        "DELETE FROM restrictions WHERE user_id=?1",
+                        params![user_id],
+                    )
+                }))
+                .await?;
+            Ok(deleted > 0)
+        };
+        Box::pin(func)
+    }
+}
+
+impl Table for RestrictionTable {
+    type Model = Restriction;
+
+    fn db(&self) -> SharedDb {
+        self.db.clone()
+    }
+
+    fn create_table(&self) -> FutRes<()> {
+        let db = self.db.clone();
+        let create = format!("{};\n{};", Restriction::create_sql(), RESTRICTION_TAGS);
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute_batch(&create)
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// Saves the restrictions of a user with their tags, replacing the
+    /// earlier ones
+    fn save(&self, model: Restriction) -> FutRes<()> {
+        let db = self.db.clone();
+        let insert = Restriction::insert_sql().replacen("INSERT", "INSERT OR REPLACE", 1);
+
+        let func = async move {
+            db.transaction(Box::new(move |tx: &Transaction| {
+                tx.execute(
+                    "DELETE FROM restriction_tags WHERE user_id=?1",
+                    params![model.user_id],
+                )?;
+                tx.execute(&insert, &model.insert_values()?)?;
+                let tags = model
+                    .allowed_tags
+                    .iter()
+                    .map(|tag| (tag, false))
+                    .chain(model.blocked_tags.iter().map(|tag| (tag, true)));
+                for (tag, blocked) in tags {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO restriction_tags (user_id, tag, blocked) \
+                         VALUES (?1, ?2, ?3)",
+                        params![model.user_id, tag, blocked],
+                    )?;
+                }
+                Ok(())
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use crate::model::{
+        migrate, Certification, CertificationTable, Movie, MovieQuery, MovieTable, User, UserTable,
+    };
+    use crate::sqlite::Runtime;
+
+    #[test]
+    fn test_allowed() {
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (db, rt) = Runtime::channel(config);
+            rt.run();
+            migrate(db.clone()).await.unwrap();
+            let movies = MovieTable::new(db.clone());
+            let certifications = CertificationTable::new(db.clone());
+            let library = [
+                ("Cars", "Animation", Some("G")),
+                ("Up", "Animation", Some("PG")),
+                ("Alien", "Horror", Some("R")),
+                ("Heat", "Crime", Some("R")),
+                ("Home Movie", "Documentary", None),
+            ];
+            for (i, (title, genre, certification)) in library.iter().enumerate() {
+                let mut movie = Movie::from(crate::model::export::ExportMovie::default());
+                movie.title = title.to_string();
+                movie.file_path = format!("/movies/{}.mkv", title);
+                movie.genres = vec![genre.to_string()];
+                movies.save(movie).await.unwrap();
+                if let Some(certification) = certification {
+                    let id = i as i32 + 1;
+                    let certification = Certification::new(id, "US", certification);
+                    certifications.set(id, vec![certification]).await.unwrap();
+                }
+            }
+            let users = UserTable::new(db.clone());
+            users.save(User::new("kid", "secret", false)).await.unwrap();
+            users.save(User::new("jan", "secret", false)).await.unwrap();
+            let t = RestrictionTable::new(db.clone());
+
+            let titles = |user_id| {
+                let query = MovieQuery {
+                    user_id: Some(user_id),
+                    ..MovieQuery::default()
+                };
+                let movies = MovieTable::new(db.clone());
+                async move {
+                    let page = movies.query(query).await.unwrap();
+                    page.items.into_iter().map(|m| m.title).collect::<Vec<_>>()
+                }
+            };
+            assert_eq!(5, titles(1).await.len());
+
+            let restriction = Restriction {
+                user_id: 1,
+                country: "US".to_owned(),
+                max_rating: "PG".to_owned(),
+                max_age: Some(8),
+                allow_unrated: false,
+                allowed_tags: vec![],
+                blocked_tags: vec![],
+            };
+            t.save(restriction.clone()).await.unwrap();
+            assert_eq!(vec!["Cars", "Up"], titles(1).await);
+            assert_eq!(5, titles(2).await.len());
+            // requests without a user only get movies everyone may see
+            assert!(movies.for_everyone(1).await.unwrap().is_some());
+            assert!(movies.for_everyone(3).await.unwrap().is_none());
+
+            let restriction = Restriction {
+                max_rating: "R".to_owned(),
+                max_age: Some(17),
+                allow_unrated: true,
+                blocked_tags: vec!["horror".to_owned()],
+                ..restriction
+            };
+            t.save(restriction.clone()).await.unwrap();
+            assert_eq!(vec!["Cars", "Heat", "Home Movie", "Up"], titles(1).await);
+            assert_eq!(Some(restriction.clone()), t.get(1).await.unwrap());
+
+            let restriction = Restriction {
+                allowed_tags: vec!["Animation".to_owned()],
+                blocked_tags: vec![],
+                ..restriction
+            };
+            t.save(restriction.clone()).await.unwrap();
+            assert_eq!(vec!["Cars", "Up"], titles(1).await);
+            assert_eq!(Some(restriction), t.get(1).await.unwrap());
+
+            assert!(t.remove(1).await.unwrap());
+            assert!(!t.remove(1).await.unwrap());
+            assert_eq!(None, t.get(1).await.unwrap());
+            assert_eq!(5, titles(1).await.len());
+            assert!(movies.for_everyone(3).await.unwrap().is_some());
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+}
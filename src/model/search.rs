@@ -0,0 +1,130 @@
+use super::{FutRes, Movie};
+use crate::sqlite::{Connection, SharedDb};
+use serde::{Deserialize, Serialize};
+
+/// Default number of hits returned per type
+pub const DEFAULT_LIMIT: u32 = 20;
+
+/// Upper bound for hits returned per type
+pub const MAX_LIMIT: u32 = 100;
+
+/// Options of a search, usually deserialized from the query string
+/// of `/search`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<u32>,
+}
+
+impl SearchQuery {
+    /// Parse a query from an url query string such as `q=tom+cruise`
+    pub fn from_query(query: &str) -> Result<SearchQuery, serde_urlencoded::de::Error> {
+        serde_urlencoded::from_str(query)
+    }
+
+    /// The effective limit per type, clamped to `MAX_LIMIT`
+    pub fn limit(&self) -> u32 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT)
+    }
+}
+
+/// Search results, grouped by type
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchResults {
+    pub movies: Vec<Movie>,
+}
+
+/// Turns user input into an FTS5 match expression. Every word is
+/// quoted, so FTS5 operators in the input have no effect, and matched
+/// as prefix. Returns `None` if the input contains no words.
+pub fn match_expr(input: &str) -> Option<String> {
+    let terms = input
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| format!("\"{}\"*", t))
+        .collect::<Vec<_>>();
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+/// The full-text index of titles, overviews and the names of the cast
+/// and crew. The index is an external content FTS5 table over `movies`
+/// and is kept in sync by triggers, so it must be created after the
+/// movies table.
+pub struct SearchIndex {
+    db: SharedDb,
+}
+
+impl SearchIndex {
+    pub fn new(db: SharedDb) -> SearchIndex {
+        SearchIndex { db }
+    }
+
+    /// Create the index and the triggers keeping it up to date. An
+    /// existing index is replaced.
+    pub fn create_index(&self) -> FutRes<()> {
+        let db = self.db.clone();
+
+        let func = async move {
+            db.spawn(Box::new(|conn: &Connection| {
+                conn.execute_batch(
+                    "DROP TRIGGER IF EXISTS movies_fts_insert;
+                DROP TRIGGER IF EXISTS movies_fts_delete;
+                DROP TRIGGER IF EXISTS movies_fts_update;
+                DROP TABLE IF EXISTS movies_fts;
+                CREATE VIRTUAL TABLE movies_fts USING fts5(
+                    title,
+                    overview,
+                    people,
+                    content='movies',
+                    content_rowid='id',
+                    tokenize='unicode61 remove_diacritics 2'
+                );
+                INSERT INTO movies_fts (movies_fts) VALUES ('rebuild');
+                CREATE TRIGGER movies_fts_insert AFTER INSERT ON movies BEGIN
+                    INSERT INTO movies_fts (rowid, title, overview, people)
+                    VALUES (new.id, new.title, new.overview, new.people);
+                END;
+                CREATE TRIGGER movies_fts_delete AFTER DELETE ON movies BEGIN
+                    INSERT INTO movies_fts (movies_fts, rowid, title, overview, people)
+                    VALUES ('delete', old.id, old.title, old.overview, old.people);
+                END;
+                CREATE TRIGGER movies_fts_update AFTER UPDATE ON movies BEGIN
+                    INSERT INTO movies_fts (movies_fts, rowid, title, overview, people)
+                    VALUES ('delete', old.id, old.title, old.overview, old.people);
+                    INSERT INTO movies_fts (rowid, title, overview, people)
+                    VALUES (new.id, new.title, new.overview, new.people);
+                END;",
+                )
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_match_expr() {
+        assert_eq!(None, match_expr(""));
+        assert_eq!(None, match_expr(" -- "));
+        assert_eq!(Some("\"heat\"*".to_owned()), match_expr("heat"));
+        assert_eq!(
+            Some("\"tom\"* \"cruise\"*".to_owned()),
+            match_expr("tom \"cruise"),
+        );
+        assert_eq!(
+            Some("\"Amélie\"* \"OR\"*".to_owned()),
+            match_expr("Amélie OR"),
+        );
+    }
+}
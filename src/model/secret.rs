@@ -0,0 +1,98 @@
+use super::FutRes;
+use crate::auth;
+use crate::sqlite::{params, Connection, OptionalExtension, SharedDb};
+
+const SECRETS: &str = "CREATE TABLE secrets (
+    name            VARCHAR(64) PRIMARY KEY,
+    value           VARCHAR(255) NOT NULL
+)";
+
+/// Name of the key signed urls are signed with
+pub const URL_KEY: &str = "url_key";
+
+/// Represents the table secrets in the database, random keys of the
+/// server which survive restarts
+pub struct SecretTable {
+    db: SharedDb,
+}
+
+impl SecretTable {
+    /// Create a new handler to the secrets table
+    pub fn new(db: SharedDb) -> SecretTable {
+        SecretTable { db }
+    }
+
+    pub fn create_table(&self) -> FutRes<()> {
+        let db = self.db.clone();
+
+        let func = async move {
+            db.spawn(Box::new(|conn: &Connection| conn.execute_batch(SECRETS)))
+                .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// The secret `name`, which is generated when it is first asked for
+    pub fn get(&self, name: &str) -> FutRes<String> {
+        let db = self.db.clone();
+        let name = name.to_owned();
+        let select = "SELECT value FROM secrets WHERE name=?1";
+
+        let func = async move {
+            let key = name.clone();
+            let value = db
+                .read(Box::new(move |conn: &Connection| {
+                    conn.query_row(select, params![key], |row| row.get(0))
+                        .optional()
+                }))
+                .await?;
+            if let Some(value) = value {
+                return Ok(value);
+            }
+
+            let value = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    // another request may have generated it meanwhile
+                    conn.execute(
+                        "INSERT OR IGNORE INTO secrets (name, value) VALUES (?1, ?2)",
+                        params![name, auth::generate_token()],
+                    )?;
+                    conn.query_row(select, params![name], |row| row.get(0))
+                }))
+                .await?;
+            Ok(value)
+        };
+        Box::pin(func)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use crate::model::migrate;
+    use crate::sqlite::Runtime;
+
+    #[test]
+    fn test_get() {
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (db, rt) = Runtime::channel(config);
+            rt.run();
+            migrate(db.clone()).await.unwrap();
+
+            let table = SecretTable::new(db);
+            let key = table.get(URL_KEY).await.unwrap();
+            assert_eq!(64, key.len());
+            assert_eq!(key, table.get(URL_KEY).await.unwrap());
+            assert_ne!(key, table.get("other").await.unwrap());
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+}
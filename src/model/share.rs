@@ -0,0 +1,250 @@
+use super::{FutRes, Model, Table};
+use crate::sqlite::{params, Connection, SharedDb};
+use serde::{Deserialize, Serialize};
+
+/// Adds the start of the last play to the shares table of schema
+/// version 6 and 7
+pub(super) const ADD_PLAYED_AT: &str =
+    "ALTER TABLE shares ADD COLUMN played_at INTEGER NOT NULL DEFAULT 0";
+
+/// Seconds after a counted play in which the client which started it
+/// can request the stream of a share again, e.g. to seek, without
+/// counting another play
+pub const VIEWING: i64 = 4 * 3600;
+
+/// A link to one movie for someone without an account
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Model)]
+#[model(table = "shares")]
+pub struct Share {
+    /// Ids aren't reused, a new share never revives the links of a
+    /// revoked one
+    #[model(id, sql = "INTEGER PRIMARY KEY AUTOINCREMENT")]
+    pub id: i32,
+    /// The user who created the share
+    #[model(sql = "INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE")]
+    pub user_id: i32,
+    #[model(sql = "INTEGER NOT NULL REFERENCES movies (id) ON DELETE CASCADE")]
+    pub movie_id: i32,
+    /// Unix time the links stop working
+    pub expires_at: i64,
+    /// Plays allowed, unlimited if not set
+    pub max_plays: Option<i32>,
+    #[model(sql = "INTEGER NOT NULL DEFAULT 0")]
+    pub plays: i32,
+    /// Unix time the last play was counted
+    #[model(sql = "INTEGER NOT NULL DEFAULT 0")]
+    pub played_at: i64,
+    /// Height of the stream in pixels
+    pub max_height: Option<i32>,
+    /// Bitrate of the video in kbit/s
+    pub max_bitrate: Option<i32>,
+    #[model(now)]
+    pub created_at: i64,
+}
+
+impl Share {
+    /// The message the links of the share are signed over
+    pub fn signed_message(&self) -> String {
+        format!(
+            "share\n{}\n{}\n{}\n{}",
+            self.id, self.movie_id, self.created_at, self.expires_at
+        )
+    }
+}
+
+/// Represents the table shares in the database
+pub struct ShareTable {
+    db: SharedDb,
+}
+
+impl ShareTable {
+    /// Create a new handler to the shares table
+    pub fn new(db: SharedDb) -> ShareTable {
+        ShareTable { db }
+    }
+
+    /// Saves a new share and returns it with its id
+    pub fn create(&self, share: Share) -> FutRes<Share> {
+        let db = self.db.clone();
+        let select = format!("{} WHERE id=?1", self.select());
+
+        let func = async move {
+            let share = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    conn.execute(&Share::insert_sql(), &share.insert_values()?)?;
+                    let id = conn.last_insert_rowid();
+                    conn.query_row(&select, params![id], Share::from_row)
+                }))
+                .await?;
+            Ok(share)
+        };
+        Box::pin(func)
+    }
+
+    /// The shares created by `user_id`, or all for `None`, newest first
+    pub fn list(&self, user_id: Option<i32>) -> FutRes<Vec<Share>> {
+        let db = self.db.clone();
+        let select = format!(
+            "{} WHERE ?1 IS NULL OR user_id=?1 ORDER BY id DESC",
+            self.select()
+        );
+
+        let func = async move {
+            let shares = db
+                .read(Box::new(move |conn: &Connection| {
+                    let mut stmt = conn.prepare(&select)?;
+                    let iter = stmt.query_map(params![user_id], Share::from_row)?;
+                    iter.collect::<Result<Vec<_>, _>>()
+                }))
+                .await?;
+            Ok(shares)
+        };
+        Box::pin(func)
+    }
+
+    /// Allows a request of the stream of a share at `now`. A request
+    /// without a `viewing` counts a new play, which is the viewing of
+    /// the number of plays. Requests within `VIEWING` of it which name
+    /// the latest viewing belong to the same play. Returns the viewing
+    /// or `None` if a new play is needed but the share has none left,
+    /// or if the share is gone.
+    pub fn play(&self, id: i32, now: i64, viewing: Option<i32>) -> FutRes<Option<i32>> {
+        let db = self.db.clone();
+
+        let func = async move {
+            let viewing = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    if let Some(viewing) = viewing {
+                        let current: bool = conn.query_row(
+                            "SELECT EXISTS (SELECT 1 FROM shares \
+                             WHERE id=?1 AND plays=?2 AND played_at > ?3)",
+                            params![id, viewing, now - VIEWING],
+                            |row| row.get(0),
+                        )?;
+                        if current {
+                            return Ok(Some(viewing));
+                        }
+                    }
+                    let counted = conn.execute(
+                        "UPDATE shares SET plays=plays + 1, played_at=?2 \
+                         WHERE id=?1 AND (max_plays IS NULL OR plays < max_plays)",
+                        params![id, now],
+                    )?;
+                    if counted == 0 {
+                        return Ok(None);
+                    }
+                    conn.query_row("SELECT plays FROM shares WHERE id=?1", params![id], |row| {
+                        row.get(0)
+                    })
+                    .map(Some)
+                }))
+                .await?;
+            Ok(viewing)
+        };
+        Box::pin(func)
+    }
+}
+
+impl Table for ShareTable {
+    type Model = Share;
+
+    fn db(&self) -> SharedDb {
+        self.db.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use crate::model::{migrate, Movie, MovieTable, User, UserTable};
+    use crate::sqlite::Runtime;
+
+    fn share(user_id: i32, max_plays: Option<i32>) -> Share {
+        Share {
+            id: 0,
+            user_id,
+            movie_id: 1,
+            expires_at: 2_000_000_000,
+            max_plays,
+            plays: 0,
+            played_at: 0,
+            max_height: None,
+            max_bitrate: None,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_shares() {
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (db, rt) = Runtime::channel(config);
+            rt.run();
+            migrate(db.clone()).await.unwrap();
+            let mut movie = Movie::from(crate::model::export::ExportMovie::default());
+            movie.title = "Heat".to_owned();
+            movie.file_path = "/movies/Heat.mkv".to_owned();
+            MovieTable::new(db.clone()).save(movie).await.unwrap();
+            let users = UserTable::new(db.clone());
+            users.save(User::new("jan", "secret", false)).await.unwrap();
+            users.save(User::new("eva", "secret", false)).await.unwrap();
+            let t = ShareTable::new(db.clone());
+
+            let first = t.create(share(1, Some(2))).await.unwrap();
+            assert_eq!(1, first.id);
+            assert!(first.created_at > 0);
+            let second = t.create(share(2, None)).await.unwrap();
+            assert_ne!(first.signed_message(), second.signed_message());
+            assert_eq!(vec![second.clone()], t.list(Some(2)).await.unwrap());
+            let ids = t
+                .list(None)
+                .await
+                .unwrap()
+                .iter()
+                .map(|s| s.id)
+                .collect::<Vec<_>>();
+            assert_eq!(vec![2, 1], ids);
+
+            let now = 1_700_000_000;
+            assert_eq!(Some(1), t.play(1, now, None).await.unwrap());
+            assert_eq!(Some(2), t.play(1, now + 60, None).await.unwrap());
+            assert_eq!(None, t.play(1, now + 120, None).await.unwrap());
+            assert_eq!(2, t.by_id(1).await.unwrap().unwrap().plays);
+            assert_eq!(Some(1), t.play(2, now, None).await.unwrap());
+
+            // seeking restarts the stream within the same viewing
+            let once = t.create(share(1, Some(1))).await.unwrap();
+            let viewing = t.play(once.id, now, None).await.unwrap();
+            assert_eq!(Some(1), viewing);
+            assert_eq!(viewing, t.play(once.id, now + 60, viewing).await.unwrap());
+            assert_eq!(
+                viewing,
+                t.play(once.id, now + VIEWING - 1, viewing).await.unwrap()
+            );
+            // another client needs a play of its own
+            assert_eq!(None, t.play(once.id, now + 60, None).await.unwrap());
+            assert_eq!(None, t.play(once.id, now + VIEWING, viewing).await.unwrap());
+            let once = t.by_id(once.id).await.unwrap().unwrap();
+            assert_eq!((1, now), (once.plays, once.played_at));
+
+            // a new play ends the viewing of the one before
+            let twice = t.create(share(1, Some(2))).await.unwrap();
+            let first = t.play(twice.id, now, None).await.unwrap();
+            let second = t.play(twice.id, now, None).await.unwrap();
+            assert_eq!(second, t.play(twice.id, now + 60, second).await.unwrap());
+            assert_eq!(None, t.play(twice.id, now + 60, first).await.unwrap());
+
+            // ids of revoked shares aren't given out again
+            assert!(t.delete(2).await.unwrap());
+            assert_eq!(None, t.play(2, now, None).await.unwrap());
+            assert_eq!(5, t.create(share(1, None)).await.unwrap().id);
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+}
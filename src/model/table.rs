@@ -0,0 +1,461 @@
+use super::error::Error;
+use super::FutRes;
+use crate::sqlite::{Connection, Row, SharedDb, SqlResult, Value};
+use rusqlite::types::{ToSql, ToSqlOutput};
+
+/// A struct stored as a row of a table. Derive it with
+/// `#[derive(Model)]` and `#[model(table = "...")]`, the fields are
+/// the columns. Field attributes:
+///
+/// * `#[model(id)]` - the primary key sqlite assigns on insert
+/// * `#[model(key)]` - a primary key column set by the caller
+/// * `#[model(now)]` - a unix timestamp which is set on insert if it is 0
+/// * `#[model(sql = "...")]` - the column definition in `CREATE TABLE`,
+///   inferred from the type otherwise
+/// * `#[model(skip)]` - not a column, `Default` when loaded
+pub trait Model: Sized + Send + Sync + 'static {
+    const TABLE: &'static str;
+    /// Columns in the order of the fields
+    const COLUMNS: &'static [&'static str];
+    /// Column of `#[model(id)]`
+    const ID: Option<&'static str>;
+    /// Primary key columns
+    const KEY: &'static [&'static str];
+    /// Columns of `#[model(now)]`
+    const NOW: &'static [&'static str];
+    /// Column definitions and constraints for `CREATE TABLE`
+    const DEFINITIONS: &'static [&'static str];
+
+    /// Builds a model from a row selected with `select_sql`
+    fn from_row(row: &Row) -> SqlResult<Self>;
+
+    /// The values of `COLUMNS`
+    fn values(&self) -> SqlResult<Vec<Value>>;
+
+    fn create_sql() -> String {
+        format!(
+            "CREATE TABLE {} (\n    {}\n)",
+            Self::TABLE,
+            Self::DEFINITIONS.join(",\n    ")
+        )
+    }
+
+    /// `SELECT` of all columns, add the `WHERE` clause
+    fn select_sql() -> String {
+        format!("SELECT {} FROM {}", Self::COLUMNS.join(","), Self::TABLE)
+    }
+
+    /// `INSERT` of all columns but the id, bind `insert_values`
+    fn insert_sql() -> String {
+        let mut columns = Vec::new();
+        let mut values = Vec::new();
+        for column in Self::COLUMNS.iter().filter(|c| Some(**c) != Self::ID) {
+            columns.push(*column);
+            let param = format!("?{}", values.len() + 1);
+            if Self::NOW.contains(column) {
+                values.push(format!(
+                    "COALESCE(NULLIF({}, 0), strftime('%s', 'now'))",
+                    param
+                ));
+            } else {
+                values.push(param);
+            }
+        }
+        format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            Self::TABLE,
+            columns.join(","),
+            values.join(",")
+        )
+    }
+
+    fn insert_values(&self) -> SqlResult<Vec<Value>> {
+        let mut values = self.values()?;
+        if let Some(i) = Self::COLUMNS.iter().position(|c| Some(*c) == Self::ID) {
+            values.remove(i);
+        }
+        Ok(values)
+    }
+
+    /// `UPDATE` of all columns by the primary key, bind `update_values`
+    fn update_sql() -> String {
+        let (set, key): (Vec<&str>, Vec<&str>) =
+            Self::COLUMNS.iter().partition(|c| !Self::KEY.contains(c));
+        let set = set
+            .iter()
+            .enumerate()
+            .map(|(i, c)| format!("{}=?{}", c, i + 1))
+            .collect::<Vec<_>>();
+        let key = key
+            .iter()
+            .enumerate()
+            .map(|(i, c)| format!("{}=?{}", c, set.len() + i + 1))
+            .collect::<Vec<_>>();
+        format!(
+            "UPDATE {} SET {} WHERE {}",
+            Self::TABLE,
+            set.join(", "),
+            key.join(" AND ")
+        )
+    }
+
+    fn update_values(&self) -> SqlResult<Vec<Value>> {
+        let (set, key): (Vec<_>, Vec<_>) = Self::COLUMNS
+            .iter()
+            .zip(self.values()?)
+            .partition(|(c, _)| !Self::KEY.contains(c));
+        Ok(set.into_iter().chain(key).map(|(_, v)| v).collect())
+    }
+}
+
+/// Converts a field for `Model::values`
+pub fn to_value<T: ToSql>(field: &T) -> SqlResult<Value> {
+    match field.to_sql()? {
+        ToSqlOutput::Borrowed(value) => Ok(value.into()),
+        ToSqlOutput::Owned(value) => Ok(value),
+    }
+}
+
+/// The single key column of `M`, used to look models up by id
+fn key<M: Model>() -> Option<&'static str> {
+    match M::KEY {
+        [key] => Some(key),
+        _ => None,
+    }
+}
+
+fn no_key<M: Model>() -> Error {
+    Error::Database(format!("table {} has no single key", M::TABLE).into())
+}
+
+fn query<M, F>(conn: &Connection, sql: &str, params: &[Value], from_row: F) -> SqlResult<Vec<M>>
+where
+    F: FnMut(&Row) -> SqlResult<M>,
+{
+    let mut stmt = conn.prepare(sql)?;
+    let iter = stmt.query_map(params, from_row)?;
+    iter.collect()
+}
+
+/// The queries of a table of `Model`s. Tables with extra columns or
+/// tables override the methods which need them.
+pub trait Table {
+    type Model: Model;
+
+    fn db(&self) -> SharedDb;
+
+    /// `SELECT` of the models, add the `WHERE` clause
+    fn select(&self) -> String {
+        Self::Model::select_sql()
+    }
+
+    /// Builds a model from a row of `select`
+    fn from_row(row: &Row) -> SqlResult<Self::Model> {
+        Self::Model::from_row(row)
+    }
+
+    /// Create the table
+    fn create_table(&self) -> FutRes<()> {
+        let db = self.db();
+        let create = Self::Model::create_sql();
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute_batch(&create)
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// Trys to fetch a model by id
+    fn by_id(&self, id: i32) -> FutRes<Option<Self::Model>> {
+        let find = key::<Self::Model>().map(|key| self.find_by(key, id));
+
+        let func = async move {
+            match find {
+                Some(find) => Ok(find.await?.into_iter().next()),
+                None => Err(no_key::<Self::Model>()),
+            }
+        };
+        Box::pin(func)
+    }
+
+    /// Get all entries from the table
+    /// WARNING: This can take a lot of resouces depending on the
+    /// table size
+    fn all(&self) -> FutRes<Vec<Self::Model>> {
+        let db = self.db();
+        let select = self.select();
+
+        let func = async move {
+            let models = db
+                .read(Box::new(move |conn: &Connection| {
+                    query(conn, &select, &[], Self::from_row)
+                }))
+                .await?;
+            Ok(models)
+        };
+        Box::pin(func)
+    }
+
+    /// Models whose `column` equals `value`
+    fn find_by<V: Into<Value>>(&self, column: &str, value: V) -> FutRes<Vec<Self::Model>> {
+        let db = self.db();
+        let column = column.to_owned();
+        let known = Self::Model::COLUMNS.contains(&column.as_str());
+        let select = format!("{} WHERE {}=?1", self.select(), column);
+        let params = vec![value.into()];
+
+        let func = async move {
+            if !known {
+                let msg = format!("no column {} in {}", column, Self::Model::TABLE);
+                return Err(Error::Database(msg.into()));
+            }
+            let models = db
+                .read(Box::new(move |conn: &Connection| {
+                    query(conn, &select, &params, Self::from_row)
+                }))
+                .await?;
+            Ok(models)
+        };
+        Box::pin(func)
+    }
+
+    /// Number of rows in the table
+    fn count(&self) -> FutRes<i64> {
+        let db = self.db();
+        let count = format!("SELECT COUNT(*) FROM {}", Self::Model::TABLE);
+
+        let func = async move {
+            let count = db
+                .read(Box::new(move |conn: &Connection| {
+                    conn.query_row(&count, &[] as &[Value], |row| row.get(0))
+                }))
+                .await?;
+            Ok(count)
+        };
+        Box::pin(func)
+    }
+
+    /// Saves a new model, its id is assigned by sqlite
+    fn save(&self, model: Self::Model) -> FutRes<()> {
+        let db = self.db();
+        let insert = Self::Model::insert_sql();
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute(&insert, &model.insert_values()?)
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// Updates all columns of a model by its key. Returns `false` if
+    /// there is no such row.
+    #[allow(dead_code)]
+    fn update(&self, model: Self::Model) -> FutRes<bool> {
+        let db = self.db();
+        let update = Self::Model::update_sql();
+        let keyless = Self::Model::KEY.is_empty();
+
+        let func = async move {
+            if keyless {
+                let msg = format!("table {} has no key", Self::Model::TABLE);
+                return Err(Error::Database(msg.into()));
+            }
+            let changed = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    conn.execute(&update, &model.update_values()?)
+                }))
+                .await?;
+            Ok(changed > 0)
+        };
+        Box::pin(func)
+    }
+
+    /// Deletes a model by id. Returns `false` if there is no such row.
+    fn delete(&self, id: i32) -> FutRes<bool> {
+        let db = self.db();
+        let delete = key::<Self::Model>()
+            .map(|key| format!("DELETE FROM {} WHERE {}=?1", Self::Model::TABLE, key));
+
+        let func = async move {
+            let delete = match delete {
+                Some(delete) => delete,
+                None => return Err(no_key::<Self::Model>()),
+            };
+            let deleted = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    conn.execute(&delete, [id])
+                }))
+                .await?;
+            Ok(deleted > 0)
+        };
+        Box::pin(func)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use crate::model::Model;
+    use crate::sqlite::Runtime;
+
+    #[derive(Debug, PartialEq, Clone, Model)]
+    #[model(table = "episodes")]
+    struct Episode {
+        #[model(id)]
+        id: i32,
+        title: String,
+        season: Option<i32>,
+        rating: f64,
+        #[model(now)]
+        added_at: i64,
+        #[model(skip)]
+        watched: bool,
+    }
+
+    #[derive(Debug, PartialEq, Clone, Model)]
+    #[model(table = "ratings")]
+    struct Rating {
+        #[model(key)]
+        user_id: i32,
+        #[model(key)]
+        episode_id: i32,
+        stars: i32,
+    }
+
+    struct EpisodeTable {
+        db: SharedDb,
+    }
+
+    impl Table for EpisodeTable {
+        type Model = Episode;
+
+        fn db(&self) -> SharedDb {
+            self.db.clone()
+        }
+    }
+
+    struct RatingTable {
+        db: SharedDb,
+    }
+
+    impl Table for RatingTable {
+        type Model = Rating;
+
+        fn db(&self) -> SharedDb {
+            self.db.clone()
+        }
+    }
+
+    fn episode(title: &str, season: Option<i32>) -> Episode {
+        Episode {
+            id: 0,
+            title: title.to_owned(),
+            season,
+            rating: 0.0,
+            added_at: 0,
+            watched: false,
+        }
+    }
+
+    #[test]
+    fn test_sql() {
+        assert_eq!(
+            "CREATE TABLE episodes (\n    id INTEGER PRIMARY KEY,\n    title TEXT NOT NULL,\n    \
+             season INTEGER,\n    rating REAL NOT NULL,\n    added_at INTEGER NOT NULL\n)",
+            Episode::create_sql()
+        );
+        assert_eq!(
+            "INSERT INTO episodes (title,season,rating,added_at) VALUES \
+             (?1,?2,?3,COALESCE(NULLIF(?4, 0), strftime('%s', 'now')))",
+            Episode::insert_sql()
+        );
+        assert_eq!(
+            "UPDATE episodes SET title=?1, season=?2, rating=?3, added_at=?4 WHERE id=?5",
+            Episode::update_sql()
+        );
+
+        assert_eq!(
+            "CREATE TABLE ratings (\n    user_id INTEGER NOT NULL,\n    \
+             episode_id INTEGER NOT NULL,\n    stars INTEGER NOT NULL,\n    \
+             PRIMARY KEY (user_id, episode_id)\n)",
+            Rating::create_sql()
+        );
+        assert_eq!(
+            "UPDATE ratings SET stars=?1 WHERE user_id=?2 AND episode_id=?3",
+            Rating::update_sql()
+        );
+        let rating = Rating {
+            user_id: 1,
+            episode_id: 2,
+            stars: 5,
+        };
+        assert_eq!(
+            vec![Value::Integer(5), Value::Integer(1), Value::Integer(2)],
+            rating.update_values().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_table() {
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (db, rt) = Runtime::channel(config);
+            rt.run();
+            let t = EpisodeTable { db: db.clone() };
+            t.create_table().await.unwrap();
+
+            t.save(episode("Pilot", Some(1))).await.unwrap();
+            t.save(episode("Special", None)).await.unwrap();
+            assert_eq!(2, t.count().await.unwrap());
+
+            let mut pilot = t.by_id(1).await.unwrap().unwrap();
+            assert_eq!("Pilot", pilot.title);
+            assert!(pilot.added_at > 0);
+            assert_eq!(None, t.by_id(3).await.unwrap());
+
+            let found = t.find_by("season", Value::Null).await.unwrap();
+            assert!(found.is_empty());
+            let found = t.find_by("title", "Special".to_owned()).await.unwrap();
+            assert_eq!(
+                vec![None],
+                found.iter().map(|e| e.season).collect::<Vec<_>>()
+            );
+            assert!(t.find_by("title; DROP", 1).await.is_err());
+
+            pilot.rating = 8.5;
+            assert!(t.update(pilot.clone()).await.unwrap());
+            assert_eq!(Some(pilot), t.by_id(1).await.unwrap());
+
+            assert!(t.delete(1).await.unwrap());
+            assert!(!t.delete(1).await.unwrap());
+            assert_eq!(1, t.all().await.unwrap().len());
+
+            let ratings = RatingTable { db };
+            ratings.create_table().await.unwrap();
+            let mut rating = Rating {
+                user_id: 1,
+                episode_id: 2,
+                stars: 3,
+            };
+            ratings.save(rating.clone()).await.unwrap();
+            rating.stars = 4;
+            assert!(ratings.update(rating.clone()).await.unwrap());
+            assert_eq!(vec![rating], ratings.all().await.unwrap());
+            assert!(ratings.by_id(1).await.is_err());
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+}
@@ -0,0 +1,95 @@
+use super::{FutRes, Model, Table};
+use crate::sqlite::{Connection, SharedDb, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Describes the generated seek preview thumbnails of a movie. The
+/// thumbnails are tiled into sprite sheets of `columns` x `rows`.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Model)]
+#[model(table = "trickplay")]
+pub struct Trickplay {
+    #[model(
+        key,
+        sql = "INTEGER PRIMARY KEY REFERENCES movies (id) ON DELETE CASCADE"
+    )]
+    pub movie_id: i32,
+    /// Duration of the movie in seconds
+    pub duration: f64,
+    /// Seconds between two thumbnails
+    pub interval: i32,
+    /// Size of one thumbnail
+    pub width: i32,
+    pub height: i32,
+    pub columns: i32,
+    pub rows: i32,
+    /// Total number of thumbnails
+    pub count: i32,
+}
+
+/// Represents the table trickplay in the database
+pub struct TrickplayTable {
+    db: SharedDb,
+}
+
+impl TrickplayTable {
+    /// Create a new handler to the trickplay table
+    pub fn new(db: SharedDb) -> TrickplayTable {
+        TrickplayTable { db }
+    }
+
+    /// The durations of the movies with the given ids in seconds,
+    /// movies without thumbnails are left out
+    pub fn durations(&self, ids: Vec<i32>) -> FutRes<HashMap<i32, f64>> {
+        let db = self.db.clone();
+        let placeholders = (1..ids.len() + 1)
+            .map(|i| format!("?{}", i))
+            .collect::<Vec<_>>();
+        let select = format!(
+            "SELECT movie_id, duration FROM {} WHERE movie_id IN ({})",
+            Trickplay::TABLE,
+            placeholders.join(",")
+        );
+        let values = ids
+            .iter()
+            .map(|id| Value::Integer((*id).into()))
+            .collect::<Vec<_>>();
+
+        let func = async move {
+            if ids.is_empty() {
+                return Ok(HashMap::new());
+            }
+            let durations = db
+                .read(Box::new(move |conn: &Connection| {
+                    let mut stmt = conn.prepare(&select)?;
+                    let iter = stmt.query_map(&values, |row| Ok((row.get(0)?, row.get(1)?)))?;
+                    iter.collect::<Result<HashMap<_, _>, _>>()
+                }))
+                .await?;
+            Ok(durations)
+        };
+        Box::pin(func)
+    }
+}
+
+impl Table for TrickplayTable {
+    type Model = Trickplay;
+
+    fn db(&self) -> SharedDb {
+        self.db.clone()
+    }
+
+    /// Saves the thumbnails of a movie, replacing older ones
+    fn save(&self, model: Trickplay) -> FutRes<()> {
+        let db = self.db.clone();
+        let insert = Trickplay::insert_sql().replacen("INSERT", "INSERT OR REPLACE", 1);
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute(&insert, &model.insert_values()?)
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+}
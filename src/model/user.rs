@@ -0,0 +1,413 @@
+use super::error::Error;
+use super::{FutRes, Model, Table};
+use crate::auth;
+use crate::sqlite::{params, Connection, OptionalExtension, SharedDb};
+use chrono::Utc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+const API_TOKENS: &str = "CREATE TABLE api_tokens (
+    token_hash      VARCHAR(64) PRIMARY KEY,
+    user_id         INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at      INTEGER NOT NULL,
+    last_used_at    INTEGER NOT NULL
+)";
+
+/// Adds the PIN to the users table of schema version 3 and below
+pub(super) const ADD_PIN: &str =
+    "ALTER TABLE users ADD COLUMN pin_hash VARCHAR(255) NOT NULL DEFAULT ''";
+
+/// Adds the failed PIN attempts to the users table of schema version 6
+/// and below
+pub(super) const ADD_PIN_FAILURES: &str =
+    "ALTER TABLE users ADD COLUMN pin_failures INTEGER NOT NULL DEFAULT 0";
+
+/// Seconds after which the last use of an API token is updated again
+const TOKEN_USE_INTERVAL: i64 = 3600;
+
+/// Failed attempts in a row after which the PIN of a user is locked
+pub const MAX_PIN_FAILURES: i32 = 5;
+
+/// Hashes a password or PIN. argon2 is slow on purpose, so it runs off
+/// the workers of the runtime.
+async fn hash(password: String) -> Result<String, Error> {
+    tokio::task::spawn_blocking(move || auth::hash_password(&password))
+        .await
+        .map_err(|e| Error::Database(Box::new(e)))
+}
+
+/// Represents the table users and their API tokens in the database
+pub struct UserTable {
+    db: SharedDb,
+}
+
+impl UserTable {
+    /// Create a new handler to the users table
+    pub fn new(db: SharedDb) -> UserTable {
+        UserTable { db }
+    }
+
+    /// Trys to fetch a user by name
+    pub fn by_name(&self, name: &str) -> FutRes<Option<User>> {
+        let find = self.find_by("name", name.to_owned());
+
+        let func = async move { Ok(find.await?.into_iter().next()) };
+        Box::pin(func)
+    }
+
+    /// Trys to fetch the user owning an API token. Updates the time
+    /// the token was last used in the background once it is older than
+    /// `TOKEN_USE_INTERVAL`, so requests don't wait for the writer.
+    pub fn by_token(&self, token: &str) -> FutRes<Option<User>> {
+        let db = self.db.clone();
+        let hash = auth::hash_token(token);
+        let fields = User::COLUMNS
+            .iter()
+            .map(|f| format!("users.{}", f))
+            .collect::<Vec<_>>();
+        let select = format!(
+            "SELECT {}, api_tokens.last_used_at FROM {} \
+             JOIN api_tokens ON api_tokens.user_id = users.id \
+             WHERE api_tokens.token_hash=?1 LIMIT 1",
+            fields.join(","),
+            User::TABLE
+        );
+
+        let func = async move {
+            let found = {
+                let hash = hash.clone();
+                db.read(Box::new(move |conn: &Connection| {
+                    conn.query_row(&select, params![hash], |row| {
+                        let last_used_at: i64 = row.get(User::COLUMNS.len())?;
+                        Ok((User::from_row(row)?, last_used_at))
+                    })
+                    .optional()
+                }))
+                .await?
+            };
+            let (user, last_used_at) = match found {
+                Some(found) => found,
+                None => return Ok(None),
+            };
+
+            let now = Utc::now().timestamp();
+            if last_used_at < now - TOKEN_USE_INTERVAL {
+                tokio::spawn(async move {
+                    let touch = db
+                        .spawn(Box::new(move |conn: &Connection| {
+                            conn.execute(
+                                "UPDATE api_tokens SET last_used_at=?1 WHERE token_hash=?2",
+                                params![now, hash],
+                            )
+                        }))
+                        .await;
+                    if let Err(e) = touch {
+                        warn!("could not update the last use of a token: {}", e);
+                    }
+                });
+            }
+            Ok(Some(user))
+        };
+        Box::pin(func)
+    }
+
+    /// Replace the password of a user. All API tokens of the user are
+    /// revoked.
+    pub fn set_password(&self, id: i32, password: &str) -> FutRes<()> {
+        let db = self.db.clone();
+        let password = password.to_owned();
+
+        let func = async move {
+            let hash = hash(password).await?;
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute(
+                    "UPDATE users SET password_hash=?1 WHERE id=?2",
+                    params![hash, id],
+                )?;
+                conn.execute("DELETE FROM api_tokens WHERE user_id=?1", params![id])
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// Set the PIN to switch to a user, `None` removes it. A locked PIN
+    /// is unlocked.
+    pub fn set_pin(&self, id: i32, pin: Option<&str>) -> FutRes<()> {
+        let db = self.db.clone();
+        let pin = pin.map(|pin| pin.to_owned());
+
+        let func = async move {
+            let hash = match pin {
+                Some(pin) => hash(pin).await?,
+                None => String::new(),
+            };
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute(
+                    "UPDATE users SET pin_hash=?1, pin_failures=0 WHERE id=?2",
+                    params![hash, id],
+                )
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// Counts an attempt to switch to a user by PIN as failed until
+    /// `pin_succeeded` says otherwise. Returns `false` and counts
+    /// nothing if the PIN is locked after `MAX_PIN_FAILURES` failed
+    /// attempts in a row.
+    pub fn pin_attempt(&self, id: i32) -> FutRes<bool> {
+        let db = self.db.clone();
+
+        let func = async move {
+            let counted = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    conn.execute(
+                        "UPDATE users SET pin_failures=pin_failures + 1 \
+                         WHERE id=?1 AND pin_failures < ?2",
+                        params![id, MAX_PIN_FAILURES],
+                    )
+                }))
+                .await?;
+            Ok(counted > 0)
+        };
+        Box::pin(func)
+    }
+
+    /// Clears the failed PIN attempts of a user after a switch
+    pub fn pin_succeeded(&self, id: i32) -> FutRes<()> {
+        let db = self.db.clone();
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute("UPDATE users SET pin_failures=0 WHERE id=?1", params![id])
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// Issue a new API token for a user. Only the hash of the token is
+    /// stored, so the returned token can't be recovered later.
+    pub fn issue_token(&self, user_id: i32) -> FutRes<String> {
+        let db = self.db.clone();
+        let token = auth::generate_token();
+        let hash = auth::hash_token(&token);
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute(
+                    "INSERT INTO api_tokens (token_hash, user_id, created_at, last_used_at) \
+                     VALUES (?1, ?2, strftime('%s', 'now'), strftime('%s', 'now'))",
+                    params![hash, user_id],
+                )
+            }))
+            .await?;
+            Ok(token)
+        };
+        Box::pin(func)
+    }
+
+    /// Revoke a single API token
+    pub fn revoke_token(&self, token: &str) -> FutRes<()> {
+        let db = self.db.clone();
+        let hash = auth::hash_token(token);
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute("DELETE FROM api_tokens WHERE token_hash=?1", params![hash])
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    /// Revoke all API tokens of a user
+    pub fn revoke_tokens(&self, user_id: i32) -> FutRes<()> {
+        let db = self.db.clone();
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute("DELETE FROM api_tokens WHERE user_id=?1", params![user_id])
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+}
+
+impl Table for UserTable {
+    type Model = User;
+
+    fn db(&self) -> SharedDb {
+        self.db.clone()
+    }
+
+    fn create_table(&self) -> FutRes<()> {
+        let db = self.db.clone();
+        let create = format!("{};\n{};", User::create_sql(), API_TOKENS);
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute_batch(&create)
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+
+    fn all(&self) -> FutRes<Vec<Self::Model>> {
+        let db = self.db.clone();
+        let select = format!("{} ORDER BY name", self.select());
+
+        let func = async move {
+            let users = db
+                .read(Box::new(move |conn: &Connection| {
+                    let mut stmt = conn.prepare(&select)?;
+                    let user_iter = stmt.query_map(params![], User::from_row)?;
+                    user_iter.collect::<Result<Vec<_>, _>>()
+                }))
+                .await?;
+            Ok(users)
+        };
+        Box::pin(func)
+    }
+}
+
+/// A user, `password_hash` must be hashed with `auth::hash_password`.
+/// The API tokens of a user are deleted with it.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Model)]
+#[model(table = "users")]
+pub struct User {
+    #[model(id)]
+    pub id: i32,
+    #[model(sql = "VARCHAR(255) NOT NULL UNIQUE COLLATE NOCASE")]
+    pub name: String,
+    #[serde(skip)]
+    #[model(sql = "VARCHAR(255) NOT NULL")]
+    pub password_hash: String,
+    #[model(sql = "BOOLEAN NOT NULL DEFAULT 0")]
+    pub is_admin: bool,
+    /// Hash of the PIN to switch to this profile, empty if it has none
+    #[serde(skip)]
+    #[model(sql = "VARCHAR(255) NOT NULL DEFAULT ''")]
+    pub pin_hash: String,
+    /// Failed PIN attempts in a row, the PIN is locked at
+    /// `MAX_PIN_FAILURES` until it is set again
+    #[serde(skip)]
+    #[model(sql = "INTEGER NOT NULL DEFAULT 0")]
+    pub pin_failures: i32,
+    /// Unix timestamp of when the user was created
+    #[model(now)]
+    pub created_at: i64,
+}
+
+impl User {
+    /// Create a new user with a hashed password, ready to be saved
+    pub fn new(name: &str, password: &str, is_admin: bool) -> User {
+        User {
+            id: 0,
+            name: name.to_owned(),
+            password_hash: auth::hash_password(password),
+            is_admin,
+            pin_hash: String::new(),
+            pin_failures: 0,
+            created_at: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use crate::sqlite::Runtime;
+
+    #[test]
+    fn test_tokens() {
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (db, rt) = Runtime::channel(config);
+            rt.run();
+            let t = UserTable::new(db.clone());
+            t.create_table().await.unwrap();
+            assert_eq!(0, t.count().await.unwrap());
+
+            t.save(User::new("Jan", "secret", true)).await.unwrap();
+            let user = t.by_name("jan").await.unwrap().unwrap();
+            assert_eq!(1, user.id);
+            assert!(user.is_admin);
+            assert!(auth::verify_password("secret", &user.password_hash));
+
+            let first = t.issue_token(user.id).await.unwrap();
+            let second = t.issue_token(user.id).await.unwrap();
+            assert_eq!(Some(user.clone()), t.by_token(&first).await.unwrap());
+            assert_eq!(None, t.by_token("invalid").await.unwrap());
+
+            // a stale time of last use is updated in the background
+            let last_used = |db: SharedDb| async move {
+                db.read(Box::new(|conn: &Connection| {
+                    conn.query_row(
+                        "SELECT MIN(last_used_at) FROM api_tokens",
+                        params![],
+                        |row| row.get::<_, i64>(0),
+                    )
+                }))
+                .await
+                .unwrap()
+            };
+            db.spawn(Box::new(|conn: &Connection| {
+                conn.execute("UPDATE api_tokens SET last_used_at=0", params![])
+            }))
+            .await
+            .unwrap();
+            assert!(t.by_token(&first).await.unwrap().is_some());
+            assert!(t.by_token(&second).await.unwrap().is_some());
+            for _ in 0..50 {
+                if last_used(db.clone()).await > 0 {
+                    break;
+                }
+                tokio::time::delay_for(std::time::Duration::from_millis(20)).await;
+            }
+            assert!(last_used(db.clone()).await > 0);
+
+            t.revoke_token(&first).await.unwrap();
+            assert_eq!(None, t.by_token(&first).await.unwrap());
+            assert_eq!(Some(user.clone()), t.by_token(&second).await.unwrap());
+
+            t.set_pin(user.id, Some("1234")).await.unwrap();
+            let pin_hash = t.by_id(user.id).await.unwrap().unwrap().pin_hash;
+            assert!(auth::verify_password("1234", &pin_hash));
+            for _ in 0..MAX_PIN_FAILURES {
+                assert!(t.pin_attempt(user.id).await.unwrap());
+            }
+            assert!(!t.pin_attempt(user.id).await.unwrap());
+            // setting the PIN again unlocks it
+            t.set_pin(user.id, Some("5678")).await.unwrap();
+            assert!(t.pin_attempt(user.id).await.unwrap());
+            t.pin_succeeded(user.id).await.unwrap();
+            assert_eq!(0, t.by_id(user.id).await.unwrap().unwrap().pin_failures);
+            t.set_pin(user.id, None).await.unwrap();
+            assert_eq!("", t.by_id(user.id).await.unwrap().unwrap().pin_hash);
+
+            t.set_password(user.id, "new secret").await.unwrap();
+            assert_eq!(None, t.by_token(&second).await.unwrap());
+
+            assert!(t.delete(user.id).await.unwrap());
+            assert_eq!(None, t.by_id(user.id).await.unwrap());
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+}
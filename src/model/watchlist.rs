@@ -0,0 +1,107 @@
+use super::query::Page;
+use super::{allowed, FutRes, Model, Table};
+use crate::sqlite::{params, Connection, SharedDb};
+use serde::{Deserialize, Serialize};
+
+/// A movie a user wants to watch
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Model)]
+#[model(table = "watchlist")]
+pub struct WatchlistEntry {
+    #[model(key, sql = "INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE")]
+    pub user_id: i32,
+    #[model(key, sql = "INTEGER NOT NULL REFERENCES movies (id) ON DELETE CASCADE")]
+    pub movie_id: i32,
+    /// Unix timestamp of when the movie was added
+    #[model(now)]
+    pub added_at: i64,
+}
+
+/// Represents the table watchlist in the database
+pub struct WatchlistTable {
+    db: SharedDb,
+}
+
+impl WatchlistTable {
+    /// Create a new handler to the watchlist table
+    pub fn new(db: SharedDb) -> WatchlistTable {
+        WatchlistTable { db }
+    }
+
+    /// Removes a movie from the watchlist of a user. Returns `false` if
+    /// it wasn't on the list.
+    pub fn remove(&self, user_id: i32, movie_id: i32) -> FutRes<bool> {
+        let db = self.db.clone();
+
+        let func = async move {
+            let deleted = db
+                .spawn(Box::new(move |conn: &Connection| {
+                    conn.execute(
+                        "DELETE FROM watchlist WHERE user_id=?1 AND movie_id=?2",
+                        params![user_id, movie_id],
+                    )
+                }))
+                .await?;
+            Ok(deleted > 0)
+        };
+        Box::pin(func)
+    }
+
+    /// One page of the watchlist of a user, latest added first. Movies
+    /// hidden from the user are left out.
+    pub fn page(&self, user_id: i32, limit: u32, offset: u32) -> FutRes<Page<WatchlistEntry>> {
+        let db = self.db.clone();
+        let count = format!(
+            "SELECT COUNT(*) FROM watchlist WHERE user_id=?1 AND {}",
+            allowed("movie_id", "?1")
+        );
+        let select = format!(
+            "{} WHERE user_id=?1 AND {} ORDER BY added_at DESC, movie_id DESC \
+             LIMIT ?2 OFFSET ?3",
+            self.select(),
+            allowed("movie_id", "?1")
+        );
+
+        let func = async move {
+            let page = db
+                .read(Box::new(move |conn: &Connection| {
+                    let total = conn.query_row(&count, params![user_id], |row| row.get(0))?;
+                    let mut stmt = conn.prepare(&select)?;
+                    let iter =
+                        stmt.query_map(params![user_id, limit, offset], WatchlistEntry::from_row)?;
+                    Ok(Page {
+                        total,
+                        limit,
+                        offset,
+                        items: iter.collect::<Result<Vec<_>, _>>()?,
+                    })
+                }))
+                .await?;
+            Ok(page)
+        };
+        Box::pin(func)
+    }
+}
+
+impl Table for WatchlistTable {
+    type Model = WatchlistEntry;
+
+    fn db(&self) -> SharedDb {
+        self.db.clone()
+    }
+
+    /// Adds a movie to the watchlist, a movie already on the list keeps
+    /// its position
+    fn save(&self, model: WatchlistEntry) -> FutRes<()> {
+        let db = self.db.clone();
+        let insert = WatchlistEntry::insert_sql().replacen("INSERT", "INSERT OR IGNORE", 1);
+
+        let func = async move {
+            db.spawn(Box::new(move |conn: &Connection| {
+                conn.execute(&insert, &model.insert_values()?)
+            }))
+            .await?;
+            Ok(())
+        };
+        Box::pin(func)
+    }
+}
@@ -0,0 +1,424 @@
+use chrono::Utc;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Characters of room codes, without the ones easily mixed up
+const CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LEN: usize = 6;
+/// Milliseconds a room nobody joined is kept, so its creator has time
+/// to connect
+const EMPTY_TIMEOUT: i64 = 10 * 60 * 1000;
+/// Updates kept for slow members, see `tokio::sync::broadcast`
+const CAPACITY: usize = 64;
+/// Seconds of drift above which members should seek
+const SEEK_DRIFT: f64 = 2.0;
+/// Seconds of drift above which members should change their playback
+/// rate to catch up
+const RATE_DRIFT: f64 = 0.25;
+/// How much faster or slower members should play to catch up
+const RATE_CHANGE: f64 = 0.05;
+
+/// Milliseconds since the epoch, the clock the members sync to
+pub fn now() -> i64 {
+    Utc::now().timestamp_millis()
+}
+
+/// The shared playback state of a room
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub struct Playback {
+    pub playing: bool,
+    /// Position in seconds at `server_time`
+    pub position: f64,
+    pub server_time: i64,
+}
+
+impl Playback {
+    /// The position at `now`, a playing movie moves on in real time
+    pub fn position_at(&self, now: i64) -> f64 {
+        if self.playing {
+            self.position + (now - self.server_time).max(0) as f64 / 1000.0
+        } else {
+            self.position
+        }
+    }
+
+    /// Plays or pauses at `position`, or where the room is now if it
+    /// isn't given
+    pub fn set_playing(&mut self, playing: bool, position: Option<f64>, now: i64) {
+        let position = position.unwrap_or_else(|| self.position_at(now));
+        self.playing = playing;
+        self.seek(position, now);
+    }
+
+    pub fn seek(&mut self, position: f64, now: i64) {
+        self.position = position.max(0.0);
+        self.server_time = now;
+    }
+
+    /// How a member at `position` gets back in sync
+    pub fn hint(&self, position: f64, now: i64) -> Hint {
+        let expected = self.position_at(now);
+        let drift = position - expected;
+        let (correction, rate) = match drift.abs() {
+            d if d > SEEK_DRIFT || (d > RATE_DRIFT && !self.playing) => (Correction::Seek, 1.0),
+            d if d > RATE_DRIFT && drift > 0.0 => (Correction::Rate, 1.0 - RATE_CHANGE),
+            d if d > RATE_DRIFT => (Correction::Rate, 1.0 + RATE_CHANGE),
+            _ => (Correction::None, 1.0),
+        };
+        Hint {
+            drift,
+            expected,
+            server_time: now,
+            correction,
+            rate,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Correction {
+    /// In sync, keep playing
+    None,
+    /// Play at `rate` until the drift is gone
+    Rate,
+    /// Seek to `expected`
+    Seek,
+}
+
+/// Answer to the position report of a member
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub struct Hint {
+    /// Seconds the member is ahead, negative if behind
+    pub drift: f64,
+    /// Where the member should be at `server_time`
+    pub expected: f64,
+    pub server_time: i64,
+    pub correction: Correction,
+    pub rate: f64,
+}
+
+/// A message of a member to its room
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    Play {
+        position: Option<f64>,
+    },
+    Pause {
+        position: Option<f64>,
+    },
+    Seek {
+        position: f64,
+    },
+    /// Clock sync, answered with `Update::Pong`
+    Ping {
+        client_time: f64,
+    },
+    /// The position of the member, answered with `Update::Drift`
+    Report {
+        position: f64,
+    },
+}
+
+/// A message of the server to the members
+#[derive(Debug, PartialEq, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Update {
+    /// The state of the room, sent on joining and after every change.
+    /// `action` is what changed and `by` the name of who did it.
+    State {
+        action: String,
+        by: String,
+        #[serde(flatten)]
+        playback: Playback,
+        members: Vec<String>,
+    },
+    Pong {
+        client_time: f64,
+        server_time: i64,
+    },
+    Drift(Hint),
+    Error {
+        message: String,
+    },
+}
+
+/// A room as returned by the API
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct RoomInfo {
+    pub code: String,
+    pub movie_id: i32,
+    /// Id of the user who created the room
+    pub host: i32,
+    #[serde(flatten)]
+    pub playback: Playback,
+    pub members: Vec<String>,
+}
+
+struct Room {
+    movie_id: i32,
+    host: i32,
+    created_at: i64,
+    playback: Playback,
+    /// Names by member id, a user with two devices is two members
+    members: HashMap<u64, String>,
+    tx: broadcast::Sender<Update>,
+}
+
+impl Room {
+    fn info(&self, code: &str) -> RoomInfo {
+        RoomInfo {
+            code: code.to_owned(),
+            movie_id: self.movie_id,
+            host: self.host,
+            playback: self.playback,
+            members: self.names(),
+        }
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names = self.members.values().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Sends the state to all members
+    fn broadcast(&self, action: &str, by: &str) {
+        let _ = self.tx.send(Update::State {
+            action: action.to_owned(),
+            by: by.to_owned(),
+            playback: self.playback,
+            members: self.names(),
+        });
+    }
+}
+
+/// A connection to a room
+pub struct Member {
+    pub id: u64,
+    pub updates: broadcast::Receiver<Update>,
+}
+
+/// The watch parties of the server. Rooms live in memory and are gone
+/// when their last member leaves or the server stops.
+#[derive(Clone, Default)]
+pub struct Parties {
+    rooms: Arc<Mutex<HashMap<String, Room>>>,
+    next_member: Arc<AtomicU64>,
+}
+
+impl Parties {
+    pub fn new() -> Parties {
+        Parties::default()
+    }
+
+    /// Opens a paused room for a movie
+    pub fn create(&self, movie_id: i32, host: i32) -> RoomInfo {
+        let now = now();
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.retain(|_, room| !room.members.is_empty() || now - room.created_at < EMPTY_TIMEOUT);
+
+        let mut code = random_code();
+        while rooms.contains_key(&code) {
+            code = random_code();
+        }
+        let (tx, _) = broadcast::channel(CAPACITY);
+        let room = Room {
+            movie_id,
+            host,
+            created_at: now,
+            playback: Playback {
+                playing: false,
+                position: 0.0,
+                server_time: now,
+            },
+            members: HashMap::new(),
+            tx,
+        };
+        let info = room.info(&code);
+        rooms.insert(code, room);
+        info
+    }
+
+    pub fn get(&self, code: &str) -> Option<RoomInfo> {
+        let code = code.to_uppercase();
+        let rooms = self.rooms.lock().unwrap();
+        rooms.get(&code).map(|room| room.info(&code))
+    }
+
+    /// Adds a member to a room and tells the others. The new member
+    /// gets the state as first update.
+    pub fn join(&self, code: &str, name: &str) -> Option<Member> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.get_mut(&code.to_uppercase())?;
+        let id = self.next_member.fetch_add(1, Ordering::SeqCst);
+        let updates = room.tx.subscribe();
+        room.members.insert(id, name.to_owned());
+        room.broadcast("join", name);
+        Some(Member { id, updates })
+    }
+
+    /// Removes a member, and the room with its last member
+    pub fn leave(&self, code: &str, member: u64) {
+        let code = code.to_uppercase();
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = match rooms.get_mut(&code) {
+            Some(room) => room,
+            None => return,
+        };
+        let name = room.members.remove(&member).unwrap_or_default();
+        if room.members.is_empty() {
+            rooms.remove(&code);
+        } else {
+            room.broadcast("leave", &name);
+        }
+    }
+
+    /// Runs the command of a member. Changes of the playback are sent
+    /// to all members, answers are returned for the member.
+    pub fn command(
+        &self,
+        code: &str,
+        member: u64,
+        command: Command,
+    ) -> Result<Option<Update>, String> {
+        let now = now();
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms
+            .get_mut(&code.to_uppercase())
+            .ok_or("the room is closed")?;
+        let name = room.members.get(&member).cloned().unwrap_or_default();
+        let action = match command {
+            Command::Play { position } => {
+                room.playback.set_playing(true, valid(position)?, now);
+                "play"
+            }
+            Command::Pause { position } => {
+                room.playback.set_playing(false, valid(position)?, now);
+                "pause"
+            }
+            Command::Seek { position } => {
+                let position = valid(Some(position))?.unwrap_or_default();
+                room.playback.seek(position, now);
+                "seek"
+            }
+            Command::Ping { client_time } => {
+                let pong = Update::Pong {
+                    client_time,
+                    server_time: now,
+                };
+                return Ok(Some(pong));
+            }
+            Command::Report { position } => {
+                return Ok(Some(Update::Drift(room.playback.hint(position, now))));
+            }
+        };
+        room.broadcast(action, &name);
+        Ok(None)
+    }
+
+    /// Closes all rooms, which ends the connections of their members
+    pub fn close(&self) {
+        self.rooms.lock().unwrap().clear();
+    }
+}
+
+/// Checks a position of a command, which may be left out
+fn valid(position: Option<f64>) -> Result<Option<f64>, String> {
+    match position {
+        Some(p) if !p.is_finite() || p < 0.0 => Err(format!("invalid position {}", p)),
+        position => Ok(position),
+    }
+}
+
+fn random_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CODE_LEN)
+        .map(|_| CODE_CHARS[rng.gen_range(0..CODE_CHARS.len())] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_playback() {
+        let mut playback = Playback {
+            playing: false,
+            position: 0.0,
+            server_time: 0,
+        };
+        playback.set_playing(true, Some(10.0), 1000);
+        assert_eq!(15.0, playback.position_at(6000));
+
+        // in sync, a little ahead, far behind
+        assert_eq!(Correction::None, playback.hint(15.1, 6000).correction);
+        let hint = playback.hint(15.5, 6000);
+        assert_eq!(Correction::Rate, hint.correction);
+        assert!(hint.rate < 1.0);
+        let hint = playback.hint(5.0, 6000);
+        assert_eq!(Correction::Seek, hint.correction);
+        assert_eq!(15.0, hint.expected);
+
+        playback.set_playing(false, None, 6000);
+        assert_eq!(15.0, playback.position_at(60000));
+        assert_eq!(Correction::Seek, playback.hint(15.5, 60000).correction);
+    }
+
+    #[test]
+    fn test_parties() {
+        let parties = Parties::new();
+        let room = parties.create(7, 1);
+        assert_eq!(CODE_LEN, room.code.len());
+        assert!(!room.playback.playing);
+
+        let mut jan = parties.join(&room.code.to_lowercase(), "jan").unwrap();
+        let mut kim = parties.join(&room.code, "kim").unwrap();
+        assert_eq!(vec!["jan", "kim"], parties.get(&room.code).unwrap().members);
+        // jan sees both joining
+        assert!(matches!(jan.updates.try_recv(), Ok(Update::State { .. })));
+        assert!(matches!(jan.updates.try_recv(), Ok(Update::State { .. })));
+        // kim starts with the state
+        assert!(matches!(kim.updates.try_recv(), Ok(Update::State { .. })));
+
+        let play = Command::Play {
+            position: Some(30.0),
+        };
+        assert_eq!(Ok(None), parties.command(&room.code, kim.id, play));
+        match kim.updates.try_recv() {
+            Ok(Update::State {
+                action,
+                by,
+                playback,
+                ..
+            }) => {
+                assert_eq!("play", action);
+                assert_eq!("kim", by);
+                assert!(playback.playing);
+                assert_eq!(30.0, playback.position);
+            }
+            update => panic!("unexpected {:?}", update),
+        }
+        let pong = parties.command(&room.code, jan.id, Command::Ping { client_time: 1.5 });
+        assert!(matches!(
+            pong,
+            Ok(Some(Update::Pong {
+                client_time,
+                ..
+            })) if client_time == 1.5
+        ));
+        let seek = Command::Seek { position: -1.0 };
+        assert!(parties.command(&room.code, jan.id, seek).is_err());
+
+        parties.leave(&room.code, jan.id);
+        assert_eq!(vec!["kim"], parties.get(&room.code).unwrap().members);
+        parties.leave(&room.code, kim.id);
+        assert!(parties.get(&room.code).is_none());
+    }
+}
@@ -0,0 +1,154 @@
+use crate::dlna::soap::escape;
+use std::str::FromStr;
+
+/// A movie in a playlist
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub title: String,
+    /// Seconds, if known
+    pub duration: Option<f64>,
+    pub url: String,
+    pub poster: Option<String>,
+}
+
+/// The playlist formats external players such as VLC and mpv open
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    M3u8,
+    Xspf,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::M3u8 => "m3u8",
+            Format::Xspf => "xspf",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::M3u8 => "audio/x-mpegurl; charset=utf-8",
+            Format::Xspf => "application/xspf+xml; charset=utf-8",
+        }
+    }
+
+    pub fn render(self, title: &str, entries: &[Entry]) -> String {
+        match self {
+            Format::M3u8 => m3u(title, entries),
+            Format::Xspf => xspf(title, entries),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "m3u8" => Ok(Format::M3u8),
+            "xspf" => Ok(Format::Xspf),
+            _ => Err(format!("unknown playlist format {}", s)),
+        }
+    }
+}
+
+/// M3U keeps one entry per line
+fn line(s: &str) -> String {
+    s.replace(['\r', '\n'], " ")
+}
+
+/// Extended M3U. The poster goes into `tvg-logo`, which VLC shows as
+/// artwork.
+pub fn m3u(title: &str, entries: &[Entry]) -> String {
+    let mut m3u = format!("#EXTM3U\n#PLAYLIST:{}\n", line(title));
+    for entry in entries {
+        let duration = entry.duration.map_or(-1, |d| d.round() as i64);
+        let logo = match &entry.poster {
+            Some(poster) => format!(" tvg-logo=\"{}\"", line(poster).replace('"', "%22")),
+            None => String::new(),
+        };
+        m3u.push_str(&format!(
+            "#EXTINF:{}{},{}\n{}\n",
+            duration,
+            logo,
+            line(&entry.title),
+            line(&entry.url)
+        ));
+    }
+    m3u
+}
+
+/// XSPF, durations are in milliseconds
+pub fn xspf(title: &str, entries: &[Entry]) -> String {
+    let mut xspf = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n\
+         <title>{}</title>\n<trackList>\n",
+        escape(title)
+    );
+    for entry in entries {
+        xspf.push_str("<track>");
+        xspf.push_str(&format!("<location>{}</location>", escape(&entry.url)));
+        xspf.push_str(&format!("<title>{}</title>", escape(&entry.title)));
+        if let Some(duration) = entry.duration {
+            let ms = (duration * 1000.0).round() as i64;
+            xspf.push_str(&format!("<duration>{}</duration>", ms));
+        }
+        if let Some(poster) = &entry.poster {
+            xspf.push_str(&format!("<image>{}</image>", escape(poster)));
+        }
+        xspf.push_str("</track>\n");
+    }
+    xspf.push_str("</trackList>\n</playlist>\n");
+    xspf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry {
+                title: "Tom & Jerry".to_owned(),
+                duration: Some(5400.4),
+                url: "http://host/stream/1?user=1&expires=2&sig=ab".to_owned(),
+                poster: Some("https://image.tmdb.org/t/p/w500/a.jpg".to_owned()),
+            },
+            Entry {
+                title: "Two\nLines".to_owned(),
+                duration: None,
+                url: "http://host/stream/2".to_owned(),
+                poster: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_m3u() {
+        assert_eq!(
+            "#EXTM3U\n#PLAYLIST:Movies\n\
+             #EXTINF:5400 tvg-logo=\"https://image.tmdb.org/t/p/w500/a.jpg\",Tom & Jerry\n\
+             http://host/stream/1?user=1&expires=2&sig=ab\n\
+             #EXTINF:-1,Two Lines\nhttp://host/stream/2\n",
+            m3u("Movies", &entries())
+        );
+    }
+
+    #[test]
+    fn test_xspf() {
+        let xspf = xspf("Movies & more", &entries());
+        assert!(xspf.contains("<title>Movies &amp; more</title>"));
+        assert!(xspf.contains(
+            "<track><location>http://host/stream/1?user=1&amp;expires=2&amp;sig=ab</location>\
+             <title>Tom &amp; Jerry</title><duration>5400400</duration>\
+             <image>https://image.tmdb.org/t/p/w500/a.jpg</image></track>"
+        ));
+        assert!(xspf.contains(
+            "<track><location>http://host/stream/2</location><title>Two\nLines</title></track>"
+        ));
+        assert_eq!(Ok(Format::Xspf), "xspf".parse());
+        assert!("pls".parse::<Format>().is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use crate::config::{Config, LibraryConfig};
+use crate::model::Movie;
 use regex::Regex;
 use std::fs::{self, DirEntry};
 use std::io;
@@ -12,6 +13,27 @@ pub struct VideoFile {
     pub path: PathBuf,
     pub poster_path: String,
     pub backdrop_path: String,
+    pub library: String,
+}
+
+impl From<VideoFile> for Movie {
+    fn from(file: VideoFile) -> Movie {
+        Movie {
+            id: 0,
+            tmdb_id: 0,
+            title: file.title,
+            overview: "".to_owned(),
+            release_year: file.release_year,
+            file_path: file.path.to_string_lossy().into_owned(),
+            poster_path: file.poster_path,
+            backdrop_path: file.backdrop_path,
+            rating: 0.0,
+            resolution: 0,
+            library: file.library,
+            added_at: 0,
+            genres: Vec::new(),
+        }
+    }
 }
 
 pub struct Scanner {
@@ -51,12 +73,20 @@ impl Scanner {
         Ok(())
     }
 
+    /// Finds the movies of all libraries, tagged with their library
     pub fn run(&mut self) -> io::Result<&[VideoFile]> {
-        let movie_path = self.config.movies.clone();
+        let config = self.config.clone();
+        for (name, dir) in config.dirs.iter() {
+            self.run_library(name, dir)?;
+        }
+        Ok(&self.movies)
+    }
+
+    fn run_library(&mut self, name: &str, movie_path: &str) -> io::Result<()> {
         let re = self.patterns[0].clone();
 
         self.visit_dir(
-            &Path::new(&movie_path),
+            Path::new(movie_path),
             &|entry: &DirEntry, movies: &mut Vec<VideoFile>| {
                 let file_name = entry.file_name().into_string().unwrap();
                 if re.is_match(&file_name) {
@@ -70,12 +100,11 @@ impl Scanner {
                         path: entry.path(),
                         poster_path: "/c6WuCykIy8GAsitJOTk1DEga1ML.jpg".to_owned(),
                         backdrop_path: "/qDVdTL1KqGmGLGsy7UPA3vksku7.jpg".to_owned(),
+                        library: name.to_owned(),
                     };
                     movies.push(movie);
                 }
             },
-        )?;
-
-        Ok(&self.movies)
+        )
     }
 }
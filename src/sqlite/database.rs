@@ -1,50 +1,109 @@
 use super::SharedDb;
 use crate::config::DatabaseConfig;
+use crate::logging;
+use crate::metrics;
+use log::{debug, warn};
 use rusqlite::backup;
-use rusqlite::{Connection, Result as SqlResult};
+use rusqlite::{
+    Connection, DatabaseName, Error as SqlError, ErrorCode, OpenFlags, Result as SqlResult,
+    Transaction, TransactionBehavior,
+};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{mpsc as std_mpsc, Arc, Mutex};
-use std::time;
-use tokio::sync::mpsc as tokio_mpsc;
+use std::{thread, time};
+use tokio::sync::oneshot;
 
-type ResultSender<T> = tokio_mpsc::Sender<SqlResult<T>>;
+type Queue = std_mpsc::Sender<Box<dyn Runable>>;
+type Func<R> = Box<dyn Fn(&mut Connection) -> SqlResult<R> + Send>;
 
 trait Runable: Send + 'static {
-    fn run(&self, conn: &Connection);
+    fn run(self: Box<Self>, conn: &mut Connection);
+
+    /// The connection stops after this job
+    fn is_last(&self) -> bool {
+        false
+    }
 }
 
-struct Job<F, R> {
-    func: Box<F>,
-    sender: ResultSender<R>,
+/// Stops a connection. Jobs sent before are still run.
+struct Close {
+    sender: oneshot::Sender<()>,
 }
 
-impl<F, R> Job<F, R>
-where
-    F: Fn(&Connection) -> SqlResult<R>,
-    F: Send + 'static,
-    R: Send + 'static,
-{
-    fn run_now(&self, conn: &Connection) {
-        let res = (self.func)(conn);
-        let mut tx = self.sender.clone();
-        tokio::spawn(async move { tx.send(res).await });
+impl Runable for Close {
+    fn run(self: Box<Self>, _: &mut Connection) {
+        let _ = self.sender.send(());
+    }
+
+    fn is_last(&self) -> bool {
+        true
+    }
+}
+
+/// Jobs taking longer are logged as slow
+const SLOW_JOB: time::Duration = time::Duration::from_millis(200);
+/// How long a job which found its table locked is retried
+const LOCK_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+const LOCK_RETRY: time::Duration = time::Duration::from_millis(1);
+
+struct Job<R> {
+    func: Func<R>,
+    sender: oneshot::Sender<SqlResult<R>>,
+    /// Request id of the task which spawned the job
+    request_id: Option<String>,
+    /// Jobs which are read-only or run in a transaction are retried
+    /// while their table is locked
+    retry: bool,
+}
+
+impl<R: Send + 'static> Runable for Job<R> {
+    fn run(self: Box<Self>, conn: &mut Connection) {
+        let Job {
+            func,
+            sender,
+            request_id,
+            retry,
+        } = *self;
+        let res = logging::with_request_id(request_id, || {
+            let start = time::Instant::now();
+            let res = loop {
+                let res = func(conn);
+                if retry && is_locked(&res) && start.elapsed() < LOCK_TIMEOUT {
+                    thread::sleep(LOCK_RETRY);
+                    continue;
+                }
+                break res;
+            };
+            let elapsed = start.elapsed();
+            if elapsed > SLOW_JOB {
+                warn!("slow sql job took {}ms", elapsed.as_millis());
+            }
+            if let Err(e) = &res {
+                debug!("sql job failed: {}", e);
+            }
+            res
+        });
+        // the caller is gone if it was dropped while waiting
+        let _ = sender.send(res);
     }
 }
 
-impl<F, R> Runable for Job<F, R>
-where
-    F: Fn(&Connection) -> SqlResult<R>,
-    F: Send + 'static,
-    R: Send + 'static,
-{
-    fn run(&self, conn: &Connection) {
-        self.run_now(conn);
+/// A table of the shared cache is locked by another connection
+fn is_locked<R>(res: &SqlResult<R>) -> bool {
+    match res {
+        Err(SqlError::SqliteFailure(e, _)) => e.code == ErrorCode::DatabaseLocked,
+        _ => false,
     }
 }
 
-/// An async `rusqlite` handler. This handler communicates
-/// with the `Runtime` ans spawn sql jobs on it.
+/// An async `rusqlite` handler. Writes run on one connection in the
+/// order they were spawned, reads are spread over a pool of reader
+/// connections. Readers only see committed changes: a reader and the
+/// writer which need the same table wait for each other.
 pub struct AsyncSqlite {
-    inner: Arc<Mutex<std_mpsc::Sender<Box<dyn Runable>>>>,
+    writer: Queue,
+    readers: Queue,
     config: DatabaseConfig,
 }
 
@@ -54,93 +113,260 @@ impl AsyncSqlite {
     pub fn into_shared(self) -> SharedDb {
         Arc::new(self)
     }
-    /// Spawn a sql job
+
+    /// Spawn a sql job on the writer. It runs in a transaction, so it
+    /// can be retried while a reader locks its table.
     pub async fn spawn<F, R>(&self, f: Box<F>) -> Result<R, Box<dyn std::error::Error>>
     where
         F: Fn(&Connection) -> SqlResult<R>,
         F: Send + 'static,
         R: Send + 'static,
     {
-        let (res_tx, mut res_rx) = tokio_mpsc::channel::<SqlResult<R>>(100);
+        self.transaction(Box::new(move |tx: &Transaction| f(tx)))
+            .await
+    }
+
+    /// Spawn a sql job on the writer outside of a transaction, for the
+    /// statements which can't run in one. It isn't retried.
+    async fn spawn_exclusive<F, R>(&self, f: Box<F>) -> Result<R, Box<dyn std::error::Error>>
+    where
+        F: Fn(&Connection) -> SqlResult<R>,
+        F: Send + 'static,
+        R: Send + 'static,
+    {
+        let rx = self.send(&self.writer, Box::new(move |conn| f(conn)), false);
+        result(rx).await
+    }
+
+    /// Spawn a read-only sql job on one of the readers
+    pub async fn read<F, R>(&self, f: Box<F>) -> Result<R, Box<dyn std::error::Error>>
+    where
+        F: Fn(&Connection) -> SqlResult<R>,
+        F: Send + 'static,
+        R: Send + 'static,
+    {
+        let rx = self.send(&self.readers, Box::new(move |conn| f(conn)), true);
+        result(rx).await
+    }
+
+    /// Spawn a sql job on the writer which runs in one transaction. The
+    /// transaction is committed if the job returns `Ok`, else rolled back.
+    pub async fn transaction<F, R>(&self, f: Box<F>) -> Result<R, Box<dyn std::error::Error>>
+    where
+        F: Fn(&Transaction) -> SqlResult<R>,
+        F: Send + 'static,
+        R: Send + 'static,
+    {
+        let func = move |conn: &mut Connection| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let res = f(&tx)?;
+            tx.commit()?;
+            Ok(res)
+        };
+        // a failed transaction is rolled back, it is safe to run again
+        let rx = self.send(&self.writer, Box::new(func), true);
+        result(rx).await
+    }
 
+    /// Queues a job, the returned receiver gets its result. `None` if
+    /// the connections stopped.
+    fn send<R: Send + 'static>(
+        &self,
+        queue: &Queue,
+        func: Func<R>,
+        retry: bool,
+    ) -> Option<oneshot::Receiver<SqlResult<R>>> {
+        let (res_tx, res_rx) = oneshot::channel();
         let job = Box::new(Job {
-            func: f,
+            func,
             sender: res_tx,
+            request_id: logging::request_id(),
+            retry,
         });
+        queue.send(job).ok().map(|_| res_rx)
+    }
 
-        let tx = self.inner.clone();
-        tokio::task::spawn_blocking(move || {
-            tx.lock().unwrap().send(job).expect("failed to send job");
-        })
-        .await?;
-
-        match res_rx.recv().await {
-            Some(r) => Ok(r?),
-            None => unimplemented!(),
+    /// Saves the database after the queued jobs and stops the runtime.
+    /// Jobs spawned later fail.
+    pub async fn close(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.save().await?;
+        let mut closed = Vec::new();
+        for _ in 0..self.config.readers {
+            closed.push(close(&self.readers));
+        }
+        closed.push(close(&self.writer));
+        for rx in closed.into_iter().flatten() {
+            let _ = rx.await;
         }
+        Ok(())
     }
 
+    /// Write the database to the configured file
     pub async fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let name = self.config.name.clone();
-        self.spawn(Box::new(move |conn: &Connection| {
-            let mut dst = Connection::open(&name)?;
+        self.backup(&self.config.name).await
+    }
+
+    /// Write the database to `path`
+    pub async fn backup<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn std::error::Error>> {
+        let path = path.as_ref().to_path_buf();
+        self.spawn_exclusive(Box::new(move |conn: &Connection| {
+            let mut dst = Connection::open(&path)?;
             let backup = backup::Backup::new(conn, &mut dst)?;
-            backup.run_to_completion(
-                5,
-                time::Duration::from_millis(250),
-                Some(|p| {
-                    println!("{:?}", p);
-                }),
-            )
+            // no pause between the steps, the writer can't run other
+            // jobs in between anyway
+            backup.run_to_completion(1024, time::Duration::from_millis(0), None)
         }))
         .await?;
         Ok(())
     }
+
+    /// Rebuild the database to reclaim unused space
+    pub async fn vacuum(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.spawn_exclusive(Box::new(|conn: &Connection| conn.execute_batch("VACUUM")))
+            .await?;
+        Ok(())
+    }
 }
 
+/// Waits for the result of a job sent with `AsyncSqlite::send`
+async fn result<R>(
+    rx: Option<oneshot::Receiver<SqlResult<R>>>,
+) -> Result<R, Box<dyn std::error::Error>> {
+    let metrics = metrics::metrics();
+    let queued = time::Instant::now();
+    metrics.sqlite_queued();
+
+    let res = match rx {
+        Some(rx) => rx.await.ok(),
+        None => None,
+    };
+    metrics.sqlite_done(queued.elapsed());
+    match res {
+        Some(r) => Ok(r?),
+        None => Err("the database is closed".into()),
+    }
+}
+
+/// Sends `Close` to a connection, `None` if it stopped already
+fn close(queue: &Queue) -> Option<oneshot::Receiver<()>> {
+    let (tx, rx) = oneshot::channel();
+    queue.send(Box::new(Close { sender: tx })).ok().map(|_| rx)
+}
+
+/// Numbers the in-memory databases of the process
+static DATABASES: AtomicUsize = AtomicUsize::new(0);
+
 /// A `rusqlite` runtime wihch can be safly used in an async context.
-/// The runtime takes care of the execution of the statements.
+/// The runtime takes care of the execution of the statements on one
+/// writer and `readers` reader connections to the same in-memory
+/// database.
 ///
 /// # Example
 ///
 /// ```
-/// let (sqlite, rt) = Runtime::channel();
-/// rt.run(); // Spawn runtime on tokio
+/// let (sqlite, rt) = Runtime::channel(config);
+/// rt.run(); // Spawn the connections on tokio
 ///
 /// // execute sql
 /// sqlite.spawn(Box::new(|conn: &Connection| Ok(0))).await;
+/// sqlite.read(Box::new(|conn: &Connection| Ok(0))).await;
 /// ```
 pub struct Runtime {
+    uri: String,
     conn: Connection,
-    rx: std_mpsc::Receiver<Box<dyn Runable>>,
+    writer: std_mpsc::Receiver<Box<dyn Runable>>,
+    readers: std_mpsc::Receiver<Box<dyn Runable>>,
+    config: DatabaseConfig,
 }
 
 impl Runtime {
     /// Returns an `AsyncSqlite` handler to spawn sql jobs and
     /// the `Runtime` itself.
     pub fn channel(config: DatabaseConfig) -> (SharedDb, Runtime) {
-        let (tx, rx) = std_mpsc::channel();
-        let conn = Connection::open_in_memory().unwrap();
+        let (writer_tx, writer_rx) = std_mpsc::channel();
+        let (readers_tx, readers_rx) = std_mpsc::channel();
+        // a named in-memory database is shared by all connections of
+        // the runtime, and dropped with the last one
+        let uri = format!(
+            "file:moviebay-{}-{}?mode=memory&cache=shared",
+            std::process::id(),
+            DATABASES.fetch_add(1, Ordering::SeqCst)
+        );
+        let conn = open(&uri).unwrap();
+        conn.execute_batch("PRAGMA foreign_keys = ON").unwrap();
         (
             AsyncSqlite {
-                inner: Arc::new(Mutex::new(tx)),
-                config,
+                writer: writer_tx,
+                readers: readers_tx,
+                config: config.clone(),
             }
             .into_shared(),
-            Runtime { conn, rx },
+            Runtime {
+                uri,
+                conn,
+                writer: writer_rx,
+                readers: readers_rx,
+                config,
+            },
         )
     }
 
-    /// Spawn self on tokio executor
+    /// Load the database from the file at `path`, if it exists. Must be
+    /// called before `run`.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> SqlResult<bool> {
+        let path: PathBuf = path.as_ref().into();
+        if !path.is_file() {
+            return Ok(false);
+        }
+        self.conn
+            .restore(DatabaseName::Main, &path, None::<fn(backup::Progress)>)?;
+        Ok(true)
+    }
+
+    /// Spawn the writer and the readers on tokio executor
     pub fn run(self) {
+        let readers = Arc::new(Mutex::new(self.readers));
+        for _ in 0..self.config.readers {
+            let mut conn = open(&self.uri).unwrap();
+            conn.execute_batch("PRAGMA query_only = ON").unwrap();
+            let readers = readers.clone();
+            tokio::task::spawn_blocking(move || loop {
+                let job = readers.lock().unwrap().recv();
+                match job {
+                    Ok(job) => {
+                        let last = job.is_last();
+                        job.run(&mut conn);
+                        if last {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            });
+        }
+
+        let (mut conn, writer) = (self.conn, self.writer);
         tokio::task::spawn_blocking(move || {
-            while let Ok(job) = self.rx.recv() {
-                job.run(&self.conn);
+            while let Ok(job) = writer.recv() {
+                let last = job.is_last();
+                job.run(&mut conn);
+                if last {
+                    break;
+                }
             }
         });
     }
 }
 
+fn open(uri: &str) -> SqlResult<Connection> {
+    let flags = OpenFlags::SQLITE_OPEN_READ_WRITE
+        | OpenFlags::SQLITE_OPEN_CREATE
+        | OpenFlags::SQLITE_OPEN_URI
+        | OpenFlags::SQLITE_OPEN_SHARED_CACHE
+        | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    Connection::open_with_flags(uri, flags)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -152,6 +378,7 @@ mod tests {
         let func = async {
             let config = DatabaseConfig {
                 name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
             };
             let (sqlite, rt) = Runtime::channel(config);
             rt.run();
@@ -176,4 +403,254 @@ mod tests {
         let mut rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(func);
     }
+
+    #[test]
+    fn test_backup_load() {
+        let path = std::env::temp_dir().join("moviebay_test_backup_load.db");
+        let _ = std::fs::remove_file(&path);
+
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (sqlite, rt) = Runtime::channel(config.clone());
+            rt.run();
+            sqlite
+                .spawn(Box::new(|conn: &Connection| {
+                    conn.execute_batch(
+                        "CREATE TABLE person (name TEXT); INSERT INTO person VALUES ('jan');",
+                    )
+                }))
+                .await
+                .unwrap();
+            sqlite.backup(&path).await.unwrap();
+
+            let (sqlite, mut rt) = Runtime::channel(config);
+            assert!(rt.load(&path).unwrap());
+            rt.run();
+            let name: String = sqlite
+                .spawn(Box::new(|conn: &Connection| {
+                    conn.query_row("SELECT name FROM person", params![], |row| row.get(0))
+                }))
+                .await
+                .unwrap();
+            assert_eq!("jan", name);
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_close() {
+        let path = std::env::temp_dir().join("moviebay_test_close.db");
+        let _ = std::fs::remove_file(&path);
+
+        let func = async {
+            let config = DatabaseConfig {
+                name: path.to_string_lossy().into_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (sqlite, rt) = Runtime::channel(config);
+            rt.run();
+            sqlite
+                .spawn(Box::new(|conn: &Connection| {
+                    conn.execute_batch("CREATE TABLE person (name TEXT)")
+                }))
+                .await
+                .unwrap();
+            sqlite.close().await.unwrap();
+
+            assert!(path.is_file());
+            let res = sqlite
+                .spawn(Box::new(|conn: &Connection| {
+                    conn.execute_batch("DROP TABLE person")
+                }))
+                .await;
+            assert!(res.is_err());
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_transaction() {
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let (sqlite, rt) = Runtime::channel(config);
+            rt.run();
+            sqlite
+                .spawn(Box::new(|conn: &Connection| {
+                    conn.execute_batch("CREATE TABLE person (name TEXT UNIQUE)")
+                }))
+                .await
+                .unwrap();
+
+            // the second insert fails, the first one is rolled back
+            let res = sqlite
+                .transaction(Box::new(|tx: &Transaction| {
+                    tx.execute("INSERT INTO person VALUES ('jan')", params![])?;
+                    tx.execute("INSERT INTO person VALUES ('jan')", params![])
+                }))
+                .await;
+            assert!(res.is_err());
+
+            sqlite
+                .transaction(Box::new(|tx: &Transaction| {
+                    for name in ["jan", "kim"].iter() {
+                        tx.execute("INSERT INTO person VALUES (?1)", params![name])?;
+                    }
+                    Ok(())
+                }))
+                .await
+                .unwrap();
+
+            let count: i64 = sqlite
+                .read(Box::new(|conn: &Connection| {
+                    conn.query_row("SELECT COUNT(*) FROM person", params![], |row| row.get(0))
+                }))
+                .await
+                .unwrap();
+            assert_eq!(2, count);
+
+            // readers wait for the transaction instead of seeing half of it
+            let writer = sqlite.clone();
+            let insert = tokio::spawn(async move {
+                writer
+                    .transaction(Box::new(|tx: &Transaction| {
+                        tx.execute("INSERT INTO person VALUES ('eva')", params![])?;
+                        thread::sleep(time::Duration::from_millis(100));
+                        tx.execute("INSERT INTO person VALUES ('tom')", params![])
+                    }))
+                    .await
+                    .unwrap();
+            });
+            tokio::time::delay_for(time::Duration::from_millis(20)).await;
+            let count: i64 = sqlite
+                .read(Box::new(|conn: &Connection| {
+                    conn.query_row("SELECT COUNT(*) FROM person", params![], |row| row.get(0))
+                }))
+                .await
+                .unwrap();
+            assert_eq!(4, count);
+            insert.await.unwrap();
+
+            let res = sqlite
+                .read(Box::new(|conn: &Connection| {
+                    conn.execute("DELETE FROM person", params![])
+                }))
+                .await;
+            assert!(res.is_err());
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+
+    /// Compares a bulk insert with one job per row against one
+    /// transaction, and concurrent reads on the writer against the
+    /// readers. Run with
+    /// `cargo test --release bench_sqlite -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_sqlite() {
+        const ROWS: i64 = 10_000;
+        const READS: usize = 2_000;
+        const TASKS: usize = 16;
+
+        let func = async {
+            let config = DatabaseConfig {
+                name: "test.db".to_owned(),
+                ..DatabaseConfig::default()
+            };
+            let readers = config.readers;
+            let (sqlite, rt) = Runtime::channel(config);
+            rt.run();
+            sqlite
+                .spawn(Box::new(|conn: &Connection| {
+                    conn.execute_batch(
+                        "CREATE TABLE movie (id INTEGER PRIMARY KEY, title TEXT, year INTEGER)",
+                    )
+                }))
+                .await
+                .unwrap();
+
+            let start = time::Instant::now();
+            for i in 0..ROWS {
+                sqlite
+                    .spawn(Box::new(move |conn: &Connection| {
+                        conn.execute(
+                            "INSERT INTO movie (title, year) VALUES (?1, ?2)",
+                            params![format!("movie {}", i), 1950 + i % 70],
+                        )
+                    }))
+                    .await
+                    .unwrap();
+            }
+            report("insert, one job per row", ROWS as usize, start.elapsed());
+
+            let start = time::Instant::now();
+            sqlite
+                .transaction(Box::new(|tx: &Transaction| {
+                    let mut stmt = tx.prepare("INSERT INTO movie (title, year) VALUES (?1, ?2)")?;
+                    for i in 0..ROWS {
+                        stmt.execute(params![format!("movie {}", i), 1950 + i % 70])?;
+                    }
+                    Ok(())
+                }))
+                .await
+                .unwrap();
+            report("insert, one transaction", ROWS as usize, start.elapsed());
+
+            for &on_readers in [false, true].iter() {
+                let start = time::Instant::now();
+                let tasks = (0..TASKS).map(|t| {
+                    let sqlite = sqlite.clone();
+                    tokio::spawn(async move {
+                        for i in 0..READS / TASKS {
+                            let year = 1950 + ((t * READS + i) % 70) as i64;
+                            let job = Box::new(move |conn: &Connection| {
+                                conn.query_row(
+                                    "SELECT COUNT(*) FROM movie WHERE year=?1",
+                                    params![year],
+                                    |row| row.get::<_, i64>(0),
+                                )
+                            });
+                            if on_readers {
+                                sqlite.read(job).await.unwrap();
+                            } else {
+                                sqlite.spawn(job).await.unwrap();
+                            }
+                        }
+                    })
+                });
+                futures::future::join_all(tasks).await;
+                let name = if on_readers {
+                    format!("read, {} readers", readers)
+                } else {
+                    "read, writer only".to_owned()
+                };
+                report(&name, READS, start.elapsed());
+            }
+        };
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(func);
+    }
+
+    fn report(name: &str, ops: usize, elapsed: time::Duration) {
+        println!(
+            "{:<26} {:>8.0} ops/s ({}ms)",
+            name,
+            ops as f64 / elapsed.as_secs_f64(),
+            elapsed.as_millis()
+        );
+    }
 }
@@ -3,6 +3,7 @@ mod database;
 use std::sync::Arc;
 
 pub use database::{AsyncSqlite, Runtime};
-pub use rusqlite::{params, Connection};
+pub use rusqlite::types::Value;
+pub use rusqlite::{params, Connection, OptionalExtension, Result as SqlResult, Row, Transaction};
 
 pub type SharedDb = Arc<AsyncSqlite>;
@@ -0,0 +1,199 @@
+use crate::config::SharedCfg;
+use crate::ffmpeg::FFmpeg;
+use crate::model::{Movie, MovieTable, Table, Trickplay, TrickplayTable};
+use crate::sqlite::SharedDb;
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Position of the fallback poster, as fraction of the duration. The
+/// first minutes are usually logos and credits.
+const POSTER_AT: f64 = 0.1;
+
+/// Width of the fallback poster
+const POSTER_WIDTH: i32 = 342;
+
+/// Directory of the generated images of a movie
+pub fn movie_dir(config: &SharedCfg, movie_id: i32) -> PathBuf {
+    PathBuf::from(&config.thumbnails.dir).join(movie_id.to_string())
+}
+
+/// Path of the generated poster of a movie
+pub fn poster_path(config: &SharedCfg, movie_id: i32) -> PathBuf {
+    movie_dir(config, movie_id).join("poster.jpg")
+}
+
+/// Path of a sprite sheet of a movie, sheets are numbered from 1
+pub fn sprite_path(config: &SharedCfg, movie_id: i32, sheet: i32) -> PathBuf {
+    movie_dir(config, movie_id).join(format!("sprite_{:03}.jpg", sheet))
+}
+
+/// Builds a WebVTT thumbnail track. Every cue points to the region of
+/// its thumbnail in a sprite sheet using a `#xywh=` media fragment.
+/// `url` returns the url of a sprite sheet by number.
+pub fn vtt<F: Fn(i32) -> String>(trickplay: &Trickplay, url: F) -> String {
+    let per_sheet = trickplay.columns * trickplay.rows;
+    let mut vtt = "WEBVTT\n".to_owned();
+
+    for i in 0..trickplay.count {
+        let start = f64::from(i * trickplay.interval);
+        let end = f64::from((i + 1) * trickplay.interval).min(trickplay.duration);
+        let tile = i % per_sheet;
+        let x = (tile % trickplay.columns) * trickplay.width;
+        let y = (tile / trickplay.columns) * trickplay.height;
+
+        write!(
+            vtt,
+            "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+            timestamp(start),
+            timestamp(end),
+            url(i / per_sheet + 1),
+            x,
+            y,
+            trickplay.width,
+            trickplay.height
+        )
+        .unwrap();
+    }
+    vtt
+}
+
+fn timestamp(secs: f64) -> String {
+    let millis = (secs * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Generates fallback posters and seek preview thumbnails, run by the
+/// `thumbnails` job
+pub struct Thumbnailer {
+    config: SharedCfg,
+    db: SharedDb,
+}
+
+impl Thumbnailer {
+    pub fn new(config: SharedCfg, db: SharedDb) -> Thumbnailer {
+        Thumbnailer { config, db }
+    }
+
+    /// Movies which don't have thumbnails yet
+    pub async fn pending(&self) -> Result<Vec<Movie>, Error> {
+        let movies = MovieTable::new(self.db.clone())
+            .without_trickplay()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(movies)
+    }
+
+    /// Generates the images of one movie, the poster only if TMDB has
+    /// none. FFmpeg blocks, so it runs on the blocking thread pool.
+    pub async fn generate(&self, movie: &Movie) -> Result<Trickplay, Error> {
+        let ffmpeg = FFmpeg::new(Arc::new(self.config.ffmpeg.clone()));
+        let config = self.config.clone();
+        let movie_id = movie.id;
+        let file = movie.file_path.clone();
+        let has_poster = !movie.poster_path.is_empty();
+
+        let (trickplay, probe) = tokio::task::spawn_blocking(move || {
+            let cfg = &config.thumbnails;
+            let dir = movie_dir(&config, movie_id);
+            fs::create_dir_all(&dir)?;
+
+            let probe = ffmpeg.probe(&file)?;
+            // TMDB art is better than any frame
+            if !has_poster {
+                ffmpeg.extract_frame(
+                    &file,
+                    probe.duration * POSTER_AT,
+                    POSTER_WIDTH,
+                    &poster_path(&config, movie_id),
+                )?;
+            }
+
+            // keep the aspect ratio, ffmpeg needs even sizes
+            let height = (cfg.width * probe.height / probe.width.max(1)) / 2 * 2;
+            ffmpeg.sprites(
+                &file,
+                cfg.interval,
+                (cfg.width, height),
+                (cfg.columns, cfg.rows),
+                &dir,
+            )?;
+
+            let interval = cfg.interval.max(1) as i32;
+            let trickplay = Trickplay {
+                movie_id,
+                duration: probe.duration,
+                interval,
+                width: cfg.width,
+                height,
+                columns: cfg.columns as i32,
+                rows: cfg.rows as i32,
+                count: (probe.duration / f64::from(interval)).ceil() as i32,
+            };
+            Ok::<_, Error>((trickplay, probe))
+        })
+        .await??;
+
+        TrickplayTable::new(self.db.clone())
+            .save(trickplay.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+        MovieTable::new(self.db.clone())
+            .set_resolution(movie_id, probe.height)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(trickplay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vtt() {
+        let trickplay = Trickplay {
+            movie_id: 1,
+            duration: 25.5,
+            interval: 10,
+            width: 160,
+            height: 90,
+            columns: 2,
+            rows: 1,
+            count: 3,
+        };
+        let vtt = vtt(&trickplay, |sheet| {
+            format!("/movies/1/trickplay/{}.jpg", sheet)
+        });
+
+        assert_eq!(
+            "WEBVTT
+
+00:00:00.000 --> 00:00:10.000
+/movies/1/trickplay/1.jpg#xywh=0,0,160,90
+
+00:00:10.000 --> 00:00:20.000
+/movies/1/trickplay/1.jpg#xywh=160,0,160,90
+
+00:00:20.000 --> 00:00:25.500
+/movies/1/trickplay/2.jpg#xywh=0,0,160,90
+",
+            vtt
+        );
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!("00:00:00.000", timestamp(0.0));
+        assert_eq!("01:02:03.450", timestamp(3723.45));
+    }
+}
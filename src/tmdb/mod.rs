@@ -1,40 +1,145 @@
 mod types;
 use crate::config::TmdbConfig;
+use crate::metrics::metrics;
 use bytes::buf::BufExt as _;
 use hyper::Client;
 use hyper_tls::HttpsConnector;
-use types::{Configuration, MovieSearch};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::de::DeserializeOwned;
+use types::{Credits, ReleaseDates};
+pub use types::{MovieResult, MovieSearch};
+
+/// Base url of posters for external clients, the `poster_path` of a
+/// movie is appended
+pub const POSTERS: &str = "https://image.tmdb.org/t/p/w500";
+
+/// Number of cast members of a movie which are kept, by billing
+const CAST_LIMIT: usize = 15;
+
+/// Crew jobs which are kept along with the cast
+const CREW_JOBS: [&str; 4] = [
+    "Director",
+    "Screenplay",
+    "Writer",
+    "Original Music Composer",
+];
+
+/// The movie genres of TMDB by id, as listed by `/genre/movie/list`
+const GENRES: [(i32, &str); 19] = [
+    (28, "Action"),
+    (12, "Adventure"),
+    (16, "Animation"),
+    (35, "Comedy"),
+    (80, "Crime"),
+    (99, "Documentary"),
+    (18, "Drama"),
+    (10751, "Family"),
+    (14, "Fantasy"),
+    (36, "History"),
+    (27, "Horror"),
+    (10402, "Music"),
+    (9648, "Mystery"),
+    (10749, "Romance"),
+    (878, "Science Fiction"),
+    (10770, "TV Movie"),
+    (53, "Thriller"),
+    (10752, "War"),
+    (37, "Western"),
+];
+
+/// The name of a TMDB genre, `None` for unknown ids
+pub fn genre(id: i32) -> Option<&'static str> {
+    GENRES.iter().find(|(i, _)| *i == id).map(|(_, name)| *name)
+}
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>;
 
-pub async fn fetch_configuration(config: TmdbConfig) -> Result<Configuration> {
+pub async fn search_movie(config: TmdbConfig, name: &str, year: i32) -> Result<MovieSearch> {
+    let api_key = config.api_key.ok_or("tmdb.api_key is not set")?;
+    let search = fetch_search(&api_key, name, year).await;
+    metrics().tmdb(search.is_ok());
+    search
+}
+
+/// The certifications of a movie by country, e.g. `("DE", "12")`.
+/// Countries without a certification are left out.
+pub async fn certifications(config: TmdbConfig, tmdb_id: i32) -> Result<Vec<(String, String)>> {
+    let api_key = config.api_key.ok_or("tmdb.api_key is not set")?;
     let url = format!(
-        "https://api.themoviedb.org/3/configuration?api_key={}",
-        &config.api_key
+        "https://api.themoviedb.org/3/movie/{}/release_dates?api_key={}",
+        tmdb_id, api_key
     );
+    let dates = fetch::<ReleaseDates>(&url).await;
+    metrics().tmdb(dates.is_ok());
 
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, hyper::Body>(https);
-    let res = client.get(url.parse().unwrap()).await?;
-    let body = hyper::body::aggregate(res).await?;
+    // a country lists one date per kind of release, the first
+    // certified one is taken
+    let certifications = dates?
+        .results
+        .into_iter()
+        .filter_map(|country| {
+            let certification = country
+                .release_dates
+                .into_iter()
+                .map(|date| date.certification.trim().to_owned())
+                .find(|certification| !certification.is_empty())?;
+            Some((country.iso_3166_1, certification))
+        })
+        .collect();
+    Ok(certifications)
+}
 
-    let configuration: Configuration = serde_json::from_reader(body.reader())?;
+/// The names of the leading cast and of the directors, writers and
+/// composers of a movie, each once
+pub async fn credits(config: TmdbConfig, tmdb_id: i32) -> Result<Vec<String>> {
+    let api_key = config.api_key.ok_or("tmdb.api_key is not set")?;
+    let url = format!(
+        "https://api.themoviedb.org/3/movie/{}/credits?api_key={}",
+        tmdb_id, api_key
+    );
+    let credits = fetch::<Credits>(&url).await;
+    metrics().tmdb(credits.is_ok());
+    let credits = credits?;
 
-    Ok(configuration)
+    let mut cast = credits.cast;
+    cast.sort_by_key(|member| member.order);
+    let crew = credits
+        .crew
+        .into_iter()
+        .filter(|member| CREW_JOBS.contains(&member.job.as_str()))
+        .map(|member| member.name);
+    let mut names = Vec::new();
+    for name in cast
+        .into_iter()
+        .take(CAST_LIMIT)
+        .map(|m| m.name)
+        .chain(crew)
+    {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    Ok(names)
 }
 
-pub async fn search_movie(config: TmdbConfig, name: &str, year: i32) -> Result<MovieSearch> {
+async fn fetch_search(api_key: &str, name: &str, year: i32) -> Result<MovieSearch> {
     let url = format!(
         "https://api.themoviedb.org/3/search/movie?api_key={}&language=en&query={}&year={}",
-        &config.api_key, name, year
+        api_key,
+        utf8_percent_encode(name, NON_ALPHANUMERIC),
+        year
     );
+    fetch(&url).await
+}
 
+async fn fetch<T: DeserializeOwned>(url: &str) -> Result<T> {
     let https = HttpsConnector::new();
     let client = Client::builder().build::<_, hyper::Body>(https);
-    let res = client.get(url.parse().unwrap()).await?;
+    let res = client.get(url.parse()?).await?;
+    if !res.status().is_success() {
+        return Err(format!("TMDB responded with {}", res.status()).into());
+    }
     let body = hyper::body::aggregate(res).await?;
 
-    let search: MovieSearch = serde_json::from_reader(body.reader())?;
-
-    Ok(search)
+    Ok(serde_json::from_reader(body.reader())?)
 }
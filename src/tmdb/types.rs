@@ -1,43 +1,62 @@
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
-pub struct Configuration {
-    images: Images,
+pub struct MovieResult {
+    pub poster_path: Option<String>,
+    pub adult: bool,
+    pub overview: String,
+    pub release_date: String,
+    pub genre_ids: Vec<i32>,
+    pub id: i32,
+    pub original_title: String,
+    pub original_language: String,
+    pub title: String,
+    pub backdrop_path: Option<String>,
+    pub popularity: f32,
+    pub vote_count: i32,
+    pub video: bool,
+    pub vote_average: f32,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct Images {
-    base_url: String,
-    secure_base_url: String,
-    backdrop_sizes: Vec<String>,
-    logo_sizes: Vec<String>,
-    poster_sizes: Vec<String>,
-    profile_sizes: Vec<String>,
-    still_sizes: Vec<String>,
+pub struct MovieSearch {
+    pub page: i32,
+    pub total_results: i32,
+    pub total_pages: i32,
+    pub results: Vec<MovieResult>,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct MovieResult {
-    poster_path: String,
-    adult: bool,
-    overview: String,
-    release_date: String,
-    genre_ids: Vec<i32>,
-    id: i32,
-    original_title: String,
-    original_language: String,
-    title: String,
-    backdrop_path: Option<String>,
-    popularity: f32,
-    vote_count: i32,
-    video: bool,
-    vote_average: f32,
+pub struct ReleaseDate {
+    pub certification: String,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct MovieSearch {
-    page: i32,
-    total_results: i32,
-    total_pages: i32,
-    results: Vec<MovieResult>,
+pub struct CountryReleases {
+    pub iso_3166_1: String,
+    pub release_dates: Vec<ReleaseDate>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReleaseDates {
+    pub results: Vec<CountryReleases>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CastMember {
+    pub name: String,
+    /// Position in the billing, starring roles first
+    pub order: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CrewMember {
+    pub name: String,
+    pub job: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Credits {
+    pub cast: Vec<CastMember>,
+    pub crew: Vec<CrewMember>,
 }
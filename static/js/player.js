@@ -1,12 +1,342 @@
+var TMDB_IMAGES = "https://image.tmdb.org/t/p/w154";
+var PAGE_SIZE = 30;
+
+var Api = {
+  token: null,
+  init: function() {
+    this.token = window.localStorage.getItem("moviebay.token");
+  },
+  request: function(method, path, body) {
+    var self = this;
+    var options = { method: method, headers: {} };
+    if (this.token) {
+      options.headers["Authorization"] = "Bearer " + this.token;
+    }
+    if (body !== undefined) {
+      options.headers["Content-Type"] = "application/json";
+      options.body = JSON.stringify(body);
+    }
+
+    return fetch(path, options).then(function(res) {
+      if (res.status === 401 && path !== "/auth/login") {
+        self.setToken(null);
+        Library.showLogin();
+      }
+      if (!res.ok) {
+        return res.text().then(function(text) {
+          throw new Error(text || res.statusText);
+        });
+      }
+      if (res.status === 204) {
+        return null;
+      }
+      return res.json();
+    });
+  },
+  get: function(path) {
+    return this.request("GET", path);
+  },
+  setToken: function(token) {
+    this.token = token;
+    if (token) {
+      window.localStorage.setItem("moviebay.token", token);
+    } else {
+      window.localStorage.removeItem("moviebay.token");
+    }
+  },
+  login: function(name, password) {
+    var self = this;
+    return this.request("POST", "/auth/login", { name: name, password: password })
+      .then(function(login) {
+        self.setToken(login.token);
+        return login.user;
+      });
+  },
+  logout: function() {
+    var self = this;
+    return this.request("POST", "/auth/logout").then(function() {
+      self.setToken(null);
+    });
+  },
+  // for requests made by the browser itself, such as <img> and <video>
+  withToken: function(path) {
+    var sep = path.indexOf("?") === -1 ? "?" : "&";
+    return path + sep + "access_token=" + encodeURIComponent(this.token);
+  },
+  streamUrl: function(id, start) {
+    return this.withToken("/stream/" + id + "?start=" + Math.floor(start || 0));
+  },
+};
+
+var Library = {
+  offset: 0,
+  total: 0,
+  init: function() {
+    var self = this;
+    Api.init();
+    Player.init();
+
+    $("#login").on("submit", function(e) {
+      e.preventDefault();
+      var form = this;
+      Api.login(form.name.value, form.password.value)
+        .then(function() {
+          form.reset();
+          self.show();
+        })
+        .catch(function(err) {
+          $("#login .error").text(err.message);
+        });
+    });
+    $("#logout").on("click", function() {
+      Api.logout().then(function() { self.showLogin(); });
+    });
+
+    var timer = null;
+    $("#search").on("input", function() {
+      clearTimeout(timer);
+      timer = setTimeout(function() { self.load(0); }, 300);
+    });
+    $("#sort").on("change", function() { self.load(0); });
+    $("#movies .prev").on("click", function() {
+      self.load(Math.max(0, self.offset - PAGE_SIZE));
+    });
+    $("#movies .next").on("click", function() {
+      self.load(self.offset + PAGE_SIZE);
+    });
+
+    if (Api.token) {
+      this.show();
+    } else {
+      this.showLogin();
+    }
+  },
+  showLogin: function() {
+    Player.stop();
+    $("#library").addClass("hidden");
+    $("#login").removeClass("hidden");
+  },
+  show: function() {
+    $("#login").addClass("hidden");
+    $("#library").removeClass("hidden");
+    this.load(this.offset);
+    this.loadContinue();
+  },
+  load: function(offset) {
+    var self = this;
+    var q = $("#search").val().trim();
+
+    if (q) {
+      return Api.get("/search?q=" + encodeURIComponent(q)).then(function(res) {
+        self.offset = 0;
+        self.total = res.movies.length;
+        self.render($("#movies .movies"), res.movies);
+        self.renderPages(res.movies.length);
+      });
+    }
+
+    var sort = $("#sort").val().split(":");
+    var path = "/movies/?limit=" + PAGE_SIZE + "&offset=" + offset +
+      "&sort=" + sort[0] + "&order=" + sort[1];
+    return Api.get(path).then(function(page) {
+      self.offset = page.offset;
+      self.total = page.total;
+      self.render($("#movies .movies"), page.items);
+      self.renderPages(page.items.length);
+    });
+  },
+  loadContinue: function() {
+    var self = this;
+    return Api.get("/users/me/continue").then(function(movies) {
+      $("#continue").toggleClass("hidden", movies.length === 0);
+      self.render($("#continue .movies"), movies);
+    });
+  },
+  renderPages: function(count) {
+    var page = Math.floor(this.offset / PAGE_SIZE) + 1;
+    var pages = Math.max(1, Math.ceil(this.total / PAGE_SIZE));
+    $("#movies .page").text(page + " / " + pages);
+    $("#movies .prev").prop("disabled", this.offset === 0);
+    $("#movies .next").prop("disabled", this.offset + count >= this.total);
+  },
+  render: function(el, movies) {
+    el.empty();
+    movies.forEach(function(movie) {
+      var item = $("<div class='movie'></div>");
+      var img = $("<img>").attr("alt", movie.title);
+      if (movie.poster_path) {
+        img.attr("src", TMDB_IMAGES + movie.poster_path);
+      } else {
+        img.attr("src", Api.withToken("/movies/" + movie.id + "/poster"));
+      }
+      item.append(img);
+      item.append($("<div class='title'></div>").text(movie.title + " (" + movie.release_year + ")"));
+      if (movie.watched) {
+        item.append($("<span class='watched'>&#10003;</span>"));
+      } else if (movie.resume_position > 0) {
+        item.append($("<div class='resume'></div>").text("Resume at " + Player.formatTime(movie.resume_position)));
+      }
+      item.on("click", function() { Player.play(movie); });
+      el.append(item);
+    });
+  },
+};
+
 var Player = {
   data: null,
-  init: function() {},
-  play: function(data) {
+  cues: [],
+  start: 0,
+  reported: 0,
+  init: function() {
     var self = this;
+    var video = $("#video");
+
+    video.on("timeupdate", function() {
+      // report every 10 seconds of playback
+      var position = self.position();
+      if (Math.abs(position - self.reported) >= 10) {
+        self.report(position);
+      }
+    });
+    video.on("pause", function() { self.report(self.position()); });
+    video.on("ended", function() {
+      if (self.data) {
+        Api.request("PUT", "/movies/" + self.data.id + "/watched");
+      }
+    });
+    $("#player .close").on("click", function() {
+      self.stop();
+      Library.show();
+    });
+
+    // the stream is transcoded live, so seeking restarts it at the position
+    var scrubber = $("#player .scrubber");
+    scrubber.on("mousemove", function(e) {
+      self.preview(self.scrubTime(e));
+    });
+    scrubber.on("click", function(e) {
+      self.seek(self.scrubTime(e));
+    });
+  },
+  loadTrickplay: function(id) {
+    var self = this;
+    this.cues = [];
+    $("#player .scrubber").addClass("hidden");
+
+    fetch("/movies/" + id + "/trickplay.vtt", {
+      headers: { "Authorization": "Bearer " + Api.token },
+    }).then(function(res) {
+      return res.ok ? res.text() : "";
+    }).then(function(text) {
+      if (!self.data || self.data.id !== id) {
+        return;
+      }
+      self.cues = Player.parseVtt(text);
+      $("#player .scrubber").toggleClass("hidden", self.cues.length === 0);
+    });
+  },
+  // parses the cues of a thumbnail track into {start, end, url, x, y, w, h}
+  parseVtt: function(text) {
+    var cues = [];
+    text.split(/\n\n+/).forEach(function(block) {
+      var lines = block.trim().split("\n");
+      var times = (lines[0] || "").split(" --> ");
+      var target = /^(.*)#xywh=(\d+),(\d+),(\d+),(\d+)$/.exec(lines[1] || "");
+      if (times.length !== 2 || !target) {
+        return;
+      }
+      cues.push({
+        start: Player.parseTime(times[0]),
+        end: Player.parseTime(times[1]),
+        url: target[1],
+        x: +target[2], y: +target[3], w: +target[4], h: +target[5],
+      });
+    });
+    return cues;
+  },
+  parseTime: function(value) {
+    return value.split(":").reduce(function(secs, part) {
+      return secs * 60 + parseFloat(part);
+    }, 0);
+  },
+  duration: function() {
+    return this.cues.length ? this.cues[this.cues.length - 1].end : 0;
+  },
+  scrubTime: function(e) {
+    var scrubber = $("#player .scrubber");
+    var fraction = (e.pageX - scrubber.offset().left) / scrubber.width();
+    return Math.max(0, Math.min(1, fraction)) * this.duration();
+  },
+  preview: function(time) {
+    var cue = this.cues.find(function(c) { return time >= c.start && time < c.end; });
+    var preview = $("#player .scrubber .preview");
+    if (!cue) {
+      return;
+    }
+    preview.css({
+      left: (time / this.duration() * 100) + "%",
+      width: cue.w,
+      height: cue.h,
+      backgroundImage: "url(\"" + cue.url + "\")",
+      backgroundPosition: (-cue.x) + "px " + (-cue.y) + "px",
+    });
+    preview.find(".time").text(this.formatTime(time, true));
+  },
+  seek: function(time) {
+    if (!this.data) {
+      return;
+    }
+    this.start = time;
+    this.report(time);
+    var videoEl = $("#video")[0];
+    videoEl.src = Api.streamUrl(this.data.id, time);
+    videoEl.play();
+  },
+  play: function(data) {
     this.data = data;
-    console.log("play " + data);
+    this.start = data.watched ? 0 : (data.resume_position || 0);
+    this.reported = this.start;
+    console.log("play " + data.title);
+
+    $("#player .title").text(data.title + " (" + data.release_year + ")");
+    $("#player .overview").text(data.overview || "");
+    $("#player").removeClass("hidden");
 
-    // var videoEl = $("#video");
-    
+    var videoEl = $("#video")[0];
+    videoEl.src = Api.streamUrl(data.id, this.start);
+    videoEl.play();
+    this.loadTrickplay(data.id);
+  },
+  stop: function() {
+    var videoEl = $("#video")[0];
+    if (this.data && !videoEl.ended) {
+      this.report(this.position());
+    }
+    this.data = null;
+    videoEl.pause();
+    videoEl.removeAttribute("src");
+    videoEl.load();
+    $("#player").addClass("hidden");
+  },
+  // the stream starts at `start`, so the position in the movie is offset
+  position: function() {
+    return this.start + $("#video")[0].currentTime;
+  },
+  report: function(position) {
+    if (!this.data || position <= 0) {
+      return;
+    }
+    this.reported = position;
+    Api.request("POST", "/movies/" + this.data.id + "/progress", { position: position });
+  },
+  formatTime: function(seconds, withSeconds) {
+    var h = Math.floor(seconds / 3600);
+    var m = Math.floor((seconds % 3600) / 60);
+    var text = h + ":" + (m < 10 ? "0" : "") + m;
+    if (withSeconds) {
+      var s = Math.floor(seconds % 60);
+      text += ":" + (s < 10 ? "0" : "") + s;
+    }
+    return text;
   },
-};
\ No newline at end of file
+};